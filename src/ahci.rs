@@ -1,490 +1,5253 @@
-use alloc::alloc::alloc_zeroed;
-use core::{alloc::Layout, marker::PhantomData, ptr::NonNull};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    alloc::Layout,
+    cell::Cell,
+    iter,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut, Range},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU32, Ordering, compiler_fence},
+};
 
-use log::{debug, error, info, warn};
+use log::debug;
 use volatile::VolatilePtr;
 
+#[cfg(feature = "xts")]
+use crate::crypt::SectorCipher;
 use crate::{
     Hal,
     ata::{
-        ATA_CMD_ID_ATA, ATA_CMD_READ, ATA_CMD_READ_EXT, ATA_CMD_WRITE, ATA_CMD_WRITE_EXT,
-        ATA_ID_FW_REV, ATA_ID_FW_REV_LEN, ATA_ID_PROD, ATA_ID_PROD_LEN, ATA_ID_SERNO,
-        ATA_ID_SERNO_LEN, ATA_ID_WORDS, SATA_FIS_TYPE_REGISTER_H2D, ata_id_has_lba48,
-        ata_id_n_sectors, ata_id_to_string,
+        ATA_ABORTED, ATA_AMAC_GET_NATIVE_MAX, ATA_AMAC_SET_ACCESSIBLE_MAX, ATA_CMD_AMAC,
+        ATA_CMD_DSM, ATA_CMD_FLUSH, ATA_CMD_FLUSH_EXT, ATA_CMD_FPDMA_READ, ATA_CMD_FPDMA_WRITE,
+        ATA_CMD_ID_ATA, ATA_CMD_NCQ_NON_DATA, ATA_CMD_PMP_READ, ATA_CMD_PMP_WRITE, ATA_CMD_READ,
+        ATA_CMD_READ_EXT, ATA_CMD_READ_LOG_EXT, ATA_CMD_READ_MULTI, ATA_CMD_READ_MULTI_EXT,
+        ATA_CMD_SET_FEATURES, ATA_CMD_SET_MULTI, ATA_CMD_SMART, ATA_CMD_VERIFY, ATA_CMD_VERIFY_EXT,
+        ATA_CMD_WRITE, ATA_CMD_WRITE_EXT, ATA_CMD_WRITE_FUA_EXT, ATA_CMD_WRITE_MULTI,
+        ATA_CMD_WRITE_MULTI_EXT, ATA_DEVSTAT_GENERAL, ATA_DEVSTAT_SSD, ATA_DSM_MAX_RANGE_LEN,
+        ATA_DSM_RANGES_PER_BLOCK, ATA_DSM_TRIM, ATA_ID_FW_REV, ATA_ID_FW_REV_LEN, ATA_ID_PROD,
+        ATA_ID_PROD_LEN, ATA_ID_SERNO, ATA_ID_SERNO_LEN, ATA_ID_WORDS, ATA_LOG_DEVICE_STATS,
+        ATA_LOG_SCT_COMMAND, ATA_SCT_ACTION_ERC, ATA_SCT_ACTION_WRITE_SAME, ATA_SCT_ERC_READ,
+        ATA_SCT_ERC_SET, ATA_SCT_ERC_WRITE, ATA_SCT_WRITE_SAME_PATTERN_FG, ATA_SMART_LBAH_PASS,
+        ATA_SMART_LBAM_PASS, ATA_SMART_WRITE_LOG, ATA_SRST, SATA_DEVSLP,
+        SATA_FIS_TYPE_REGISTER_D2H, SATA_FIS_TYPE_REGISTER_H2D, SETFEATURES_AAM_OFF,
+        SETFEATURES_AAM_ON, SETFEATURES_APM_OFF, SETFEATURES_APM_ON, SETFEATURES_RA_OFF,
+        SETFEATURES_RA_ON, SETFEATURES_SATA_ENABLE, SETFEATURES_WC_OFF, SETFEATURES_WC_ON,
+        ata_id_alignment_offset, ata_id_devslp_enabled, ata_id_has_aam, ata_id_has_amac,
+        ata_id_has_apm, ata_id_has_devslp, ata_id_has_gpl, ata_id_has_sct_erc, ata_id_has_wwn,
+        ata_id_is_ssd, ata_id_logical_sector_size, ata_id_max_multiple, ata_id_n_sectors,
+        ata_id_physical_sector_size, ata_id_smart_enabled, ata_id_to_string, ata_id_wwn,
+    },
+    config::DriverConfig,
+    controller::AhciController,
+    diag::{EngineState, PortDiagnostics, RegisterMismatch, ShadowedRegister},
+    disk_id::DiskId,
+    dma::DmaBuffer,
+    erase::EraseReport,
+    error::AhciError,
+    event::{AhciEvent, HungCommandCheck, LinkPowerState},
+    filter::CommandFilter,
+    hal::{
+        DmaAddr, DmaCoherence, DmaDirection, HalClock, wait_all_timeout, wait_until_idle,
+        wait_until_timeout,
+    },
+    identify::{Identify, IdentityChange},
+    irq::{IrqCoalescing, IrqHandler, IrqStatus},
+    lba::{
+        MAX_LBA28, MAX_LBA48, MAX_SECTORS_LBA28, MAX_SECTORS_LBA48, byte_range_to_lba_range,
+        sector_count_field,
     },
-    hal::wait_until_timeout,
+    maintenance::{Maintenance, MaintenanceState},
     mmio::{
         AhciMmio, AhciMmioVolatileFieldAccess, CAP, GenericHostControlVolatileFieldAccess, ICC,
-        PortRegisters, PortRegistersVolatileFieldAccess, PxCMD, PxI,
+        ISS as LinkSpeed, PortRegisters, PortRegistersVolatileFieldAccess, PxCMD, PxI, PxSCTL,
+        PxSERR,
     },
+    negotiate::Negotiated,
+    pmp::{
+        PMP_CONTROL, PMP_GSCR_PORT_INFO, PMP_GSCR_PROD_ID, PMP_GSCR_REV, PMP_PSCR_SCONTROL,
+        PMP_PSCR_SERROR, PMP_PSCR_SSTATUS, PortMultiplier,
+    },
+    policy::{DrivePolicy, FeatureLevel, ZeroWriteOffload},
+    port::Port,
+    probe::{DeviceType, ProbeMode, ProbeOutcome, ProbeReport, ProbeTimeouts},
+    sink::{ErrorRecord, ErrorSink, Recovery},
+    stats::AhciStats,
+    taskfile::{DataDirection, TaskFile, TaskFileResult, VendorCommand},
+    timeouts::{RetryPolicy, Timeouts},
     types::{
-        AHCI_MAX_BYTES_PER_CMD, AHCI_MAX_BYTES_PER_SG, AHCI_MAX_SG, ahci_cmd_hdr, ahci_cmd_list,
-        ahci_cmd_tbl, ahci_cmd_tblVolatileFieldAccess, ahci_rx_fis, ahci_sg, sata_fis_h2d,
+        AHCI_MAX_BYTES_PER_CMD, AHCI_MAX_BYTES_PER_SG, AHCI_MAX_CMDS, AHCI_MAX_SG, ahci_cmd_hdr,
+        ahci_cmd_list, ahci_cmd_tbl, ahci_cmd_tblVolatileFieldAccess, ahci_rx_fis, ahci_sg,
+        sata_fis_h2d,
+    },
+    vendor::VendorRegisters,
+    verbosity::{port_debug, port_error, port_info, port_warn},
+    wear::SsdWear,
+};
+#[cfg(feature = "ncq")]
+use crate::{
+    ata::{
+        ATA_LOG_HYBRID_INFO, ATA_LOG_NCQ_ERROR, ATA_LOG_NCQ_QUEUE_MGMT,
+        ATA_NCQ_HYBRID_CHANGE_BY_LBA, SATA_HYBRID_INFO, ata_id_has_hybrid, ata_id_has_ncq,
+        ata_id_has_ncq_queue_mgmt, ata_id_hybrid_enabled, ata_id_queue_depth,
+    },
+    ncq::{HYBRID_INFO_MAX_PRIORITY, NcqErrorLog, NcqQueueManagement, NcqStats},
+};
+#[cfg(feature = "smart")]
+use crate::{
+    ata::{
+        ATA_SMART_LBAH_FAIL, ATA_SMART_LBAM_FAIL, ATA_SMART_READ_DATA, ATA_SMART_READ_THRESHOLDS,
+        ATA_SMART_STATUS,
     },
+    smart::{SmartData, SmartThreshold, parse_thresholds},
 };
 
-fn alloc<T: Sized>(align: usize) -> VolatilePtr<'static, T> {
+/// Shortest run of zero sectors worth offloading.
+const ZERO_OFFLOAD_MIN_SECTORS: usize = 8;
+
+/// Number of COMRESETs issued before falling back to lower link speeds when
+/// PxSSTS.DET is stuck at 1.
+pub(crate) const COMRESET_RETRIES: usize = 3;
+
+/// Device Sleep Present bit of PxDEVSLP: the DEVSLP signal is connected
+/// to the device on this port.
+const DEVSLP_DSP: u8 = 1 << 1;
+
+/// Offset of the Set Device Bits FIS in the received FIS area.
+const RX_FIS_SDB: usize = 0x58;
+/// Offset of the PIO Setup FIS in the received FIS area.
+const RX_FIS_PIO_SETUP: usize = 0x20;
+/// Offset of the D2H Register FIS in the received FIS area.
+const RX_FIS_D2H_REG: usize = 0x40;
+
+/// Time for a device behind a port multiplier to establish its link or to
+/// answer a software reset.
+const PMP_LINK_TIMEOUT_MS: u64 = 1000;
+
+/// Interval at which a wait for queued commands reads PxSACT even though no
+/// Set Device Bits FIS arrived, in milliseconds.
+const SDB_FALLBACK_POLL_MS: u64 = 10;
+
+/// Most data [`AhciDriver::copy_within`] moves per command.
+const COPY_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Sector size presented when [`DrivePolicy::emulate_512`] is set.
+const EMULATED_BLOCK_SIZE: usize = 512;
+
+/// Size of the bounce buffers, and so the most a command staged in one
+/// transfers.
+const BOUNCE_BUFFER_SIZE: usize = 1024 * 1024;
+/// Number of bounce buffers kept for reuse.
+const BOUNCE_POOL_SIZE: usize = 2;
+
+/// Allocate a zeroed `T` that is accessed by the HBA with [`Hal::dma_alloc`],
+/// returning it with its address as seen by the HBA.
+fn dma_alloc<H: Hal, T>(align: usize, dma32: bool) -> Option<(VolatilePtr<'static, T>, DmaAddr)> {
+    let layout = Layout::from_size_align(size_of::<T>(), align).unwrap();
+    let (va, addr) = H::dma_alloc(layout, dma32)?;
+    // SAFETY: `dma_alloc` returns a zeroed allocation of `layout`, which is
+    // only freed once the HBA no longer accesses it.
+    let ptr = unsafe { VolatilePtr::new(NonNull::new_unchecked(va as *mut T)) };
+    Some((ptr, addr))
+}
+
+/// Free memory allocated with [`dma_alloc`].
+fn dma_dealloc<H: Hal, T>(ptr: VolatilePtr<'static, T>, addr: DmaAddr, align: usize) {
+    let layout = Layout::from_size_align(size_of::<T>(), align).unwrap();
+    H::dma_dealloc(ptr.as_raw_ptr().addr().get(), addr, layout);
+}
+
+pub(crate) fn port_regs(
+    host: &VolatilePtr<'static, AhciMmio>,
+    i: u8,
+) -> VolatilePtr<'static, PortRegisters> {
     unsafe {
-        VolatilePtr::new(NonNull::new_unchecked(
-            alloc_zeroed(Layout::from_size_align(size_of::<T>(), align).unwrap()).cast(),
-        ))
+        host.ports()
+            .map(|ports| ports.cast::<PortRegisters>().add(i as usize))
     }
 }
 
-struct AhciPort<H> {
-    port: VolatilePtr<'static, PortRegisters>,
+fn sync_for_device<H: Hal>(va: usize, len: usize, dir: DmaDirection) {
+    if H::DMA_COHERENCE != DmaCoherence::All {
+        H::dma_sync_for_device(va, len, dir);
+    }
+}
 
-    cmd_list: VolatilePtr<'static, ahci_cmd_list>,
-    #[allow(dead_code)]
-    fis: VolatilePtr<'static, ahci_rx_fis>,
-    cmd_tbl: VolatilePtr<'static, ahci_cmd_tbl>,
+fn sync_for_cpu<H: Hal>(va: usize, len: usize, dir: DmaDirection) {
+    if H::DMA_COHERENCE != DmaCoherence::All && dir != DmaDirection::ToDevice {
+        H::dma_sync_for_cpu(va, len, dir);
+    }
+}
 
-    _h: PhantomData<H>,
+/// Like [`sync_for_device`], for the command list, command tables and
+/// received FIS area, which need no maintenance if memory from
+/// [`Hal::dma_alloc`] is coherent.
+fn sync_desc_for_device<H: Hal>(va: usize, len: usize, dir: DmaDirection) {
+    if H::DMA_COHERENCE == DmaCoherence::None {
+        sync_for_device::<H>(va, len, dir);
+    }
 }
 
-impl<H: Hal> AhciPort<H> {
-    fn try_new(host: &VolatilePtr<'static, AhciMmio>, i: u8) -> Option<Self> {
-        let port = unsafe {
-            host.ports()
-                .map(|ports| ports.cast::<PortRegisters>().add(i as usize))
-        };
+/// Like [`sync_for_cpu`], for the structures [`sync_desc_for_device`]
+/// covers.
+fn sync_desc_for_cpu<H: Hal>(va: usize, len: usize, dir: DmaDirection) {
+    if H::DMA_COHERENCE == DmaCoherence::None {
+        sync_for_cpu::<H>(va, len, dir);
+    }
+}
 
-        // 1. Stop the port (ST=0, FRE=0)
-        port.CMD().update(|cmd| cmd.with_ST(false).with_FRE(false));
+/// The data phase of a command, with the direction of the transfer encoded
+/// in the type. The buffer must stay valid until the command completes.
+#[derive(Clone, Copy)]
+enum Transfer {
+    /// A non-data command.
+    None,
+    /// The device fills the buffer.
+    FromDevice(*mut [u8]),
+    /// The buffer is sent to the device.
+    ToDevice(*const [u8]),
+}
 
-        // Wait for CR and FR to clear
-        if !wait_until_timeout::<H>(|| !port.CMD().read().CR(), 500) {
-            warn!("Port {i} stop engine timeout (CR)");
-        }
-        if !wait_until_timeout::<H>(|| !port.CMD().read().FR(), 500) {
-            warn!("Port {i} stop FIS receive timeout (FR)");
+impl Transfer {
+    fn buf(self) -> *const [u8] {
+        match self {
+            Self::None => ptr::slice_from_raw_parts(ptr::null(), 0),
+            Self::FromDevice(buf) => buf,
+            Self::ToDevice(buf) => buf,
         }
+    }
 
-        // 2. Check if device is busy (BSY or DRQ) and try CLO
-        let tfd = port.TFD().read();
-        if tfd.STS_BSY() || tfd.STS_DRQ() {
-            debug!("Port {i} busy (TFD: {tfd:?}), trying CLO");
-            let cap = host.host().cap().read();
-            if cap.SCLO() {
-                port.CMD().update(|cmd| cmd.with_CLO(true));
-                if !wait_until_timeout::<H>(|| !port.CMD().read().CLO(), 1000) {
-                    warn!("Port {i} CLO timeout");
-                }
-            }
-        }
+    fn is_write(self) -> bool {
+        matches!(self, Self::ToDevice(_))
+    }
+}
 
-        // 3. Spin up
-        port.CMD().update(|cmd| cmd.with_SUD(true));
-        if !wait_until_timeout::<H>(|| port.CMD().read().SUD(), 1000) {
-            warn!("Port {i} set Spin-Up Device timeout");
-            return None;
-        }
+impl From<&mut [u8]> for Transfer {
+    fn from(buf: &mut [u8]) -> Self {
+        Self::FromDevice(buf)
+    }
+}
 
-        // 4. Wait for Link Up
-        if !wait_until_timeout::<H>(
-            || {
-                let det = port.SSTS().read().DET();
-                det == 0x1 || det == 0x3
-            },
-            1000,
-        ) {
-            warn!("Port {i} sata link timeout");
-            return None;
-        }
-        debug!("Port {i} sata link up");
+impl From<&[u8]> for Transfer {
+    fn from(buf: &[u8]) -> Self {
+        Self::ToDevice(buf)
+    }
+}
 
-        // 5. Clear Errors
-        port.SERR().write(port.SERR().read());
-        port.IS().write(port.IS().read());
+/// The buffer of a read or write, borrowed for as long as the transfer.
+enum IoBuf<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
 
-        // 6. Enable Interrupts
-        port.IE().write(PxI::default_enable().with_DP(true));
+impl IoBuf<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Read(buf) => buf.len(),
+            Self::Write(buf) => buf.len(),
+        }
+    }
+
+    /// The bytes in `range`, as the data of a command.
+    fn transfer(&mut self, range: Range<usize>) -> Transfer {
+        match self {
+            Self::Read(buf) => (&mut buf[range]).into(),
+            Self::Write(buf) => (&buf[range]).into(),
+        }
+    }
+}
 
-        host.host().is().write(1 << i);
+/// A data buffer mapped for DMA, one page at a time.
+struct DmaMapping {
+    va: usize,
+    /// Length of the prefix of the buffer described by `prds`.
+    len: usize,
+    dir: DmaDirection,
+    /// The regions mapped with [`Hal::dma_map`], as `(va, addr, len)`.
+    pages: Vec<(usize, DmaAddr, usize)>,
+    /// Address and length of each PRD entry.
+    prds: Vec<(DmaAddr, usize)>,
+}
 
-        if port.SSTS().read().DET() != 3 {
-            // Try to wait a bit more if it is 1
-            if !wait_until_timeout::<H>(|| port.SSTS().read().DET() == 3, 1000) {
-                warn!(
-                    "Port {i} physical link not established (DET={})",
-                    port.SSTS().read().DET()
-                );
-                return None;
+impl DmaMapping {
+    /// Map the data of a command page by page, merging pages the HBA sees at
+    /// contiguous addresses into one PRD entry. `None` for commands without
+    /// data.
+    ///
+    /// Mapping stops once the PRDT is full, and the length mapped is then
+    /// rounded down to a multiple of `granule`; the rest of the buffer must
+    /// be transferred by another command.
+    fn new<H: Hal>(data: Transfer, granule: usize) -> Option<Self> {
+        let buf = data.buf();
+        if buf.is_empty() {
+            return None;
+        }
+        let dir = if data.is_write() {
+            DmaDirection::ToDevice
+        } else {
+            DmaDirection::FromDevice
+        };
+        let mut mapping = Self {
+            va: buf.addr(),
+            len: 0,
+            dir,
+            pages: Vec::new(),
+            prds: Vec::new(),
+        };
+        while mapping.len < buf.len() {
+            let va = mapping.va + mapping.len;
+            let len = (H::PAGE_SIZE - va % H::PAGE_SIZE).min(buf.len() - mapping.len);
+            let addr = H::dma_map(va, len, dir);
+            mapping.pages.push((va, addr, len));
+            let contiguous = mapping.prds.last().is_some_and(|&(prd_addr, prd_len)| {
+                prd_addr + prd_len == addr && prd_len + len <= AHCI_MAX_BYTES_PER_SG
+            });
+            if contiguous {
+                mapping.prds.last_mut().unwrap().1 += len;
+            } else if mapping.prds.len() < AHCI_MAX_SG {
+                mapping.prds.push((addr, len));
+            } else {
+                // The page stays mapped until the mapping is released.
+                break;
             }
+            mapping.len += len;
         }
 
-        let cmd_list = alloc::<ahci_cmd_list>(1024);
-        let cmd_list_addr = H::virt_to_phys(cmd_list.as_raw_ptr().addr().get());
-        debug!(
-            "Port {i} cmd_list va={:#x} pa={:#x}",
-            cmd_list.as_raw_ptr().addr().get(),
-            cmd_list_addr
-        );
-        port.CLB().write(cmd_list_addr as u32);
-        port.CLBU().write((cmd_list_addr >> 32) as u32);
-
-        let fis = alloc::<ahci_rx_fis>(256);
-        let fis_addr = H::virt_to_phys(fis.as_raw_ptr().addr().get());
-        debug!(
-            "Port {i} fis va={:#x} pa={:#x}",
-            fis.as_raw_ptr().addr().get(),
-            fis_addr
-        );
-        port.FB().write(fis_addr as u32);
-        port.FBU().write((fis_addr >> 32) as u32);
-
-        let cmd_tbl = alloc::<ahci_cmd_tbl>(128);
-        debug!(
-            "Port {i} cmd_tbl va={:#x} pa={:#x}",
-            cmd_tbl.as_raw_ptr().addr().get(),
-            H::virt_to_phys(cmd_tbl.as_raw_ptr().addr().get())
-        );
+        let mut excess = mapping.len % granule;
+        mapping.len -= excess;
+        while excess > 0 {
+            let (_, prd_len) = mapping.prds.last_mut().unwrap();
+            let cut = excess.min(*prd_len);
+            *prd_len -= cut;
+            excess -= cut;
+            if *prd_len == 0 {
+                mapping.prds.pop();
+            }
+        }
+        Some(mapping)
+    }
 
-        // Note: We used to check for BSY/DRQ here, but some devices (like QEMU)
-        // might be busy after spin-up/link-up. The original driver for reference
-        // proceeds to start the port without waiting for BSY to clear here.
-        // It waits for BSY to clear *after* setting the start bits.
+    fn is_write(&self) -> bool {
+        self.dir == DmaDirection::ToDevice
+    }
 
-        port.CMD().write(
-            PxCMD::new()
-                .with_ICC(ICC::Active)
-                .with_FRE(true)
-                .with_POD(true)
-                .with_SUD(true)
-                .with_ST(true),
-        );
+    /// The mapped prefix of the buffer, for mapping it again.
+    fn transfer(&self) -> Transfer {
+        if self.is_write() {
+            Transfer::ToDevice(ptr::slice_from_raw_parts(self.va as *const u8, self.len))
+        } else {
+            Transfer::FromDevice(ptr::slice_from_raw_parts_mut(self.va as *mut u8, self.len))
+        }
+    }
 
-        if !wait_until_timeout::<H>(
-            || {
-                let tfd = port.TFD().read();
-                if tfd.STS_ERR() {
-                    // warn!("Port {i} error after start (TFD: {:?})", tfd);
-                }
-                !(tfd.STS_ERR() | tfd.STS_DRQ() | tfd.STS_BSY())
-            },
-            1000,   //try not to wait too long
-        ) {
-            warn!("Port {i} start timeout (TFD: {:?})", port.TFD().read());
-            return None;
+    /// Check the PRD entries against the limits of the DMA engine: data
+    /// base addresses and byte counts must be even, and PRDTL is 16 bits
+    /// wide. If `dma32` is set, the HBA only addresses the first 4 GiB.
+    /// Rejections are logged for `port`.
+    fn validate(&self, port: u8, dma32: bool) -> Result<(), AhciError> {
+        if dma32 && !self.is_dma32() {
+            // Without CAP.S64A the upper address bits are ignored, and the
+            // HBA would access the wrong memory.
+            port_error!(
+                port,
+                "Port {port} DMA buffer above 4 GiB without 64-bit addressing"
+            );
+            return Err(AhciError::InvalidArgument);
+        }
+        if self.prds.len() > AHCI_MAX_SG.min(u16::MAX as usize) {
+            let count = self.prds.len();
+            port_error!(port, "Port {port} {count} PRD entries exceed the PRDT");
+            return Err(AhciError::InvalidArgument);
         }
+        if let Some(&(addr, len)) = self
+            .prds
+            .iter()
+            .find(|&&(addr, len)| addr % 2 != 0 || len % 2 != 0 || len > AHCI_MAX_BYTES_PER_SG)
+        {
+            port_error!(
+                port,
+                "Port {port} invalid PRD entry: {len} bytes at {addr:#x}"
+            );
+            return Err(AhciError::InvalidArgument);
+        }
+        Ok(())
+    }
 
-        Some(Self {
-            port,
-            cmd_list,
-            fis,
-            cmd_tbl,
-            _h: PhantomData,
-        })
+    /// Whether the HBA sees the whole buffer below 4 GiB.
+    fn is_dma32(&self) -> bool {
+        self.prds
+            .iter()
+            .all(|&(addr, len)| (addr + len) as u64 <= 1 << 32)
     }
 
-    fn exec_cmd(&mut self, cfis: sata_fis_h2d, buf: *mut [u8], is_write: bool) -> bool {
-        // Always use slot 0 for simplicity (like reference driver)
-        let slot: u32 = 0;
+    fn sync_for_device<H: Hal>(&self) {
+        sync_for_device::<H>(self.va, self.len, self.dir);
+    }
 
-        // Wait for slot 0 to be free
-        if !wait_until_timeout::<H>(|| self.port.CI().read() & 1 == 0, 1000) {
-            error!("Slot 0 busy timeout");
-            return false;
-        }
+    fn sync_for_cpu<H: Hal>(&self) {
+        sync_for_cpu::<H>(self.va, self.len, self.dir);
+    }
 
-        if buf.len() > AHCI_MAX_BYTES_PER_CMD {
-            error!("Exceeding max transfer data limit");
-            return false;
+    fn unmap<H: Hal>(self) {
+        for (va, addr, len) in self.pages {
+            H::dma_unmap(va, addr, len, self.dir);
         }
+    }
+}
 
-        // Write command FIS to command table
-        self.cmd_tbl.hdr().write(cfis);
+/// A command issued to the HBA that has not been completed yet.
+struct Inflight {
+    /// Submission order, used to complete commands in order.
+    seq: u64,
+    issued_at: u64,
+    /// Opcode, starting LBA and transfer size, for reporting.
+    command: u8,
+    lba: u64,
+    bytes: usize,
+    /// CPU the command was submitted on.
+    cpu: Option<usize>,
+    /// Whether the command was already reported as hung.
+    hung_reported: bool,
+    /// Whether this is a native queued command, tracked in PxSACT.
+    queued: bool,
+    data: Option<DmaMapping>,
+    /// For cache flushes, the last flush request covered by the command.
+    flush: Option<u64>,
+    /// For commands submitted with [`AhciDriver::try_submit`], the ticket
+    /// identifying the request.
+    ticket: Option<u64>,
+}
 
-        let sg_cnt = if !buf.is_null() && !buf.is_empty() {
-            let sg_cnt = ((buf.len() - 1) / AHCI_MAX_BYTES_PER_SG) + 1;
-            if sg_cnt > AHCI_MAX_SG {
-                error!("Exceeding max sg limit");
-                return false;
-            }
+/// A request made with [`AhciDriver::try_submit`], holding on to its buffer
+/// until the caller collects it.
+struct Submitted<H> {
+    buf: DmaBuffer<H>,
+    /// `None` while the command is outstanding, otherwise its outcome.
+    result: Option<Result<(), AhciError>>,
+}
 
-            let mut remaining = buf.len();
-            for i in 0..sg_cnt {
-                let offset = i * AHCI_MAX_BYTES_PER_SG;
-                let len = remaining.min(AHCI_MAX_BYTES_PER_SG);
+/// A chunk written by [`AhciDriver::copy_within`] to the block it holds.
+enum CopyWrite<H> {
+    /// Submitted with [`AhciDriver::try_submit`] and not collected yet.
+    Queued(IoTicket, u64),
+    /// Already written.
+    Done(DmaBuffer<H>, u64),
+}
 
-                let buf_addr = H::virt_to_phys(unsafe { (buf as *mut u8).add(offset).addr() });
-                let sg = unsafe { &mut self.cmd_tbl.sgs().map(|sg| sg.cast::<ahci_sg>().add(i)) };
-                sg.write(ahci_sg {
-                    addr_lo: buf_addr as u32,
-                    addr_hi: (buf_addr >> 32) as u32,
-                    flags_size: (len - 1) as u32 & 0x3fffff, // DBC: Data Byte Count (0-based)
-                    ..Default::default()
-                });
+/// Commands issued to the HBA, indexed by command slot (tag).
+struct SlotTable {
+    slots: [Option<Inflight>; AHCI_MAX_CMDS],
+    /// Sequence number of the next issued command.
+    next_seq: u64,
+    /// Whether completions are processed in submission order.
+    ordered: bool,
+    /// Number of commands completed and aborted so far.
+    completed: u64,
+    aborted: u64,
+}
 
-                remaining -= len;
-            }
+impl SlotTable {
+    fn new() -> Self {
+        Self {
+            slots: [const { None }; AHCI_MAX_CMDS],
+            next_seq: 0,
+            ordered: false,
+            completed: 0,
+            aborted: 0,
+        }
+    }
 
-            sg_cnt
-        } else {
-            0
-        };
+    fn is_empty(&self) -> bool {
+        self.slots.iter().all(Option::is_none)
+    }
 
-        // Build command header options:
-        // Bits 0-4: Command FIS length in DWORDs (5 for sata_fis_h2d which is 20 bytes
-        // = 5 DWORDs) Bit 6: Write (1) or Read (0)
-        // Bits 16-31: PRDTL (Physical Region Descriptor Table Length)
-        let cfl = size_of::<sata_fis_h2d>() / 4; // 20 / 4 = 5
-        let opts = (cfl as u32) | ((sg_cnt as u32) << 16) | ((is_write as u32) << 6);
+    fn iter(&self) -> impl Iterator<Item = &Inflight> {
+        self.slots.iter().flatten()
+    }
 
-        let cmd_tbl_addr = H::virt_to_phys(self.cmd_tbl.as_raw_ptr().addr().get());
+    fn get_mut(&mut self, tag: usize) -> Option<&mut Inflight> {
+        self.slots[tag].as_mut()
+    }
 
-        debug!(
-            "exec_cmd: slot={} opts={:#x} cmd_tbl_addr={:#x} sg_cnt={} buf_len={}",
-            slot,
-            opts,
-            cmd_tbl_addr,
-            sg_cnt,
-            buf.len()
-        );
+    /// Record `inflight` as the command in slot `tag`, assigning it the next
+    /// sequence number.
+    fn insert(&mut self, tag: usize, mut inflight: Inflight) {
+        debug_assert!(self.slots[tag].is_none());
+        inflight.seq = self.next_seq;
+        self.next_seq += 1;
+        self.slots[tag] = Some(inflight);
+    }
 
-        // Write command header to slot 0
-        unsafe {
-            self.cmd_list
-                .map(|list| list.cast::<ahci_cmd_hdr>().add(slot as usize))
-        }
-        .write(ahci_cmd_hdr {
-            opts,
-            status: 0,
-            tbl_addr_lo: cmd_tbl_addr as u32,
-            tbl_addr_hi: (cmd_tbl_addr >> 32) as u32,
-            reserved: [0; 4],
-        });
+    /// The outstanding command submitted first.
+    fn oldest(&self) -> Option<&Inflight> {
+        self.iter().min_by_key(|i| i.seq)
+    }
 
-        H::flush_dcache();
+    /// Slot of the outstanding command submitted first.
+    fn oldest_tag(&self) -> Option<usize> {
+        (0..AHCI_MAX_CMDS)
+            .filter(|&tag| self.slots[tag].is_some())
+            .min_by_key(|&tag| self.slots[tag].as_ref().map(|i| i.seq))
+    }
 
-        // Issue command
-        self.port.CI().write(1 << slot);
+    /// A free slot among the first `depth` ones.
+    fn free_slot(&self, depth: usize) -> Option<usize> {
+        (0..depth).find(|&tag| self.slots[tag].is_none())
+    }
 
-        // Wait for completion
-        if !wait_until_timeout::<H>(|| self.port.CI().read() & (1 << slot) == 0, 1000) {
-            let is = self.port.IS().read();
-            let tfd = self.port.TFD().read();
-            error!(
-                "AHCI command timeout: CI={:#x} IS={:?} TFD={:?}",
-                self.port.CI().read(),
-                is,
-                tfd
-            );
-            return false;
-        }
+    /// Take the commands whose slots are no longer set in `issued`, in
+    /// submission order.
+    ///
+    /// With ordered completion, a finished command is held back while an
+    /// older one is still outstanding.
+    fn take_completed(&mut self, issued: u32) -> Vec<Inflight> {
+        let pending = |tag: usize| issued & (1 << tag) != 0;
+        let oldest_pending = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(tag, i)| i.as_ref().filter(|_| pending(tag)))
+            .map(|i| i.seq)
+            .min();
+        let ordered = self.ordered;
+        let mut done: Vec<_> = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .filter(|(tag, i)| {
+                !pending(*tag)
+                    && i.as_ref().is_some_and(|i| {
+                        !ordered || oldest_pending.is_none_or(|oldest| i.seq < oldest)
+                    })
+            })
+            .filter_map(|(_, i)| i.take())
+            .collect();
+        done.sort_unstable_by_key(|i| i.seq);
+        self.completed += done.len() as u64;
+        done
+    }
 
-        H::flush_dcache();
-        true
+    /// Take all outstanding commands.
+    fn take_all(&mut self) -> Vec<Inflight> {
+        let mut all: Vec<_> = self.slots.iter_mut().filter_map(Option::take).collect();
+        all.sort_unstable_by_key(|i| i.seq);
+        self.aborted += all.len() as u64;
+        all
     }
 }
 
-pub struct AhciDriver<H> {
-    #[allow(dead_code)]
-    mmio: VolatilePtr<'static, AhciMmio>,
-    port: AhciPort<H>,
+/// Values the driver last wrote to the registers it owns.
+#[derive(Default)]
+struct RegisterShadow {
+    ie: PxI,
+    cmd: PxCMD,
+    clb: DmaAddr,
+    fb: DmaAddr,
+    sctl: PxSCTL,
+}
 
-    block_size: usize,
-    max_lba: u64,
-    is_lba48: bool,
+impl RegisterShadow {
+    /// PxCMD bits that only change when the driver writes them.
+    const CMD_MASK: u32 = PxCMD::new()
+        .with_ST(true)
+        .with_SUD(true)
+        .with_POD(true)
+        .with_FRE(true)
+        .with_ASP(true)
+        .with_ALPE(true)
+        .into_bits();
+    /// PxSCTL fields that only change when the driver writes them.
+    const SCTL_MASK: u32 = PxSCTL::new()
+        .with_IPM(0xf)
+        .with_SPD(0xf)
+        .with_DET(0xf)
+        .into_bits();
 
-    _h: PhantomData<H>,
+    fn verify(&self, i: u8, port: &VolatilePtr<'static, PortRegisters>) -> Vec<RegisterMismatch> {
+        let addr = |lo: u32, hi: u32| (hi as u64) << 32 | lo as u64;
+        let checks = [
+            (
+                ShadowedRegister::Ie,
+                self.ie.into_bits() as u64,
+                port.IE().read().into_bits() as u64,
+            ),
+            (
+                ShadowedRegister::Cmd,
+                (self.cmd.into_bits() & Self::CMD_MASK) as u64,
+                (port.CMD().read().into_bits() & Self::CMD_MASK) as u64,
+            ),
+            (
+                ShadowedRegister::Clb,
+                self.clb as u64,
+                addr(port.CLB().read(), port.CLBU().read()),
+            ),
+            (
+                ShadowedRegister::Fb,
+                self.fb as u64,
+                addr(port.FB().read(), port.FBU().read()),
+            ),
+            (
+                ShadowedRegister::Sctl,
+                (self.sctl.into_bits() & Self::SCTL_MASK) as u64,
+                (port.SCTL().read().into_bits() & Self::SCTL_MASK) as u64,
+            ),
+        ];
+        checks
+            .into_iter()
+            .filter(|(_, expected, actual)| expected != actual)
+            .map(|(register, expected, actual)| RegisterMismatch {
+                port: i,
+                register,
+                expected,
+                actual,
+            })
+            .collect()
+    }
 }
 
-/// Safety:
-/// - `Send`: The driver takes ownership of the MMIO region and can be safely moved between threads.
-/// - `Sync`: The driver's mutating operations require `&mut self`, ensuring exclusive access.
-///   Read-only operations (like getting block size) are safe to perform concurrently.
-unsafe impl<H: Hal> Send for AhciDriver<H> {}
-unsafe impl<H: Hal> Sync for AhciDriver<H> {}
+/// Coalescing state of asynchronous cache flushes.
+#[derive(Default)]
+struct FlushState {
+    /// FLUSH CACHE or FLUSH CACHE EXT.
+    command: u8,
+    /// Number of flushes requested so far.
+    requested: u64,
+    /// Last request covered by a finished flush.
+    completed: u64,
+    /// Requests covered by failed flushes, as ascending inclusive ranges.
+    /// Ranges of consecutive failures are merged.
+    failed: Vec<(u64, u64)>,
+    /// Why the last failed flush failed.
+    error: Option<AhciError>,
+}
 
-impl<H: Hal> AhciDriver<H> {
-    /// Try to construct a new AHCI driver from the given MMIO base address.
-    ///
-    /// # Safety
-    ///
-    /// The caller must ensure that:
-    /// - `base` is a valid virtual address pointing to the AHCI controller's MMIO register block.
-    /// - The memory region starting at `base` is properly mapped and accessible.
-    /// - No other code is concurrently accessing the same AHCI controller.
-    /// - The AHCI controller hardware is present and functional at the given address.
-    pub unsafe fn try_new(base: usize) -> Option<Self> {
-        // SAFETY: The caller guarantees `base` is a valid AHCI MMIO base address.
-        let mmio = unsafe { VolatilePtr::new(NonNull::new(base as *mut _).unwrap()) };
-        let host = mmio.host();
+impl FlushState {
+    fn fail(&mut self, covered: u64, error: AhciError) {
+        let first = self.completed + 1;
+        match self.failed.last_mut() {
+            Some((_, last)) if *last + 1 == first => *last = covered,
+            _ => self.failed.push((first, covered)),
+        }
+        self.completed = covered;
+        self.error = Some(error);
+    }
 
-        // reset ahci controller
-        host.ghc().update(|mut ghc| {
-            if !ghc.HR() {
-                ghc.set_HR(true);
-            }
-            ghc
-        });
-        if !wait_until_timeout::<H>(|| !host.ghc().read().HR(), 1000) {
-            error!("AHCI HBA reset timeout");
+    /// `None` while request `seq` is pending, otherwise whether it succeeded.
+    fn status(&self, seq: u64) -> Option<bool> {
+        if seq > self.completed {
             return None;
         }
+        Some(
+            !self
+                .failed
+                .iter()
+                .any(|&(first, last)| (first..=last).contains(&seq)),
+        )
+    }
+}
 
-        // enable ahci
-        host.ghc().update(|ghc| ghc.with_AE(true));
-        wait_until_timeout::<H>(|| false, 1);
+/// The command table of a command slot.
+struct CmdTable {
+    tbl: VolatilePtr<'static, ahci_cmd_tbl>,
+    /// Address of `tbl` as seen by the HBA.
+    addr: DmaAddr,
+}
 
-        // init cap and pi
-        host.cap().write(CAP::new().with_SMPS(true).with_SSS(true));
-        host.pi().write(0xf);
+pub(crate) struct AhciPort<H> {
+    index: u8,
+    /// Whether the HBA supports Command List Override (CAP.SCLO).
+    sclo: bool,
+    /// Whether the HBA only addresses the first 4 GiB (no CAP.S64A).
+    dma32: bool,
+    /// Port multiplier port addressed by new commands, 0 without a port
+    /// multiplier.
+    pmp: u8,
+    /// Port multiplier port of the outstanding commands.
+    busy_pmp: u8,
+    port: VolatilePtr<'static, PortRegisters>,
 
-        let vs = host.vs().read();
-        info!("AHCI ver {vs}");
+    cmd_list: VolatilePtr<'static, ahci_cmd_list>,
+    fis: VolatilePtr<'static, ahci_rx_fis>,
+    /// Command tables, one per command slot supported by the HBA.
+    cmd_tbls: Vec<CmdTable>,
 
-        let cap = host.cap().read();
-        info!("AHCI cap {cap}");
+    /// Outstanding commands.
+    slots: SlotTable,
+    /// Bitmap of the ports of the HBA with outstanding commands, shared by
+    /// all ports, for [`Hal::link_idle_hint`].
+    activity: Arc<AtomicU32>,
+    flush: FlushState,
+    /// Requests made with [`AhciDriver::try_submit`] and not collected yet,
+    /// by ticket.
+    submitted: BTreeMap<u64, Submitted<H>>,
+    next_ticket: u64,
+    shadow: RegisterShadow,
+    /// Cleared once the device was found to be gone.
+    present: bool,
+    /// Interrupt status acknowledged by an [`IrqHandler`].
+    irq: Arc<IrqStatus>,
+    /// Whether waiting for commands blocks in [`Hal::irq_wait`].
+    irq_mode: bool,
+    /// Which commands are polled in interrupt mode.
+    coalescing: Option<IrqCoalescing>,
+    /// Interface power state last reported.
+    power_state: LinkPowerState,
 
-        let cap2 = host.cap2().read();
-        info!("AHCI cap2 {cap2:?}");
+    /// Latency in milliseconds above which completions are reported.
+    slow_io_threshold: Option<u64>,
+    /// Hard cap on the service time of a command in milliseconds, after
+    /// which it is aborted.
+    max_latency: Option<u64>,
+    timeouts: Timeouts,
+    retry: RetryPolicy,
+    /// Set during a software reset, which recovery must not start again.
+    resetting: bool,
+    /// Set for ports brought up in [`ProbeMode::Passive`], whose device
+    /// recovery must never reset.
+    passive: bool,
+    /// Set when recovery reset the device, which loses the settings the
+    /// drive policy made, until the driver applies it again.
+    settings_lost: bool,
+    /// NCQ Command Error log read after the last queued command failed.
+    #[cfg(feature = "ncq")]
+    ncq_error: Option<NcqErrorLog>,
+    events: VecDeque<AhciEvent>,
+    error_sink: Option<Box<dyn ErrorSink>>,
+    /// Number of commands, and of those writes, issued so far.
+    issued: u64,
+    writes_issued: u64,
+    stats: AhciStats,
 
-        let pi = host.pi().read();
-        info!("AHCI ports implemented {pi}");
+    /// [`Self::shut_down`], captured so that dropping needs no `Hal` bound.
+    shut_down: fn(&mut Self),
+    _h: PhantomData<H>,
+}
 
-        host.ghc().update(|ghc| ghc.with_IE(true));
+impl<H> Drop for AhciPort<H> {
+    fn drop(&mut self) {
+        (self.shut_down)(self)
+    }
+}
 
-        let mut port = None;
-        for i in 0..cap.NP() + 1 {
-            if let Some(p) = AhciPort::<H>::try_new(&mmio, i) {
-                port = Some(p);
-            }
-        }
+impl<H: Hal> AhciPort<H> {
+    /// Bring up all of `ports`, returning the ports that were started and a
+    /// report for every probed port.
+    ///
+    /// The slow phases (link detection, Phy communication and waiting for the
+    /// device to become ready) are polled for all ports at once, so probing
+    /// takes about as long as probing the slowest port.
+    pub(crate) fn probe_all(
+        host: &VolatilePtr<'static, AhciMmio>,
+        ports: impl IntoIterator<Item = u8>,
+        irq: &Arc<IrqStatus>,
+        timeouts: &ProbeTimeouts,
+    ) -> (Vec<Self>, Vec<ProbeReport>) {
+        let cap = host.host().cap().read();
+        let sclo = cap.SCLO();
+        let nslots = cap.NCS() as usize + 1;
+        let activity = Arc::new(AtomicU32::new(0));
+        let mut reports: Vec<_> = ports
+            .into_iter()
+            .map(|i| ProbeReport {
+                port: i,
+                ..Default::default()
+            })
+            .collect();
+        let regs: Vec<_> = reports.iter().map(|r| port_regs(host, r.port)).collect();
 
-        let Some(mut port) = port else {
-            error!("No AHCI ports initialized");
-            return None;
-        };
+        let mut pending: Vec<usize> = (0..reports.len())
+            .filter(|&n| Self::spin_up(&regs[n], sclo, timeouts.spin_up_ms, &mut reports[n]))
+            .collect();
 
-        let mut id = [0u16; ATA_ID_WORDS];
-        port.exec_cmd(
-            sata_fis_h2d {
-                fis_type: SATA_FIS_TYPE_REGISTER_H2D,
-                pm_port_c: 0x80,
-                command: ATA_CMD_ID_ATA,
-                ..Default::default()
+        // 4. Wait for Link Up
+        let ready = wait_all_timeout::<HalClock<H>, _>(
+            &pending,
+            |&n| {
+                let det = regs[n].SSTS().read().DET();
+                det == 0x1 || det == 0x3
             },
-            unsafe {
-                core::slice::from_raw_parts_mut(id.as_mut_ptr().cast::<u8>(), size_of_val(&id))
+            timeouts.link_detect_ms,
+        );
+        pending = pending
+            .into_iter()
+            .zip(ready)
+            .filter_map(|(n, ready)| {
+                let i = reports[n].port;
+                if !ready {
+                    port_warn!(i, "Port {i} sata link timeout");
+                    reports[n].outcome = ProbeOutcome::NoDevice;
+                    reports[n].serr = regs[n].SERR().read().into_bits();
+                    return None;
+                }
+                port_debug!(i, "Port {i} sata link up");
+                Self::enable(host, &regs[n], &mut reports[n]);
+                Some(n)
+            })
+            .collect();
+
+        // Try to wait a bit more if DET is 1, then fall back to lower speeds
+        let ready = wait_all_timeout::<HalClock<H>, _>(
+            &pending,
+            |&n| regs[n].SSTS().read().DET() == 3,
+            timeouts.link_establish_ms,
+        );
+        let (established, stuck): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .zip(ready)
+            .partition(|&(_, ready)| ready);
+        pending = established.into_iter().map(|(n, _)| n).collect();
+        let stuck: Vec<_> = stuck.into_iter().map(|(n, _)| n).collect();
+        let links: Vec<_> = stuck.iter().map(|&n| (reports[n].port, regs[n])).collect();
+        let iss = host.host().cap().read().ISS();
+        for (n, up) in stuck.into_iter().zip(Self::establish_links(&links, iss)) {
+            if up {
+                pending.push(n);
+                continue;
+            }
+            let i = reports[n].port;
+            port_warn!(
+                i,
+                "Port {i} physical link not established (DET={})",
+                regs[n].SSTS().read().DET()
+            );
+            reports[n].outcome = ProbeOutcome::NoCommunication;
+        }
+        pending.sort_unstable();
+
+        let dma32 = !cap.S64A();
+        let (started, pending): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .filter_map(|n| {
+                let port = Self::start(
+                    regs[n],
+                    reports[n].port,
+                    sclo,
+                    nslots,
+                    dma32,
+                    activity.clone(),
+                    irq.clone(),
+                );
+                if let Err(outcome) = port {
+                    if outcome == ProbeOutcome::DmaAllocFailed {
+                        port_warn!(
+                            reports[n].port,
+                            "Port {} DMA allocation failed",
+                            reports[n].port
+                        );
+                    }
+                    reports[n].outcome = outcome;
+                }
+                Some((port.ok()?, n))
+            })
+            .unzip();
+        let ready = wait_all_timeout::<HalClock<H>, _>(
+            &started,
+            |port| {
+                let tfd = port.port.TFD().read();
+                !(tfd.STS_ERR() | tfd.STS_DRQ() | tfd.STS_BSY())
             },
-            false,
+            timeouts.device_ready_ms,
         );
+        let mut ports = Vec::new();
+        for ((port, ready), n) in started.into_iter().zip(ready).zip(pending) {
+            if ready {
+                reports[n].outcome = ProbeOutcome::Started;
+                ports.push(port);
+            } else {
+                port_warn!(
+                    port.index,
+                    "Port {} start timeout (TFD: {:?})",
+                    port.index,
+                    port.port.TFD().read()
+                );
+                reports[n].outcome = ProbeOutcome::StartTimeout;
+            }
+        }
 
-        let product = ata_id_to_string(&id, ATA_ID_PROD, ATA_ID_PROD_LEN);
-        let serial = ata_id_to_string(&id, ATA_ID_SERNO, ATA_ID_SERNO_LEN);
-        let rev = ata_id_to_string(&id, ATA_ID_FW_REV, ATA_ID_FW_REV_LEN);
+        for (report, regs) in reports.iter_mut().zip(&regs) {
+            report.sig = regs.SIG().read().into_bits();
+            report.ssts = regs.SSTS().read().into_bits();
+            debug!("{report}");
+        }
 
-        info!("AHCI device: {product} {serial} {rev}");
+        (ports, reports)
+    }
 
-        let max_lba = ata_id_n_sectors(&id);
-        let is_lba48 = ata_id_has_lba48(&id);
-        let block_size = 512;
+    /// Stop the port and spin up the device.
+    fn spin_up(
+        port: &VolatilePtr<'static, PortRegisters>,
+        sclo: bool,
+        timeout: u64,
+        report: &mut ProbeReport,
+    ) -> bool {
+        let i = report.port;
+        report.initial_tfd = port.TFD().read().into_bits();
 
-        Some(Self {
-            mmio,
+        // 1. Stop the port (ST=0, FRE=0) and wait for CR and FR to clear
+        if Port::from_regs(i, *port).stop::<H>().is_err() {
+            port_warn!(i, "Port {i} stop engine timeout (CR/FR)");
+        }
+
+        // 2. Check if device is busy (BSY or DRQ) and try CLO
+        let tfd = port.TFD().read();
+        if tfd.STS_BSY() || tfd.STS_DRQ() {
+            port_debug!(i, "Port {i} busy (TFD: {tfd:?}), trying CLO");
+            if sclo {
+                Self::clo(port, i);
+            }
+        }
+
+        // 3. Spin up
+        port.CMD().update(|cmd| cmd.with_SUD(true));
+        if !wait_until_timeout::<HalClock<H>>(|| port.CMD().read().SUD(), timeout) {
+            port_warn!(i, "Port {i} set Spin-Up Device timeout");
+            report.outcome = ProbeOutcome::SpinUpTimeout;
+            report.serr = port.SERR().read().into_bits();
+            return false;
+        }
+        true
+    }
+
+    /// Clear errors and enable interrupts once a device was detected.
+    fn enable(
+        host: &VolatilePtr<'static, AhciMmio>,
+        port: &VolatilePtr<'static, PortRegisters>,
+        report: &mut ProbeReport,
+    ) {
+        // 5. Clear Errors
+        let serr = port.SERR().read();
+        report.serr = serr.into_bits();
+        port.SERR().write(serr);
+        H::with_irqs_disabled(|| port.IS().write(port.IS().read()));
+
+        // 6. Enable Interrupts
+        port.IE().write(Self::interrupts());
+
+        H::with_irqs_disabled(|| host.host().is().write(1 << report.port));
+    }
+
+    /// Interrupts enabled on started ports.
+    fn interrupts() -> PxI {
+        PxI::default_enable().with_DP(true)
+    }
+
+    /// Set up the command structures and start the command engine, without
+    /// waiting for the device to become ready.
+    ///
+    /// Fails with [`ProbeOutcome::DmaAllocFailed`] if the command structures
+    /// can't be allocated, below 4 GiB if `dma32` is set, and with
+    /// [`ProbeOutcome::StartTimeout`] if the port can't be stopped to program
+    /// them or FIS receive does not start.
+    fn start(
+        port: VolatilePtr<'static, PortRegisters>,
+        i: u8,
+        sclo: bool,
+        nslots: usize,
+        dma32: bool,
+        activity: Arc<AtomicU32>,
+        irq: Arc<IrqStatus>,
+    ) -> Result<Self, ProbeOutcome> {
+        let Ok(mut idle) = Port::from_regs(i, port).stop::<H>() else {
+            port_warn!(i, "Port {i} stop engine timeout (CR/FR)");
+            return Err(ProbeOutcome::StartTimeout);
+        };
+        let failed = ProbeOutcome::DmaAllocFailed;
+        let (cmd_list, cmd_list_addr) = dma_alloc::<H, ahci_cmd_list>(1024, dma32).ok_or(failed)?;
+        let Some((fis, fis_addr)) = dma_alloc::<H, ahci_rx_fis>(256, dma32) else {
+            dma_dealloc::<H, _>(cmd_list, cmd_list_addr, 1024);
+            return Err(failed);
+        };
+        let mut cmd_tbls = Vec::with_capacity(nslots);
+        for _ in 0..nslots {
+            let Some((tbl, addr)) = dma_alloc::<H, ahci_cmd_tbl>(128, dma32) else {
+                for CmdTable { tbl, addr } in cmd_tbls {
+                    dma_dealloc::<H, _>(tbl, addr, 128);
+                }
+                dma_dealloc::<H, _>(fis, fis_addr, 256);
+                dma_dealloc::<H, _>(cmd_list, cmd_list_addr, 1024);
+                return Err(failed);
+            };
+            cmd_tbls.push(CmdTable { tbl, addr });
+        }
+
+        port_debug!(
+            i,
+            "Port {i} cmd_list va={:#x} pa={:#x}",
+            cmd_list.as_raw_ptr().addr().get(),
+            cmd_list_addr
+        );
+        port_debug!(
+            i,
+            "Port {i} fis va={:#x} pa={:#x}",
+            fis.as_raw_ptr().addr().get(),
+            fis_addr
+        );
+        // SAFETY: Both were allocated above for the HBA, zeroed, and are only
+        // freed once the port is stopped again.
+        unsafe {
+            idle.set_command_list_base(cmd_list_addr);
+            idle.set_fis_base(fis_addr);
+        }
+
+        port_debug!(
+            i,
+            "Port {i} cmd_tbl[0] va={:#x} pa={:#x}, {nslots} slots",
+            cmd_tbls[0].tbl.as_raw_ptr().addr().get(),
+            cmd_tbls[0].addr
+        );
+
+        // Note: We used to check for BSY/DRQ here, but some devices (like QEMU)
+        // might be busy after spin-up/link-up. The original driver for reference
+        // proceeds to start the port without waiting for BSY to clear here.
+        // It waits for BSY to clear *after* setting the start bits.
+
+        // Only touch the RW bits, leaving RO and hardware-maintained ones
+        // (CPS, CCS, HPCP, ...) as they are. POD is only writable when cold
+        // presence detection is supported.
+        port.CMD().update(|old| {
+            old.with_ICC(ICC::Active)
+                .with_POD(old.POD() || old.CPD())
+                .with_SUD(true)
+        });
+        // SAFETY: Every command header is written with the address of its
+        // command table before its slot is issued, and the command list is
+        // zeroed until then, so the HBA only fetches the headers.
+        if unsafe { idle.start::<H>() }.is_err() {
+            port_warn!(i, "Port {i} FIS receive start timeout (FR)");
+            // The HBA may still start FIS receive, so the structures are
+            // leaked.
+            return Err(ProbeOutcome::StartTimeout);
+        }
+        let shadow = RegisterShadow {
+            ie: Self::interrupts(),
+            cmd: port.CMD().read(),
+            clb: cmd_list_addr,
+            fb: fis_addr,
+            sctl: port.SCTL().read(),
+        };
+
+        Ok(Self {
+            index: i,
+            sclo,
+            dma32,
+            pmp: 0,
+            busy_pmp: 0,
             port,
-            block_size,
-            max_lba,
-            is_lba48,
+            cmd_list,
+            fis,
+            cmd_tbls,
+            slots: SlotTable::new(),
+            activity,
+            flush: FlushState::default(),
+            submitted: BTreeMap::new(),
+            next_ticket: 0,
+            shadow,
+            present: true,
+            irq,
+            irq_mode: false,
+            coalescing: None,
+            power_state: LinkPowerState::from_ipm(port.SSTS().read().IPM()),
+            slow_io_threshold: None,
+            max_latency: None,
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default(),
+            resetting: false,
+            passive: false,
+            settings_lost: false,
+            #[cfg(feature = "ncq")]
+            ncq_error: None,
+            events: VecDeque::new(),
+            error_sink: None,
+            issued: 0,
+            writes_issued: 0,
+            stats: AhciStats::default(),
+            shut_down: Self::shut_down,
             _h: PhantomData,
         })
     }
 
-    pub fn capacity(&self) -> u64 {
-        self.max_lba
+    /// Stop FIS receive and the command engine, mask the port's interrupts
+    /// and free the command structures.
+    ///
+    /// Outstanding commands are aborted. The command structures, and the
+    /// data buffers of outstanding commands, are leaked if the engine does
+    /// not stop, as the HBA may still access them.
+    fn shut_down(&mut self) {
+        let i = self.index;
+        self.update_cmd(|cmd| cmd.with_ST(false));
+        let mut stopped = wait_until_timeout::<HalClock<H>>(
+            || !self.port.CMD().read().CR(),
+            self.timeouts.engine_stop_ms,
+        );
+        if stopped {
+            self.abort_inflight();
+        } else {
+            port_error!(i, "Port {i} stop engine timeout (CR)");
+            self.leak_inflight();
+        }
+        self.update_cmd(|cmd| cmd.with_FRE(false));
+        if !wait_until_timeout::<HalClock<H>>(
+            || !self.port.CMD().read().FR(),
+            self.timeouts.engine_stop_ms,
+        ) {
+            port_error!(i, "Port {i} stop FIS receive timeout (FR)");
+            stopped = false;
+        }
+
+        self.shadow.ie = PxI::new();
+        self.port.IE().write(self.shadow.ie);
+        H::with_irqs_disabled(|| self.take_is(PxI::from_bits(!0)));
+
+        if !stopped {
+            port_error!(i, "Port {i} leaking its command structures");
+            return;
+        }
+        for CmdTable { tbl, addr } in self.cmd_tbls.drain(..) {
+            dma_dealloc::<H, _>(tbl, addr, 128);
+        }
+        dma_dealloc::<H, _>(self.fis, self.shadow.fb, 256);
+        dma_dealloc::<H, _>(self.cmd_list, self.shadow.clb, 1024);
+        port_debug!(i, "Port {i} shut down");
+    }
+
+    /// Clear BSY and DRQ with Command List Override.
+    ///
+    /// The command engine must be stopped and CAP.SCLO set.
+    fn clo(port: &VolatilePtr<'static, PortRegisters>, i: u8) -> bool {
+        port.CMD().update(|cmd| cmd.with_CLO(true));
+        if !wait_until_timeout::<HalClock<H>>(|| !port.CMD().read().CLO(), 1000) {
+            port_warn!(i, "Port {i} CLO timeout");
+            return false;
+        }
+        true
+    }
+
+    /// Issue a COMRESET, limiting the negotiated speed to `spd` (0 means no
+    /// limit), and return the value left in PxSCTL.
+    fn comreset(port: &VolatilePtr<'static, PortRegisters>, spd: u8) -> PxSCTL {
+        let sctl = port.SCTL().read().with_SPD(spd);
+        port.SCTL().write(sctl.with_DET(1));
+        // DET must stay at 1 for at least 1ms
+        wait_until_timeout::<HalClock<H>>(|| false, 1);
+        port.SCTL().write(sctl.with_DET(0));
+        sctl.with_DET(0)
+    }
+
+    /// Read-modify-write PxCMD, recording the written value in the shadow.
+    fn update_cmd(&mut self, f: impl FnOnce(PxCMD) -> PxCMD) {
+        let mut cmd = PxCMD::new();
+        self.port.CMD().update(|old| {
+            cmd = f(old);
+            cmd
+        });
+        self.shadow.cmd = cmd;
+    }
+
+    /// Compare the registers the driver owns against the values it last
+    /// wrote.
+    fn verify_registers(&self) -> Vec<RegisterMismatch> {
+        self.shadow.verify(self.index, &self.port)
+    }
+
+    /// Recover links stuck at DET=1 (device present, no Phy communication),
+    /// as drives often are after a warm reboot, by forcing a few COMRESETs
+    /// at the current speed limit.
+    ///
+    /// Failing that, link negotiation is retried with decreasing speed
+    /// limits, down to Gen 1: some marginal PHY/board combinations only
+    /// establish a link below the highest speed they advertise.
+    ///
+    /// Every attempt is made on all of `ports` at once. Returns whether a
+    /// link was established on each.
+    fn establish_links(
+        ports: &[(u8, VolatilePtr<'static, PortRegisters>)],
+        iss: LinkSpeed,
+    ) -> Vec<bool> {
+        let max = match iss {
+            LinkSpeed::Reserved => LinkSpeed::Gen3,
+            iss => iss,
+        };
+        // The speed limit of each attempt, `None` for the current one.
+        let attempts = iter::repeat_n(None, COMRESET_RETRIES)
+            .chain((1..max.into_bits()).rev().map(Some))
+            .enumerate();
+        let mut up = alloc::vec![false; ports.len()];
+        for (attempt, spd) in attempts {
+            let stuck: Vec<_> = (0..ports.len()).filter(|&n| !up[n]).collect();
+            if stuck.is_empty() {
+                break;
+            }
+            for &n in &stuck {
+                let (i, port) = ports[n];
+                match spd {
+                    None => port_debug!(
+                        i,
+                        "Port {i} no Phy communication, COMRESET attempt {}",
+                        attempt + 1
+                    ),
+                    Some(spd) => port_debug!(
+                        i,
+                        "Port {i} retrying link limited to {}",
+                        LinkSpeed::from_bits(spd)
+                    ),
+                }
+                Self::comreset(&port, spd.unwrap_or(port.SCTL().read().SPD()));
+            }
+            let ready = wait_all_timeout::<HalClock<H>, _>(
+                &stuck,
+                |&n| ports[n].1.SSTS().read().DET() == 3,
+                1000,
+            );
+            for (n, ready) in stuck.into_iter().zip(ready) {
+                let (i, port) = ports[n];
+                if ready {
+                    match spd {
+                        None => port_info!(i, "Port {i} link established after COMRESET"),
+                        Some(spd) => {
+                            port_info!(
+                                i,
+                                "Port {i} link established at {}",
+                                LinkSpeed::from_bits(spd)
+                            )
+                        }
+                    }
+                    port.SERR().write(port.SERR().read());
+                    up[n] = true;
+                } else if spd.is_none() {
+                    // Clear DIAG.X, or the next COMINIT from the device goes
+                    // unnoticed.
+                    port.SERR().write(PxSERR::new().with_DIAG_X(true));
+                }
+            }
+        }
+        up
+    }
+
+    fn exec_cmd(&mut self, cfis: sata_fis_h2d, data: Option<DmaMapping>) -> Result<(), AhciError> {
+        if let Err(err) = self.drain() {
+            if let Some(data) = data {
+                data.unmap::<H>();
+            }
+            return Err(err);
+        }
+        // The first command after leaving a low power state may fail
+        // spuriously, so it gets one more try. The drive may also have to
+        // spin up.
+        let woke = self.wake_link();
+        let mut retries = self.retry.command_retries as usize + woke as usize;
+        let timeout = match woke {
+            true => self.capped(self.timeouts.spin_up_ms),
+            false => self.command_timeout(),
+        };
+        let transfer = data.as_ref().map_or(Transfer::None, DmaMapping::transfer);
+        self.issue_mapped(0, cfis, data)?;
+        loop {
+            match self.wait_slot(0, timeout) {
+                // A command the device aborted would only be aborted again.
+                Err(err @ AhciError::TaskFile { error, .. }) if error & ATA_ABORTED != 0 => {
+                    return Err(err);
+                }
+                Err(err @ (AhciError::TaskFile { .. } | AhciError::Timeout { .. }))
+                    if retries > 0 =>
+                {
+                    // The port was recovered by now.
+                    port_warn!(self.index, "Port {} {err}, retrying", self.index);
+                    retries -= 1;
+                    self.stats.retries += 1;
+                    let data = self.map(transfer)?;
+                    self.issue_mapped(0, cfis, data)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Bring the link back to the Active state if it is in Partial, Slumber
+    /// or DevSleep, returning whether it was.
+    ///
+    /// Setting PxCMD.ICC to Active makes the HBA send COMWAKE, or go through
+    /// the OOB sequence again for DevSleep. This leaves PhyRdy Change,
+    /// COMWAKE and Exchanged set in PxSERR, which are cleared so they are
+    /// not taken for an error of the next command.
+    fn wake_link(&mut self) -> bool {
+        let asleep = |ipm| {
+            matches!(
+                LinkPowerState::from_ipm(ipm),
+                LinkPowerState::Partial | LinkPowerState::Slumber | LinkPowerState::DevSleep
+            )
+        };
+        if !asleep(self.port.SSTS().read().IPM()) {
+            return false;
+        }
+        let i = self.index;
+        port_debug!(i, "Port {i} waking up link");
+        self.update_cmd(|cmd| cmd.with_ICC(ICC::Active));
+        // DevSleep exit takes up to 20 ms by default (DETO).
+        if !wait_until_timeout::<HalClock<H>>(
+            || {
+                let ssts = self.port.SSTS().read();
+                ssts.DET() == 3 && !asleep(ssts.IPM())
+            },
+            20,
+        ) {
+            port_warn!(i, "Port {i} link wake-up timeout");
+        }
+        self.port.SERR().write(
+            PxSERR::new()
+                .with_DIAG_N(true)
+                .with_DIAG_W(true)
+                .with_DIAG_X(true),
+        );
+        self.check_power_state(PxI::new().with_PRC(true));
+        true
+    }
+
+    /// Map all of `data`, failing if the PRDT can't describe it.
+    fn map(&self, data: Transfer) -> Result<Option<DmaMapping>, AhciError> {
+        let len = data.buf().len();
+        if len > AHCI_MAX_BYTES_PER_CMD {
+            port_error!(
+                self.index,
+                "Port {} exceeding max transfer data limit",
+                self.index
+            );
+            return Err(AhciError::InvalidArgument);
+        }
+        match DmaMapping::new::<H>(data, 1) {
+            Some(mapping) if mapping.len < len => {
+                port_error!(self.index, "Port {} exceeding max sg limit", self.index);
+                mapping.unmap::<H>();
+                Err(AhciError::InvalidArgument)
+            }
+            mapping => Ok(mapping),
+        }
+    }
+
+    /// Build the command in `slot` and issue it without waiting for its
+    /// completion. The slot must be free.
+    ///
+    /// READ and WRITE FPDMA QUEUED commands are issued as native queued
+    /// commands, with `slot` as their tag; the FIS must carry the same tag.
+    fn issue_cmd(
+        &mut self,
+        slot: usize,
+        cfis: sata_fis_h2d,
+        data: Transfer,
+    ) -> Result<(), AhciError> {
+        let data = self.map(data)?;
+        self.issue_mapped(slot, cfis, data)
+    }
+
+    /// Like [`issue_cmd`](Self::issue_cmd), with the data already mapped.
+    /// The mapping is released on failure.
+    fn issue_mapped(
+        &mut self,
+        slot: usize,
+        mut cfis: sata_fis_h2d,
+        data: Option<DmaMapping>,
+    ) -> Result<(), AhciError> {
+        let mut check = match &data {
+            _ if !self.present => Err(AhciError::DeviceGone),
+            Some(data) => data.validate(self.index, self.dma32),
+            None => Ok(()),
+        };
+        if check.is_ok() && self.pmp != self.busy_pmp && !self.slots.is_empty() {
+            // Without FIS-based switching, only one device behind a port
+            // multiplier may have commands outstanding.
+            check = self.drain();
+        }
+        if let Err(err) = check {
+            if let Some(data) = data {
+                data.unmap::<H>();
+            }
+            return Err(err);
+        }
+        self.wake_link();
+        let queued = matches!(
+            cfis.command,
+            ATA_CMD_FPDMA_READ | ATA_CMD_FPDMA_WRITE | ATA_CMD_NCQ_NON_DATA
+        );
+        let cmd_tbl = &self.cmd_tbls[slot];
+        let is_write = data.as_ref().is_some_and(DmaMapping::is_write);
+        let bytes = data.as_ref().map_or(0, |data| data.len);
+
+        // Write command FIS to command table
+        cfis.pm_port_c = (cfis.pm_port_c & 0x80) | self.pmp;
+        cmd_tbl.tbl.hdr().write(cfis);
+
+        let prds = data.as_ref().map_or(&[][..], |data| &data.prds);
+        for (i, &(addr, len)) in prds.iter().enumerate() {
+            let sg = unsafe { &mut cmd_tbl.tbl.sgs().map(|sg| sg.cast::<ahci_sg>().add(i)) };
+            sg.write(ahci_sg {
+                addr_lo: addr as u32,
+                addr_hi: (addr >> 32) as u32,
+                flags_size: (len - 1) as u32 & 0x3fffff, // DBC: Data Byte Count (0-based)
+                ..Default::default()
+            });
+        }
+        let sg_cnt = prds.len();
+
+        // Build command header options:
+        // Bits 0-4: Command FIS length in DWORDs (5 for sata_fis_h2d which is 20 bytes
+        // = 5 DWORDs) Bit 6: Write (1) or Read (0)
+        // Bits 8 and 10: Reset and Clear Busy upon R_OK, for a control FIS
+        // asserting SRST
+        // Bits 12-15: Port Multiplier Port
+        // Bits 16-31: PRDTL (Physical Region Descriptor Table Length)
+        let cfl = size_of::<sata_fis_h2d>() / 4; // 20 / 4 = 5
+        let reset = cfis.pm_port_c & 0x80 == 0 && cfis.control & ATA_SRST != 0;
+        let opts = (cfl as u32)
+            | ((sg_cnt as u32) << 16)
+            | ((is_write as u32) << 6)
+            | ((reset as u32) * (1 << 8 | 1 << 10))
+            | ((self.pmp as u32) << 12);
+
+        let cmd_tbl_addr = cmd_tbl.addr;
+
+        port_debug!(
+            self.index,
+            "exec_cmd: slot={} opts={:#x} cmd_tbl_addr={:#x} sg_cnt={} buf_len={}",
+            slot,
+            opts,
+            cmd_tbl_addr,
+            sg_cnt,
+            bytes
+        );
+
+        let hdr = unsafe {
+            self.cmd_list
+                .map(|list| list.cast::<ahci_cmd_hdr>().add(slot))
+        };
+        hdr.write(ahci_cmd_hdr {
+            opts,
+            status: 0,
+            tbl_addr_lo: cmd_tbl_addr as u32,
+            tbl_addr_hi: (cmd_tbl_addr >> 32) as u32,
+            reserved: [0; 4],
+        });
+
+        sync_desc_for_device::<H>(
+            hdr.as_raw_ptr().addr().get(),
+            size_of::<ahci_cmd_hdr>(),
+            DmaDirection::Bidirectional,
+        );
+        sync_desc_for_device::<H>(
+            cmd_tbl.tbl.as_raw_ptr().addr().get(),
+            size_of::<ahci_cmd_tbl>(),
+            DmaDirection::ToDevice,
+        );
+        if let Some(data) = &data {
+            data.sync_for_device::<H>();
+        }
+
+        // Issue command. The slot state and PxCI must change together, or an
+        // interrupt in between could complete the wrong command. A task file
+        // error left over from a command that already completed would make
+        // the new one look failed.
+        H::with_irqs_disabled(|| {
+            if self.slots.is_empty() {
+                self.take_is(PxI::new().with_TFE(true));
+            }
+            self.slots.insert(
+                slot,
+                Inflight {
+                    seq: 0,
+                    issued_at: H::current_ms(),
+                    command: cfis.command,
+                    lba: cfis.lba(),
+                    bytes,
+                    cpu: H::current_cpu(),
+                    hung_reported: false,
+                    queued,
+                    data,
+                    flush: None,
+                    ticket: None,
+                },
+            );
+            // PxSACT must be set before PxCI for queued commands.
+            if queued {
+                self.port.SACT().write(1 << slot);
+            }
+            self.port.CI().write(1 << slot);
+        });
+        self.busy_pmp = self.pmp;
+        self.issued += 1;
+        self.writes_issued += is_write as u64;
+        self.stats.commands += 1;
+        self.sync_activity();
+        Ok(())
+    }
+
+    /// Complete the outstanding commands the HBA is done with.
+    ///
+    /// Returns `true` if no command is outstanding anymore.
+    fn try_complete(&mut self) -> bool {
+        if self.slots.is_empty() {
+            return true;
+        }
+        let queued = self.slots.iter().any(|i| i.queued);
+        let done = H::with_irqs_disabled(|| {
+            let mut pending = self.port.CI().read();
+            if queued {
+                pending |= self.port.SACT().read();
+            }
+            self.slots.take_completed(pending)
+        });
+        for inflight in done {
+            self.complete(inflight);
+        }
+        self.sync_activity();
+        self.slots.is_empty()
+    }
+
+    /// Like [`try_complete`](Self::try_complete), but if only queued
+    /// commands are outstanding, skips reading the registers unless a Set
+    /// Device Bits FIS arrived.
+    ///
+    /// A FIS arriving while the previous one is consumed can go unnoticed,
+    /// so callers fall back to [`try_complete`](Self::try_complete) from
+    /// time to time.
+    fn try_complete_fast(&mut self) -> bool {
+        if !self.slots.is_empty() && self.slots.iter().all(|i| i.queued) && !self.take_sdb_fis() {
+            return false;
+        }
+        self.try_complete()
+    }
+
+    /// Check for and consume a Set Device Bits FIS in the received FIS area.
+    ///
+    /// Its SActive field holds the tags the device completed. The field is
+    /// cleared after reading so the next FIS can be told apart. PxSACT is
+    /// still read to determine the completed tags, as a FIS arriving before
+    /// the previous one was consumed overwrites it.
+    fn take_sdb_fis(&self) -> bool {
+        // SAFETY: The SActive field is within the received FIS area.
+        let sactive = unsafe {
+            self.fis
+                .map(|fis| fis.cast::<u32>().add(RX_FIS_SDB / 4 + 1))
+        };
+        let va = sactive.as_raw_ptr().addr().get();
+        sync_desc_for_cpu::<H>(va, 4, DmaDirection::FromDevice);
+        if sactive.read() == 0 {
+            return false;
+        }
+        sactive.write(0);
+        sync_desc_for_device::<H>(va, 4, DmaDirection::Bidirectional);
+        true
+    }
+
+    /// Update this port's bit in the activity bitmap, telling the platform
+    /// when the port and when the whole HBA become idle or busy.
+    fn sync_activity(&self) {
+        let bit = 1 << self.index;
+        if self.slots.is_empty() {
+            let prev = self.activity.fetch_and(!bit, Ordering::AcqRel);
+            if prev & bit != 0 {
+                H::activity_led(self.index, false);
+                if prev == bit {
+                    H::link_idle_hint(true);
+                }
+            }
+        } else {
+            let prev = self.activity.fetch_or(bit, Ordering::AcqRel);
+            if prev & bit == 0 {
+                H::activity_led(self.index, true);
+                if prev == 0 {
+                    H::link_idle_hint(false);
+                }
+            }
+        }
+    }
+
+    /// Finish a command the HBA is done with.
+    fn complete(&mut self, inflight: Inflight) {
+        let latency_ms = H::current_ms() - inflight.issued_at;
+        self.stats.complete(latency_ms);
+        if let Some(data) = &inflight.data
+            && (inflight.queued || !self.port.TFD().read().STS_ERR())
+        {
+            match data.dir {
+                DmaDirection::FromDevice => self.stats.bytes_read += data.len as u64,
+                DmaDirection::ToDevice => self.stats.bytes_written += data.len as u64,
+                DmaDirection::Bidirectional => {}
+            }
+        }
+        if self.slow_io_threshold.is_some_and(|t| latency_ms > t) {
+            port_warn!(
+                self.index,
+                "Port {} slow I/O: command {:#x} LBA {} {} bytes took {latency_ms} ms",
+                self.index,
+                inflight.command,
+                inflight.lba,
+                inflight.bytes
+            );
+            self.events.push_back(AhciEvent::SlowIo {
+                port: self.index,
+                command: inflight.command,
+                lba: inflight.lba,
+                bytes: inflight.bytes,
+                latency_ms,
+                cpu: inflight.cpu,
+            });
+        }
+        if let Some(data) = inflight.data {
+            data.sync_for_cpu::<H>();
+            data.unmap::<H>();
+        }
+
+        if let Some(ticket) = inflight.ticket {
+            // A queued command is only completed once the device succeeded.
+            let result = if inflight.queued {
+                Ok(())
+            } else {
+                self.check_task_file(inflight.command)
+            };
+            if let Some(io) = self.submitted.get_mut(&ticket) {
+                io.result = Some(result);
+            }
+        }
+        if let Some(covered) = inflight.flush {
+            let tfd = self.port.TFD().read();
+            if tfd.STS_ERR() {
+                port_error!(
+                    self.index,
+                    "Port {} cache flush failed (TFD: {tfd:?})",
+                    self.index
+                );
+                let error = self.flush_error();
+                self.flush.fail(covered, error);
+                self.report_error(self.error_record(self.flush.command, None, error));
+            } else {
+                self.flush.completed = covered;
+            }
+            self.kick_flush();
+        }
+    }
+
+    /// Time a command may take before it times out, in milliseconds.
+    fn command_timeout(&self) -> u64 {
+        self.capped(self.timeouts.command_ms)
+    }
+
+    /// Limit `timeout` to the latency bound, if any.
+    fn capped(&self, timeout: u64) -> u64 {
+        self.max_latency.map_or(timeout, |max| max.min(timeout))
+    }
+
+    /// Wait for the command in `slot` to complete.
+    ///
+    /// A task file error stops the command engine, so all outstanding
+    /// commands are aborted. So does a timeout, which also resets the
+    /// device.
+    fn wait_slot(&mut self, slot: usize, timeout: u64) -> Result<(), AhciError> {
+        let (command, bytes, queued) = self.slots.slots[slot]
+            .as_ref()
+            .map_or((0, 0, false), |i| (i.command, i.bytes, i.queued));
+        let bit = 1 << slot;
+        let port = self.port;
+        let pending = || (port.CI().read() | port.SACT().read()) & bit != 0;
+        // A queued command is only looked up in the registers once a Set
+        // Device Bits FIS arrived, or every few milliseconds in case one
+        // went unnoticed.
+        let next_poll = Cell::new(H::current_ms() + SDB_FALLBACK_POLL_MS);
+        let maybe_done = || {
+            if !queued || self.take_sdb_fis() {
+                return true;
+            }
+            let now = H::current_ms();
+            if now < next_poll.get() {
+                return false;
+            }
+            next_poll.set(now + SDB_FALLBACK_POLL_MS);
+            true
+        };
+        let (index, irq_mode) = (self.index, self.irq_mode);
+        let poll_interval = self
+            .coalescing
+            .filter(|c| irq_mode && bytes >= c.poll_threshold)
+            .map(|c| c.poll_interval_ms.max(1));
+        if poll_interval.is_some() {
+            // Without completion interrupts nothing wakes the waiter before
+            // the poll interval passes, unless an error is signalled.
+            port.IE().write(
+                self.shadow
+                    .ie
+                    .with_DHR(false)
+                    .with_PS(false)
+                    .with_SDB(false)
+                    .with_DP(false),
+            );
+        }
+        let done = wait_until_idle::<HalClock<H>>(
+            || (maybe_done() && !pending()) || self.take_is(PxI::new()).TFE() || !self.link_up(),
+            timeout,
+            |remaining| {
+                if irq_mode {
+                    H::irq_wait(
+                        index,
+                        poll_interval.map_or(remaining, |ms| ms.min(remaining)),
+                    );
+                } else {
+                    core::hint::spin_loop();
+                }
+            },
+        );
+        if poll_interval.is_some() {
+            port.IE().write(self.shadow.ie);
+        }
+        if self.check_gone() {
+            return Err(AhciError::DeviceGone);
+        }
+        let lba = self.slots.slots[slot].as_ref().map(|i| i.lba);
+        if done && pending() {
+            let err = self.task_file_error(command);
+            port_error!(
+                self.index,
+                "Port {} command in slot {slot}: {err}",
+                self.index
+            );
+            #[cfg(feature = "ncq")]
+            let queued = self.slots.iter().any(|i| i.queued);
+            let mut record = self.error_record(command, lba, err);
+            record.recovery = self.recover(false).into();
+            #[cfg(feature = "ncq")]
+            if queued {
+                self.log_ncq_error(&mut record);
+            }
+            self.report_error(record);
+            return Err(err);
+        }
+        if !done {
+            let is = self.port.IS().read();
+            let tfd = self.port.TFD().read();
+            port_error!(
+                self.index,
+                "AHCI command timeout: IS={:?} TFD={:?} {}",
+                is,
+                tfd,
+                self.engine_state()
+            );
+            let err = AhciError::Timeout { command };
+            let mut record = self.error_record(command, lba, err);
+            record.recovery = self.recover(true).into();
+            self.report_error(record);
+            return Err(err);
+        }
+        self.try_complete();
+        Ok(())
+    }
+
+    /// Wait until all slots are free, completing any asynchronous commands.
+    fn drain(&mut self) -> Result<(), AhciError> {
+        while let Some(slot) = self.slots.oldest_tag() {
+            self.wait_slot(slot, self.command_timeout())
+                .inspect_err(|_| port_error!(self.index, "Slot {slot} busy timeout"))?;
+        }
+        Ok(())
+    }
+
+    /// The error the device reported for `command` in PxTFD.
+    fn task_file_error(&self, command: u8) -> AhciError {
+        let tfd = self.port.TFD().read();
+        AhciError::TaskFile {
+            command,
+            status: tfd.into_bits() as u8,
+            error: tfd.ERR(),
+        }
+    }
+
+    /// Fail if the device reported an error for the completed non-queued
+    /// `command`.
+    fn check_task_file(&self, command: u8) -> Result<(), AhciError> {
+        if self.port.TFD().read().STS_ERR() {
+            return Err(self.task_file_error(command));
+        }
+        Ok(())
+    }
+
+    /// Issue a native queued read or write of `count` sectors at `lba`,
+    /// using one of the first `depth` slots as its tag.
+    ///
+    /// Waits for the oldest command if all of them are in use. Commands that
+    /// can't be queued are completed first, as the two kinds must not be
+    /// outstanding at the same time.
+    fn issue_queued(
+        &mut self,
+        lba: u64,
+        count: u16,
+        data: DmaMapping,
+        depth: usize,
+    ) -> Result<(), AhciError> {
+        let slot = match self.free_queued_slot(depth) {
+            Ok(slot) => slot,
+            Err(err) => {
+                data.unmap::<H>();
+                return Err(err);
+            }
+        };
+        let fis = fpdma_fis(data.is_write(), lba, count, slot);
+        self.issue_mapped(slot, fis, Some(data))
+    }
+
+    /// Issue an NCQ NON-DATA command with `subcommand` and wait for it,
+    /// using one of the first `depth` slots as its tag.
+    #[cfg(feature = "ncq")]
+    fn ncq_non_data(
+        &mut self,
+        subcommand: u16,
+        lba: u64,
+        auxiliary: u32,
+        depth: usize,
+    ) -> Result<(), AhciError> {
+        let slot = self.free_queued_slot(depth)?;
+        let mut fis = sata_fis_h2d::command(ATA_CMD_NCQ_NON_DATA);
+        fis.set_lba48(lba, 0);
+        fis.features = subcommand as u8;
+        fis.features_exp = (subcommand >> 8) as u8;
+        fis.sector_count = (slot as u8) << 3;
+        fis.auxiliary = auxiliary.to_le_bytes();
+        self.issue_mapped(slot, fis, None)?;
+        self.wait_slot(slot, self.command_timeout())
+    }
+
+    /// Wait for a slot to issue a queued command in, using at most `depth`
+    /// slots.
+    fn free_queued_slot(&mut self, depth: usize) -> Result<usize, AhciError> {
+        if self.slots.iter().any(|i| !i.queued) {
+            self.drain()?;
+        }
+        loop {
+            if let Some(slot) = self.slots.free_slot(depth) {
+                return Ok(slot);
+            }
+            let oldest = self.slots.oldest_tag().ok_or(AhciError::InvalidArgument)?;
+            self.wait_slot(oldest, self.command_timeout())?;
+        }
+    }
+
+    /// Read PxIS, including the bits an [`IrqHandler`] acknowledged since it
+    /// was last read, and clear the bits in `mask`.
+    fn take_is(&self, mask: PxI) -> PxI {
+        let mask = mask.into_bits();
+        let is = self.port.IS().read().into_bits();
+        if is & mask != 0 {
+            self.port.IS().write(PxI::from_bits(is & mask));
+        }
+        PxI::from_bits(is | self.irq.take(self.index, mask))
+    }
+
+    /// Forget the outstanding commands after they were aborted, releasing
+    /// their DMA mappings.
+    fn abort_inflight(&mut self) {
+        for inflight in H::with_irqs_disabled(|| self.slots.take_all()) {
+            if let Some(data) = inflight.data {
+                data.unmap::<H>();
+            }
+            if let Some(covered) = inflight.flush {
+                self.flush.fail(covered, AhciError::Aborted);
+            }
+            if let Some(io) = inflight.ticket.and_then(|t| self.submitted.get_mut(&t)) {
+                io.result = Some(Err(AhciError::Aborted));
+            }
+        }
+        self.sync_activity();
+    }
+
+    /// Forget the outstanding commands while the command engine may still
+    /// be running, leaking their DMA mappings and the buffers of requests
+    /// made with [`AhciDriver::try_submit`].
+    ///
+    /// The HBA may still transfer data to or from them, so they must neither
+    /// be unmapped nor handed back to the caller: such requests stay
+    /// outstanding for good.
+    fn leak_inflight(&mut self) {
+        let leaked = H::with_irqs_disabled(|| self.slots.take_all());
+        port_error!(
+            self.index,
+            "Port {} leaking the buffers of {} commands",
+            self.index,
+            leaked.len()
+        );
+        for inflight in leaked {
+            // Dropped without being unmapped.
+            drop(inflight.data);
+            if let Some(covered) = inflight.flush {
+                self.flush.fail(covered, AhciError::DeviceGone);
+            }
+            if let Some(io) = inflight.ticket.and_then(|t| self.submitted.remove(&t)) {
+                mem::forget(io.buf);
+            }
+        }
+        self.sync_activity();
+    }
+
+    /// Recover the port if a queued command failed.
+    ///
+    /// Failed queued commands stay set in PxSACT until the command engine is
+    /// restarted, so they would never be completed otherwise.
+    fn check_queued_error(&mut self) {
+        if self.slots.iter().any(|i| i.queued) && self.take_is(PxI::new()).TFE() {
+            port_error!(
+                self.index,
+                "Port {} queued command failed (TFD: {:?})",
+                self.index,
+                self.port.TFD().read()
+            );
+            let command = self
+                .slots
+                .iter()
+                .find(|i| i.queued)
+                .map_or(0, |i| i.command);
+            let mut record = self.error_record(command, None, self.task_file_error(command));
+            record.recovery = self.recover(false).into();
+            #[cfg(feature = "ncq")]
+            self.log_ncq_error(&mut record);
+            self.report_error(record);
+        }
+    }
+
+    /// Read the NCQ Command Error log once a failed queued command was
+    /// recovered from, keeping it and filling in the failed LBA of
+    /// `record`.
+    ///
+    /// The drive aborts all queued commands on an error and rejects new
+    /// ones until the log is read.
+    #[cfg(feature = "ncq")]
+    fn log_ncq_error(&mut self, record: &mut ErrorRecord) {
+        if record.recovery != Recovery::Recovered {
+            return;
+        }
+        let mut page = DataBlock::new();
+        match self.read_log_ext(ATA_LOG_NCQ_ERROR, 0, &mut page) {
+            Ok(()) => {
+                let log = NcqErrorLog::from_log(&page.0);
+                port_error!(self.index, "Port {} NCQ error log: {log:?}", self.index);
+                record.lba = Some(log.lba);
+                self.ncq_error = Some(log);
+            }
+            Err(err) => port_warn!(
+                self.index,
+                "Port {} failed to read NCQ error log: {err}",
+                self.index
+            ),
+        }
+    }
+
+    /// Whether the Phy communication with the device is established.
+    fn link_up(&self) -> bool {
+        self.port.SSTS().read().DET() == 3
+    }
+
+    /// If the device dropped off the link with commands outstanding, abort
+    /// them and report an [`AhciEvent::DeviceGone`].
+    ///
+    /// The command engine is stopped first, which also clears PxCI and
+    /// PxSACT, so the HBA no longer references the commands' data buffers
+    /// by the time they are released. If it does not stop, the link is
+    /// disabled to end any transfer in progress and the stop is retried;
+    /// failing that, the buffers are leaked rather than released, see
+    /// [`leak_inflight`](Self::leak_inflight). Returns whether the device
+    /// is gone.
+    fn check_gone(&mut self) -> bool {
+        if !self.present {
+            return true;
+        }
+        if self.slots.is_empty() || self.link_up() {
+            return false;
+        }
+        let i = self.index;
+        port_error!(i, "Port {i} device removed with I/O outstanding");
+        self.present = false;
+
+        let port = self.port;
+        let idle = || !port.CMD().read().CR() && port.CI().read() == 0;
+        self.update_cmd(|cmd| cmd.with_ST(false));
+        let mut stopped = wait_until_timeout::<HalClock<H>>(idle, self.timeouts.engine_stop_ms);
+        if !stopped {
+            port_warn!(i, "Port {i} stop engine timeout (CR), disabling the link");
+            // DET=4 takes the Phy offline. DET is cleared again afterwards so
+            // that a new device can still be detected.
+            let sctl = port.SCTL().read();
+            port.SCTL().write(sctl.with_DET(4));
+            stopped = wait_until_timeout::<HalClock<H>>(idle, self.timeouts.engine_stop_ms);
+            port.SCTL().write(sctl.with_DET(0));
+            self.shadow.sctl = sctl.with_DET(0);
+        }
+        let aborted = self.slots.iter().count();
+        if stopped {
+            self.abort_inflight();
+        } else {
+            port_error!(i, "Port {i} stop engine timeout (CR)");
+            self.leak_inflight();
+        }
+        H::with_irqs_disabled(|| self.take_is(PxI::from_bits(!0)));
+        self.port.SERR().write(self.port.SERR().read());
+
+        self.events
+            .push_back(AhciEvent::DeviceGone { port: i, aborted });
+        true
+    }
+
+    /// Report an [`AhciEvent::LinkPowerStateChanged`] if `is` has the PhyRdy
+    /// Change or Incorrect Port Multiplier status set and the interface power
+    /// state differs from the one last reported.
+    ///
+    /// Interface power transitions toggle PhyRdy, so PxSERR.DIAG_N is
+    /// cleared to rearm PxIS.PRCS, which mirrors it.
+    fn check_power_state(&mut self, is: PxI) {
+        if !is.PRC() && !is.IPM() {
+            return;
+        }
+        self.port.SERR().write(PxSERR::new().with_DIAG_N(true));
+        let state = LinkPowerState::from_ipm(self.port.SSTS().read().IPM());
+        if state == self.power_state {
+            return;
+        }
+        port_debug!(self.index, "Port {} link power state {state:?}", self.index);
+        self.events.push_back(AhciEvent::LinkPowerStateChanged {
+            port: self.index,
+            previous: self.power_state,
+            state,
+        });
+        self.power_state = state;
+    }
+
+    /// Request a cache flush and return the request's sequence number.
+    ///
+    /// Requests arriving while a flush is outstanding are satisfied together
+    /// by the next one. FLUSH CACHE cannot be queued, so any other
+    /// outstanding command, queued or not, is completed first; this also
+    /// makes the flush cover every write issued before the request.
+    fn flush_async(&mut self, command: u8) -> u64 {
+        self.flush.command = command;
+        self.flush.requested += 1;
+        if self.slots.iter().any(|i| i.flush.is_none()) {
+            // A failed command reports its error on its own.
+            let _ = self.drain();
+        }
+        self.try_complete();
+        self.kick_flush();
+        self.flush.requested
+    }
+
+    /// Issue a flush covering all pending requests if the slot is free.
+    fn kick_flush(&mut self) {
+        if !self.slots.is_empty() || self.flush.requested == self.flush.completed {
+            return;
+        }
+        let fis = sata_fis_h2d::command(self.flush.command);
+        if self.issue_cmd(0, fis, Transfer::None).is_ok() {
+            if let Some(inflight) = self.slots.get_mut(0) {
+                inflight.flush = Some(self.flush.requested);
+            }
+        } else {
+            self.flush.fail(self.flush.requested, AhciError::DeviceGone);
+        }
+    }
+
+    /// The error of a failed flush, with the first sector that could not be
+    /// written as reported in the D2H Register FIS.
+    fn flush_error(&self) -> AhciError {
+        let tfd = self.port.TFD().read();
+        let fis = TaskFileResult::from_fis(
+            &self.read_rx_fis::<20>(RX_FIS_D2H_REG),
+            tfd.into_bits() as u8,
+            tfd.ERR(),
+        );
+        let lba = if self.flush.command == ATA_CMD_FLUSH {
+            fis.lba & 0xff_ffff | ((fis.device & 0x0f) as u64) << 24
+        } else {
+            fis.lba
+        };
+        AhciError::FlushFailed {
+            lba,
+            status: fis.status,
+            error: fis.error,
+        }
+    }
+
+    fn engine_state(&self) -> EngineState {
+        let cmd = self.port.CMD().read();
+        EngineState {
+            started: cmd.ST(),
+            running: cmd.CR(),
+            fis_receive_enabled: cmd.FRE(),
+            fis_receive_running: cmd.FR(),
+            current_slot: cmd.CCS(),
+            issued: self.port.CI().read(),
+        }
+    }
+
+    fn diagnostics(&self) -> PortDiagnostics {
+        PortDiagnostics {
+            port: self.index,
+            engine: self.engine_state(),
+            is: self.port.IS().read().into_bits(),
+            tfd: self.port.TFD().read().into_bits(),
+            ssts: self.port.SSTS().read().into_bits(),
+            serr: self.port.SERR().read().into_bits(),
+            sact: self.port.SACT().read(),
+        }
+    }
+
+    /// Report the oldest outstanding command's slot, age and submitting CPU
+    /// once it exceeds `threshold_ms`.
+    fn check_hung(&mut self, threshold_ms: u64) -> Option<(u8, u64, Option<usize>)> {
+        if self.try_complete() {
+            return None;
+        }
+        H::with_irqs_disabled(|| {
+            let slot = self.slots.oldest_tag()?;
+            let inflight = self.slots.get_mut(slot)?;
+            let age = H::current_ms() - inflight.issued_at;
+            if age <= threshold_ms || inflight.hung_reported {
+                return None;
+            }
+            inflight.hung_reported = true;
+            Some((slot as u8, age, inflight.cpu))
+        })
+    }
+
+    /// Stop and restart the command engine, dropping any outstanding command.
+    ///
+    /// With `reset`, the device is reset as well, e.g. because it stopped
+    /// responding: with a software reset if it is behind a port multiplier,
+    /// otherwise with a COMRESET. A device that was reset is flagged as
+    /// having lost its settings. In passive mode, the device is never reset,
+    /// and recovery fails if it is still busy once the engine stopped.
+    fn recover(&mut self, reset: bool) -> bool {
+        let i = self.index;
+        let reset = reset && !self.resetting && !self.passive;
+        port_warn!(i, "Port {i} recovering");
+
+        self.update_cmd(|cmd| cmd.with_ST(false));
+        if !wait_until_timeout::<HalClock<H>>(
+            || !self.port.CMD().read().CR(),
+            self.timeouts.engine_stop_ms,
+        ) {
+            port_error!(i, "Port {i} stop engine timeout (CR)");
+            return false;
+        }
+        self.abort_inflight();
+
+        self.port.SERR().write(self.port.SERR().read());
+        H::with_irqs_disabled(|| self.take_is(PxI::from_bits(!0)));
+
+        // The engine must not be started while the device is busy. Override
+        // BSY/DRQ with CLO, or reset the device if the HBA can't.
+        let busy = || {
+            let tfd = self.port.TFD().read();
+            tfd.STS_BSY() || tfd.STS_DRQ()
+        };
+        let cleared = !busy() || (self.sclo && Self::clo(&self.port, i) && !busy());
+        if !cleared && self.passive {
+            port_error!(i, "Port {i} device busy, not resetting it in passive mode");
+            return false;
+        }
+        // A COMRESET would reset the port multiplier and all of its devices.
+        let soft_reset = reset && cleared && self.shadow.cmd.PMA();
+        if !cleared || (reset && !soft_reset) {
+            port_debug!(i, "Port {i} resetting the link with COMRESET");
+            let link_up = (0..=self.retry.comreset_retries).any(|attempt| {
+                if attempt > 0 {
+                    port_debug!(i, "Port {i} COMRESET attempt {}", attempt + 1);
+                    // Clear DIAG.X, or the next COMINIT from the device goes
+                    // unnoticed.
+                    self.port.SERR().write(PxSERR::new().with_DIAG_X(true));
+                }
+                self.shadow.sctl = Self::comreset(&self.port, self.port.SCTL().read().SPD());
+                wait_until_timeout::<HalClock<H>>(
+                    || self.port.SSTS().read().DET() == 3,
+                    self.timeouts.link_up_ms,
+                )
+            });
+            if !link_up {
+                port_error!(i, "Port {i} link lost after COMRESET");
+                return false;
+            }
+            self.port.SERR().write(self.port.SERR().read());
+            self.settings_lost = true;
+        }
+
+        self.update_cmd(|cmd| cmd.with_ST(true));
+        if !wait_until_timeout::<HalClock<H>>(
+            || {
+                let tfd = self.port.TFD().read();
+                !(tfd.STS_ERR() | tfd.STS_DRQ() | tfd.STS_BSY())
+            },
+            self.timeouts.device_ready_ms,
+        ) {
+            port_error!(
+                i,
+                "Port {i} still busy after recovery (TFD: {:?})",
+                self.port.TFD().read()
+            );
+            return false;
+        }
+        if soft_reset {
+            if let Err(err) = self.soft_reset(self.pmp) {
+                port_error!(i, "Port {i}.{} software reset failed: {err}", self.pmp);
+                return false;
+            }
+            self.settings_lost = true;
+        }
+        true
+    }
+
+    /// Like [`exec_cmd`](Self::exec_cmd), but also fails if the device
+    /// reported an error in the task file.
+    fn exec_checked(&mut self, cfis: sata_fis_h2d, data: Transfer) -> Result<(), AhciError> {
+        let data = self.map(data)?;
+        self.exec_mapped(cfis, data)
+    }
+
+    /// Like [`exec_checked`](Self::exec_checked), with the data already
+    /// mapped.
+    fn exec_mapped(
+        &mut self,
+        cfis: sata_fis_h2d,
+        data: Option<DmaMapping>,
+    ) -> Result<(), AhciError> {
+        self.exec_cmd(cfis, data)?;
+        self.check_task_file(cfis.command).inspect_err(|&err| {
+            port_warn!(self.index, "Port {}: {err}", self.index);
+            self.report_error(self.error_record(cfis.command, Some(cfis.lba()), err));
+        })
+    }
+
+    /// Describe an error detected on the port, capturing PxTFD and PxSERR
+    /// before recovery clears them.
+    fn error_record(&self, command: u8, lba: Option<u64>, error: AhciError) -> ErrorRecord {
+        ErrorRecord {
+            port: self.index,
+            command,
+            lba,
+            error,
+            tfd: self.port.TFD().read().into_bits(),
+            serr: self.port.SERR().read().into_bits(),
+            recovery: Recovery::NotAttempted,
+        }
+    }
+
+    /// Count the error of `record` and pass it to the error sink, if one is
+    /// set.
+    fn report_error(&mut self, record: ErrorRecord) {
+        match record.error {
+            AhciError::Timeout { .. } => self.stats.timeouts += 1,
+            AhciError::TaskFile { .. } | AhciError::FlushFailed { .. } => {
+                self.stats.task_file_errors += 1
+            }
+            _ => {}
+        }
+        if let Some(sink) = &mut self.error_sink {
+            sink.report(&record);
+        }
+    }
+
+    /// Issue a pass-through command and return the registers the device
+    /// reported on completion.
+    ///
+    /// Successful PIO data-in commands end with a PIO Setup FIS, all others
+    /// with a D2H Register FIS. Both are cleared first, so the one received
+    /// for this command can be told apart.
+    fn exec_task_file(
+        &mut self,
+        tf: TaskFile,
+        data: Transfer,
+    ) -> Result<TaskFileResult, AhciError> {
+        self.clear_rx_fis(RX_FIS_PIO_SETUP);
+        self.clear_rx_fis(RX_FIS_D2H_REG);
+        self.exec_checked(tf.to_fis(), data)?;
+
+        let tfd = self.port.TFD().read();
+        let d2h = self.read_rx_fis::<20>(RX_FIS_D2H_REG);
+        let fis = if d2h[0] == SATA_FIS_TYPE_REGISTER_D2H {
+            d2h
+        } else {
+            self.read_rx_fis::<20>(RX_FIS_PIO_SETUP)
+        };
+        Ok(TaskFileResult::from_fis(
+            &fis,
+            tfd.into_bits() as u8,
+            tfd.ERR(),
+        ))
+    }
+
+    /// Clear the type of the FIS at `offset` in the received FIS area.
+    fn clear_rx_fis(&mut self, offset: usize) {
+        // SAFETY: `offset` is within the received FIS area.
+        let ty = unsafe { self.fis.map(|fis| fis.cast::<u8>().add(offset)) };
+        ty.write(0);
+        sync_desc_for_device::<H>(ty.as_raw_ptr().addr().get(), 1, DmaDirection::Bidirectional);
+    }
+
+    /// Copy `N` bytes at `offset` out of the received FIS area.
+    fn read_rx_fis<const N: usize>(&self, offset: usize) -> [u8; N] {
+        // SAFETY: The range is within the received FIS area.
+        let base = unsafe { self.fis.map(|fis| fis.cast::<u8>().add(offset)) };
+        sync_desc_for_cpu::<H>(base.as_raw_ptr().addr().get(), N, DmaDirection::FromDevice);
+        core::array::from_fn(|i| {
+            // SAFETY: As above.
+            unsafe { base.map(|b| b.add(i)) }.read()
+        })
+    }
+
+    /// Set the number of sectors per data block of READ/WRITE MULTIPLE.
+    fn set_multiple(&mut self, count: u8) -> Result<(), AhciError> {
+        let mut fis = sata_fis_h2d::command(ATA_CMD_SET_MULTI);
+        fis.sector_count = count;
+        self.exec_checked(fis, Transfer::None)
+    }
+
+    /// Issue a non-data SET FEATURES command.
+    fn set_features(&mut self, feature: u8, count: u8) -> Result<(), AhciError> {
+        let mut fis = sata_fis_h2d::command(ATA_CMD_SET_FEATURES);
+        fis.features = feature;
+        fis.sector_count = count;
+        self.exec_checked(fis, Transfer::None)
+    }
+
+    /// Discard `count` sectors starting at `lba` with DATA SET MANAGEMENT,
+    /// sending one block of range entries per command.
+    fn trim(&mut self, mut lba: u64, mut count: u64) -> Result<(), AhciError> {
+        while count > 0 {
+            let mut ranges = DataBlock::new();
+            for range in ranges.0.chunks_exact_mut(8).take(ATA_DSM_RANGES_PER_BLOCK) {
+                if count == 0 {
+                    break;
+                }
+                // 48-bit LBA with the 16-bit sector count in the upper bits.
+                let n = count.min(ATA_DSM_MAX_RANGE_LEN);
+                range.copy_from_slice(&(lba | (n << 48)).to_le_bytes());
+                lba += n;
+                count -= n;
+            }
+
+            let mut fis = sata_fis_h2d::command(ATA_CMD_DSM);
+            fis.features = ATA_DSM_TRIM;
+            fis.set_lba48(0, 1);
+            self.exec_checked(fis, ranges.0.as_slice().into())?;
+        }
+        Ok(())
+    }
+
+    /// Fill `count` sectors starting at `lba` with a repeated 32-bit
+    /// `pattern` using SCT WRITE SAME.
+    fn sct_write_same(&mut self, lba: u64, count: u64, pattern: u32) -> Result<(), AhciError> {
+        let mut cmd = [0u16; 12];
+        cmd[0] = ATA_SCT_ACTION_WRITE_SAME;
+        cmd[1] = ATA_SCT_WRITE_SAME_PATTERN_FG;
+        for i in 0..4 {
+            cmd[2 + i] = (lba >> (16 * i)) as u16;
+            cmd[6 + i] = (count >> (16 * i)) as u16;
+        }
+        cmd[10] = pattern as u16;
+        cmd[11] = (pattern >> 16) as u16;
+        self.sct_command(&cmd)
+    }
+
+    /// Limit the time the drive spends on error recovery for reads and
+    /// writes to `limit` times 100 ms with SCT Error Recovery Control, or
+    /// remove the limit if it is 0.
+    fn sct_erc(&mut self, limit: u16) -> Result<(), AhciError> {
+        for selection in [ATA_SCT_ERC_READ, ATA_SCT_ERC_WRITE] {
+            self.sct_command(&[ATA_SCT_ACTION_ERC, ATA_SCT_ERC_SET, selection, limit])?;
+        }
+        Ok(())
+    }
+
+    /// Issue an SCT command made of the key sector words `words`.
+    fn sct_command(&mut self, words: &[u16]) -> Result<(), AhciError> {
+        let mut cmd = alloc::vec![0u16; 256];
+        for (word, &value) in cmd.iter_mut().zip(words) {
+            *word = value.to_le();
+        }
+
+        let mut fis = sata_fis_h2d::command(ATA_CMD_SMART);
+        fis.features = ATA_SMART_WRITE_LOG;
+        fis.sector_count = 1;
+        fis.lba_low = ATA_LOG_SCT_COMMAND;
+        fis.lba_mid = ATA_SMART_LBAM_PASS;
+        fis.lba_high = ATA_SMART_LBAH_PASS;
+        let buf = ptr::slice_from_raw_parts(cmd.as_ptr().cast::<u8>(), size_of_val(cmd.as_slice()));
+        self.exec_checked(fis, Transfer::ToDevice(buf))
+    }
+
+    /// Whether SMART RETURN STATUS reports that no attribute crossed its
+    /// threshold.
+    #[cfg(feature = "smart")]
+    fn smart_status(&mut self) -> Result<bool, AhciError> {
+        let tf = TaskFile {
+            features: ATA_SMART_STATUS as u16,
+            lba: (ATA_SMART_LBAH_PASS as u64) << 16 | (ATA_SMART_LBAM_PASS as u64) << 8,
+            device: 0,
+            ..TaskFile::new(ATA_CMD_SMART)
+        };
+        let result = self.exec_task_file(tf, Transfer::None)?;
+        let (mid, high) = ((result.lba >> 8) as u8, (result.lba >> 16) as u8);
+        Ok(!(mid == ATA_SMART_LBAM_FAIL && high == ATA_SMART_LBAH_FAIL))
+    }
+
+    /// Verify `count` sectors at `lba` with READ VERIFY SECTORS (EXT), which
+    /// reads them on the drive without transferring any data.
+    fn verify(&mut self, lba: u64, count: usize, lba48: bool) -> Result<(), AhciError> {
+        let count = sector_count_field(count, lba48).ok_or(AhciError::InvalidArgument)?;
+        let mut fis = sata_fis_h2d::command(if lba48 {
+            ATA_CMD_VERIFY_EXT
+        } else {
+            ATA_CMD_VERIFY
+        });
+        set_lba(&mut fis, lba, count, lba48);
+        self.exec_checked(fis, Transfer::None)
+    }
+
+    /// Read the IDENTIFY DEVICE data of the drive into `id`.
+    fn identify(&mut self, id: &mut IdentifyData) -> Result<(), AhciError> {
+        self.exec_checked(
+            sata_fis_h2d::command(ATA_CMD_ID_ATA),
+            Transfer::FromDevice(ptr::slice_from_raw_parts_mut(
+                id.as_mut_ptr().cast::<u8>(),
+                size_of_val(id),
+            )),
+        )
+    }
+
+    /// Enumerate the disks behind the port multiplier attached to the port,
+    /// resetting each of its device ports.
+    fn probe_pm(&mut self) -> Option<PortMultiplier> {
+        let i = self.index;
+        // PxCMD.PMA may only change while the command engine is stopped.
+        self.update_cmd(|cmd| cmd.with_ST(false));
+        if !wait_until_timeout::<HalClock<H>>(
+            || !self.port.CMD().read().CR(),
+            self.timeouts.engine_stop_ms,
+        ) {
+            port_error!(i, "Port {i} stop engine timeout (CR)");
+            return None;
+        }
+        self.update_cmd(|cmd| cmd.with_PMA(true).with_ST(true));
+
+        let gscr = (|| {
+            self.soft_reset(PMP_CONTROL)?;
+            Ok::<_, AhciError>(PortMultiplier::from_gscr(
+                self.pm_read(PMP_CONTROL, PMP_GSCR_PROD_ID)?,
+                self.pm_read(PMP_CONTROL, PMP_GSCR_REV)?,
+                self.pm_read(PMP_CONTROL, PMP_GSCR_PORT_INFO)?,
+            ))
+        })();
+        let mut pm = match gscr {
+            Ok(pm) => pm,
+            Err(err) => {
+                port_warn!(i, "Port {i} port multiplier not responding: {err}");
+                return None;
+            }
+        };
+        port_info!(
+            i,
+            "Port {i} port multiplier {:04x}:{:04x} with {} ports",
+            pm.vendor_id,
+            pm.device_id,
+            pm.fan_out
+        );
+
+        for pmp in 0..pm.fan_out {
+            match self.pm_link_up(pmp) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    port_warn!(i, "Port {i}.{pmp} link reset failed: {err}");
+                    continue;
+                }
+            }
+            match self.soft_reset(pmp).map(DeviceType::from_signature) {
+                Ok(DeviceType::Ata) => pm.disks.push(pmp),
+                Ok(ty) => port_info!(i, "Port {i}.{pmp} ignoring {ty:?} device"),
+                Err(err) => port_warn!(i, "Port {i}.{pmp} soft reset failed: {err}"),
+            }
+        }
+        Some(pm)
+    }
+
+    /// Reset the link of port multiplier port `pmp` with a COMRESET through
+    /// its SControl register, returning whether a device came up.
+    fn pm_link_up(&mut self, pmp: u8) -> Result<bool, AhciError> {
+        let sctl = self.pm_read(pmp, PMP_PSCR_SCONTROL)? & !0xf;
+        self.pm_write(pmp, PMP_PSCR_SCONTROL, sctl | 1)?;
+        // DET must stay at 1 for at least 1ms
+        wait_until_timeout::<HalClock<H>>(|| false, 1);
+        self.pm_write(pmp, PMP_PSCR_SCONTROL, sctl)?;
+
+        let deadline = H::current_ms() + PMP_LINK_TIMEOUT_MS;
+        while self.pm_read(pmp, PMP_PSCR_SSTATUS)? & 0xf != 3 {
+            if H::current_ms() >= deadline {
+                return Ok(false);
+            }
+        }
+        self.pm_write(pmp, PMP_PSCR_SERROR, !0)?;
+        Ok(true)
+    }
+
+    /// Read register `reg` of port multiplier port `pmp`, or of the port
+    /// multiplier itself for the control port.
+    fn pm_read(&mut self, pmp: u8, reg: u16) -> Result<u32, AhciError> {
+        let tf = TaskFile {
+            features: reg,
+            device: pmp,
+            ..TaskFile::new(ATA_CMD_PMP_READ)
+        };
+        let target = mem::replace(&mut self.pmp, PMP_CONTROL);
+        let result = self.exec_task_file(tf, Transfer::None);
+        self.pmp = target;
+        let result = result?;
+        Ok(result.count as u8 as u32 | (result.lba as u32 & 0xff_ffff) << 8)
+    }
+
+    /// Write `value` to register `reg` of port multiplier port `pmp`, or of
+    /// the port multiplier itself for the control port.
+    fn pm_write(&mut self, pmp: u8, reg: u16, value: u32) -> Result<(), AhciError> {
+        let tf = TaskFile {
+            features: reg,
+            device: pmp,
+            count: value as u8 as u16,
+            lba: (value >> 8) as u64,
+            ..TaskFile::new(ATA_CMD_PMP_WRITE)
+        };
+        let target = mem::replace(&mut self.pmp, PMP_CONTROL);
+        let result = self.exec_task_file(tf, Transfer::None);
+        self.pmp = target;
+        result.map(drop)
+    }
+
+    /// Reset the device at port multiplier port `pmp` by asserting and
+    /// releasing SRST, returning the signature it reports.
+    fn soft_reset(&mut self, pmp: u8) -> Result<u32, AhciError> {
+        let target = mem::replace(&mut self.pmp, pmp);
+        self.resetting = true;
+        let result = (|| {
+            let mut fis = sata_fis_h2d {
+                fis_type: SATA_FIS_TYPE_REGISTER_H2D,
+                control: ATA_SRST,
+                ..Default::default()
+            };
+            self.exec_checked(fis, Transfer::None)?;
+            // SRST must stay asserted for at least 5 µs.
+            wait_until_timeout::<HalClock<H>>(|| false, 1);
+            self.clear_rx_fis(RX_FIS_D2H_REG);
+            fis.control = 0;
+            self.exec_checked(fis, Transfer::None)?;
+            let d2h = || self.read_rx_fis::<20>(RX_FIS_D2H_REG);
+            if !wait_until_timeout::<HalClock<H>>(
+                || d2h()[0] == SATA_FIS_TYPE_REGISTER_D2H,
+                PMP_LINK_TIMEOUT_MS,
+            ) {
+                return Err(AhciError::Timeout { command: 0 });
+            }
+            let fis = d2h();
+            Ok(u32::from_le_bytes([fis[12], fis[4], fis[5], fis[6]]))
+        })();
+        self.pmp = target;
+        self.resetting = false;
+        result
+    }
+
+    /// Read a SMART data sector, `features` being SMART READ DATA or SMART
+    /// READ THRESHOLDS.
+    #[cfg(feature = "smart")]
+    fn smart_read(&mut self, features: u8, buf: &mut DataBlock) -> Result<(), AhciError> {
+        let mut fis = sata_fis_h2d::command(ATA_CMD_SMART);
+        fis.features = features;
+        fis.lba_mid = ATA_SMART_LBAM_PASS;
+        fis.lba_high = ATA_SMART_LBAH_PASS;
+        self.exec_checked(fis, buf.0.as_mut_slice().into())
+    }
+
+    /// Read `page` of the general purpose log `log` with READ LOG EXT.
+    fn read_log_ext(&mut self, log: u8, page: u16, buf: &mut DataBlock) -> Result<(), AhciError> {
+        let mut fis = sata_fis_h2d::command(ATA_CMD_READ_LOG_EXT);
+        fis.set_lba48(0, 1);
+        fis.lba_low = log;
+        fis.lba_mid = page as u8;
+        fis.lba_mid_exp = (page >> 8) as u8;
+        fis.device = 0;
+        self.exec_checked(fis, buf.0.as_mut_slice().into())
+    }
+}
+
+/// A 512-byte data block for log, SMART and DSM commands, aligned for DMA.
+#[repr(C, align(8))]
+struct DataBlock([u8; 512]);
+
+impl DataBlock {
+    fn new() -> Self {
+        Self([0; 512])
+    }
+}
+
+/// A temporary buffer, e.g. for partial sector I/O, that is optionally wiped
+/// when dropped.
+struct Scratch {
+    buf: Vec<u8>,
+    zeroize: bool,
+}
+
+impl Scratch {
+    fn new(len: usize, zeroize: bool) -> Self {
+        Self {
+            buf: alloc::vec![0; len],
+            zeroize,
+        }
+    }
+}
+
+impl Deref for Scratch {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for Scratch {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if self.zeroize {
+            zeroize(&mut self.buf);
+        }
+    }
+}
+
+/// Overwrite `buf` with zeroes in a way the compiler can't elide.
+fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // SAFETY: `b` is a valid, aligned reference.
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// View a buffer of `u64`s as bytes.
+fn u64_bytes_mut(buf: &mut [u64]) -> &mut [u8] {
+    // SAFETY: Any bytes are valid `u8`s, and the length is in bounds.
+    unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast(), size_of_val(buf)) }
+}
+
+/// Fill in the LBA and sector count of a 48-bit or 28-bit command.
+///
+/// `count` is the value of the sector count field, see
+/// [`sector_count_field`].
+fn set_lba(fis: &mut sata_fis_h2d, lba: u64, count: u16, lba48: bool) {
+    fis.set_lba48(lba, count);
+    if !lba48 {
+        fis.lba_low_exp = 0;
+        fis.lba_mid_exp = 0;
+        fis.lba_high_exp = 0;
+        fis.device |= (lba >> 24) as u8 & 0x0f;
+        fis.sector_count_exp = 0;
+    }
+}
+
+/// Build a READ or WRITE FPDMA QUEUED command for `count` sectors at `lba`.
+///
+/// The sector count goes in the features field, encoded as for a 48-bit
+/// command, and the tag in bits 7:3 of the sector count field.
+fn fpdma_fis(is_write: bool, lba: u64, count: u16, tag: usize) -> sata_fis_h2d {
+    let command = if is_write {
+        ATA_CMD_FPDMA_WRITE
+    } else {
+        ATA_CMD_FPDMA_READ
+    };
+    let mut fis = sata_fis_h2d::command(command);
+    fis.set_lba48(lba, 0);
+    fis.features = count as u8;
+    fis.features_exp = (count >> 8) as u8;
+    fis.sector_count = (tag as u8) << 3;
+    fis
+}
+
+/// Magic at the start of the dirty shutdown marker sector.
+const DIRTY_MAGIC: [u8; 8] = *b"AHCIDRTY";
+
+/// A sector reserved for recording whether the disk is in use, see
+/// [`AhciDriver::set_dirty_marker`].
+struct DirtyMarker {
+    /// Native LBA of the sector.
+    lba: u64,
+    /// One native sector, reused by every read and write of the marker.
+    /// `u64` elements keep it aligned for DMA.
+    sector: Vec<u64>,
+}
+
+/// Identifies a request made with [`AhciDriver::try_submit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IoTicket(u64);
+
+/// Identifies a request made with [`AhciDriver::flush_async`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FlushTicket(u64);
+
+/// Raw IDENTIFY DEVICE data, in the drive's word order.
+pub type IdentifyData = [u16; ATA_ID_WORDS];
+
+pub struct AhciDriver<H> {
+    mmio: VolatilePtr<'static, AhciMmio>,
+    port: AhciPort<H>,
+
+    id: IdentifyData,
+    block_size: usize,
+    max_lba: u64,
+    negotiated: Negotiated,
+    policy: DrivePolicy,
+    probe_reports: Vec<ProbeReport>,
+    hung_check: Option<HungCommandCheck>,
+    command_filter: CommandFilter,
+    mode: ProbeMode,
+    dirty_marker: Option<DirtyMarker>,
+    maintenance: Option<MaintenanceState>,
+    /// Staging buffers for transfers the HBA can't do in place.
+    bounce_pool: Vec<DmaBuffer<H>>,
+    #[cfg(feature = "xts")]
+    cipher: Option<Box<dyn SectorCipher>>,
+    port_multiplier: Option<PortMultiplier>,
+    /// The other disks behind the port multiplier.
+    pm_disks: Vec<PmDisk>,
+
+    _h: PhantomData<H>,
+}
+
+/// What the driver keeps for each disk behind a port multiplier.
+struct PmDisk {
+    pmp: u8,
+    id: IdentifyData,
+    block_size: usize,
+    max_lba: u64,
+    negotiated: Negotiated,
+}
+
+impl PmDisk {
+    /// Identify the disk the port addresses.
+    fn identify<H: Hal>(port: &mut AhciPort<H>, cap: CAP, policy: &DrivePolicy) -> Option<Self> {
+        let mut id = [0u16; ATA_ID_WORDS];
+        if port.identify(&mut id).is_err() {
+            port_warn!(port.index, "Port {} IDENTIFY DEVICE failed", port.index);
+            return None;
+        }
+
+        let product = ata_id_to_string(&id, ATA_ID_PROD, ATA_ID_PROD_LEN);
+        let serial = ata_id_to_string(&id, ATA_ID_SERNO, ATA_ID_SERNO_LEN);
+        let rev = ata_id_to_string(&id, ATA_ID_FW_REV, ATA_ID_FW_REV_LEN);
+
+        port_info!(
+            port.index,
+            "AHCI device on port {}: {product} {serial} {rev}",
+            port.index
+        );
+
+        let block_size = ata_id_logical_sector_size(&id);
+        Some(Self {
+            pmp: port.pmp,
+            id,
+            block_size,
+            max_lba: ata_id_n_sectors(&id),
+            negotiated: Negotiated::new(cap, &id, policy, None, block_size),
+        })
+    }
+}
+
+/// Safety:
+/// - `Send`: The driver takes ownership of the MMIO region and can be safely moved between threads.
+/// - `Sync`: The driver's mutating operations require `&mut self`, ensuring exclusive access.
+///   Read-only operations (like getting block size) are safe to perform concurrently.
+unsafe impl<H: Hal> Send for AhciDriver<H> {}
+unsafe impl<H: Hal> Sync for AhciDriver<H> {}
+
+impl<H: Hal> AhciDriver<H> {
+    /// Try to construct a new AHCI driver from the given MMIO base address.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that:
+    /// - `base` is a valid virtual address pointing to the AHCI controller's MMIO register block.
+    /// - The memory region starting at `base` is properly mapped and accessible.
+    /// - No other code is concurrently accessing the same AHCI controller.
+    /// - The AHCI controller hardware is present and functional at the given address.
+    pub unsafe fn try_new(base: usize) -> Option<Self> {
+        // SAFETY: Forwarded to the caller.
+        unsafe { Self::try_new_with_policy(base, DrivePolicy::default()) }
+    }
+
+    /// Like [`try_new`](Self::try_new), but applies `policy` to the drive once
+    /// it has been identified.
+    ///
+    /// # Safety
+    ///
+    /// See [`try_new`](Self::try_new).
+    pub unsafe fn try_new_with_policy(base: usize, policy: DrivePolicy) -> Option<Self> {
+        // SAFETY: Forwarded to the caller.
+        let controller = unsafe { AhciController::<H>::try_new_with_policy(base, policy) }.ok()?;
+        controller.into_disks().pop()
+    }
+
+    /// Identify the device behind a started port, or the disks behind the
+    /// port multiplier attached to it, and apply `policy` to them.
+    pub(crate) fn attach(
+        mmio: VolatilePtr<'static, AhciMmio>,
+        mut port: AhciPort<H>,
+        policy: DrivePolicy,
+        probe_reports: Vec<ProbeReport>,
+        mode: ProbeMode,
+    ) -> Option<Self> {
+        port.passive = mode == ProbeMode::Passive;
+        let cap = mmio.host().cap().read();
+        let port_multiplier = match DeviceType::from_signature(port.port.SIG().read().into_bits()) {
+            DeviceType::PortMultiplier if cap.SPM() => Some(port.probe_pm()?),
+            _ => None,
+        };
+        let pmps = port_multiplier
+            .as_ref()
+            .map_or(alloc::vec![0], |pm| pm.disks.clone());
+        let mut disks: Vec<_> = pmps
+            .into_iter()
+            .filter_map(|pmp| {
+                port.pmp = pmp;
+                PmDisk::identify(&mut port, cap, &policy)
+            })
+            .collect();
+        if disks.is_empty() {
+            return None;
+        }
+        let disk = disks.remove(0);
+        port.pmp = disk.pmp;
+
+        let mut driver = Self {
+            mmio,
+            port,
+            id: disk.id,
+            block_size: disk.block_size,
+            max_lba: disk.max_lba,
+            negotiated: disk.negotiated,
+            policy,
+            probe_reports,
+            hung_check: None,
+            command_filter: match mode {
+                ProbeMode::Active => CommandFilter::default(),
+                ProbeMode::Passive => CommandFilter::non_destructive(),
+            },
+            mode,
+            dirty_marker: None,
+            maintenance: None,
+            bounce_pool: Vec::new(),
+            #[cfg(feature = "xts")]
+            cipher: None,
+            port_multiplier,
+            pm_disks: disks,
+            _h: PhantomData,
+        };
+        if mode == ProbeMode::Active {
+            // Rejected settings are logged, the disk is usable regardless.
+            let _ = driver.apply_policy_all();
+        }
+        port_info!(
+            driver.port.index,
+            "Port {} negotiated {}",
+            driver.port.index,
+            driver.negotiated
+        );
+        Some(driver)
+    }
+
+    /// Index of the port the disk is attached to.
+    pub fn port_index(&self) -> u8 {
+        self.port.index
+    }
+
+    /// The port multiplier the disk is attached through, if any.
+    pub fn port_multiplier(&self) -> Option<&PortMultiplier> {
+        self.port_multiplier.as_ref()
+    }
+
+    /// Port multiplier port of the disk the driver addresses, if it is
+    /// attached through a port multiplier.
+    pub fn pm_port(&self) -> Option<u8> {
+        self.port_multiplier.as_ref().map(|_| self.port.pmp)
+    }
+
+    /// Address the disk at port multiplier port `pmp` from now on, see
+    /// [`DiskHandle::pm_handle`](crate::DiskHandle::pm_handle).
+    ///
+    /// All disks behind a port multiplier share the driver, which keeps the
+    /// identity, capacity and negotiated features of each; other settings,
+    /// such as the policy, apply to whichever disk is addressed. Fails with
+    /// [`AhciError::InvalidArgument`] if no disk was found at `pmp`.
+    pub fn select_pm_port(&mut self, pmp: u8) -> Result<(), AhciError> {
+        if self.port.pmp == pmp {
+            return Ok(());
+        }
+        let disk = self
+            .pm_disks
+            .iter_mut()
+            .find(|disk| disk.pmp == pmp)
+            .ok_or(AhciError::InvalidArgument)?;
+        mem::swap(&mut disk.id, &mut self.id);
+        mem::swap(&mut disk.block_size, &mut self.block_size);
+        mem::swap(&mut disk.max_lba, &mut self.max_lba);
+        mem::swap(&mut disk.negotiated, &mut self.negotiated);
+        disk.pmp = mem::replace(&mut self.port.pmp, pmp);
+        port_debug!(
+            self.port.index,
+            "Port {} addressing port multiplier port {pmp}",
+            self.port.index
+        );
+        Ok(())
+    }
+
+    /// Serial number reported by IDENTIFY DEVICE, without padding.
+    pub fn serial(&self) -> String {
+        ata_id_to_string(&self.id, ATA_ID_SERNO, ATA_ID_SERNO_LEN)
+            .trim()
+            .into()
+    }
+
+    /// World Wide Name reported by IDENTIFY DEVICE, if any.
+    pub fn wwn(&self) -> Option<u64> {
+        Some(ata_id_wwn(&self.id)).filter(|&wwn| ata_id_has_wwn(&self.id) && wwn != 0)
+    }
+
+    /// Kind of device attached to the port.
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from_signature(self.port.port.SIG().read().into_bits())
+    }
+
+    /// Raw IDENTIFY DEVICE data of the disk.
+    pub fn identify_data(&self) -> &IdentifyData {
+        &self.id
+    }
+
+    /// IDENTIFY DEVICE data of the disk, as read when it was attached.
+    pub fn identify(&self) -> Identify {
+        Identify::from_raw(&self.id)
+    }
+
+    /// Issue IDENTIFY DEVICE again, e.g. to see the effect of SET FEATURES
+    /// issued with [`submit_ata_command`](Self::submit_ata_command).
+    ///
+    /// The driver keeps operating with the data read when the disk was
+    /// attached, see [`refresh_identity`](Self::refresh_identity) to update
+    /// it.
+    pub fn read_identify(&mut self) -> Result<Identify, AhciError> {
+        let mut id = [0u16; ATA_ID_WORDS];
+        self.port.identify(&mut id)?;
+        Ok(Identify::from_raw(&id))
+    }
+
+    /// Issue IDENTIFY DEVICE again and operate the disk with the new data
+    /// from now on, e.g. after a Host Protected Area was removed or a
+    /// feature was toggled with SET FEATURES.
+    ///
+    /// The capacity, sector size and [`negotiated`](Self::negotiated)
+    /// features are updated, also if the change reports another drive.
+    /// Returns what changed.
+    pub fn refresh_identity(&mut self) -> Result<IdentityChange, AhciError> {
+        let mut id = [0u16; ATA_ID_WORDS];
+        self.port.identify(&mut id)?;
+        let change = Identify::from_raw(&self.id).diff(&Identify::from_raw(&id));
+        if change.is_empty() {
+            return Ok(change);
+        }
+
+        port_info!(
+            self.port.index,
+            "Port {} identity changed: {change:?}",
+            self.port.index
+        );
+        self.id = id;
+        self.max_lba = ata_id_n_sectors(&id);
+        self.block_size = ata_id_logical_sector_size(&id);
+        self.negotiate(self.negotiated.multiple);
+        if let Some(state) = &mut self.maintenance
+            && state.scrub_lba >= self.max_lba
+        {
+            state.scrub_lba = 0;
+        }
+        Ok(change)
+    }
+
+    /// Negotiated link speed, [`LinkSpeed::Reserved`] if there is no link.
+    pub fn link_speed(&self) -> LinkSpeed {
+        LinkSpeed::from_bits(self.port.port.SSTS().read().SPD())
+    }
+
+    /// Highest speed the link may negotiate (PxSCTL.SPD), `None` if it is
+    /// only limited by the HBA and the drive.
+    pub fn max_link_speed(&self) -> Option<LinkSpeed> {
+        match LinkSpeed::from_bits(self.port.port.SCTL().read().SPD()) {
+            LinkSpeed::Reserved => None,
+            speed => Some(speed),
+        }
+    }
+
+    /// Limit the speed of the link to `speed`, or lift the limit with
+    /// `None`, e.g. for a marginal cable that only works at Gen 1.
+    ///
+    /// The limit takes effect when the link is next negotiated, see
+    /// [`renegotiate_link`](Self::renegotiate_link). Fails with
+    /// [`AhciError::InvalidArgument`] for [`LinkSpeed::Reserved`].
+    pub fn set_max_link_speed(&mut self, speed: Option<LinkSpeed>) -> Result<(), AhciError> {
+        let spd = match speed {
+            Some(LinkSpeed::Reserved) => return Err(AhciError::InvalidArgument),
+            Some(speed) => speed.into_bits(),
+            None => 0,
+        };
+        let sctl = self.port.port.SCTL().read().with_SPD(spd).with_DET(0);
+        self.port.port.SCTL().write(sctl);
+        self.port.shadow.sctl = sctl;
+        Ok(())
+    }
+
+    /// Negotiate the link again with a COMRESET, applying the limit set
+    /// with [`set_max_link_speed`](Self::set_max_link_speed), and return the
+    /// negotiated speed (PxSSTS.SPD).
+    ///
+    /// Fails with [`AhciError::WouldBlock`] while commands are outstanding,
+    /// with [`AhciError::Unsupported`] behind a port multiplier, where the
+    /// COMRESET would reset all of its disks, and with
+    /// [`AhciError::DeviceGone`] if the link does not come back.
+    pub fn renegotiate_link(&mut self) -> Result<LinkSpeed, AhciError> {
+        if !self.port.slots.is_empty() {
+            return Err(AhciError::WouldBlock);
+        }
+        if self.port_multiplier.is_some() {
+            return Err(AhciError::Unsupported);
+        }
+        if !self.port.recover(true) {
+            return Err(AhciError::DeviceGone);
+        }
+        self.restore_policy();
+        let speed = self.link_speed();
+        port_info!(
+            self.port.index,
+            "Port {} link renegotiated at {speed}",
+            self.port.index
+        );
+        Ok(speed)
+    }
+
+    /// Current interface power state of the link (PxSSTS.IPM).
+    pub fn link_power_state(&self) -> LinkPowerState {
+        LinkPowerState::from_ipm(self.port.port.SSTS().read().IPM())
+    }
+
+    /// Let the HBA move the link to Partial or Slumber on its own whenever
+    /// the port is idle, or stop it from doing so with `None` (PxCMD.ALPE
+    /// and PxCMD.ASP).
+    ///
+    /// Fails with [`AhciError::Unsupported`] if the HBA lacks aggressive
+    /// link power management (CAP.SALP) or the state (CAP.PSC, CAP.SSC),
+    /// and with [`AhciError::InvalidArgument`] for any other state.
+    pub fn set_alpm(&mut self, state: Option<LinkPowerState>) -> Result<(), AhciError> {
+        let cap = self.mmio.host().cap().read();
+        let slumber = match state {
+            None => {
+                self.port.update_cmd(|cmd| cmd.with_ALPE(false));
+                return Ok(());
+            }
+            Some(LinkPowerState::Partial) => false,
+            Some(LinkPowerState::Slumber) => true,
+            Some(_) => return Err(AhciError::InvalidArgument),
+        };
+        if !cap.SALP() || !(if slumber { cap.SSC() } else { cap.PSC() }) {
+            return Err(AhciError::Unsupported);
+        }
+        self.port
+            .update_cmd(|cmd| cmd.with_ASP(slumber).with_ALPE(true));
+        Ok(())
+    }
+
+    /// Move the link to `state` now (PxCMD.ICC). The next command wakes it
+    /// up again.
+    ///
+    /// DevSleep is enabled on the drive first if needed. On HBAs that only
+    /// enter DevSleep from Slumber (CAP2.DESO), the link is put in Slumber
+    /// on the way.
+    ///
+    /// Fails with [`AhciError::WouldBlock`] while commands are outstanding,
+    /// with [`AhciError::Unsupported`] if the HBA lacks the state (CAP.PSC,
+    /// CAP.SSC, CAP2.SDS and PxDEVSLP.DSP) or the drive lacks DevSleep, and
+    /// with [`AhciError::InvalidArgument`] for a state that can't be
+    /// requested.
+    pub fn set_link_power_state(&mut self, state: LinkPowerState) -> Result<(), AhciError> {
+        if !self.port.slots.is_empty() {
+            return Err(AhciError::WouldBlock);
+        }
+        let host = self.mmio.host();
+        let (cap, cap2) = (host.cap().read(), host.cap2().read());
+        let (icc, supported) = match state {
+            LinkPowerState::Active => {
+                self.port.wake_link();
+                return Ok(());
+            }
+            LinkPowerState::Partial => (ICC::Partial, cap.PSC()),
+            LinkPowerState::Slumber => (ICC::Slumber, cap.SSC()),
+            LinkPowerState::DevSleep => (
+                ICC::DevSleep,
+                cap2.SDS()
+                    && self.port.port.DEVSLP().read() & DEVSLP_DSP != 0
+                    && ata_id_has_devslp(&self.id),
+            ),
+            _ => return Err(AhciError::InvalidArgument),
+        };
+        if !supported {
+            return Err(AhciError::Unsupported);
+        }
+
+        if icc == ICC::DevSleep {
+            if !ata_id_devslp_enabled(&self.id) {
+                self.port
+                    .set_features(SETFEATURES_SATA_ENABLE, SATA_DEVSLP)?;
+                self.refresh_identity()?;
+            }
+            if cap2.DESO() && self.link_power_state() != LinkPowerState::Slumber {
+                self.port.update_cmd(|cmd| cmd.with_ICC(ICC::Slumber));
+                let port = &self.port.port;
+                if !wait_until_timeout::<HalClock<H>>(|| port.SSTS().read().IPM() == 6, 10) {
+                    port_warn!(
+                        self.port.index,
+                        "Port {} did not enter Slumber",
+                        self.port.index
+                    );
+                }
+            }
+        }
+        port_debug!(
+            self.port.index,
+            "Port {} entering {state:?}",
+            self.port.index
+        );
+        self.port.update_cmd(|cmd| cmd.with_ICC(icc));
+        Ok(())
+    }
+
+    /// How the disk was brought up.
+    pub fn probe_mode(&self) -> ProbeMode {
+        self.mode
+    }
+
+    /// Whether the disk is still attached, i.e. no
+    /// [`AhciEvent::DeviceGone`] was reported for it.
+    pub fn is_present(&self) -> bool {
+        self.port.present
+    }
+
+    /// Stable identity of the disk, independent of probe order.
+    pub fn disk_id(&self) -> DiskId {
+        DiskId::from_identify(&self.id)
+    }
+
+    /// The vendor specific registers of the disk's port (offsets
+    /// 0x70–0x7F).
+    ///
+    /// # Safety
+    ///
+    /// See [`AhciController::vendor_registers`].
+    pub unsafe fn port_vendor_registers(&mut self) -> VendorRegisters<'_> {
+        // SAFETY: The port vendor area has 4 registers.
+        unsafe { VendorRegisters::new(self.port.port.vs()) }
+    }
+
+    /// Port Multiplier ports that sent an asynchronous notification, e.g.
+    /// for a media change on an ATAPI device, as a bitmap (PxSNTF.PMN).
+    ///
+    /// Bits stay set until acknowledged with
+    /// [`ack_notifications`](Self::ack_notifications). Returns `None` if the
+    /// HBA does not implement PxSNTF (CAP.SSNTF).
+    pub fn notifications(&self) -> Option<u16> {
+        if !self.mmio.host().cap().read().SSNTF() {
+            return None;
+        }
+        Some(self.port.port.SNTF().read() as u16)
+    }
+
+    /// Acknowledge the notifications in `handled`, leaving the others
+    /// pending.
+    pub fn ack_notifications(&mut self, handled: u16) {
+        if self.mmio.host().cap().read().SSNTF() {
+            self.port.port.SNTF().write(handled as u32);
+        }
+    }
+
+    /// Take the oldest pending event.
+    pub fn pop_event(&mut self) -> Option<AhciEvent> {
+        self.port.events.pop_front()
+    }
+
+    /// Commands processed on the port since it was attached or
+    /// [`reset_stats`](Self::reset_stats) was called.
+    pub fn stats(&self) -> AhciStats {
+        self.port.stats
+    }
+
+    /// Reset the statistics returned by [`stats`](Self::stats).
+    pub fn reset_stats(&mut self) {
+        self.port.stats = AhciStats::default();
+    }
+
+    /// Time limits of the commands issued to the disk and of port recovery.
+    pub fn timeouts(&self) -> Timeouts {
+        self.port.timeouts
+    }
+
+    /// Replace the time limits of the commands issued to the disk and of
+    /// port recovery.
+    ///
+    /// [`DrivePolicy::max_latency_ms`] still caps the command timeouts.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.port.timeouts = timeouts;
+    }
+
+    /// How failed commands are retried.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.port.retry
+    }
+
+    /// Replace how failed commands are retried.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.port.retry = retry;
+    }
+
+    /// Report completions that took longer than `threshold_ms` milliseconds
+    /// with [`AhciEvent::SlowIo`], or disable reporting with `None`.
+    pub fn set_slow_io_threshold(&mut self, threshold_ms: Option<u64>) {
+        self.port.slow_io_threshold = threshold_ms;
+    }
+
+    /// Configure the maintenance run by [`tick`](Self::tick) while the disk
+    /// is idle, or disable it with `None`.
+    pub fn set_maintenance(&mut self, config: Option<Maintenance>) {
+        self.maintenance = config.map(|config| MaintenanceState::new(config, H::current_ms()));
+    }
+
+    /// Run a step of maintenance if the disk has been idle for long enough.
+    fn run_maintenance(&mut self) {
+        let Some(mut state) = self.maintenance.take() else {
+            return;
+        };
+        let now = H::current_ms();
+        let port = &self.port;
+        if port.issued != state.issued || !port.slots.is_empty() {
+            state.unflushed |= port.writes_issued != state.writes;
+            state.idle_since = now;
+            state.slumbering = false;
+        } else if now.saturating_sub(state.idle_since) >= state.config.idle_ms {
+            self.maintenance_step(&mut state, now);
+        }
+        // Commands issued for maintenance are not foreground I/O.
+        state.issued = self.port.issued;
+        state.writes = self.port.writes_issued;
+        self.maintenance = Some(state);
+    }
+
+    fn maintenance_step(&mut self, state: &mut MaintenanceState, now: u64) {
+        let config = state.config;
+        let index = self.port.index;
+
+        if state.unflushed {
+            state.unflushed = false;
+            if config.flush_write_cache && self.negotiated.write_cache == Some(true) {
+                port_debug!(index, "Port {index} idle, flushing write cache");
+                let _ = self.flush();
+                return;
+            }
+        }
+
+        #[cfg(feature = "smart")]
+        if let Some(interval) = config.smart_interval_ms
+            && now.saturating_sub(state.last_smart) >= interval
+            && ata_id_smart_enabled(&self.id)
+        {
+            state.last_smart = now;
+            if let Ok(false) = self.port.smart_status() {
+                port_warn!(index, "Port {index} SMART threshold exceeded");
+                self.port
+                    .events
+                    .push_back(AhciEvent::SmartThresholdExceeded { port: index });
+            }
+            return;
+        }
+
+        if let Some(sectors) = config.scrub_sectors
+            && sectors > 0
+            && now >= state.scrub_after
+            && self.max_lba > 0
+        {
+            let max = if self.negotiated.lba48 {
+                MAX_SECTORS_LBA48
+            } else {
+                MAX_SECTORS_LBA28
+            };
+            let lba = state.scrub_lba;
+            let count = (sectors as usize).min(max) as u64;
+            let count = count.min(self.max_lba - lba);
+            if self
+                .port
+                .verify(lba, count as usize, self.negotiated.lba48)
+                .is_err()
+            {
+                port_warn!(index, "Port {index} scrub failed at LBA {lba}");
+                self.port.events.push_back(AhciEvent::ScrubError {
+                    port: index,
+                    lba,
+                    count,
+                });
+            }
+            state.scrub_lba += count;
+            if state.scrub_lba >= self.max_lba {
+                port_info!(index, "Port {index} scrub pass complete");
+                state.scrub_lba = 0;
+                state.scrub_after = now + config.scrub_interval_ms;
+            }
+            return;
+        }
+
+        if config.slumber && !state.slumbering && self.mmio.host().cap().read().SSC() {
+            port_debug!(index, "Port {index} idle, entering Slumber");
+            self.port.update_cmd(|cmd| cmd.with_ICC(ICC::Slumber));
+            state.slumbering = true;
+        }
+    }
+
+    /// Configure the hung command detector run by [`tick`](Self::tick), or
+    /// disable it with `None`.
+    pub fn set_hung_command_check(&mut self, check: Option<HungCommandCheck>) {
+        self.hung_check = check;
+    }
+
+    /// CPU that submitted the oldest outstanding command, as reported by
+    /// [`Hal::current_cpu`].
+    ///
+    /// An interrupt handler can use this to steer completion processing to
+    /// the submitting CPU.
+    pub fn inflight_cpu(&self) -> Option<usize> {
+        self.port.slots.oldest().and_then(|inflight| inflight.cpu)
+    }
+
+    /// Process command completions in submission order, holding back a
+    /// finished command until all commands submitted before it have
+    /// finished, for callers that rely on in-order semantics.
+    pub fn set_ordered_completion(&mut self, ordered: bool) {
+        self.port.slots.ordered = ordered;
+    }
+
+    /// Service an interrupt of the disk's port, signalled on an MSI vector of
+    /// its own, see [`AhciController::msi_vector`].
+    ///
+    /// Only the port's registers are accessed: the global IS register is
+    /// neither read nor cleared, as it is not used with per-port vectors.
+    /// Returns whether the port had an interrupt pending.
+    pub fn handle_port_irq(&mut self) -> bool {
+        let is = H::with_irqs_disabled(|| self.port.take_is(PxI::from_bits(!0)));
+        if is.into_bits() == 0 {
+            return false;
+        }
+        if !self.port.check_gone() {
+            self.port.check_power_state(is);
+            self.port.try_complete();
+        }
+        true
+    }
+
+    /// Wait for commands by blocking in [`Hal::irq_wait`] instead of
+    /// spinning.
+    ///
+    /// The HBA's interrupt must then be serviced by an [`IrqHandler`], which
+    /// wakes the waiter through [`Hal::irq_notify`]. Timeouts still apply,
+    /// so a missed interrupt only delays completion.
+    pub fn set_irq_mode(&mut self, enabled: bool) {
+        self.port.irq_mode = enabled;
+    }
+
+    /// Poll large transfers instead of waiting for their completion
+    /// interrupt in interrupt mode, or disable this with `None`.
+    pub fn set_irq_coalescing(&mut self, coalescing: Option<IrqCoalescing>) {
+        self.port.coalescing = coalescing;
+    }
+
+    /// A handler for the HBA's interrupt, see
+    /// [`set_irq_mode`](Self::set_irq_mode).
+    pub fn irq_handler(&self) -> IrqHandler<H> {
+        IrqHandler::new(self.mmio, self.port.irq.clone())
+    }
+
+    /// Snapshot of the configuration in effect for the disk.
+    ///
+    /// The cache settings are the ones requested by the policy if the drive
+    /// supports them, otherwise the ones the drive reported when attached.
+    pub fn current_config(&self) -> DriverConfig {
+        DriverConfig {
+            command_timeout_ms: self.port.command_timeout(),
+            comreset_retries: COMRESET_RETRIES,
+            queue_depth: self.queue_depth(),
+            max_queue_depth: self.max_queue_depth(),
+            ordered_completion: self.port.slots.ordered,
+            write_cache: self.negotiated.write_cache,
+            read_lookahead: self.negotiated.read_lookahead,
+            multiple_count: self.negotiated.multiple,
+            link_power_management: self.port.port.CMD().read().ALPE(),
+            block_size: self.block_size(),
+            native_block_size: self.native_block_size(),
+            physical_block_size: self.physical_block_size(),
+            slow_io_threshold_ms: self.port.slow_io_threshold,
+            hung_command_check: self.hung_check,
+            timeouts: self.port.timeouts,
+            retry_policy: self.port.retry,
+            policy: self.policy.clone(),
+        }
+    }
+
+    /// Perform periodic housekeeping.
+    ///
+    /// Should be called regularly, e.g. from a timer interrupt. Reports each
+    /// command outstanding for longer than the configured threshold once with
+    /// an [`AhciEvent::HungCommand`], recovering the port if requested, and
+    /// reports interface power state changes with
+    /// [`AhciEvent::LinkPowerStateChanged`]. Also runs the
+    /// [`Maintenance`] configured with
+    /// [`set_maintenance`](Self::set_maintenance).
+    pub fn tick(&mut self) {
+        if self.port.check_gone() {
+            return;
+        }
+        self.port.check_queued_error();
+        let is = H::with_irqs_disabled(|| self.port.take_is(PxI::new().with_IPM(true)));
+        self.port.check_power_state(is);
+        // Read the registers rather than rely on the Set Device Bits FIS, to
+        // catch completions whose FIS went unnoticed.
+        self.port.try_complete();
+        self.run_maintenance();
+
+        let Some(check) = self.hung_check else {
+            return;
+        };
+        let Some((slot, age_ms, cpu)) = self.port.check_hung(check.threshold_ms) else {
+            return;
+        };
+
+        let engine = self.port.engine_state();
+        port_warn!(
+            self.port.index,
+            "Port {} command hung for {age_ms} ms ({engine})",
+            self.port.index
+        );
+        let inflight = self.port.slots.slots[slot as usize].as_ref();
+        let (command, lba) = inflight.map_or((0, None), |i| (i.command, Some(i.lba)));
+        let mut record = self
+            .port
+            .error_record(command, lba, AhciError::Timeout { command });
+        let recovered = check.recover && self.port.recover(true);
+        if check.recover {
+            record.recovery = recovered.into();
+        }
+        self.port.report_error(record);
+        self.restore_policy();
+        self.port.events.push_back(AhciEvent::HungCommand {
+            port: self.port.index,
+            slot,
+            age_ms,
+            engine,
+            recovered,
+            cpu,
+        });
+    }
+
+    /// Check that the registers the driver programmed still hold the values it
+    /// wrote, and return the ones that don't.
+    ///
+    /// Mismatches mean that something else, such as firmware running in SMM
+    /// or another driver, reprogrammed the port, which otherwise shows up as
+    /// inexplicable hangs.
+    pub fn verify_registers(&self) -> Vec<RegisterMismatch> {
+        let mismatches = self.port.verify_registers();
+        for mismatch in &mismatches {
+            port_warn!(
+                self.port.index,
+                "Port {} register changed behind the driver's back: {mismatch}",
+                self.port.index
+            );
+        }
+        mismatches
+    }
+
+    /// Take a snapshot of the port's registers.
+    pub fn diagnostics(&self) -> PortDiagnostics {
+        self.port.diagnostics()
+    }
+
+    /// Flush the write cache if it is enabled, then stop the port and free
+    /// its command structures.
+    ///
+    /// Dropping the driver does the same without the flush. The port is
+    /// stopped even if the flush fails.
+    pub fn shutdown(mut self) -> Result<(), AhciError> {
+        if self.is_present() && self.negotiated.write_cache == Some(true) {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Request a flush of the drive's volatile write cache without waiting
+    /// for it.
+    ///
+    /// Requests made while a flush is outstanding are coalesced into a single
+    /// follow-up flush. Use [`flush_status`](Self::flush_status) to find out
+    /// when the returned ticket has been satisfied.
+    pub fn flush_async(&mut self) -> FlushTicket {
+        let command = if self.negotiated.lba48 {
+            ATA_CMD_FLUSH_EXT
+        } else {
+            ATA_CMD_FLUSH
+        };
+        FlushTicket(self.port.flush_async(command))
+    }
+
+    /// Flush the drive's volatile write cache and wait for it.
+    ///
+    /// Commands outstanding when this is called are completed before the
+    /// flush is issued, and no command is issued until it completes, so it
+    /// acts as a barrier between the writes before and after it. Fails with
+    /// [`AhciError::FlushFailed`] if the drive could not write back its
+    /// cache.
+    pub fn flush(&mut self) -> Result<(), AhciError> {
+        let ticket = self.flush_async();
+        self.port.drain()?;
+        match self.flush_status(ticket) {
+            Some(true) => Ok(()),
+            Some(false) => Err(self.port.flush.error.unwrap_or(AhciError::Aborted)),
+            None => Err(AhciError::Timeout {
+                command: self.port.flush.command,
+            }),
+        }
+    }
+
+    /// `None` while the flush is still pending, otherwise whether it
+    /// succeeded.
+    pub fn flush_status(&mut self, ticket: FlushTicket) -> Option<bool> {
+        self.port.try_complete();
+        self.port.flush.status(ticket.0)
+    }
+
+    /// Number of commands that can be submitted with
+    /// [`try_submit`](Self::try_submit) without blocking.
+    ///
+    /// This is 0 while a command that can't be queued, such as a cache
+    /// flush, is outstanding.
+    pub fn available_slots(&mut self) -> usize {
+        self.port.check_queued_error();
+        self.port.try_complete_fast();
+        let slots = &self.port.slots;
+        if slots.iter().any(|i| !i.queued) {
+            return 0;
+        }
+        let depth = self.ncq_depth().unwrap_or(1);
+        (0..depth).filter(|&tag| slots.slots[tag].is_none()).count()
+    }
+
+    /// Submit a read into or a write from `buf` at block `block_id` without
+    /// waiting for it, using a native queued command if the drive supports
+    /// them.
+    ///
+    /// The driver keeps `buf` until the request is collected with
+    /// [`poll_io`](Self::poll_io). Fails with [`AhciError::WouldBlock`] if
+    /// no slot is free, see [`available_slots`](Self::available_slots), and
+    /// with [`AhciError::InvalidArgument`] unless `buf` covers whole native
+    /// sectors that fit in a single command. Encrypted disks are not
+    /// supported. The buffer is handed back on failure.
+    pub fn try_submit(
+        &mut self,
+        block_id: u64,
+        mut buf: DmaBuffer<H>,
+        is_write: bool,
+    ) -> Result<IoTicket, (AhciError, DmaBuffer<H>)> {
+        let lba = self.native_lba(block_id, buf.len());
+        let max = self.negotiated.max_sectors * self.block_size;
+        #[cfg(feature = "xts")]
+        let crypt = self.cipher.is_some();
+        #[cfg(not(feature = "xts"))]
+        let crypt = false;
+        let Some(lba) = lba.filter(|_| !buf.is_empty() && buf.len() <= max && !crypt) else {
+            return Err((AhciError::InvalidArgument, buf));
+        };
+        if !self.port.present {
+            return Err((AhciError::DeviceGone, buf));
+        }
+        if self.available_slots() == 0 {
+            return Err((AhciError::WouldBlock, buf));
+        }
+
+        let Some(count) = sector_count_field(buf.len() / self.block_size, self.negotiated.lba48)
+        else {
+            return Err((AhciError::InvalidArgument, buf));
+        };
+        let (slot, fis) = match self.ncq_depth() {
+            Some(depth) => {
+                let slot = self.port.slots.free_slot(depth).unwrap();
+                (slot, fpdma_fis(is_write, lba, count, slot))
+            }
+            None => {
+                let mut fis = sata_fis_h2d::command(self.rw_command(is_write));
+                set_lba(&mut fis, lba, count, self.negotiated.lba48);
+                (0, fis)
+            }
+        };
+        let data = if is_write {
+            Transfer::from(&*buf)
+        } else {
+            Transfer::from(&mut *buf)
+        };
+        if let Err(err) = self.port.issue_cmd(slot, fis, data) {
+            return Err((err, buf));
+        }
+
+        let port = &mut self.port;
+        let ticket = port.next_ticket;
+        port.next_ticket += 1;
+        port.submitted
+            .insert(ticket, Submitted { buf, result: None });
+        H::with_irqs_disabled(|| {
+            if let Some(inflight) = port.slots.get_mut(slot) {
+                inflight.ticket = Some(ticket);
+            }
+        });
+        Ok(IoTicket(ticket))
+    }
+
+    /// Collect a request made with [`try_submit`](Self::try_submit).
+    ///
+    /// Returns `None` while it is outstanding, otherwise its outcome and its
+    /// buffer.
+    pub fn poll_io(&mut self, ticket: IoTicket) -> Option<(Result<(), AhciError>, DmaBuffer<H>)> {
+        self.port.check_queued_error();
+        self.port.try_complete_fast();
+        self.port.submitted.get(&ticket.0)?.result.as_ref()?;
+        let io = self.port.submitted.remove(&ticket.0)?;
+        Some((io.result?, io.buf))
+    }
+
+    /// Like [`poll_io`](Self::poll_io), but waits for the request to
+    /// complete first.
+    ///
+    /// Returns `None` if it is still outstanding after the command timeout.
+    pub fn wait_io(&mut self, ticket: IoTicket) -> Option<(Result<(), AhciError>, DmaBuffer<H>)> {
+        let slot = self.port.slots.slots.iter().position(|inflight| {
+            inflight
+                .as_ref()
+                .is_some_and(|i| i.ticket == Some(ticket.0))
+        });
+        if let Some(slot) = slot {
+            // The outcome is recorded with the request.
+            let _ = self.port.wait_slot(slot, self.port.command_timeout());
+        }
+        self.poll_io(ticket)
+    }
+
+    /// Opcode of a non-queued read or write.
+    fn rw_command(&self, is_write: bool) -> u8 {
+        match (
+            self.negotiated.multiple.is_some(),
+            self.negotiated.lba48,
+            is_write,
+        ) {
+            (false, true, true) => ATA_CMD_WRITE_EXT,
+            (false, true, false) => ATA_CMD_READ_EXT,
+            (false, false, true) => ATA_CMD_WRITE,
+            (false, false, false) => ATA_CMD_READ,
+            (true, true, true) => ATA_CMD_WRITE_MULTI_EXT,
+            (true, true, false) => ATA_CMD_READ_MULTI_EXT,
+            (true, false, true) => ATA_CMD_WRITE_MULTI,
+            (true, false, false) => ATA_CMD_READ_MULTI,
+        }
+    }
+
+    /// Opcode [`try_submit`](Self::try_submit) uses for a read or write.
+    pub(crate) fn io_command(&self, is_write: bool) -> u8 {
+        match (self.ncq_depth(), is_write) {
+            (Some(_), true) => ATA_CMD_FPDMA_WRITE,
+            (Some(_), false) => ATA_CMD_FPDMA_READ,
+            (None, _) => self.rw_command(is_write),
+        }
+    }
+
+    /// Read the NCQ Command Error log, describing the queued command that
+    /// failed last.
+    ///
+    /// The driver reads it when recovering from a failed queued command,
+    /// see [`last_ncq_error`](Self::last_ncq_error); reading it again
+    /// returns what the drive holds now. Fails with
+    /// [`AhciError::Unsupported`] if the drive has no NCQ or no general
+    /// purpose logging.
+    #[cfg(feature = "ncq")]
+    pub fn read_ncq_error_log(&mut self) -> Result<NcqErrorLog, AhciError> {
+        if !ata_id_has_ncq(&self.id) || !ata_id_has_gpl(&self.id) {
+            return Err(AhciError::Unsupported);
+        }
+        let mut page = DataBlock::new();
+        self.port.read_log_ext(ATA_LOG_NCQ_ERROR, 0, &mut page)?;
+        Ok(NcqErrorLog::from_log(&page.0))
+    }
+
+    /// NCQ Command Error log read when the driver last recovered from a
+    /// failed queued command.
+    #[cfg(feature = "ncq")]
+    pub fn last_ncq_error(&self) -> Option<NcqErrorLog> {
+        self.port.ncq_error
+    }
+
+    /// Queue depth used for reads and writes, if they are issued as native
+    /// queued commands.
+    fn ncq_depth(&self) -> Option<usize> {
+        // Let the queued paths be compiled out without the feature.
+        if !cfg!(feature = "ncq") {
+            return None;
+        }
+        self.negotiated.ncq_depth.map(usize::from)
+    }
+
+    /// The features used for the disk, decided when it was attached and
+    /// whenever the policy changes.
+    pub fn negotiated(&self) -> &Negotiated {
+        &self.negotiated
+    }
+
+    /// Decide the features used for the disk again, after the policy or the
+    /// READ/WRITE MULTIPLE block size changed.
+    fn negotiate(&mut self, multiple: Option<u8>) {
+        let cap = self.mmio.host().cap().read();
+        let negotiated = Negotiated::new(cap, &self.id, &self.policy, multiple, self.block_size);
+        if negotiated != self.negotiated {
+            port_debug!(
+                self.port.index,
+                "Port {} negotiated {negotiated}",
+                self.port.index
+            );
+            self.negotiated = negotiated;
+        }
+    }
+
+    /// Register snapshots of every port probed during initialization.
+    pub fn probe_reports(&self) -> &[ProbeReport] {
+        &self.probe_reports
+    }
+
+    /// The policy applied to the drive whenever it is attached.
+    pub fn policy(&self) -> &DrivePolicy {
+        &self.policy
+    }
+
+    /// Replace the drive policy and apply it immediately to every disk on
+    /// the port.
+    ///
+    /// Fails if any of the settings was rejected by a drive.
+    pub fn set_policy(&mut self, policy: DrivePolicy) -> Result<(), AhciError> {
+        self.policy = policy;
+        self.apply_policy_all()
+    }
+
+    /// Number of queued commands supported by both the HBA and the drive.
+    ///
+    /// This is 1 if either of them lacks native command queuing.
+    pub fn max_queue_depth(&self) -> u8 {
+        self.negotiated.max_queue_depth
+    }
+
+    /// Number of queued commands the driver may use for the drive.
+    pub fn queue_depth(&self) -> u8 {
+        self.negotiated.queue_depth()
+    }
+
+    /// Cap the number of queued commands used for the drive at `depth`, for
+    /// drives that misbehave at full depth.
+    ///
+    /// Outstanding commands are completed first. The value is kept in the
+    /// drive policy, so it survives [`set_policy`](Self::set_policy) only if
+    /// the new policy carries it over. Fails if `depth` is 0 or the
+    /// outstanding commands could not be completed.
+    pub fn set_queue_depth(&mut self, depth: u8) -> Result<(), AhciError> {
+        if depth == 0 {
+            return Err(AhciError::InvalidArgument);
+        }
+        self.port.drain()?;
+        self.policy.ncq_depth = Some(depth);
+        self.negotiate(self.negotiated.multiple);
+        Ok(())
+    }
+
+    /// Queueing statistics of the drive, for tuning the queue depth.
+    ///
+    /// Reads the NCQ Queue Management log if the drive implements it. Returns
+    /// `None` if the drive does not support native command queuing.
+    #[cfg(feature = "ncq")]
+    pub fn ncq_stats(&mut self) -> Option<NcqStats> {
+        if !ata_id_has_ncq(&self.id) {
+            return None;
+        }
+
+        let mut page = DataBlock::new();
+        let queue_management = (ata_id_has_gpl(&self.id)
+            && ata_id_has_ncq_queue_mgmt(&self.id)
+            && self
+                .port
+                .read_log_ext(ATA_LOG_NCQ_QUEUE_MGMT, 0, &mut page)
+                .is_ok())
+        .then(|| NcqQueueManagement::from_log(&page.0));
+
+        Some(NcqStats {
+            max_queue_depth: ata_id_queue_depth(&self.id),
+            queue_depth: self.queue_depth(),
+            completed: self.port.slots.completed,
+            aborted: self.port.slots.aborted,
+            queue_management,
+        })
+    }
+
+    /// Hint a hybrid drive (SSHD) to keep `range` of blocks in its
+    /// non-volatile cache, with HYBRID CHANGE BY LBA RANGE at the highest
+    /// priority the drive supports.
+    ///
+    /// The Hybrid Information feature is enabled first if needed. Fails with
+    /// [`AhciError::Unsupported`] unless the drive supports the feature and
+    /// is used with native command queuing, and with
+    /// [`AhciError::InvalidArgument`] if `range` is empty or beyond the
+    /// capacity.
+    #[cfg(feature = "ncq")]
+    pub fn hint_pin(&mut self, range: Range<u64>) -> Result<(), AhciError> {
+        if range.is_empty() || range.end > self.capacity() {
+            return Err(AhciError::InvalidArgument);
+        }
+        let Some(depth) = self.ncq_depth() else {
+            return Err(AhciError::Unsupported);
+        };
+        if !ata_id_has_hybrid(&self.id) || !ata_id_has_gpl(&self.id) {
+            return Err(AhciError::Unsupported);
+        }
+        if !ata_id_hybrid_enabled(&self.id) {
+            self.port
+                .set_features(SETFEATURES_SATA_ENABLE, SATA_HYBRID_INFO)?;
+            self.refresh_identity()?;
+        }
+
+        let mut page = DataBlock::new();
+        self.port.read_log_ext(ATA_LOG_HYBRID_INFO, 0, &mut page)?;
+        let priority = (page.0[HYBRID_INFO_MAX_PRIORITY] & 0xf) as u16;
+
+        let ratio = self.emulation_ratio() as u64;
+        let (mut lba, end) = (range.start / ratio, range.end.div_ceil(ratio));
+        while lba < end {
+            let len = (end - lba).min(u16::MAX as u64);
+            self.port.ncq_non_data(
+                priority << 12 | ATA_NCQ_HYBRID_CHANGE_BY_LBA,
+                lba,
+                len as u32,
+                depth,
+            )?;
+            lba += len;
+        }
+        Ok(())
+    }
+
+    /// Whether the drive has SMART enabled.
+    pub fn smart_enabled(&self) -> bool {
+        ata_id_smart_enabled(&self.id)
+    }
+
+    /// Read the SMART attributes with SMART READ DATA.
+    ///
+    /// Returns [`AhciError::Unsupported`] if SMART is not enabled.
+    #[cfg(feature = "smart")]
+    pub fn read_smart_data(&mut self) -> Result<SmartData, AhciError> {
+        if !self.smart_enabled() {
+            return Err(AhciError::Unsupported);
+        }
+        let mut data = DataBlock::new();
+        self.port.smart_read(ATA_SMART_READ_DATA, &mut data)?;
+        Ok(SmartData::parse(&data.0))
+    }
+
+    /// Read the attribute thresholds with SMART READ THRESHOLDS.
+    ///
+    /// This command is obsolete since ATA8-ACS; drives that don't implement
+    /// it fail it, or return an all-zero table.
+    #[cfg(feature = "smart")]
+    pub fn read_smart_thresholds(&mut self) -> Result<Vec<SmartThreshold>, AhciError> {
+        if !self.smart_enabled() {
+            return Err(AhciError::Unsupported);
+        }
+        let mut data = DataBlock::new();
+        self.port.smart_read(ATA_SMART_READ_THRESHOLDS, &mut data)?;
+        Ok(parse_thresholds(&data.0))
+    }
+
+    /// Whether the drive considers itself healthy, i.e. SMART RETURN STATUS
+    /// reports no attribute at or below its threshold.
+    #[cfg(feature = "smart")]
+    pub fn health_ok(&mut self) -> Result<bool, AhciError> {
+        if !self.smart_enabled() {
+            return Err(AhciError::Unsupported);
+        }
+        self.port.smart_status()
+    }
+
+    /// Estimate the wear of a solid state drive.
+    ///
+    /// Prefers the standard Device Statistics log and, with the `smart`
+    /// feature, falls back to the vendor specific SMART attributes 231, 233
+    /// and 241. Returns `None` if
+    /// the drive is not an SSD or reports neither.
+    pub fn ssd_wear(&mut self) -> Option<SsdWear> {
+        if !ata_id_is_ssd(&self.id) {
+            return None;
+        }
+
+        let mut wear = SsdWear::default();
+        if ata_id_has_gpl(&self.id) {
+            let mut pages = DataBlock::new();
+            if self
+                .port
+                .read_log_ext(ATA_LOG_DEVICE_STATS, 0, &mut pages)
+                .is_ok()
+            {
+                let pages = &pages.0;
+                let supported = &pages[9..9 + (pages[8] as usize).min(512 - 9)];
+                let mut general = DataBlock::new();
+                let mut ssd = DataBlock::new();
+                // A page that can't be read is left zeroed, as if it reported
+                // nothing.
+                if supported.contains(&ATA_DEVSTAT_GENERAL) {
+                    let _ = self.port.read_log_ext(
+                        ATA_LOG_DEVICE_STATS,
+                        ATA_DEVSTAT_GENERAL as u16,
+                        &mut general,
+                    );
+                }
+                if supported.contains(&ATA_DEVSTAT_SSD) {
+                    let _ = self.port.read_log_ext(
+                        ATA_LOG_DEVICE_STATS,
+                        ATA_DEVSTAT_SSD as u16,
+                        &mut ssd,
+                    );
+                }
+                wear = SsdWear::from_device_stats(&general.0, &ssd.0, self.block_size);
+            }
+        }
+
+        #[cfg(feature = "smart")]
+        if (wear.percentage_used.is_none() || wear.bytes_written.is_none())
+            && let Ok(data) = self.read_smart_data()
+        {
+            wear = wear.or(SsdWear::from_smart(&data));
+        }
+
+        (!wear.is_empty()).then_some(wear)
+    }
+
+    /// Check that the blocks in `range` read back as zeroes, e.g. after
+    /// SANITIZE or TRIM, by reading every `sample_stride`th block.
+    ///
+    /// Returns `None` if a read fails.
+    pub fn verify_erased(&mut self, range: Range<u64>, sample_stride: u64) -> Option<EraseReport> {
+        let mut report = EraseReport::default();
+        let mut block = self.scratch(self.block_size());
+        for lba in range.step_by(sample_stride.max(1) as usize) {
+            if let Err(err) = self.read(lba, &mut block) {
+                port_warn!(
+                    self.port.index,
+                    "Port {} read of block {lba} failed: {err}",
+                    self.port.index
+                );
+                return None;
+            }
+            report.sampled += 1;
+            if block.iter().any(|&b| b != 0) {
+                report.non_zero += 1;
+                report.first_non_zero.get_or_insert(lba);
+            }
+        }
+        Some(report)
+    }
+
+    /// Copy `count` blocks starting at block `src` to block `dst`, e.g. to
+    /// move a partition. Overlapping ranges are copied as if through an
+    /// intermediate buffer.
+    ///
+    /// The data moves in chunks through two buffers, so when the drive
+    /// queues commands the next chunk is read while the previous one is
+    /// written. With `verify`, every chunk is read back once written and
+    /// compared, failing with [`AhciError::Miscompare`]. `progress` is
+    /// called with the number of blocks copied after each chunk.
+    ///
+    /// Fails with [`AhciError::InvalidArgument`] if either range extends
+    /// past the end of the disk. On error, part of the range may have been
+    /// copied.
+    pub fn copy_within(
+        &mut self,
+        src: u64,
+        dst: u64,
+        count: u64,
+        verify: bool,
+        mut progress: impl FnMut(u64),
+    ) -> Result<(), AhciError> {
+        let capacity = self.capacity();
+        let fits = |start: u64| start.checked_add(count).is_some_and(|end| end <= capacity);
+        if !fits(src) || !fits(dst) {
+            return Err(AhciError::InvalidArgument);
+        }
+        if count == 0 || src == dst {
+            return Ok(());
+        }
+        let mut writing = None;
+        let result = self.copy_chunks(src, dst, count, verify, &mut progress, &mut writing);
+        if let Some(CopyWrite::Queued(ticket, _)) = writing {
+            // Collect the chunk being written so its buffer is not left with
+            // the driver.
+            let _ = self.wait_io(ticket);
+        }
+        result
+    }
+
+    fn copy_chunks(
+        &mut self,
+        src: u64,
+        dst: u64,
+        count: u64,
+        verify: bool,
+        progress: &mut impl FnMut(u64),
+        writing: &mut Option<CopyWrite<H>>,
+    ) -> Result<(), AhciError> {
+        let block_size = self.block_size();
+        let max = COPY_CHUNK_SIZE.min(self.negotiated.max_sectors * self.block_size);
+        let chunk = (max / block_size).max(1) as u64;
+        #[cfg(feature = "xts")]
+        let crypt = self.cipher.is_some();
+        #[cfg(not(feature = "xts"))]
+        let crypt = false;
+        // Requests made with `try_submit` must be whole native sectors.
+        let queued = self.emulation_ratio() == 1 && !crypt;
+        // Copy from the end if the destination overlaps the tail of the
+        // source, so nothing is overwritten before it is read.
+        let backwards = dst > src && dst < src + count;
+
+        let len = chunk as usize * block_size;
+        let mut free = alloc::vec![self.alloc_dma_buffer(len)?, self.alloc_dma_buffer(len)?];
+        let (mut read, mut copied) = (0, 0);
+        while read < count {
+            let n = chunk.min(count - read);
+            let offset = if backwards { count - read - n } else { read };
+            let mut buf = free.pop().ok_or(AhciError::InvalidArgument)?;
+            if buf.len() != n as usize * block_size {
+                buf = self.alloc_dma_buffer(n as usize * block_size)?;
+            }
+
+            // Without native command queuing the write must complete first.
+            if self.available_slots() == 0
+                && let Some(write) = writing.take()
+            {
+                free.push(self.finish_copy_write(write, verify, &mut copied)?);
+                progress(copied);
+            }
+            let buf = self.copy_read(src + offset, buf, queued)?;
+            if let Some(write) = writing.take() {
+                free.push(self.finish_copy_write(write, verify, &mut copied)?);
+                progress(copied);
+            }
+            *writing = Some(self.start_copy_write(dst + offset, buf, queued)?);
+            read += n;
+        }
+        if let Some(write) = writing.take() {
+            self.finish_copy_write(write, verify, &mut copied)?;
+            progress(copied);
+        }
+        Ok(())
+    }
+
+    /// Read a chunk for [`copy_within`](Self::copy_within).
+    fn copy_read(
+        &mut self,
+        block: u64,
+        mut buf: DmaBuffer<H>,
+        queued: bool,
+    ) -> Result<DmaBuffer<H>, AhciError> {
+        if !queued {
+            self.read(block, &mut buf)?;
+            return Ok(buf);
+        }
+        let ticket = self.try_submit(block, buf, false).map_err(|(err, _)| err)?;
+        let command = self.io_command(false);
+        let (result, buf) = self.wait_io(ticket).ok_or(AhciError::Timeout { command })?;
+        result.map(|()| buf)
+    }
+
+    /// Start writing a chunk for [`copy_within`](Self::copy_within).
+    fn start_copy_write(
+        &mut self,
+        block: u64,
+        buf: DmaBuffer<H>,
+        queued: bool,
+    ) -> Result<CopyWrite<H>, AhciError> {
+        if !queued {
+            self.write(block, &buf)?;
+            return Ok(CopyWrite::Done(buf, block));
+        }
+        let ticket = self.try_submit(block, buf, true).map_err(|(err, _)| err)?;
+        Ok(CopyWrite::Queued(ticket, block))
+    }
+
+    /// Wait for a chunk written by [`copy_within`](Self::copy_within), read
+    /// it back if `verify` is set and count its blocks in `copied`,
+    /// returning its buffer.
+    fn finish_copy_write(
+        &mut self,
+        write: CopyWrite<H>,
+        verify: bool,
+        copied: &mut u64,
+    ) -> Result<DmaBuffer<H>, AhciError> {
+        let (buf, block) = match write {
+            CopyWrite::Queued(ticket, block) => {
+                let command = self.io_command(true);
+                let (result, buf) = self.wait_io(ticket).ok_or(AhciError::Timeout { command })?;
+                result?;
+                (buf, block)
+            }
+            CopyWrite::Done(buf, block) => (buf, block),
+        };
+        let block_size = self.block_size();
+        if verify {
+            let mut check = self.scratch(buf.len());
+            self.read(block, &mut check)?;
+            let differs = buf
+                .chunks(block_size)
+                .zip(check.chunks(block_size))
+                .position(|(written, read)| written != read);
+            if let Some(n) = differs {
+                return Err(AhciError::Miscompare {
+                    block: block + n as u64,
+                });
+            }
+        }
+        *copied += (buf.len() / block_size) as u64;
+        Ok(buf)
+    }
+
+    /// Reserve the native sector `lba` for recording whether the disk is in
+    /// use, to detect unclean shutdowns at the next boot.
+    ///
+    /// The sector is overwritten by [`set_dirty_flag`](Self::set_dirty_flag)
+    /// and [`clear_dirty_flag`](Self::clear_dirty_flag), so it must not hold
+    /// any data. `None` releases it. Fails with
+    /// [`AhciError::InvalidArgument`] if `lba` is beyond the disk or, for
+    /// drives without 48-bit addressing, beyond what 28-bit commands reach.
+    pub fn set_dirty_marker(&mut self, lba: Option<u64>) -> Result<(), AhciError> {
+        let limit = if self.negotiated.lba48 {
+            MAX_LBA48
+        } else {
+            MAX_LBA28
+        };
+        if lba.is_some_and(|lba| lba >= self.max_lba.min(limit)) {
+            return Err(AhciError::InvalidArgument);
+        }
+        self.dirty_marker = lba.map(|lba| DirtyMarker {
+            lba,
+            sector: alloc::vec![0; self.block_size / 8],
+        });
+        Ok(())
+    }
+
+    /// Mark the disk as in use, e.g. when a file system is mounted.
+    ///
+    /// The marker is written through to the media. Fails with
+    /// [`AhciError::Unsupported`] if no marker sector is set.
+    pub fn set_dirty_flag(&mut self) -> Result<(), AhciError> {
+        self.write_dirty_marker(true)
+    }
+
+    /// Mark the disk as cleanly shut down.
+    ///
+    /// Call it after the data has been flushed. Fails with
+    /// [`AhciError::Unsupported`] if no marker sector is set.
+    pub fn clear_dirty_flag(&mut self) -> Result<(), AhciError> {
+        self.write_dirty_marker(false)
+    }
+
+    /// Whether the disk was marked as in use and not cleanly shut down since.
+    ///
+    /// Returns `None` if no marker sector is set or it can't be read. A
+    /// sector that was never written as a marker reads as clean.
+    pub fn dirty_flag(&mut self) -> Option<bool> {
+        let mut marker = self.dirty_marker.take()?;
+        let sector = u64_bytes_mut(&mut marker.sector);
+        let lba48 = self.negotiated.lba48;
+        let mut fis = sata_fis_h2d::command(if lba48 {
+            ATA_CMD_READ_EXT
+        } else {
+            ATA_CMD_READ
+        });
+        set_lba(&mut fis, marker.lba, 1, lba48);
+        let result = self.port.exec_checked(fis, sector.into());
+        let dirty = sector[..8] == DIRTY_MAGIC && sector[8] != 0;
+        self.dirty_marker = Some(marker);
+        result.ok().map(|()| dirty)
+    }
+
+    fn write_dirty_marker(&mut self, dirty: bool) -> Result<(), AhciError> {
+        let Some(marker) = &mut self.dirty_marker else {
+            return Err(AhciError::Unsupported);
+        };
+        let lba = marker.lba;
+        let sector = u64_bytes_mut(&mut marker.sector);
+        sector.fill(0);
+        sector[..8].copy_from_slice(&DIRTY_MAGIC);
+        sector[8] = dirty as u8;
+
+        // Force unit access if the drive can, otherwise flush.
+        let (lba48, fua) = (self.negotiated.lba48, self.negotiated.fua);
+        let command = match (lba48, fua) {
+            (true, true) => ATA_CMD_WRITE_FUA_EXT,
+            (true, false) => ATA_CMD_WRITE_EXT,
+            (false, _) => ATA_CMD_WRITE,
+        };
+        let mut fis = sata_fis_h2d::command(command);
+        set_lba(&mut fis, lba, 1, lba48);
+        self.port.exec_checked(fis, (&*sector).into())?;
+        if fua {
+            return Ok(());
+        }
+        let flush = if lba48 {
+            ATA_CMD_FLUSH_EXT
+        } else {
+            ATA_CMD_FLUSH
+        };
+        self.port
+            .exec_checked(sata_fis_h2d::command(flush), Transfer::None)
+    }
+
+    /// Pass every command error of the disk to `sink`, or stop reporting
+    /// them with `None`.
+    ///
+    /// Errors are reported in addition to being returned to the caller, and
+    /// include those of commands issued by the driver itself.
+    pub fn set_error_sink(&mut self, sink: Option<Box<dyn ErrorSink>>) {
+        self.port.error_sink = sink;
     }
 
-    pub fn block_size(&self) -> usize {
-        self.block_size
+    /// Restrict the commands that can be issued with
+    /// [`submit_ata_command`](Self::submit_ata_command) and
+    /// [`exec_ata`](Self::exec_ata), e.g. before handing the driver to a less
+    /// trusted component.
+    pub fn set_command_filter(&mut self, filter: CommandFilter) {
+        self.command_filter = filter;
+    }
+
+    /// Issue an arbitrary ATA command, transferring `buf` to the device if
+    /// `is_write` is set or from it otherwise.
+    ///
+    /// `lba` and `count` are placed in the 48-bit LBA and sector count
+    /// fields. The command is not retried. Fails with
+    /// [`AhciError::Denied`] if the command is denied by the
+    /// [`CommandFilter`].
+    pub fn exec_ata(
+        &mut self,
+        command: u8,
+        features: u16,
+        lba: u64,
+        count: u16,
+        buf: &mut [u8],
+        is_write: bool,
+    ) -> Result<(), AhciError> {
+        let tf = TaskFile {
+            features,
+            lba,
+            count,
+            ..TaskFile::new(command)
+        };
+        let dir = match (is_write, buf.is_empty()) {
+            (true, _) => DataDirection::ToDevice,
+            (false, true) => DataDirection::None,
+            (false, false) => DataDirection::FromDevice,
+        };
+        self.submit_ata_command(tf, dir, buf).map(|_| ())
+    }
+
+    /// Issue an arbitrary ATA command and return the registers the device
+    /// reported on completion.
+    ///
+    /// `buf` is transferred in `dir`, and must be empty for
+    /// [`DataDirection::None`]. Native queued commands can't be passed
+    /// through. The command is not retried, as it may not be safe to issue
+    /// twice. Fails with [`AhciError::Denied`] if the command is denied by
+    /// the [`CommandFilter`], and with [`AhciError::TaskFile`] if the device
+    /// reports an error.
+    pub fn submit_ata_command(
+        &mut self,
+        tf: TaskFile,
+        dir: DataDirection,
+        buf: &mut [u8],
+    ) -> Result<TaskFileResult, AhciError> {
+        self.pass_through(tf, dir, buf, 0)
     }
 
-    pub fn read(&mut self, block_id: u64, buf: &mut [u8]) -> bool {
-        self.rw_common(block_id, buf, false)
+    /// Issue a command for [`submit_ata_command`](Self::submit_ata_command),
+    /// retrying it up to `retries` times instead of following the
+    /// [`RetryPolicy`].
+    fn pass_through(
+        &mut self,
+        tf: TaskFile,
+        dir: DataDirection,
+        buf: &mut [u8],
+        retries: u8,
+    ) -> Result<TaskFileResult, AhciError> {
+        let command = tf.command;
+        if !self.command_filter.is_allowed(command) {
+            port_warn!(
+                self.port.index,
+                "Port {} ATA command {command:#x} denied by filter",
+                self.port.index
+            );
+            return Err(AhciError::Denied { command });
+        }
+        if (dir == DataDirection::None && !buf.is_empty())
+            || matches!(
+                command,
+                ATA_CMD_FPDMA_READ | ATA_CMD_FPDMA_WRITE | ATA_CMD_NCQ_NON_DATA
+            )
+        {
+            return Err(AhciError::InvalidArgument);
+        }
+        let data = match dir {
+            DataDirection::None => Transfer::None,
+            DataDirection::FromDevice => buf.into(),
+            DataDirection::ToDevice => (&*buf).into(),
+        };
+        let policy = self.port.retry;
+        self.port.retry.command_retries = retries;
+        let result = self.port.exec_task_file(tf, data);
+        self.port.retry = policy;
+        result
     }
 
-    pub fn write(&mut self, block_id: u64, buf: &[u8]) -> bool {
-        // Cast to mut ptr for internal handling, but we won't modify it if it's write
-        let buf_mut =
-            unsafe { core::slice::from_raw_parts_mut(buf.as_ptr() as *mut u8, buf.len()) };
-        self.rw_common(block_id, buf_mut, true)
+    /// Issue a vendor specific command, transferring `buf` in the direction
+    /// given by `cmd`.
+    ///
+    /// Fails with [`AhciError::InvalidArgument`] if `cmd` does not
+    /// [validate](VendorCommand::validate) or `buf` is not exactly its
+    /// payload size, and otherwise like
+    /// [`submit_ata_command`](Self::submit_ata_command).
+    pub fn exec_vendor_command(
+        &mut self,
+        cmd: &VendorCommand,
+        buf: &mut [u8],
+    ) -> Result<TaskFileResult, AhciError> {
+        cmd.validate()?;
+        if buf.len() != cmd.payload_len() {
+            return Err(AhciError::InvalidArgument);
+        }
+        let timeouts = self.port.timeouts;
+        if let Some(timeout) = cmd.timeout() {
+            self.port.timeouts.command_ms = timeout;
+            self.port.timeouts.spin_up_ms = timeout;
+        }
+        let retries = cmd.retry_count().unwrap_or(0);
+        let result = self.pass_through(cmd.task_file(), cmd.direction(), buf, retries);
+        self.port.timeouts = timeouts;
+        result
     }
 
-    fn rw_common(&mut self, block_id: u64, buf: &mut [u8], is_write: bool) -> bool {
-        let mut start = block_id;
-        let mut remaining_bytes = buf.len();
-        let mut buf_offset = 0;
+    /// Bring the drive back after the system resumed from a sleep state in
+    /// which it may have lost power, resetting it and applying the drive
+    /// policy again to every disk on the port.
+    ///
+    /// Outstanding commands are completed first. Fails with
+    /// [`AhciError::DeviceGone`] if the drive does not come back, or if any
+    /// of the settings was rejected by a drive.
+    pub fn resume(&mut self) -> Result<(), AhciError> {
+        self.port.drain()?;
+        if !self.port.recover(true) {
+            return Err(AhciError::DeviceGone);
+        }
+        self.port.settings_lost = false;
+        self.apply_policy_all()
+    }
 
-        while remaining_bytes > 0 {
-            let sectors = remaining_bytes.div_ceil(self.block_size);
-            let max_sectors = if self.is_lba48 { 65536 } else { 256 };
-            let count = sectors.min(max_sectors);
-            let byte_count = count * self.block_size;
-            let current_bytes = byte_count.min(remaining_bytes);
+    /// Apply the drive policy again if recovery reset the drive since.
+    fn restore_policy(&mut self) {
+        if !mem::take(&mut self.port.settings_lost) || self.mode != ProbeMode::Active {
+            return;
+        }
+        port_info!(
+            self.port.index,
+            "Port {} reset, applying the drive policy again",
+            self.port.index
+        );
+        // Rejected settings are logged, the disk is usable regardless.
+        let _ = self.apply_policy_all();
+    }
 
-            // Construct FIS
-            let mut fis = sata_fis_h2d {
-                fis_type: SATA_FIS_TYPE_REGISTER_H2D,
-                pm_port_c: 0x80,
-                ..Default::default()
-            };
+    /// Apply the drive policy to every disk on the port, ending with the
+    /// selected one.
+    ///
+    /// Every disk is attempted; the first failure is reported.
+    fn apply_policy_all(&mut self) -> Result<(), AhciError> {
+        let selected = self.port.pmp;
+        let pmps: Vec<_> = self.pm_disks.iter().map(|disk| disk.pmp).collect();
+        let mut result = Ok(());
+        for pmp in pmps {
+            result = result.and(self.select_pm_port(pmp).and_then(|()| self.apply_policy()));
+        }
+        self.select_pm_port(selected)?;
+        result.and(self.apply_policy())
+    }
+
+    /// Apply the current drive policy.
+    ///
+    /// This happens automatically when the drive is attached, after
+    /// recovery reset it and on [`resume`](Self::resume), but must be
+    /// repeated if the drive lost its settings otherwise, e.g. after a power
+    /// cycle.
+    /// Every setting is attempted; the first one rejected by the drive is
+    /// reported.
+    pub fn apply_policy(&mut self) -> Result<(), AhciError> {
+        let policy = self.policy.clone();
+        let port = self.port.index;
+        let mut result = Ok(());
+        self.negotiate(None);
 
-            if self.is_lba48 {
-                fis.command = if is_write {
-                    ATA_CMD_WRITE_EXT
+        if let Some(enable) = policy.write_cache {
+            if self.negotiated.write_cache.is_some() {
+                let feature = if enable {
+                    SETFEATURES_WC_ON
                 } else {
-                    ATA_CMD_READ_EXT
+                    SETFEATURES_WC_OFF
                 };
-                fis.lba_low = start as u8;
-                fis.lba_mid = (start >> 8) as u8;
-                fis.lba_high = (start >> 16) as u8;
-                fis.lba_low_exp = (start >> 24) as u8;
-                fis.lba_mid_exp = (start >> 32) as u8;
-                fis.lba_high_exp = (start >> 40) as u8;
-                fis.device = 0x40; // LBA mode
-                fis.sector_count = (count & 0xff) as u8;
-                fis.sector_count_exp = ((count >> 8) & 0xff) as u8;
+                result = result.and(self.port.set_features(feature, 0));
             } else {
-                fis.command = if is_write {
-                    ATA_CMD_WRITE
+                port_debug!(
+                    port,
+                    "Port {port} write cache not supported, ignoring policy"
+                );
+            }
+        }
+
+        if let Some(enable) = policy.read_lookahead {
+            if self.negotiated.read_lookahead.is_some() {
+                let feature = if enable {
+                    SETFEATURES_RA_ON
                 } else {
-                    ATA_CMD_READ
+                    SETFEATURES_RA_OFF
                 };
-                fis.lba_low = start as u8;
-                fis.lba_mid = (start >> 8) as u8;
-                fis.lba_high = (start >> 16) as u8;
-                fis.device = 0x40 | ((start >> 24) as u8 & 0x0f); // LBA mode + top 4 bits
-                fis.sector_count = (count & 0xff) as u8;
+                result = result.and(self.port.set_features(feature, 0));
+            } else {
+                port_debug!(
+                    port,
+                    "Port {port} read look-ahead not supported, ignoring policy"
+                );
+            }
+        }
+
+        if let Some(level) = policy.apm {
+            if ata_id_has_apm(&self.id) {
+                result = result.and(match level {
+                    FeatureLevel::Disabled => self.port.set_features(SETFEATURES_APM_OFF, 0),
+                    FeatureLevel::Level(level) => self.port.set_features(SETFEATURES_APM_ON, level),
+                });
+            } else {
+                port_debug!(port, "Port {port} APM not supported, ignoring policy");
+            }
+        }
+
+        if let Some(level) = policy.aam {
+            if ata_id_has_aam(&self.id) {
+                result = result.and(match level {
+                    FeatureLevel::Disabled => self.port.set_features(SETFEATURES_AAM_OFF, 0),
+                    FeatureLevel::Level(level) => self.port.set_features(SETFEATURES_AAM_ON, level),
+                });
+            } else {
+                port_debug!(port, "Port {port} AAM not supported, ignoring policy");
+            }
+        }
+
+        self.port.max_latency = policy.max_latency_ms;
+        if let Some(limit) = policy.error_recovery_limit {
+            if ata_id_has_sct_erc(&self.id) {
+                result = result.and(self.port.sct_erc(limit));
+            } else {
+                port_debug!(
+                    port,
+                    "Port {port} SCT Error Recovery Control not supported, ignoring policy"
+                );
+            }
+        }
+
+        if let Some(enable) = policy.link_power_management {
+            if self.mmio.host().cap().read().SALP() {
+                self.port.update_cmd(|cmd| cmd.with_ALPE(enable));
+            } else {
+                port_debug!(
+                    port,
+                    "Port {port} aggressive link power management not supported, ignoring policy"
+                );
+            }
+        }
+
+        if policy.quirks.pio_multiple {
+            let count = ata_id_max_multiple(&self.id);
+            if count == 0 {
+                port_debug!(
+                    port,
+                    "Port {port} READ/WRITE MULTIPLE not supported, ignoring quirk"
+                );
+            } else {
+                let set = self.port.set_multiple(count);
+                if set.is_ok() {
+                    port_info!(
+                        self.port.index,
+                        "Port {} using READ/WRITE MULTIPLE, {count} sectors per block",
+                        self.port.index
+                    );
+                    self.negotiate(Some(count));
+                }
+                result = result.and(set);
+            }
+        }
+
+        if let Err(err) = result {
+            port_warn!(
+                port,
+                "Port {port} failed to apply drive policy {policy:?}: {err}"
+            );
+        }
+        result
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.max_lba * self.emulation_ratio() as u64
+    }
+
+    /// Capacity of the drive in blocks without any limit set with
+    /// [`set_accessible_capacity`](Self::set_accessible_capacity), read with
+    /// GET NATIVE MAX ADDRESS EXT.
+    ///
+    /// Fails with [`AhciError::Unsupported`] if the drive does not support
+    /// Accessible Max Address Configuration.
+    pub fn native_capacity(&mut self) -> Result<u64, AhciError> {
+        if !ata_id_has_amac(&self.id) {
+            return Err(AhciError::Unsupported);
+        }
+        let tf = TaskFile {
+            features: ATA_AMAC_GET_NATIVE_MAX,
+            ..TaskFile::new(ATA_CMD_AMAC)
+        };
+        let result = self.port.exec_task_file(tf, Transfer::None)?;
+        Ok((result.lba + 1) * self.emulation_ratio() as u64)
+    }
+
+    /// Limit the capacity the drive reports to `lba` blocks with SET
+    /// ACCESSIBLE MAX ADDRESS EXT, e.g. to over-provision an SSD, and operate
+    /// the disk with the new capacity.
+    ///
+    /// The limit is kept across power cycles. Setting it to the
+    /// [`native_capacity`](Self::native_capacity) removes it. Fails with
+    /// [`AhciError::InvalidArgument`] unless `lba` is a non-zero number of
+    /// whole native sectors, and with [`AhciError::Unsupported`] if the drive
+    /// does not support Accessible Max Address Configuration.
+    pub fn set_accessible_capacity(&mut self, lba: u64) -> Result<(), AhciError> {
+        let ratio = self.emulation_ratio() as u64;
+        if lba == 0 || !lba.is_multiple_of(ratio) || lba / ratio > MAX_LBA48 {
+            return Err(AhciError::InvalidArgument);
+        }
+        if !ata_id_has_amac(&self.id) {
+            return Err(AhciError::Unsupported);
+        }
+        let tf = TaskFile {
+            features: ATA_AMAC_SET_ACCESSIBLE_MAX,
+            lba: lba / ratio - 1,
+            ..TaskFile::new(ATA_CMD_AMAC)
+        };
+        self.port.exec_task_file(tf, Transfer::None)?;
+        self.refresh_identity()?;
+        port_info!(
+            self.port.index,
+            "Port {} accessible capacity set to {} blocks",
+            self.port.index,
+            self.capacity()
+        );
+        Ok(())
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size / self.emulation_ratio()
+    }
+
+    /// Allocate a buffer for [`read_dma`](Self::read_dma) and
+    /// [`write_dma`](Self::write_dma), below 4 GiB if the HBA does not
+    /// support 64-bit addressing (CAP.S64A).
+    pub fn alloc_dma_buffer(&self, len: usize) -> Result<DmaBuffer<H>, AhciError> {
+        DmaBuffer::new(len, !self.negotiated.dma64)
+    }
+
+    /// Like [`read`](Self::read), but the data is transferred straight
+    /// into `buf`, never through a bounce buffer.
+    ///
+    /// Fails with [`AhciError::InvalidArgument`] unless `buf` covers whole
+    /// native sectors, or if the HBA can't address it, i.e. it was allocated
+    /// above 4 GiB rather than with
+    /// [`alloc_dma_buffer`](Self::alloc_dma_buffer).
+    pub fn read_dma(&mut self, block_id: u64, buf: &mut DmaBuffer<H>) -> Result<(), AhciError> {
+        let lba = self
+            .native_lba(block_id, buf.len())
+            .ok_or(AhciError::InvalidArgument)?;
+        self.restore_policy();
+        self.without_bounce_buffers(|driver| driver.rw_common(lba, IoBuf::Read(buf)))
+    }
+
+    /// Like [`write`](Self::write), but the data is transferred straight
+    /// from `buf`, never through a bounce buffer.
+    ///
+    /// Fails like [`read_dma`](Self::read_dma). Writes to an encrypted disk
+    /// are staged regardless, as `buf` must not be modified.
+    pub fn write_dma(&mut self, block_id: u64, buf: &DmaBuffer<H>) -> Result<(), AhciError> {
+        let lba = self
+            .native_lba(block_id, buf.len())
+            .ok_or(AhciError::InvalidArgument)?;
+        self.restore_policy();
+        self.without_bounce_buffers(|driver| driver.write_native(lba, buf))
+    }
+
+    /// Run `f` with [`DrivePolicy::disable_bounce_buffers`] set.
+    fn without_bounce_buffers<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let disable = mem::replace(&mut self.policy.disable_bounce_buffers, true);
+        let result = f(self);
+        self.policy.disable_bounce_buffers = disable;
+        result
+    }
+
+    /// Logical sector size of the drive, which differs from
+    /// [`block_size`](Self::block_size) when 512-byte sectors are emulated.
+    pub fn native_block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Physical sector size of the drive. Writes not covering whole physical
+    /// sectors are done with read-modify-write cycles by the drive.
+    pub fn physical_block_size(&self) -> usize {
+        ata_id_physical_sector_size(&self.id)
+    }
+
+    /// Offset in bytes of the first block aligned to a physical sector, i.e.
+    /// blocks starting at `alignment_offset() + k * physical_block_size()`
+    /// bytes are aligned.
+    pub fn alignment_offset(&self) -> usize {
+        let physical = self.physical_block_size();
+        let offset = ata_id_alignment_offset(&self.id) as usize * self.block_size;
+        // Word 209 gives the offset of LBA 0 within its physical sector.
+        (physical - offset % physical) % physical
+    }
+
+    /// Number of emulated blocks per native sector.
+    fn emulation_ratio(&self) -> usize {
+        if self.policy.emulate_512 && self.block_size > EMULATED_BLOCK_SIZE {
+            self.block_size / EMULATED_BLOCK_SIZE
+        } else {
+            1
+        }
+    }
+
+    /// Read whole blocks starting at `block_id`.
+    ///
+    /// An empty buffer reads nothing. Fails with
+    /// [`AhciError::PartialBlock`] if the length of `buf` is not a multiple of
+    /// the [`block_size`](Self::block_size).
+    pub fn read(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        self.check_blocks(buf.len())?;
+        self.restore_policy();
+        match self.emulation_ratio() {
+            1 => self.rw_common(block_id, IoBuf::Read(buf)),
+            _ => self.read_bytes(block_id * EMULATED_BLOCK_SIZE as u64, buf),
+        }
+    }
+
+    /// Write whole blocks starting at `block_id`.
+    ///
+    /// Like [`read`](Self::read), an empty buffer writes nothing and partial
+    /// blocks are rejected.
+    pub fn write(&mut self, block_id: u64, buf: &[u8]) -> Result<(), AhciError> {
+        self.check_blocks(buf.len())?;
+        self.restore_policy();
+        match self.emulation_ratio() {
+            1 => self.write_native(block_id, buf),
+            _ => self.write_bytes(block_id * EMULATED_BLOCK_SIZE as u64, buf),
+        }
+    }
+
+    /// Read `buf.len()` bytes at byte offset `offset` of the disk, which
+    /// need not be aligned to blocks.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.read_bytes(offset, buf)
+    }
+
+    /// Write `buf` at byte offset `offset` of the disk, reading back the
+    /// sectors it covers only partially.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), AhciError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.write_bytes(offset, buf)
+    }
+
+    /// Discard `num_blocks` blocks starting at `block_id` with DATA SET
+    /// MANAGEMENT TRIM, so an SSD can reclaim them.
+    ///
+    /// Discarded blocks read back as undefined data unless the drive
+    /// guarantees zeroes. With emulated 512-byte blocks, native sectors only
+    /// partially covered by the range are left alone. Fails with
+    /// [`AhciError::Unsupported`] if the drive does not support TRIM, and
+    /// with [`AhciError::InvalidArgument`] if the range exceeds the disk.
+    pub fn discard(&mut self, block_id: u64, num_blocks: u64) -> Result<(), AhciError> {
+        if !self.negotiated.trim {
+            return Err(AhciError::Unsupported);
+        }
+        let end = block_id
+            .checked_add(num_blocks)
+            .filter(|&end| end <= self.capacity())
+            .ok_or(AhciError::InvalidArgument)?;
+        let ratio = self.emulation_ratio() as u64;
+        let (lba, end) = (block_id.div_ceil(ratio), end / ratio);
+        if lba >= end {
+            return Ok(());
+        }
+        self.port.trim(lba, end - lba)
+    }
+
+    /// Fail unless `len` bytes are whole blocks; `Ok` for empty buffers too,
+    /// which the transfer loops turn into no-ops.
+    fn check_blocks(&self, len: usize) -> Result<(), AhciError> {
+        let block_size = self.block_size();
+        if !len.is_multiple_of(block_size) {
+            return Err(AhciError::PartialBlock { len, block_size });
+        }
+        Ok(())
+    }
+
+    /// Map a byte range to the native sector that contains its start, the
+    /// byte offset of the range in that sector, and the length of the native
+    /// sectors that cover it.
+    fn native_range(&self, offset: u64, len: usize) -> (u64, usize, usize) {
+        let lbas = byte_range_to_lba_range(offset..offset + len as u64, self.block_size);
+        let head = (offset % self.block_size as u64) as usize;
+        (
+            lbas.start,
+            head,
+            (lbas.end - lbas.start) as usize * self.block_size,
+        )
+    }
+
+    /// The native sector at which `len` bytes at block `block_id` start, if
+    /// they cover whole native sectors.
+    fn native_lba(&self, block_id: u64, len: usize) -> Option<u64> {
+        match self.emulation_ratio() {
+            1 => len.is_multiple_of(self.block_size).then_some(block_id),
+            _ => {
+                let offset = block_id * EMULATED_BLOCK_SIZE as u64;
+                let (lba, head, native_len) = self.native_range(offset, len);
+                (head == 0 && native_len == len).then_some(lba)
+            }
+        }
+    }
+
+    fn scratch(&self, len: usize) -> Scratch {
+        Scratch::new(len, self.policy.zeroize_buffers)
+    }
+
+    /// A bounce buffer from the pool, or a new one if the pool is empty.
+    fn bounce_buffer(&mut self) -> Result<DmaBuffer<H>, AhciError> {
+        match self.bounce_pool.pop() {
+            Some(buf) => Ok(buf),
+            None => self.alloc_dma_buffer(BOUNCE_BUFFER_SIZE),
+        }
+    }
+
+    /// Return a bounce buffer to the pool, wiping it first if requested.
+    fn release_bounce_buffer(&mut self, mut buf: DmaBuffer<H>) {
+        if self.policy.zeroize_buffers {
+            zeroize(&mut buf);
+        }
+        if self.bounce_pool.len() < BOUNCE_POOL_SIZE {
+            self.bounce_pool.push(buf);
+        }
+    }
+
+    fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        let (lba, head, len) = self.native_range(offset, buf.len());
+        if head == 0 && len == buf.len() {
+            return self.rw_common(lba, IoBuf::Read(buf));
+        }
+
+        let mut sectors = self.scratch(len);
+        self.rw_common(lba, IoBuf::Read(&mut sectors))?;
+        buf.copy_from_slice(&sectors[head..head + buf.len()]);
+        Ok(())
+    }
+
+    /// Write a byte range, reading back the native sectors that are only
+    /// partially covered by `buf`.
+    fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> Result<(), AhciError> {
+        let bs = self.block_size;
+        let (lba, head, len) = self.native_range(offset, buf.len());
+        if head == 0 && len == buf.len() {
+            return self.write_native(lba, buf);
+        }
+
+        let mut sectors = self.scratch(len);
+        if head != 0 {
+            self.rw_common(lba, IoBuf::Read(&mut sectors[..bs]))?;
+        }
+        let tail = head + buf.len();
+        if !tail.is_multiple_of(bs) && (len > bs || head == 0) {
+            let last = lba + (len / bs - 1) as u64;
+            self.rw_common(last, IoBuf::Read(&mut sectors[len - bs..]))?;
+        }
+        sectors[head..tail].copy_from_slice(buf);
+        self.write_native(lba, &sectors)
+    }
+
+    /// Write whole native sectors starting at `block_id`.
+    fn write_native(&mut self, block_id: u64, buf: &[u8]) -> Result<(), AhciError> {
+        #[cfg(feature = "xts")]
+        let encrypted = self.cipher.is_some();
+        #[cfg(not(feature = "xts"))]
+        let encrypted = false;
+
+        if self.policy.zero_write_offload != ZeroWriteOffload::Disabled
+            && !encrypted
+            && buf.len().is_multiple_of(self.block_size)
+        {
+            return self.write_offloading_zeroes(block_id, buf);
+        }
+        self.write_data(block_id, buf)
+    }
+
+    /// Write `buf`, replacing runs of all-zero sectors with the configured
+    /// [`ZeroWriteOffload`].
+    fn write_offloading_zeroes(&mut self, block_id: u64, buf: &[u8]) -> Result<(), AhciError> {
+        let bs = self.block_size;
+        let is_zero = |i: usize| buf[i * bs..(i + 1) * bs].iter().all(|&b| b == 0);
+        let sectors = buf.len() / bs;
+
+        let mut i = 0;
+        while i < sectors {
+            let zero = is_zero(i);
+            let mut j = i + 1;
+            while j < sectors && is_zero(j) == zero {
+                j += 1;
+            }
+
+            let lba = block_id + i as u64;
+            let count = j - i;
+            let offloaded =
+                zero && count >= ZERO_OFFLOAD_MIN_SECTORS && self.zero_range(lba, count as u64);
+            if !offloaded {
+                self.write_data(lba, &buf[i * bs..j * bs])?;
+            }
+            i = j;
+        }
+        Ok(())
+    }
+
+    /// Zero `count` sectors at `lba` without transferring the data, if the
+    /// drive supports the configured [`ZeroWriteOffload`].
+    fn zero_range(&mut self, lba: u64, count: u64) -> bool {
+        match self.negotiated.zero_write_offload {
+            ZeroWriteOffload::Disabled => false,
+            ZeroWriteOffload::Trim => self.port.trim(lba, count).is_ok(),
+            ZeroWriteOffload::WriteSame => self.port.sct_write_same(lba, count, 0).is_ok(),
+        }
+    }
+
+    fn write_data(&mut self, block_id: u64, buf: &[u8]) -> Result<(), AhciError> {
+        self.rw_common(block_id, IoBuf::Write(buf))
+    }
+
+    /// Set the cipher used to transparently encrypt all sectors, or disable
+    /// encryption with `None`.
+    #[cfg(feature = "xts")]
+    pub fn set_sector_cipher(&mut self, cipher: Option<Box<dyn SectorCipher>>) {
+        self.cipher = cipher;
+    }
+
+    #[cfg(feature = "xts")]
+    fn crypt_sectors(&self, lba: u64, buf: &mut [u8], encrypt: bool) {
+        let Some(cipher) = &self.cipher else {
+            return;
+        };
+        for (i, sector) in buf.chunks_exact_mut(self.block_size).enumerate() {
+            let lba = lba + i as u64;
+            if encrypt {
+                cipher.encrypt_sector(lba, sector);
+            } else {
+                cipher.decrypt_sector(lba, sector);
             }
+        }
+    }
+
+    fn rw_common(&mut self, block_id: u64, mut buf: IoBuf<'_>) -> Result<(), AhciError> {
+        let (len, is_write) = (buf.len(), matches!(buf, IoBuf::Write(_)));
+        // Every command transfers whole sectors, which must fit in `buf`.
+        if !len.is_multiple_of(self.block_size) {
+            return Err(AhciError::PartialBlock {
+                len,
+                block_size: self.block_size,
+            });
+        }
 
-            let slice = &mut buf[buf_offset..buf_offset + current_bytes];
+        let command = self.rw_command(is_write);
+        let max_bytes = self.negotiated.max_sectors * self.block_size;
 
-            // Check buffer alignment. AHCI requires data buffer to be even-byte aligned.
-            // We use 4-byte alignment to be safe.
-            if slice.as_ptr() as usize % 4 != 0 {
-                let mut temp_buf = alloc::vec![0u8; slice.len()];
-                if is_write {
-                    temp_buf.copy_from_slice(slice);
+        // Chunks transferred in place are issued as queued commands and
+        // waited for at the end. Encrypted I/O is processed chunk by chunk,
+        // so it is never queued.
+        #[cfg(feature = "xts")]
+        let crypt = self.cipher.is_some();
+        #[cfg(not(feature = "xts"))]
+        let crypt = false;
+        let ncq = self.ncq_depth().filter(|_| !crypt);
+
+        let mut lba = block_id;
+        let mut start = 0;
+        while start < len {
+            let end = (start + max_bytes).min(len);
+
+            // Encrypted writes must not modify the caller's buffer.
+            #[cfg(feature = "xts")]
+            let encrypt = is_write && self.cipher.is_some();
+            #[cfg(not(feature = "xts"))]
+            let encrypt = false;
+
+            // The HBA needs data buffers to be word aligned, 4-byte alignment
+            // is used to be safe. A buffer spread over more pages than the
+            // PRDT has entries for takes more than one command.
+            let chunk = buf.transfer(start..end);
+            let mut direct = None;
+            if !encrypt && chunk.buf().addr().is_multiple_of(4) {
+                let data = DmaMapping::new::<H>(chunk, self.block_size).unwrap();
+                if self.negotiated.dma64 || data.is_dma32() {
+                    direct = Some(data);
+                } else {
+                    data.unmap::<H>();
                 }
-                
-                if !self.port.exec_cmd(fis, temp_buf.as_mut_slice(), is_write) {
-                    return false;
+            }
+            let mut bounce = None;
+            let data = match direct {
+                Some(data) => data,
+                None => {
+                    if !encrypt && self.policy.disable_bounce_buffers {
+                        let _ = self.port.drain();
+                        return Err(AhciError::InvalidArgument);
+                    }
+                    let staging = match self.bounce_buffer() {
+                        Ok(staging) => bounce.insert(staging),
+                        Err(err) => {
+                            let _ = self.port.drain();
+                            return Err(err);
+                        }
+                    };
+                    let staged =
+                        (end - start).min(staging.len() / self.block_size * self.block_size);
+                    let staging = &mut staging[..staged];
+                    let data = match &buf {
+                        IoBuf::Write(data) => {
+                            staging.copy_from_slice(&data[start..start + staged]);
+                            #[cfg(feature = "xts")]
+                            self.crypt_sectors(lba, staging, true);
+                            Transfer::from(&*staging)
+                        }
+                        IoBuf::Read(_) => Transfer::from(staging),
+                    };
+                    DmaMapping::new::<H>(data, self.block_size).unwrap()
                 }
+            };
+            let range = start..start + data.len;
+            let count = range.len() / self.block_size;
 
-                if !is_write {
-                    slice.copy_from_slice(&temp_buf);
+            let result = match sector_count_field(count, self.negotiated.lba48) {
+                None => {
+                    // Not even a sector fits in the PRDT.
+                    data.unmap::<H>();
+                    let _ = self.port.drain();
+                    Err(AhciError::InvalidArgument)
                 }
-            } else {
-                if !self.port.exec_cmd(fis, slice, is_write) {
-                    return false;
+                Some(count) => {
+                    if let Some(depth) = ncq
+                        && bounce.is_none()
+                    {
+                        self.port
+                            .issue_queued(lba, count, data, depth)
+                            .inspect_err(|_| {
+                                // Commands already queued still reference the buffer.
+                                let _ = self.port.drain();
+                            })
+                    } else {
+                        let mut fis = sata_fis_h2d::command(command);
+                        set_lba(&mut fis, lba, count, self.negotiated.lba48);
+                        self.port.exec_mapped(fis, Some(data))
+                    }
+                }
+            };
+            if let Some(staging) = bounce {
+                if result.is_ok()
+                    && let IoBuf::Read(data) = &mut buf
+                {
+                    data[range.clone()].copy_from_slice(&staging[..range.len()]);
                 }
+                self.release_bounce_buffer(staging);
+            }
+            result?;
+            #[cfg(feature = "xts")]
+            if let IoBuf::Read(data) = &mut buf {
+                self.crypt_sectors(lba, &mut data[range.clone()], false);
             }
 
-            start += count as u64;
-            remaining_bytes -= current_bytes;
-            buf_offset += current_bytes;
+            lba += count as u64;
+            start = range.end;
+        }
+        if ncq.is_some() {
+            self.port.drain()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    use crate::mock::{self, MockDisk};
+
+    /// A `Hal` under which no two pages are contiguous for the HBA.
+    struct ScatteredHal;
+
+    impl Hal for ScatteredHal {
+        fn virt_to_phys(va: usize) -> usize {
+            va * 2
+        }
+
+        fn current_ms() -> u64 {
+            0
+        }
+
+        fn flush_dcache() {}
+    }
+
+    #[test]
+    fn flush_failures_in_a_row() {
+        let mut flush = FlushState {
+            requested: 2,
+            ..Default::default()
+        };
+        flush.fail(1, AhciError::Aborted);
+        flush.fail(2, AhciError::Aborted);
+        assert_eq!(flush.status(1), Some(false));
+        assert_eq!(flush.status(2), Some(false));
+        assert_eq!(flush.status(3), None);
+    }
+
+    #[test]
+    fn flush_failures_around_success() {
+        let mut flush = FlushState {
+            requested: 5,
+            ..Default::default()
+        };
+        flush.fail(2, AhciError::Aborted);
+        flush.completed = 3;
+        flush.fail(5, AhciError::Aborted);
+        assert_eq!(flush.status(1), Some(false));
+        assert_eq!(flush.status(2), Some(false));
+        assert_eq!(flush.status(3), Some(true));
+        assert_eq!(flush.status(4), Some(false));
+        assert_eq!(flush.status(5), Some(false));
+    }
+
+    #[test]
+    fn chunk_capped_by_prdt() {
+        let pages = AHCI_MAX_SG + 2;
+        let buf = vec![0u8; (pages + 2) * 4096];
+        let start = 4096 - buf.as_ptr().addr() % 4096 + 512;
+        let data = &buf[start..start + pages * 4096];
+        let mapping = DmaMapping::new::<ScatteredHal>(Transfer::from(data), 512).unwrap();
+        assert_eq!(mapping.prds.len(), AHCI_MAX_SG);
+        assert_eq!(mapping.len, AHCI_MAX_SG * 4096 - 512);
+        // Only whole 4 KiB sectors are transferred.
+        let mapping = DmaMapping::new::<ScatteredHal>(Transfer::from(data), 4096).unwrap();
+        assert_eq!(mapping.len, (AHCI_MAX_SG - 1) * 4096);
+        assert_eq!(
+            mapping.prds.iter().map(|&(_, len)| len).sum::<usize>(),
+            mapping.len
+        );
+    }
+
+    /// A mapping made of `prds`, without any pages to unmap.
+    fn mapping(prds: Vec<(DmaAddr, usize)>) -> DmaMapping {
+        DmaMapping {
+            va: 0,
+            len: prds.iter().map(|&(_, len)| len).sum(),
+            dir: DmaDirection::ToDevice,
+            pages: Vec::new(),
+            prds,
+        }
+    }
+
+    #[test]
+    fn valid_prds() {
+        let prds = vec![(0x1000, AHCI_MAX_BYTES_PER_SG), (0x2000_0002, 2)];
+        assert_eq!(mapping(prds).validate(0, true), Ok(()));
+        let full = vec![(0x1000, 4096); AHCI_MAX_SG];
+        assert_eq!(mapping(full).validate(0, true), Ok(()));
+    }
+
+    #[test]
+    fn odd_prds_rejected() {
+        for prd in [(0x1001, 512), (0x1000, 511), (0x1000, 1)] {
+            let prds = vec![(0x8000, 4096), prd];
+            assert_eq!(
+                mapping(prds).validate(0, false),
+                Err(AhciError::InvalidArgument)
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_prd_rejected() {
+        let prds = vec![(0x1000, AHCI_MAX_BYTES_PER_SG + 2)];
+        assert_eq!(
+            mapping(prds).validate(0, false),
+            Err(AhciError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn too_many_prds_rejected() {
+        for count in [AHCI_MAX_SG + 1, u16::MAX as usize + 1] {
+            let prds = vec![(0x1000, 512); count];
+            assert_eq!(
+                mapping(prds).validate(0, false),
+                Err(AhciError::InvalidArgument)
+            );
+        }
+    }
+
+    #[test]
+    fn prds_above_4gib_need_dma64() {
+        let below = vec![(0x1000, 4096), ((1 << 32) - 4096, 4096)];
+        assert_eq!(mapping(below).validate(0, true), Ok(()));
+        for prd in [(1 << 32, 4096), ((1 << 32) - 512, 1024)] {
+            let prds = vec![(0x1000, 4096), prd];
+            assert_eq!(mapping(prds.clone()).validate(0, false), Ok(()));
+            assert_eq!(
+                mapping(prds).validate(0, true),
+                Err(AhciError::InvalidArgument)
+            );
+        }
+    }
+
+    #[test]
+    fn random_buffer_shapes() {
+        let mut disk = mock::driver(MockDisk::default());
+        let mut rng = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move |max: u64| {
+            // xorshift64
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng % max
+        };
+        let mut buf = vec![0u8; 300 * 512 + 64];
+        for _ in 0..200 {
+            let offset = next(64) as usize;
+            let len = (next(300) as usize + 1) * 512;
+            let lba = next(8192 - 300);
+            let data = &mut buf[offset..offset + len];
+            if next(2) == 0 {
+                data.fill(next(256) as u8);
+                disk.write(lba, data).unwrap();
+                assert_eq!(mock::sectors(lba..lba + len as u64 / 512), *data);
+            } else {
+                disk.read(lba, data).unwrap();
+                assert_eq!(*data, mock::sectors(lba..lba + len as u64 / 512));
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_capped_by_max_sectors() {
+        for sector_size in [512, 4096] {
+            let mut disk = mock::driver(MockDisk {
+                sector_size,
+                lba48: false,
+                ncq_depth: 1,
+                sectors: 1024,
+                ..Default::default()
+            });
+            assert_eq!(disk.block_size(), sector_size);
+            let mut buf = vec![0; 300 * sector_size];
+            mock::take_commands();
+            disk.read(10, &mut buf).unwrap();
+            assert_eq!(buf, mock::sectors(10..310));
+            assert_eq!(mock::take_commands(), [ATA_CMD_READ; 2]);
+        }
+    }
+
+    #[test]
+    fn whole_sectors_in_one_command() {
+        for sector_size in [512, 4096] {
+            let mut disk = mock::driver(MockDisk {
+                sector_size,
+                ncq_depth: 1,
+                ..Default::default()
+            });
+            let data = vec![0xa5; 16 * sector_size];
+            mock::take_commands();
+            disk.write(3, &data).unwrap();
+            assert_eq!(mock::sectors(3..19), data);
+            assert_eq!(mock::take_commands(), [ATA_CMD_WRITE_EXT]);
+        }
+    }
+
+    #[test]
+    fn odd_length_is_partial_block() {
+        for sector_size in [512, 4096] {
+            let mut disk = mock::driver(MockDisk {
+                sector_size,
+                ..Default::default()
+            });
+            for len in [1, sector_size - 1, sector_size + 2] {
+                let err = AhciError::PartialBlock {
+                    len,
+                    block_size: sector_size,
+                };
+                assert_eq!(disk.read(0, &mut vec![0; len]), Err(err));
+                assert_eq!(disk.write(0, &vec![0; len]), Err(err));
+            }
+            mock::take_commands();
+            assert_eq!(disk.read(0, &mut []), Ok(()));
+            assert!(mock::take_commands().is_empty());
         }
-        true
     }
 }
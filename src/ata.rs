@@ -10,6 +10,11 @@ pub const SATA_FIS_TYPE_DMA_SETUP_BI: u8 = 65;
 pub const SATA_FIS_TYPE_DMA_ACT_D2H: u8 = 57;
 pub const SATA_FIS_TYPE_REGISTER_D2H: u8 = 52;
 pub const SATA_FIS_TYPE_REGISTER_H2D: u8 = 39;
+pub const ATA_SRST: u8 = 1 << 2;
+pub const ATA_DRDY: u8 = 1 << 6;
+pub const ATA_ERR: u8 = 1 << 0;
+pub const ATA_UNC: u8 = 1 << 6;
+pub const ATA_ABORTED: u8 = 1 << 2;
 
 pub const ATA_CMD_DEV_RESET: u8 = 0x08;
 pub const ATA_CMD_CHK_POWER: u8 = 0xE5;
@@ -64,6 +69,7 @@ pub const ATA_CMD_READ_NATIVE_MAX: u8 = 0xF8;
 pub const ATA_CMD_READ_NATIVE_MAX_EXT: u8 = 0x27;
 pub const ATA_CMD_SET_MAX: u8 = 0xF9;
 pub const ATA_CMD_SET_MAX_EXT: u8 = 0x37;
+pub const ATA_CMD_AMAC: u8 = 0x78;
 pub const ATA_CMD_READ_LOG_EXT: u8 = 0x2F;
 pub const ATA_CMD_WRITE_LOG_EXT: u8 = 0x3F;
 pub const ATA_CMD_READ_LOG_DMA_EXT: u8 = 0x47;
@@ -100,6 +106,52 @@ pub const ATA_CMD_SANITIZE_DEVICE: u8 = 0xB4;
 pub const ATA_CMD_ZAC_MGMT_IN: u8 = 0x4A;
 pub const ATA_CMD_ZAC_MGMT_OUT: u8 = 0x9F;
 
+pub const SETFEATURES_WC_ON: u8 = 0x02;
+pub const SETFEATURES_WC_OFF: u8 = 0x82;
+pub const SETFEATURES_APM_ON: u8 = 0x05;
+pub const SETFEATURES_APM_OFF: u8 = 0x85;
+pub const SETFEATURES_AAM_ON: u8 = 0x42;
+pub const SETFEATURES_AAM_OFF: u8 = 0xC2;
+pub const SETFEATURES_RA_ON: u8 = 0xAA;
+pub const SETFEATURES_RA_OFF: u8 = 0x55;
+pub const SETFEATURES_SATA_ENABLE: u8 = 0x10;
+pub const SATA_DEVSLP: u8 = 0x09;
+pub const SATA_HYBRID_INFO: u8 = 0x0A;
+
+pub const ATA_DSM_TRIM: u8 = 0x01;
+pub const ATA_DSM_RANGES_PER_BLOCK: usize = 64;
+pub const ATA_DSM_MAX_RANGE_LEN: u64 = 0xffff;
+
+pub const ATA_SMART_READ_DATA: u8 = 0xD0;
+pub const ATA_SMART_READ_THRESHOLDS: u8 = 0xD1;
+pub const ATA_SMART_WRITE_LOG: u8 = 0xD6;
+pub const ATA_SMART_STATUS: u8 = 0xDA;
+pub const ATA_SMART_LBAM_PASS: u8 = 0x4F;
+pub const ATA_SMART_LBAH_PASS: u8 = 0xC2;
+pub const ATA_SMART_LBAM_FAIL: u8 = 0xF4;
+pub const ATA_SMART_LBAH_FAIL: u8 = 0x2C;
+
+pub const ATA_LOG_DEVICE_STATS: u8 = 0x04;
+pub const ATA_LOG_NCQ_ERROR: u8 = 0x10;
+pub const ATA_LOG_NCQ_QUEUE_MGMT: u8 = 0x12;
+pub const ATA_LOG_HYBRID_INFO: u8 = 0x14;
+pub const ATA_LOG_SCT_COMMAND: u8 = 0xE0;
+
+pub const ATA_DEVSTAT_GENERAL: u8 = 0x01;
+pub const ATA_DEVSTAT_SSD: u8 = 0x07;
+
+pub const ATA_AMAC_GET_NATIVE_MAX: u16 = 0x0000;
+pub const ATA_AMAC_SET_ACCESSIBLE_MAX: u16 = 0x0001;
+
+pub const ATA_NCQ_HYBRID_CHANGE_BY_LBA: u16 = 0x03;
+
+pub const ATA_SCT_ACTION_WRITE_SAME: u16 = 0x0002;
+pub const ATA_SCT_WRITE_SAME_PATTERN_FG: u16 = 0x0101;
+pub const ATA_SCT_ACTION_ERC: u16 = 0x0003;
+pub const ATA_SCT_ERC_SET: u16 = 0x0001;
+pub const ATA_SCT_ERC_READ: u16 = 0x0001;
+pub const ATA_SCT_ERC_WRITE: u16 = 0x0002;
+
 pub const ATA_ID_WORDS: usize = 256;
 pub const ATA_ID_CONFIG: usize = 0;
 pub const ATA_ID_CYLS: usize = 1;
@@ -133,6 +185,7 @@ pub const ATA_ID_QUEUE_DEPTH: usize = 75;
 pub const ATA_ID_SATA_CAPABILITY: usize = 76;
 pub const ATA_ID_SATA_CAPABILITY_2: usize = 77;
 pub const ATA_ID_FEATURE_SUPP: usize = 78;
+pub const ATA_ID_FEATURE_ENABLE: usize = 79;
 pub const ATA_ID_MAJOR_VER: usize = 80;
 pub const ATA_ID_COMMAND_SET_1: usize = 82;
 pub const ATA_ID_COMMAND_SET_2: usize = 83;
@@ -157,6 +210,7 @@ pub const ATA_ID_CFA_KEY_MGMT: usize = 162;
 pub const ATA_ID_CFA_MODES: usize = 163;
 pub const ATA_ID_DATA_SET_MGMT: usize = 169;
 pub const ATA_ID_SCT_CMD_XPORT: usize = 206;
+pub const ATA_ID_SECTOR_ALIGNMENT: usize = 209;
 pub const ATA_ID_ROT_SPEED: usize = 217;
 pub const ATA_ID_PIO4: usize = 2;
 
@@ -213,3 +267,168 @@ pub fn ata_id_n_sectors(id: &[u16]) -> u64 {
         0
     }
 }
+
+pub fn ata_id_has_wcache(id: &[u16]) -> bool {
+    (id[ATA_ID_COMMAND_SET_1] & (1 << 5)) != 0
+}
+
+pub fn ata_id_has_read_lookahead(id: &[u16]) -> bool {
+    (id[ATA_ID_COMMAND_SET_1] & (1 << 6)) != 0
+}
+
+pub fn ata_id_wcache_enabled(id: &[u16]) -> bool {
+    (id[ATA_ID_CFS_ENABLE_1] & (1 << 5)) != 0
+}
+
+pub fn ata_id_read_lookahead_enabled(id: &[u16]) -> bool {
+    (id[ATA_ID_CFS_ENABLE_1] & (1 << 6)) != 0
+}
+
+pub fn ata_id_has_apm(id: &[u16]) -> bool {
+    if (id[ATA_ID_COMMAND_SET_2] & 0xc000) != 0x4000 {
+        return false;
+    }
+    (id[ATA_ID_COMMAND_SET_2] & (1 << 3)) != 0
+}
+
+pub fn ata_id_has_aam(id: &[u16]) -> bool {
+    if (id[ATA_ID_COMMAND_SET_2] & 0xc000) != 0x4000 {
+        return false;
+    }
+    (id[ATA_ID_COMMAND_SET_2] & (1 << 9)) != 0
+}
+
+pub fn ata_id_major_version(id: &[u16]) -> u32 {
+    let ver = id[ATA_ID_MAJOR_VER];
+    if ver == 0x0000 || ver == 0xffff {
+        return 0;
+    }
+    (1..15)
+        .rev()
+        .find(|&bit| ver & (1 << bit) != 0)
+        .unwrap_or(0)
+}
+
+pub fn ata_id_has_trim(id: &[u16]) -> bool {
+    ata_id_major_version(id) >= 7 && (id[ATA_ID_DATA_SET_MGMT] & 1) != 0
+}
+
+pub fn ata_id_has_zero_after_trim(id: &[u16]) -> bool {
+    ata_id_has_trim(id) && (id[ATA_ID_ADDITIONAL_SUPP] & 0x4020) == 0x4020
+}
+
+pub fn ata_id_has_sct_write_same(id: &[u16]) -> bool {
+    (id[ATA_ID_SCT_CMD_XPORT] & 0x5) == 0x5
+}
+
+pub fn ata_id_has_sct_erc(id: &[u16]) -> bool {
+    (id[ATA_ID_SCT_CMD_XPORT] & 0x9) == 0x9
+}
+
+pub fn ata_id_has_amac(id: &[u16]) -> bool {
+    (id[ATA_ID_COMMAND_SET_3] & 0xc100) == 0x4100
+}
+
+pub fn ata_id_has_wwn(id: &[u16]) -> bool {
+    (id[ATA_ID_CSF_DEFAULT] & 0xc100) == 0x4100
+}
+
+pub fn ata_id_wwn(id: &[u16]) -> u64 {
+    (id[ATA_ID_WWN] as u64) << 48
+        | (id[ATA_ID_WWN + 1] as u64) << 32
+        | (id[ATA_ID_WWN + 2] as u64) << 16
+        | id[ATA_ID_WWN + 3] as u64
+}
+
+pub fn ata_id_logical_sector_size(id: &[u16]) -> usize {
+    // Word 106 is valid when bit 14 is set and bit 15 is clear; bit 12 means
+    // the logical sector is longer than 256 words.
+    if (id[ATA_ID_SECTOR_SIZE] & 0xd000) == 0x5000 {
+        return ata_id_u32(id, ATA_ID_LOGICAL_SECTOR_SIZE) as usize * 2;
+    }
+    512
+}
+
+/// Physical sector size in bytes, a power of two multiple of the logical
+/// sector size.
+pub fn ata_id_physical_sector_size(id: &[u16]) -> usize {
+    let logical = ata_id_logical_sector_size(id);
+    // Bit 13 means bits 3:0 hold log2 of the logical sectors per physical
+    // sector.
+    if (id[ATA_ID_SECTOR_SIZE] & 0xe000) == 0x6000 {
+        return logical << (id[ATA_ID_SECTOR_SIZE] & 0xf);
+    }
+    logical
+}
+
+/// Offset of logical sector 0 within the first physical sector, in logical
+/// sectors.
+pub fn ata_id_alignment_offset(id: &[u16]) -> u16 {
+    if (id[ATA_ID_SECTOR_ALIGNMENT] & 0xc000) != 0x4000 {
+        return 0;
+    }
+    id[ATA_ID_SECTOR_ALIGNMENT] & 0x3fff
+}
+
+pub fn ata_id_has_ncq(id: &[u16]) -> bool {
+    (id[ATA_ID_SATA_CAPABILITY] & (1 << 8)) != 0
+}
+
+pub fn ata_id_has_ncq_queue_mgmt(id: &[u16]) -> bool {
+    (id[ATA_ID_SATA_CAPABILITY_2] & (1 << 5)) != 0
+}
+
+pub fn ata_id_has_devslp(id: &[u16]) -> bool {
+    (id[ATA_ID_FEATURE_SUPP] & (1 << 8)) != 0
+}
+
+pub fn ata_id_devslp_enabled(id: &[u16]) -> bool {
+    (id[ATA_ID_FEATURE_ENABLE] & (1 << 8)) != 0
+}
+
+pub fn ata_id_has_hybrid(id: &[u16]) -> bool {
+    (id[ATA_ID_FEATURE_SUPP] & (1 << 9)) != 0
+}
+
+pub fn ata_id_hybrid_enabled(id: &[u16]) -> bool {
+    (id[ATA_ID_FEATURE_ENABLE] & (1 << 9)) != 0
+}
+
+/// Maximum number of sectors per DRQ data block of READ/WRITE MULTIPLE, 0 if
+/// the commands are not supported.
+pub fn ata_id_max_multiple(id: &[u16]) -> u8 {
+    let w = id[ATA_ID_MAX_MULTSECT];
+    if w >> 8 != 0x80 {
+        return 0;
+    }
+    w as u8
+}
+
+pub fn ata_id_queue_depth(id: &[u16]) -> u8 {
+    (id[ATA_ID_QUEUE_DEPTH] & 0x1f) as u8 + 1
+}
+
+pub fn ata_id_smart_enabled(id: &[u16]) -> bool {
+    if (id[ATA_ID_CSF_DEFAULT] & 0xc000) != 0x4000 {
+        return false;
+    }
+    (id[ATA_ID_CFS_ENABLE_1] & 1) != 0
+}
+
+pub fn ata_id_has_gpl(id: &[u16]) -> bool {
+    if (id[ATA_ID_CFSSE] & 0xc000) != 0x4000 {
+        return false;
+    }
+    (id[ATA_ID_CFSSE] & (1 << 5)) != 0
+}
+
+pub fn ata_id_has_fua(id: &[u16]) -> bool {
+    if (id[ATA_ID_CFSSE] & 0xc000) != 0x4000 {
+        return false;
+    }
+    (id[ATA_ID_CFSSE] & (1 << 6)) != 0
+}
+
+pub fn ata_id_is_ssd(id: &[u16]) -> bool {
+    id[ATA_ID_ROT_SPEED] == 0x01
+}
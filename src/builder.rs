@@ -0,0 +1,130 @@
+//! Configuration of how an HBA and its disks are brought up.
+
+use crate::{AhciController, AhciError, DrivePolicy, Hal, ProbeMode, ProbeTimeouts};
+
+/// Configures the bring-up of an HBA, then brings it up with
+/// [`build`](Self::build).
+///
+/// The defaults match [`AhciController::try_new`]: the HBA is reset, every
+/// implemented port is probed in parallel and the disks wait for commands by
+/// polling.
+#[derive(Debug, Clone)]
+pub struct AhciDriverBuilder {
+    pub(crate) policy: DrivePolicy,
+    pub(crate) mode: ProbeMode,
+    pub(crate) port_mask: u32,
+    pub(crate) boot_port: Option<u8>,
+    pub(crate) reset: bool,
+    pub(crate) staggered_spin_up: bool,
+    pub(crate) hba_interrupts: bool,
+    pub(crate) irq_mode: bool,
+    pub(crate) timeouts: ProbeTimeouts,
+}
+
+impl Default for AhciDriverBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AhciDriverBuilder {
+    /// A builder with the default configuration.
+    pub fn new() -> Self {
+        Self {
+            policy: DrivePolicy::default(),
+            mode: ProbeMode::Active,
+            port_mask: !0,
+            boot_port: None,
+            reset: true,
+            staggered_spin_up: false,
+            hba_interrupts: true,
+            irq_mode: false,
+            timeouts: ProbeTimeouts::default(),
+        }
+    }
+
+    /// Apply `policy` to every disk once it has been identified.
+    pub fn policy(mut self, policy: DrivePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Bring the disks up in `mode`.
+    pub fn probe_mode(mut self, mode: ProbeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Only use the implemented ports set in `mask`. The others are never
+    /// probed nor touched otherwise, e.g. because firmware still uses them.
+    pub fn port_mask(mut self, mask: u32) -> Self {
+        self.port_mask = mask;
+        self
+    }
+
+    /// Probe only `port` at first, see
+    /// [`AhciController::try_new_with_boot_port`].
+    pub fn boot_port(mut self, port: u8) -> Self {
+        self.boot_port = Some(port);
+        self
+    }
+
+    /// Whether to reset the HBA (GHC.HR) first, which is the default.
+    ///
+    /// The reset stops every port, aborting whatever firmware left running.
+    /// Skipping it keeps the configuration the firmware set up, e.g. PHY
+    /// settings it does not restore after a reset, but the ports of the
+    /// mask are still stopped and reprogrammed. The HBA is never reset in
+    /// [`ProbeMode::Passive`].
+    pub fn hba_reset(mut self, reset: bool) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    /// Spin up and probe the ports one at a time instead of all at once,
+    /// for power supplies that can't spin up every disk together.
+    ///
+    /// Only HBAs supporting staggered spin-up (CAP.SSS) leave the disks
+    /// spun down until their port is probed.
+    pub fn staggered_spin_up(mut self, staggered: bool) -> Self {
+        self.staggered_spin_up = staggered;
+        self
+    }
+
+    /// Whether the HBA may raise interrupts (GHC.IE), which is the default.
+    ///
+    /// Disable them on systems that don't service the HBA's interrupt line.
+    pub fn hba_interrupts(mut self, enabled: bool) -> Self {
+        self.hba_interrupts = enabled;
+        self
+    }
+
+    /// Whether the disks start in interrupt mode, see
+    /// [`AhciDriver::set_irq_mode`](crate::AhciDriver::set_irq_mode).
+    pub fn irq_mode(mut self, enabled: bool) -> Self {
+        self.irq_mode = enabled;
+        self
+    }
+
+    /// Timeouts of the phases of bring-up.
+    pub fn timeouts(mut self, timeouts: ProbeTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Bring up the HBA at the given MMIO base address and its disks.
+    ///
+    /// Fails with [`AhciError::InvalidArgument`] if interrupt mode is
+    /// requested with the HBA's interrupts disabled.
+    ///
+    /// # Safety
+    ///
+    /// See [`AhciDriver::try_new`](crate::AhciDriver::try_new).
+    pub unsafe fn build<H: Hal>(self, base: usize) -> Result<AhciController<H>, AhciError> {
+        if self.irq_mode && !self.hba_interrupts {
+            return Err(AhciError::InvalidArgument);
+        }
+        // SAFETY: Forwarded to the caller.
+        unsafe { AhciController::from_builder(base, self) }
+    }
+}
@@ -0,0 +1,49 @@
+//! Snapshot of a driver's effective configuration.
+
+use crate::{DrivePolicy, HungCommandCheck, RetryPolicy, Timeouts};
+
+/// The configuration in effect for a disk, see
+/// [`AhciDriver::current_config`](crate::AhciDriver::current_config).
+///
+/// Intended for display and for comparing the settings of several disks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DriverConfig {
+    /// Time a command may take before it is considered timed out, in
+    /// milliseconds.
+    pub command_timeout_ms: u64,
+    /// Number of COMRESETs tried on a link that does not come up.
+    pub comreset_retries: usize,
+    /// Number of queued commands the driver may use.
+    pub queue_depth: u8,
+    /// Number of queued commands supported by both the HBA and the drive.
+    pub max_queue_depth: u8,
+    /// Whether completions are processed in submission order.
+    pub ordered_completion: bool,
+    /// Whether the volatile write cache is enabled, `None` if the drive has
+    /// none.
+    pub write_cache: Option<bool>,
+    /// Whether read look-ahead is enabled, `None` if not supported.
+    pub read_lookahead: Option<bool>,
+    /// Sectors per data block when transferring with READ/WRITE MULTIPLE,
+    /// `None` when transferring with DMA.
+    pub multiple_count: Option<u8>,
+    /// Whether aggressive link power management is enabled (PxCMD.ALPE).
+    pub link_power_management: bool,
+    /// Block size presented to the user, in bytes.
+    pub block_size: usize,
+    /// Logical sector size of the drive, in bytes.
+    pub native_block_size: usize,
+    /// Physical sector size of the drive, in bytes.
+    pub physical_block_size: usize,
+    /// Latency above which completions are reported, in milliseconds.
+    pub slow_io_threshold_ms: Option<u64>,
+    /// Configuration of the hung command detector.
+    pub hung_command_check: Option<HungCommandCheck>,
+    /// Time limits of commands and port recovery.
+    pub timeouts: Timeouts,
+    /// How failed commands are retried.
+    pub retry_policy: RetryPolicy,
+    /// The drive policy applied on attach.
+    pub policy: DrivePolicy,
+}
@@ -0,0 +1,449 @@
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::ptr::NonNull;
+
+use log::{error, info, warn};
+use volatile::VolatilePtr;
+
+use crate::{
+    Hal,
+    ahci::{AhciDriver, AhciPort, IdentifyData, port_regs},
+    builder::AhciDriverBuilder,
+    disk_id::DiskId,
+    error::AhciError,
+    hal::{HalClock, wait_until_timeout},
+    hotplug::{HotplugEvent, HotplugSink},
+    irq::{IrqHandler, IrqStatus},
+    mmio::{
+        AhciMmio, AhciMmioVolatileFieldAccess, GenericHostControl,
+        GenericHostControlVolatileFieldAccess, ISS as LinkSpeed, PortRegistersVolatileFieldAccess,
+        PxSERR,
+    },
+    passive::ReadOnlyDisk,
+    policy::DrivePolicy,
+    probe::{DeviceType, ProbeMode, ProbeReport, ProbeTimeouts},
+    vendor::VendorRegisters,
+    verbosity::{port_info, port_warn},
+};
+
+/// An AHCI host bus adapter and the disks found behind it.
+///
+/// Ports are probed in index order, which depends on how the disks are
+/// cabled. Use [`find_by_wwn`](Self::find_by_wwn),
+/// [`find_by_serial`](Self::find_by_serial) or [`find_by_id`](Self::find_by_id)
+/// to locate a specific disk regardless of the port it is attached to.
+pub struct AhciController<H> {
+    mmio: VolatilePtr<'static, AhciMmio>,
+    disks: Vec<AhciDriver<H>>,
+    probe_reports: Vec<ProbeReport>,
+    irq: Arc<IrqStatus>,
+    policy: DrivePolicy,
+    mode: ProbeMode,
+    /// Implemented ports the driver may use, as a bitmap.
+    ports: u32,
+    /// Of those, the ports not probed yet.
+    unprobed: u32,
+    staggered_spin_up: bool,
+    irq_mode: bool,
+    timeouts: ProbeTimeouts,
+    hotplug_sink: Option<Box<dyn HotplugSink>>,
+}
+
+/// Safety: See [`AhciDriver`].
+unsafe impl<H: Hal> Send for AhciController<H> {}
+unsafe impl<H: Hal> Sync for AhciController<H> {}
+
+impl<H: Hal> AhciController<H> {
+    /// Try to reset the controller at the given MMIO base address and bring up
+    /// all of its ports.
+    ///
+    /// # Safety
+    ///
+    /// See [`AhciDriver::try_new`].
+    pub unsafe fn try_new(base: usize) -> Result<Self, AhciError> {
+        // SAFETY: Forwarded to the caller.
+        unsafe { Self::try_new_with_policy(base, DrivePolicy::default()) }
+    }
+
+    /// Like [`try_new`](Self::try_new), but applies `policy` to every disk
+    /// once it has been identified.
+    ///
+    /// # Safety
+    ///
+    /// See [`AhciDriver::try_new`].
+    pub unsafe fn try_new_with_policy(base: usize, policy: DrivePolicy) -> Result<Self, AhciError> {
+        // SAFETY: Forwarded to the caller.
+        unsafe { AhciDriverBuilder::new().policy(policy).build(base) }
+    }
+
+    /// Like [`try_new_with_policy`](Self::try_new_with_policy), but only
+    /// probes `boot_port`, e.g. the port firmware booted from, so the boot
+    /// disk is available without waiting for the other ports.
+    ///
+    /// The other ports are probed with
+    /// [`probe_remaining`](Self::probe_remaining), or right away if no disk
+    /// is found on `boot_port`.
+    ///
+    /// # Safety
+    ///
+    /// See [`AhciDriver::try_new`].
+    pub unsafe fn try_new_with_boot_port(
+        base: usize,
+        policy: DrivePolicy,
+        boot_port: u8,
+    ) -> Result<Self, AhciError> {
+        // SAFETY: Forwarded to the caller.
+        unsafe {
+            AhciDriverBuilder::new()
+                .policy(policy)
+                .boot_port(boot_port)
+                .build(base)
+        }
+    }
+
+    /// Bring up the controller at the given MMIO base address and its disks
+    /// in [`ProbeMode::Passive`], for inspecting them without writing to
+    /// them, changing their settings or resetting them.
+    ///
+    /// # Safety
+    ///
+    /// See [`AhciDriver::try_new`].
+    pub unsafe fn try_new_passive(base: usize) -> Result<Vec<ReadOnlyDisk<H>>, AhciError> {
+        // SAFETY: Forwarded to the caller.
+        let controller = unsafe {
+            AhciDriverBuilder::new()
+                .probe_mode(ProbeMode::Passive)
+                .build(base)?
+        };
+        Ok(controller
+            .into_disks()
+            .into_iter()
+            .map(ReadOnlyDisk::new)
+            .collect())
+    }
+
+    /// See [`AhciDriverBuilder::build`].
+    pub(crate) unsafe fn from_builder(
+        base: usize,
+        config: AhciDriverBuilder,
+    ) -> Result<Self, AhciError> {
+        // SAFETY: The caller guarantees `base` is a valid AHCI MMIO base address.
+        let mmio = unsafe { VolatilePtr::new(NonNull::new(base as *mut _).unwrap()) };
+        let host = mmio.host();
+
+        if host.cap2().read().BOH() {
+            Self::bios_handoff(&host);
+        }
+
+        // reset ahci controller
+        if config.reset && config.mode != ProbeMode::Passive {
+            host.ghc().update(|mut ghc| {
+                if !ghc.HR() {
+                    ghc.set_HR(true);
+                }
+                ghc
+            });
+            if !wait_until_timeout::<HalClock<H>>(
+                || !host.ghc().read().HR(),
+                config.timeouts.hba_reset_ms,
+            ) {
+                error!("AHCI HBA reset timeout");
+                return Err(AhciError::HbaResetTimeout);
+            }
+        }
+
+        // enable ahci
+        host.ghc().update(|ghc| ghc.with_AE(true));
+        wait_until_timeout::<HalClock<H>>(|| false, 1);
+        let ghc = host.ghc().read();
+        if !ghc.AE() {
+            let err = AhciError::ControllerInLegacyMode {
+                sam: host.cap().read().SAM(),
+                ghc: ghc.into_bits(),
+            };
+            error!("{err}");
+            return Err(err);
+        }
+
+        let vs = host.vs().read();
+        info!("AHCI ver {vs}");
+
+        // CAP and PI are HwInit: the firmware programs them and they are
+        // read-only to software, so they are only read.
+        let cap = host.cap().read();
+        info!("AHCI cap {cap}");
+
+        let cap2 = host.cap2().read();
+        info!("AHCI cap2 {cap2:?}");
+
+        let pi = host.pi().read();
+        info!("AHCI ports implemented {pi}");
+
+        host.ghc().update(|ghc| ghc.with_IE(config.hba_interrupts));
+
+        let ports = pi & config.port_mask;
+        let mut controller = Self {
+            mmio,
+            disks: Vec::new(),
+            probe_reports: Vec::new(),
+            irq: Arc::new(IrqStatus::new()),
+            policy: config.policy,
+            mode: config.mode,
+            ports,
+            unprobed: ports,
+            staggered_spin_up: config.staggered_spin_up,
+            irq_mode: config.irq_mode,
+            timeouts: config.timeouts,
+            hotplug_sink: None,
+        };
+        let boot_port = config.boot_port;
+        if let Some(port) = boot_port.filter(|&port| port < 32 && ports & (1 << port) != 0) {
+            controller.probe_ports(1 << port);
+        }
+        if controller.disks.is_empty() {
+            controller.probe_remaining();
+        }
+        if controller.disks.is_empty() {
+            error!("No AHCI ports initialized");
+            for report in &controller.probe_reports {
+                error!("{report}");
+            }
+            return Err(AhciError::NoDisks);
+        }
+        Ok(controller)
+    }
+
+    /// Take ownership of the HBA from the BIOS (CAP2.BOH), so that it stops
+    /// accessing the HBA, e.g. from SMM.
+    ///
+    /// The BIOS has 25 ms to release the HBA, or two more seconds if it sets
+    /// BOHC.BB to signal it is busy. The HBA is taken over regardless.
+    fn bios_handoff(host: &VolatilePtr<'_, GenericHostControl>) {
+        host.bohc().update(|bohc| bohc.with_OOS(true));
+        let released = wait_until_timeout::<HalClock<H>>(|| !host.bohc().read().BOS(), 25)
+            || (host.bohc().read().BB()
+                && wait_until_timeout::<HalClock<H>>(|| !host.bohc().read().BOS(), 2000));
+        if !released {
+            warn!(
+                "AHCI BIOS did not release the HBA (BOHC: {:?})",
+                host.bohc().read()
+            );
+        }
+        // Acknowledge the ownership change.
+        host.bohc().update(|bohc| bohc.with_OOC(true));
+    }
+
+    /// Probe the implemented ports in `ports` that were not probed yet,
+    /// returning the number of disks found.
+    fn probe_ports(&mut self, ports: u32) -> usize {
+        let ports = ports & self.unprobed;
+        self.unprobed &= !ports;
+        // PI may be sparse and implement ports beyond CAP.NP, so probe by
+        // bit position rather than by count.
+        let indices = (0..32).filter(|i| ports & (1 << i) != 0);
+        let (started, reports): (Vec<_>, Vec<_>) = if self.staggered_spin_up {
+            indices
+                .map(|i| AhciPort::<H>::probe_all(&self.mmio, [i], &self.irq, &self.timeouts))
+                .unzip()
+        } else {
+            [AhciPort::<H>::probe_all(
+                &self.mmio,
+                indices,
+                &self.irq,
+                &self.timeouts,
+            )]
+            .into_iter()
+            .unzip()
+        };
+        self.probe_reports
+            .retain(|report| ports & (1 << report.port) == 0);
+        self.probe_reports.extend(reports.into_iter().flatten());
+        self.probe_reports.sort_by_key(|report| report.port);
+
+        let before = self.disks.len();
+        for port in started.into_iter().flatten() {
+            let disk = AhciDriver::attach(
+                self.mmio,
+                port,
+                self.policy.clone(),
+                self.probe_reports.clone(),
+                self.mode,
+            );
+            self.disks.extend(disk.map(|mut disk| {
+                disk.set_irq_mode(self.irq_mode);
+                disk
+            }));
+        }
+        let found = self.disks.len() - before;
+        self.disks.sort_by_key(|disk| disk.port_index());
+        found
+    }
+
+    /// Probe the ports left out by
+    /// [`try_new_with_boot_port`](Self::try_new_with_boot_port), returning
+    /// the number of disks found.
+    pub fn probe_remaining(&mut self) -> usize {
+        self.probe_ports(self.unprobed)
+    }
+
+    /// Deliver the events found by [`poll_hotplug`](Self::poll_hotplug) to
+    /// `sink`, or stop delivering them with `None`.
+    pub fn set_hotplug_sink(&mut self, sink: Option<Box<dyn HotplugSink>>) {
+        self.hotplug_sink = sink;
+    }
+
+    /// Look for devices attached to or removed from the probed ports since
+    /// the last call, reporting them to the hot-plug sink and returning
+    /// their number.
+    ///
+    /// A disk whose link went down is removed and dropped. An empty port on
+    /// which a device was connected (PxIS.PCS) or established communication
+    /// (PxIS.PRCS) is probed again and its device identified. Meant to be
+    /// called periodically, e.g. from the timer that calls
+    /// [`AhciDriver::tick`]. Disks taken with [`into_disks`](Self::into_disks)
+    /// are not monitored.
+    pub fn poll_hotplug(&mut self) -> usize {
+        let probed = self.ports & !self.unprobed;
+        let mut events = 0;
+        for port in (0..32).filter(|i| probed & (1 << i) != 0) {
+            let regs = port_regs(&self.mmio, port);
+            let link_up = regs.SSTS().read().DET() == 3;
+            let disk = self.disks.iter().position(|disk| disk.port_index() == port);
+            let event = match disk {
+                Some(n) if !link_up => {
+                    port_warn!(port, "Port {port} device removed");
+                    drop(self.disks.remove(n));
+                    HotplugEvent::DeviceRemoved { port }
+                }
+                None => {
+                    let is = regs.IS().read();
+                    if !is.PC() && !is.PRC() {
+                        continue;
+                    }
+                    // PCS and PRCS mirror these and are cleared with them.
+                    regs.SERR()
+                        .write(PxSERR::new().with_DIAG_X(true).with_DIAG_N(true));
+                    if !link_up {
+                        continue;
+                    }
+                    port_info!(port, "Port {port} device connected");
+                    self.unprobed |= 1 << port;
+                    if self.probe_ports(1 << port) == 0 {
+                        continue;
+                    }
+                    HotplugEvent::DeviceAttached { port }
+                }
+                _ => continue,
+            };
+            events += 1;
+            if let Some(sink) = &mut self.hotplug_sink {
+                sink.notify(event);
+            }
+        }
+        events
+    }
+
+    /// Ports left for [`probe_remaining`](Self::probe_remaining), as a
+    /// bitmap.
+    pub fn unprobed_ports(&self) -> u32 {
+        self.unprobed
+    }
+
+    /// Number of ports the HBA supports (CAP.NP + 1).
+    ///
+    /// The implemented ports are not necessarily `0..num_ports()`, see
+    /// [`ports_implemented`](Self::ports_implemented).
+    pub fn num_ports(&self) -> u8 {
+        self.mmio.host().cap().read().NP() + 1
+    }
+
+    /// Bitmap of the implemented ports (PI).
+    pub fn ports_implemented(&self) -> u32 {
+        self.mmio.host().pi().read()
+    }
+
+    /// The global vendor specific registers (offsets 0xA0–0xFF).
+    ///
+    /// # Safety
+    ///
+    /// The registers are HBA specific and may control anything, including
+    /// state the driver depends on. The caller must only perform accesses
+    /// documented by the vendor for this HBA, such as PHY tuning errata, and
+    /// must not leave the HBA in a state the driver does not expect.
+    pub unsafe fn vendor_registers(&mut self) -> VendorRegisters<'_> {
+        // SAFETY: The vendor area has 24 registers.
+        unsafe { VendorRegisters::new(self.mmio.vendor()) }
+    }
+
+    /// MSI vector on which the HBA signals the interrupts of port `port`,
+    /// out of `vectors` vectors allocated to it.
+    ///
+    /// With multiple vectors, each port has its own up to the last vector,
+    /// which the remaining ports share. A port with its own vector can be
+    /// serviced with [`AhciDriver::handle_port_irq`] on the CPU its I/O is
+    /// submitted from. Returns 0 for every port if the HBA reverted to a
+    /// single vector (GHC.MRSM).
+    pub fn msi_vector(&self, port: u8, vectors: u16) -> u16 {
+        if vectors <= 1 || self.mmio.host().ghc().read().MRSM() {
+            return 0;
+        }
+        (port as u16).min(vectors - 1)
+    }
+
+    /// A handler for the HBA's interrupt, for disks in interrupt mode.
+    pub fn irq_handler(&self) -> IrqHandler<H> {
+        IrqHandler::new(self.mmio, self.irq.clone())
+    }
+
+    /// Reports from the bring-up of each port.
+    pub fn probe_reports(&self) -> &[ProbeReport] {
+        &self.probe_reports
+    }
+
+    /// The disks found, in port order.
+    pub fn disks(&self) -> &[AhciDriver<H>] {
+        &self.disks
+    }
+
+    /// The disks found, in port order.
+    pub fn disks_mut(&mut self) -> &mut [AhciDriver<H>] {
+        &mut self.disks
+    }
+
+    /// Iterate over the disks with the metadata needed to register them: port
+    /// index, device type, IDENTIFY data and negotiated link speed.
+    pub fn disks_iter(
+        &self,
+    ) -> impl Iterator<Item = (u8, DeviceType, &IdentifyData, LinkSpeed)> + '_ {
+        self.disks.iter().map(|disk| {
+            (
+                disk.port_index(),
+                disk.device_type(),
+                disk.identify_data(),
+                disk.link_speed(),
+            )
+        })
+    }
+
+    /// Take ownership of the disks, e.g. to hand them to separate block
+    /// device instances.
+    pub fn into_disks(self) -> Vec<AhciDriver<H>> {
+        self.disks
+    }
+
+    /// Find the disk whose serial number is `serial`, ignoring surrounding
+    /// whitespace.
+    pub fn find_by_serial(&mut self, serial: &str) -> Option<&mut AhciDriver<H>> {
+        let serial = serial.trim();
+        self.disks.iter_mut().find(|disk| disk.serial() == serial)
+    }
+
+    /// Find the disk whose World Wide Name is `wwn`.
+    pub fn find_by_wwn(&mut self, wwn: u64) -> Option<&mut AhciDriver<H>> {
+        self.disks.iter_mut().find(|disk| disk.wwn() == Some(wwn))
+    }
+
+    /// Find the disk with the given identity.
+    pub fn find_by_id(&mut self, id: &DiskId) -> Option<&mut AhciDriver<H>> {
+        self.disks.iter_mut().find(|disk| disk.disk_id() == *id)
+    }
+}
@@ -0,0 +1,344 @@
+//! Transparent sector encryption (AES-XTS style) for data at rest.
+
+/// A 128-bit block cipher keyed by the OS, typically AES.
+pub trait BlockCipher: Send + Sync {
+    /// Encrypt a single block in place.
+    fn encrypt_block(&self, block: &mut [u8; 16]);
+
+    /// Decrypt a single block in place.
+    fn decrypt_block(&self, block: &mut [u8; 16]);
+}
+
+/// Encrypts whole sectors, using the LBA as the tweak.
+pub trait SectorCipher: Send + Sync {
+    /// Encrypt `sector`, stored at `lba`, in place.
+    fn encrypt_sector(&self, lba: u64, sector: &mut [u8]);
+
+    /// Decrypt `sector`, read from `lba`, in place.
+    fn decrypt_sector(&self, lba: u64, sector: &mut [u8]);
+}
+
+/// XTS mode (IEEE 1619) over a [`BlockCipher`].
+///
+/// Sector sizes are always a multiple of the cipher block size, so
+/// ciphertext stealing is not needed.
+pub struct Xts<C> {
+    data: C,
+    tweak: C,
+}
+
+impl<C: BlockCipher> Xts<C> {
+    /// Create an XTS cipher from the data key cipher and the tweak key
+    /// cipher.
+    pub fn new(data: C, tweak: C) -> Self {
+        Self { data, tweak }
+    }
+
+    fn crypt(&self, lba: u64, sector: &mut [u8], encrypt: bool) {
+        assert!(sector.len().is_multiple_of(16));
+
+        let mut t = [0u8; 16];
+        t[..8].copy_from_slice(&lba.to_le_bytes());
+        self.tweak.encrypt_block(&mut t);
+
+        for block in sector.chunks_exact_mut(16) {
+            let block: &mut [u8; 16] = block.try_into().unwrap();
+            xor(block, &t);
+            if encrypt {
+                self.data.encrypt_block(block);
+            } else {
+                self.data.decrypt_block(block);
+            }
+            xor(block, &t);
+            mul_alpha(&mut t);
+        }
+    }
+}
+
+impl<C: BlockCipher> SectorCipher for Xts<C> {
+    fn encrypt_sector(&self, lba: u64, sector: &mut [u8]) {
+        self.crypt(lba, sector, true);
+    }
+
+    fn decrypt_sector(&self, lba: u64, sector: &mut [u8]) {
+        self.crypt(lba, sector, false);
+    }
+}
+
+fn xor(block: &mut [u8; 16], t: &[u8; 16]) {
+    for (b, t) in block.iter_mut().zip(t) {
+        *b ^= t;
+    }
+}
+
+/// Multiply the tweak by the primitive element of GF(2^128).
+fn mul_alpha(t: &mut [u8; 16]) {
+    let mut carry = 0;
+    for b in t.iter_mut() {
+        let next = *b >> 7;
+        *b = (*b << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        t[0] ^= 0x87;
+    }
+}
+
+#[cfg(all(test, feature = "xts"))]
+mod tests {
+    use alloc::vec::Vec;
+    use core::array;
+
+    use super::*;
+
+    /// A plain AES for checking against the IEEE 1619 test vectors. Slow
+    /// and not constant time.
+    struct Aes {
+        round_keys: Vec<[u8; 16]>,
+        sbox: [u8; 256],
+        inv_sbox: [u8; 256],
+    }
+
+    /// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1.
+    fn gmul(mut a: u8, mut b: u8) -> u8 {
+        let mut p = 0;
+        while b != 0 {
+            if b & 1 != 0 {
+                p ^= a;
+            }
+            a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+            b >>= 1;
+        }
+        p
+    }
+
+    impl Aes {
+        fn new(key: &[u8]) -> Self {
+            let mut sbox = [0; 256];
+            let mut inv_sbox = [0; 256];
+            for x in 0..=255u8 {
+                // The multiplicative inverse is x^254, followed by the affine
+                // transformation.
+                let mut inv = 1;
+                for _ in 0..254 {
+                    inv = gmul(inv, x);
+                }
+                let s = inv
+                    ^ inv.rotate_left(1)
+                    ^ inv.rotate_left(2)
+                    ^ inv.rotate_left(3)
+                    ^ inv.rotate_left(4)
+                    ^ 0x63;
+                sbox[x as usize] = s;
+                inv_sbox[s as usize] = x;
+            }
+
+            let nk = key.len() / 4;
+            let rounds = nk + 6;
+            let mut words: Vec<[u8; 4]> = key.chunks(4).map(|w| w.try_into().unwrap()).collect();
+            let mut rcon = 1;
+            for i in nk..4 * (rounds + 1) {
+                let mut t = words[i - 1];
+                if i % nk == 0 {
+                    t.rotate_left(1);
+                    t = t.map(|b| sbox[b as usize]);
+                    t[0] ^= rcon;
+                    rcon = gmul(rcon, 2);
+                } else if nk > 6 && i % nk == 4 {
+                    t = t.map(|b| sbox[b as usize]);
+                }
+                let prev = words[i - nk];
+                words.push(array::from_fn(|j| prev[j] ^ t[j]));
+            }
+            let round_keys = words
+                .chunks(4)
+                .map(|w| array::from_fn(|i| w[i / 4][i % 4]))
+                .collect();
+            Self {
+                round_keys,
+                sbox,
+                inv_sbox,
+            }
+        }
+
+        fn add_round_key(&self, block: &mut [u8; 16], round: usize) {
+            xor(block, &self.round_keys[round]);
+        }
+
+        /// The state is stored column by column, so row `r` of column `c` is
+        /// byte `r + 4 * c`.
+        fn shift_rows(block: &mut [u8; 16], inverse: bool) {
+            let old = *block;
+            for r in 1..4 {
+                for c in 0..4 {
+                    let (to, from) = (r + 4 * c, r + 4 * ((c + r) % 4));
+                    if inverse {
+                        block[from] = old[to];
+                    } else {
+                        block[to] = old[from];
+                    }
+                }
+            }
+        }
+
+        fn mix_columns(block: &mut [u8; 16], coefficients: [u8; 4]) {
+            for column in block.chunks_exact_mut(4) {
+                let a: [u8; 4] = column.try_into().unwrap();
+                for (r, b) in column.iter_mut().enumerate() {
+                    *b = (0..4).fold(0, |b, i| b ^ gmul(coefficients[(4 + i - r) % 4], a[i]));
+                }
+            }
+        }
+    }
+
+    impl BlockCipher for Aes {
+        fn encrypt_block(&self, block: &mut [u8; 16]) {
+            let rounds = self.round_keys.len() - 1;
+            self.add_round_key(block, 0);
+            for round in 1..=rounds {
+                *block = block.map(|b| self.sbox[b as usize]);
+                Self::shift_rows(block, false);
+                if round < rounds {
+                    Self::mix_columns(block, [2, 3, 1, 1]);
+                }
+                self.add_round_key(block, round);
+            }
+        }
+
+        fn decrypt_block(&self, block: &mut [u8; 16]) {
+            let rounds = self.round_keys.len() - 1;
+            self.add_round_key(block, rounds);
+            for round in (0..rounds).rev() {
+                Self::shift_rows(block, true);
+                *block = block.map(|b| self.inv_sbox[b as usize]);
+                self.add_round_key(block, round);
+                if round > 0 {
+                    Self::mix_columns(block, [14, 11, 13, 9]);
+                }
+            }
+        }
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Check an IEEE 1619 test vector in both directions.
+    fn check(key1: &str, key2: &str, lba: u64, ptx: &[u8], ctx: &str) {
+        let xts = Xts::new(Aes::new(&hex(key1)), Aes::new(&hex(key2)));
+        let mut sector = ptx.to_vec();
+        xts.encrypt_sector(lba, &mut sector);
+        assert_eq!(sector, hex(ctx));
+        xts.decrypt_sector(lba, &mut sector);
+        assert_eq!(sector, ptx);
+    }
+
+    /// The plaintext of the 512-byte vectors: 0x00 to 0xff, twice.
+    fn counting() -> Vec<u8> {
+        (0..512).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn aes_block() {
+        // FIPS-197, appendix C.1 and C.3.
+        let plain = hex("00112233445566778899aabbccddeeff");
+        for (key, cipher) in [
+            (
+                "000102030405060708090a0b0c0d0e0f",
+                "69c4e0d86a7b0430d8cdb78070b4c55a",
+            ),
+            (
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+                "8ea2b7ca516745bfeafc49904b496089",
+            ),
+        ] {
+            let aes = Aes::new(&hex(key));
+            let mut block: [u8; 16] = plain[..].try_into().unwrap();
+            aes.encrypt_block(&mut block);
+            assert_eq!(block[..], hex(cipher));
+            aes.decrypt_block(&mut block);
+            assert_eq!(block[..], plain);
+        }
+    }
+
+    #[test]
+    fn xts_aes_128_vector_1() {
+        check(
+            "00000000000000000000000000000000",
+            "00000000000000000000000000000000",
+            0,
+            &[0; 32],
+            "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e",
+        );
+    }
+
+    #[test]
+    fn xts_aes_128_vector_2() {
+        check(
+            "11111111111111111111111111111111",
+            "22222222222222222222222222222222",
+            0x3333333333,
+            &[0x44; 32],
+            "c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0",
+        );
+    }
+
+    #[test]
+    fn xts_aes_128_vector_4() {
+        check(
+            "27182818284590452353602874713526",
+            "31415926535897932384626433832795",
+            0,
+            &counting(),
+            concat!(
+                "27a7479befa1d476489f308cd4cfa6e2a96e4bbe3208ff25287dd3819616e89c",
+                "c78cf7f5e543445f8333d8fa7f56000005279fa5d8b5e4ad40e736ddb4d35412",
+                "328063fd2aab53e5ea1e0a9f332500a5df9487d07a5c92cc512c8866c7e860ce",
+                "93fdf166a24912b422976146ae20ce846bb7dc9ba94a767aaef20c0d61ad0265",
+                "5ea92dc4c4e41a8952c651d33174be51a10c421110e6d81588ede82103a252d8",
+                "a750e8768defffed9122810aaeb99f9172af82b604dc4b8e51bcb08235a6f434",
+                "1332e4ca60482a4ba1a03b3e65008fc5da76b70bf1690db4eae29c5f1badd03c",
+                "5ccf2a55d705ddcd86d449511ceb7ec30bf12b1fa35b913f9f747a8afd1b130e",
+                "94bff94effd01a91735ca1726acd0b197c4e5b03393697e126826fb6bbde8ecc",
+                "1e08298516e2c9ed03ff3c1b7860f6de76d4cecd94c8119855ef5297ca67e9f3",
+                "e7ff72b1e99785ca0a7e7720c5b36dc6d72cac9574c8cbbc2f801e23e56fd344",
+                "b07f22154beba0f08ce8891e643ed995c94d9a69c9f1b5f499027a78572aeebd",
+                "74d20cc39881c213ee770b1010e4bea718846977ae119f7a023ab58cca0ad752",
+                "afe656bb3c17256a9f6e9bf19fdd5a38fc82bbe872c5539edb609ef4f79c203e",
+                "bb140f2e583cb2ad15b4aa5b655016a8449277dbd477ef2c8d6c017db738b18d",
+                "eb4a427d1923ce3ff262735779a418f20a282df920147beabe421ee5319d0568",
+            ),
+        );
+    }
+
+    #[test]
+    fn xts_aes_256_vector_10() {
+        check(
+            "2718281828459045235360287471352662497757247093699959574966967627",
+            "3141592653589793238462643383279502884197169399375105820974944592",
+            0xff,
+            &counting(),
+            concat!(
+                "1c3b3a102f770386e4836c99e370cf9bea00803f5e482357a4ae12d414a3e63b",
+                "5d31e276f8fe4a8d66b317f9ac683f44680a86ac35adfc3345befecb4bb188fd",
+                "5776926c49a3095eb108fd1098baec70aaa66999a72a82f27d848b21d4a741b0",
+                "c5cd4d5fff9dac89aeba122961d03a757123e9870f8acf1000020887891429ca",
+                "2a3e7a7d7df7b10355165c8b9a6d0a7de8b062c4500dc4cd120c0f7418dae3d0",
+                "b5781c34803fa75421c790dfe1de1834f280d7667b327f6c8cd7557e12ac3a0f",
+                "93ec05c52e0493ef31a12d3d9260f79a289d6a379bc70c50841473d1a8cc81ec",
+                "583e9645e07b8d9670655ba5bbcfecc6dc3966380ad8fecb17b6ba02469a020a",
+                "84e18e8f84252070c13e9f1f289be54fbc481457778f616015e1327a02b140f1",
+                "505eb309326d68378f8374595c849d84f4c333ec4423885143cb47bd71c5edae",
+                "9be69a2ffeceb1bec9de244fbe15992b11b77c040f12bd8f6a975a44a0f90c29",
+                "a9abc3d4d893927284c58754cce294529f8614dcd2aba991925fedc4ae74ffac",
+                "6e333b93eb4aff0479da9a410e4450e0dd7ae4c6e2910900575da401fc07059f",
+                "645e8b7e9bfdef33943054ff84011493c27b3429eaedb4ed5376441a77ed4385",
+                "1ad77f16f541dfd269d50d6a5f14fb0aab1cbb4c1550be97f7ab4066193c4caa",
+                "773dad38014bd2092fa755c824bb5e54c4f36ffda9fcea70b9c6e693e148c151",
+            ),
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use core::fmt;
+
+/// State of a port's command engine, taken from PxCMD and PxCI.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EngineState {
+    /// Start (PxCMD.ST): the engine is allowed to process the command list.
+    pub started: bool,
+    /// Command List Running (PxCMD.CR).
+    pub running: bool,
+    /// FIS Receive Enable (PxCMD.FRE).
+    pub fis_receive_enabled: bool,
+    /// FIS Receive Running (PxCMD.FR).
+    pub fis_receive_running: bool,
+    /// Current Command Slot (PxCMD.CCS), the slot being processed while
+    /// `running` is set.
+    pub current_slot: u8,
+    /// Commands Issued (PxCI).
+    pub issued: u32,
+}
+
+impl EngineState {
+    /// Whether the engine has nothing to do.
+    pub fn is_idle(&self) -> bool {
+        self.issued == 0
+    }
+
+    /// Whether the engine is stopped while commands are still issued, which
+    /// means they will never complete.
+    pub fn is_wedged(&self) -> bool {
+        self.issued != 0 && !self.running
+    }
+}
+
+impl fmt::Display for EngineState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ST={} CR={} FRE={} FR={} CCS={} CI={:#x}",
+            self.started as u8,
+            self.running as u8,
+            self.fis_receive_enabled as u8,
+            self.fis_receive_running as u8,
+            self.current_slot,
+            self.issued
+        )
+    }
+}
+
+/// Snapshot of a port's registers for diagnostics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PortDiagnostics {
+    /// Port index.
+    pub port: u8,
+    /// Command engine state.
+    pub engine: EngineState,
+    /// Interrupt Status (PxIS).
+    pub is: u32,
+    /// Task File Data (PxTFD).
+    pub tfd: u32,
+    /// Serial ATA Status (PxSSTS).
+    pub ssts: u32,
+    /// Serial ATA Error (PxSERR).
+    pub serr: u32,
+    /// Serial ATA Active (PxSACT).
+    pub sact: u32,
+}
+
+impl fmt::Display for PortDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "port {}: {} IS={:#x} TFD={:#x} SSTS={:#x} SERR={:#x} SACT={:#x}",
+            self.port, self.engine, self.is, self.tfd, self.ssts, self.serr, self.sact
+        )
+    }
+}
+
+/// A register of which the driver keeps a copy of the value it last wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowedRegister {
+    /// Interrupt Enable (PxIE).
+    Ie,
+    /// Command and Status (PxCMD), only the bits controlled by the driver:
+    /// ST, SUD, POD, FRE and ALPE.
+    Cmd,
+    /// Command List Base Address (PxCLB and PxCLBU).
+    Clb,
+    /// FIS Base Address (PxFB and PxFBU).
+    Fb,
+    /// Serial ATA Control (PxSCTL), only the DET, SPD and IPM fields.
+    Sctl,
+}
+
+/// A register whose value differs from the value the driver last wrote,
+/// meaning something else reprogrammed the port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterMismatch {
+    /// Port index.
+    pub port: u8,
+    /// The register.
+    pub register: ShadowedRegister,
+    /// Value last written by the driver.
+    pub expected: u64,
+    /// Value read from the hardware.
+    pub actual: u64,
+}
+
+impl fmt::Display for RegisterMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "port {}: {:?} is {:#x}, expected {:#x}",
+            self.port, self.register, self.actual, self.expected
+        )
+    }
+}
@@ -0,0 +1,61 @@
+use alloc::string::String;
+use core::fmt;
+
+use crate::ata::{
+    ATA_ID_PROD, ATA_ID_PROD_LEN, ATA_ID_SERNO, ATA_ID_SERNO_LEN, ata_id_has_wwn, ata_id_to_string,
+    ata_id_wwn,
+};
+
+/// Identity of a disk that does not depend on the port it is attached to.
+///
+/// Derived from IDENTIFY DEVICE: the World Wide Name when the drive reports
+/// one, otherwise its model number and serial number.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DiskId {
+    /// World Wide Name (IDENTIFY words 108-111).
+    Wwn(u64),
+    /// Model number and serial number, without padding.
+    ModelSerial {
+        /// Model number (IDENTIFY words 27-46).
+        model: String,
+        /// Serial number (IDENTIFY words 10-19).
+        serial: String,
+    },
+}
+
+impl DiskId {
+    pub(crate) fn from_identify(id: &[u16]) -> Self {
+        if ata_id_has_wwn(id) {
+            let wwn = ata_id_wwn(id);
+            if wwn != 0 {
+                return Self::Wwn(wwn);
+            }
+        }
+        Self::ModelSerial {
+            model: ata_id_to_string(id, ATA_ID_PROD, ATA_ID_PROD_LEN)
+                .trim()
+                .into(),
+            serial: ata_id_to_string(id, ATA_ID_SERNO, ATA_ID_SERNO_LEN)
+                .trim()
+                .into(),
+        }
+    }
+}
+
+/// Formats the identity the way Linux names `/dev/disk/by-id` links, e.g.
+/// `wwn-0x5000c500a1b2c3d4` or `ata-MODEL_SERIAL`.
+impl fmt::Display for DiskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Wwn(wwn) => write!(f, "wwn-{wwn:#018x}"),
+            Self::ModelSerial { model, serial } => {
+                f.write_str("ata-")?;
+                for c in model.chars().chain(['_']).chain(serial.chars()) {
+                    let c = if c == ' ' { '_' } else { c };
+                    write!(f, "{c}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Buffers that are known to be usable for DMA by the HBA.
+
+use core::{
+    alloc::Layout,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    slice,
+};
+
+use crate::{AhciError, Hal, hal::DmaAddr};
+
+/// Alignment of [`DmaBuffer`]s: a page, so that no PRD entry crosses into
+/// another page.
+const DMA_BUFFER_ALIGN: usize = 4096;
+
+/// A zero-initialized, page aligned and physically contiguous buffer
+/// allocated with [`Hal::dma_alloc`].
+///
+/// Transfers to and from a `DmaBuffer` never need a bounce buffer: whether
+/// the HBA can access the memory is checked once, when the buffer is
+/// allocated, rather than on every command.
+pub struct DmaBuffer<H> {
+    va: usize,
+    addr: DmaAddr,
+    len: usize,
+    layout: Layout,
+    /// [`Hal::dma_dealloc`], captured so that dropping needs no `Hal` bound.
+    dealloc: fn(usize, DmaAddr, Layout),
+    _h: PhantomData<H>,
+}
+
+/// Safety: The buffer is uniquely owned, like a `Box<[u8]>`.
+unsafe impl<H> Send for DmaBuffer<H> {}
+unsafe impl<H> Sync for DmaBuffer<H> {}
+
+impl<H: Hal> DmaBuffer<H> {
+    /// Allocate a buffer of `len` bytes, below 4 GiB if `dma32` is set.
+    ///
+    /// Use [`AhciDriver::alloc_dma_buffer`](crate::AhciDriver::alloc_dma_buffer)
+    /// to let the driver decide whether the HBA needs 32-bit addresses.
+    pub fn new(len: usize, dma32: bool) -> Result<Self, AhciError> {
+        let failed = AhciError::DmaAllocFailed { len };
+        let layout = Layout::from_size_align(len.max(1), DMA_BUFFER_ALIGN).map_err(|_| failed)?;
+        let (va, addr) = H::dma_alloc(layout, dma32).ok_or(failed)?;
+        if !va.is_multiple_of(DMA_BUFFER_ALIGN) {
+            H::dma_dealloc(va, addr, layout);
+            return Err(failed);
+        }
+        Ok(Self {
+            va,
+            addr,
+            len,
+            layout,
+            dealloc: H::dma_dealloc,
+            _h: PhantomData,
+        })
+    }
+}
+
+impl<H> DmaBuffer<H> {
+    /// Address of the buffer as seen by the HBA.
+    pub fn dma_addr(&self) -> DmaAddr {
+        self.addr
+    }
+}
+
+impl<H> Deref for DmaBuffer<H> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: The allocation is at least `len` bytes and zero-initialized.
+        unsafe { slice::from_raw_parts(self.va as *const u8, self.len) }
+    }
+}
+
+impl<H> DerefMut for DmaBuffer<H> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: As above, and the buffer is uniquely borrowed.
+        unsafe { slice::from_raw_parts_mut(self.va as *mut u8, self.len) }
+    }
+}
+
+impl<H> Drop for DmaBuffer<H> {
+    fn drop(&mut self) {
+        (self.dealloc)(self.va, self.addr, self.layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::alloc::{alloc_zeroed, dealloc};
+    use core::cell::Cell;
+
+    use super::*;
+
+    std::thread_local! {
+        static FREED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// A [`Hal`] handing out memory 8 bytes past a page boundary.
+    struct MisalignedHal;
+
+    impl MisalignedHal {
+        fn padded(layout: Layout) -> Layout {
+            Layout::from_size_align(layout.size() + 8, layout.align()).unwrap()
+        }
+    }
+
+    impl Hal for MisalignedHal {
+        fn virt_to_phys(va: usize) -> usize {
+            va
+        }
+
+        fn dma_alloc(layout: Layout, _dma32: bool) -> Option<(usize, DmaAddr)> {
+            // SAFETY: The layout is not zero-sized.
+            let va = unsafe { alloc_zeroed(Self::padded(layout)) } as usize + 8;
+            Some((va, va))
+        }
+
+        fn dma_dealloc(va: usize, _addr: DmaAddr, layout: Layout) {
+            FREED.set(FREED.get() + 1);
+            // SAFETY: Allocated by `dma_alloc` with the same layout.
+            unsafe { dealloc((va - 8) as *mut u8, Self::padded(layout)) };
+        }
+
+        fn current_ms() -> u64 {
+            0
+        }
+
+        fn flush_dcache() {}
+    }
+
+    #[test]
+    fn misaligned_allocation_is_freed() {
+        assert!(matches!(
+            DmaBuffer::<MisalignedHal>::new(512, false),
+            Err(AhciError::DmaAllocFailed { len: 512 })
+        ));
+        assert_eq!(FREED.get(), 1);
+    }
+}
@@ -0,0 +1,110 @@
+//! Reading from a disk before timers are available, e.g. in a bootloader.
+
+use core::{
+    alloc::Layout,
+    marker::PhantomData,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    AhciController, AhciError,
+    hal::{DmaAddr, DmaCoherence, DmaDirection, Hal},
+};
+
+/// Polling iterations counted as one millisecond by [`SpinClock`].
+///
+/// Each iteration usually includes an MMIO read, so this is generous on
+/// current CPUs: delays the hardware requires are met, while timeouts take
+/// longer than nominal before giving up.
+const SPINS_PER_MS: u64 = 100_000;
+
+static SPINS: AtomicU64 = AtomicU64::new(0);
+
+/// A [`Hal`] whose clock advances each time it is read, turning every
+/// timeout of the driver into a bounded number of polling iterations.
+struct SpinClock<H>(PhantomData<H>);
+
+impl<H: Hal> Hal for SpinClock<H> {
+    const DMA_COHERENCE: DmaCoherence = H::DMA_COHERENCE;
+    const PAGE_SIZE: usize = H::PAGE_SIZE;
+
+    fn virt_to_phys(va: usize) -> usize {
+        H::virt_to_phys(va)
+    }
+
+    fn dma_map(va: usize, len: usize, dir: DmaDirection) -> DmaAddr {
+        H::dma_map(va, len, dir)
+    }
+
+    fn dma_unmap(va: usize, addr: DmaAddr, len: usize, dir: DmaDirection) {
+        H::dma_unmap(va, addr, len, dir)
+    }
+
+    fn dma_alloc(layout: Layout, dma32: bool) -> Option<(usize, DmaAddr)> {
+        H::dma_alloc(layout, dma32)
+    }
+
+    fn dma_dealloc(va: usize, addr: DmaAddr, layout: Layout) {
+        H::dma_dealloc(va, addr, layout)
+    }
+
+    fn with_irqs_disabled<R>(f: impl FnOnce() -> R) -> R {
+        H::with_irqs_disabled(f)
+    }
+
+    fn current_cpu() -> Option<usize> {
+        H::current_cpu()
+    }
+
+    fn link_idle_hint(idle: bool) {
+        H::link_idle_hint(idle)
+    }
+
+    fn activity_led(port: u8, active: bool) {
+        H::activity_led(port, active)
+    }
+
+    fn irq_wait(port: u8, timeout_ms: u64) {
+        H::irq_wait(port, timeout_ms)
+    }
+
+    fn irq_notify(port: u8) {
+        H::irq_notify(port)
+    }
+
+    fn current_ms() -> u64 {
+        SPINS.fetch_add(1, Ordering::Relaxed) / SPINS_PER_MS
+    }
+
+    fn flush_dcache() {
+        H::flush_dcache()
+    }
+
+    fn dma_sync_for_device(va: usize, len: usize, dir: DmaDirection) {
+        H::dma_sync_for_device(va, len, dir)
+    }
+
+    fn dma_sync_for_cpu(va: usize, len: usize, dir: DmaDirection) {
+        H::dma_sync_for_cpu(va, len, dir)
+    }
+}
+
+/// Bring up the controller at `base` and read from the first disk found,
+/// without ever calling [`Hal::current_ms`].
+///
+/// Timeouts are replaced with bounded polling loops, so this can be used
+/// before any timer has been set up. The controller is reset and fully
+/// probed on every call, so it is only meant for loading a few blocks.
+///
+/// # Safety
+///
+/// See [`AhciDriver::try_new`](crate::AhciDriver::try_new).
+pub unsafe fn early_read<H: Hal>(base: usize, lba: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+    // SAFETY: Forwarded to the caller.
+    let mut controller = unsafe { AhciController::<SpinClock<H>>::try_new(base) }?;
+    controller
+        .disks_mut()
+        .first_mut()
+        .ok_or(AhciError::NoDisks)?
+        .read(lba, buf)
+}
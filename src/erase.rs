@@ -0,0 +1,33 @@
+use core::fmt;
+
+/// Result of [`AhciDriver::verify_erased`](crate::AhciDriver::verify_erased).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EraseReport {
+    /// Number of blocks read.
+    pub sampled: u64,
+    /// Number of sampled blocks that contained a non-zero byte.
+    pub non_zero: u64,
+    /// The first sampled block that contained a non-zero byte.
+    pub first_non_zero: Option<u64>,
+}
+
+impl EraseReport {
+    /// Whether every sampled block read back as zeroes.
+    pub fn is_erased(&self) -> bool {
+        self.non_zero == 0
+    }
+}
+
+impl fmt::Display for EraseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} sampled blocks non-zero",
+            self.non_zero, self.sampled
+        )?;
+        if let Some(block) = self.first_non_zero {
+            write!(f, " (first at {block})")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use thiserror::Error;
+
+/// Errors reported by the driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum AhciError {
+    /// GHC.HR did not clear after resetting the HBA.
+    #[error("HBA reset timed out")]
+    HbaResetTimeout,
+
+    /// GHC.AE could not be set, so the HBA is stuck in legacy (IDE) mode and
+    /// its AHCI registers are inoperative.
+    ///
+    /// This happens when the HBA supports a legacy interface (CAP.SAM clear)
+    /// and the firmware locked it into that mode. Switching the SATA mode to
+    /// AHCI in the firmware setup usually fixes it.
+    #[error(
+        "controller is in legacy IDE mode (CAP.SAM={}, GHC={ghc:#x}), switch it to AHCI in the firmware setup",
+        *sam as u8
+    )]
+    ControllerInLegacyMode {
+        /// CAP.SAM: whether the HBA claims to support AHCI mode only.
+        sam: bool,
+        /// GHC as read back after trying to set AE.
+        ghc: u32,
+    },
+
+    /// No port with a usable disk was found.
+    #[error("no disks found")]
+    NoDisks,
+
+    /// [`Hal::dma_alloc`](crate::Hal::dma_alloc) failed to provide a buffer
+    /// the HBA can access.
+    #[error("failed to allocate a {len} byte DMA buffer")]
+    DmaAllocFailed {
+        /// Requested size in bytes.
+        len: usize,
+    },
+
+    /// A command did not complete in time.
+    #[error("command {command:#x} timed out")]
+    Timeout {
+        /// ATA command opcode.
+        command: u8,
+    },
+
+    /// The device completed a command with an error.
+    #[error("command {command:#x} failed (status {status:#x}, error {error:#x})")]
+    TaskFile {
+        /// ATA command opcode.
+        command: u8,
+        /// Status register from PxTFD.
+        status: u8,
+        /// Error register from PxTFD.
+        error: u8,
+    },
+
+    /// FLUSH CACHE (EXT) failed to write part of the volatile write cache to
+    /// the media.
+    #[error("cache flush failed at LBA {lba} (status {status:#x}, error {error:#x})")]
+    FlushFailed {
+        /// First sector that could not be written.
+        lba: u64,
+        /// Status register from PxTFD.
+        status: u8,
+        /// Error register from PxTFD.
+        error: u8,
+    },
+
+    /// The command was aborted before it completed, e.g. during error
+    /// recovery after another command failed.
+    #[error("command aborted")]
+    Aborted,
+
+    /// The command is denied by the
+    /// [`CommandFilter`](crate::CommandFilter).
+    #[error("command {command:#x} denied by the command filter")]
+    Denied {
+        /// ATA command opcode.
+        command: u8,
+    },
+
+    /// The drive does not support the operation, or nothing is configured
+    /// for it.
+    #[error("not supported")]
+    Unsupported,
+
+    /// No command slot is free; retry once outstanding commands completed.
+    #[error("no command slot available")]
+    WouldBlock,
+
+    /// The request can't be carried out as specified.
+    #[error("invalid request")]
+    InvalidArgument,
+
+    /// A buffer passed to a block API does not cover whole blocks.
+    #[error(
+        "{len} bytes is not a multiple of the {block_size} byte block size, use read_at/write_at for byte-addressed I/O"
+    )]
+    PartialBlock {
+        /// Length of the buffer.
+        len: usize,
+        /// Block size of the disk.
+        block_size: usize,
+    },
+
+    /// Data read back after writing differs from what was written.
+    #[error("block {block} reads back different data than written")]
+    Miscompare {
+        /// First block that differs.
+        block: u64,
+    },
+
+    /// The link to the device was lost.
+    #[error("device gone")]
+    DeviceGone,
+}
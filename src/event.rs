@@ -0,0 +1,118 @@
+use crate::diag::EngineState;
+
+/// Notable things that happened on the controller, queued until the user
+/// retrieves them with [`AhciDriver::pop_event`](crate::AhciDriver::pop_event).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AhciEvent {
+    /// A command has been outstanding for longer than the configured
+    /// threshold.
+    HungCommand {
+        /// Port the command was issued on.
+        port: u8,
+        /// Command slot.
+        slot: u8,
+        /// Time since the command was issued, in milliseconds.
+        age_ms: u64,
+        /// Command engine state when the command was found hung.
+        engine: EngineState,
+        /// Whether the port was recovered afterwards.
+        recovered: bool,
+        /// CPU the command was submitted on, see
+        /// [`Hal::current_cpu`](crate::Hal::current_cpu).
+        cpu: Option<usize>,
+    },
+    /// A command took longer than the threshold set with
+    /// [`AhciDriver::set_slow_io_threshold`](crate::AhciDriver::set_slow_io_threshold).
+    SlowIo {
+        /// Port the command was issued on.
+        port: u8,
+        /// ATA command opcode.
+        command: u8,
+        /// Starting LBA, meaningless for commands without one.
+        lba: u64,
+        /// Number of bytes transferred.
+        bytes: usize,
+        /// Time from issue to completion, in milliseconds.
+        latency_ms: u64,
+        /// CPU the command was submitted on, see
+        /// [`Hal::current_cpu`](crate::Hal::current_cpu).
+        cpu: Option<usize>,
+    },
+    /// The device dropped off the link with commands outstanding.
+    ///
+    /// The commands were aborted after stopping the command engine, so the
+    /// HBA no longer accesses their buffers. Further I/O to the disk fails.
+    DeviceGone {
+        /// Port the device was attached to.
+        port: u8,
+        /// Number of commands aborted.
+        aborted: usize,
+    },
+    /// SMART RETURN STATUS reported that an attribute crossed its threshold,
+    /// predicting a failure of the drive.
+    SmartThresholdExceeded {
+        /// Port the drive is attached to.
+        port: u8,
+    },
+    /// A background scrub could not read a range of sectors, see
+    /// [`Maintenance::scrub_sectors`](crate::Maintenance::scrub_sectors).
+    ScrubError {
+        /// Port the drive is attached to.
+        port: u8,
+        /// First sector of the range.
+        lba: u64,
+        /// Number of sectors in the range.
+        count: u64,
+    },
+    /// The interface power state of the link changed, e.g. because the
+    /// device initiated a transition to Partial or Slumber.
+    LinkPowerStateChanged {
+        /// Port of the link.
+        port: u8,
+        /// State last reported for the link.
+        previous: LinkPowerState,
+        /// New state, from PxSSTS.IPM.
+        state: LinkPowerState,
+    },
+}
+
+/// Interface power state of a link (PxSSTS.IPM).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkPowerState {
+    /// No device present or communication not established.
+    NotPresent,
+    /// Active.
+    Active,
+    /// Partial power management state.
+    Partial,
+    /// Slumber power management state.
+    Slumber,
+    /// DevSleep power management state.
+    DevSleep,
+    /// A value reserved by the AHCI specification.
+    Reserved(u8),
+}
+
+impl LinkPowerState {
+    pub(crate) fn from_ipm(ipm: u8) -> Self {
+        match ipm {
+            0x0 => Self::NotPresent,
+            0x1 => Self::Active,
+            0x2 => Self::Partial,
+            0x6 => Self::Slumber,
+            0x8 => Self::DevSleep,
+            _ => Self::Reserved(ipm),
+        }
+    }
+}
+
+/// Configuration of the hung command detector run by
+/// [`AhciDriver::tick`](crate::AhciDriver::tick).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HungCommandCheck {
+    /// Age in milliseconds after which an outstanding command is reported.
+    pub threshold_ms: u64,
+    /// Whether to recover the port after reporting a hung command.
+    pub recover: bool,
+}
@@ -0,0 +1,112 @@
+use crate::ata::{
+    ATA_CMD_AMAC, ATA_CMD_CFA_ERASE, ATA_CMD_CFA_TRANS_SECT, ATA_CMD_CFA_WRITE_MULT_NE,
+    ATA_CMD_CFA_WRITE_NE, ATA_CMD_CONF_OVERLAY, ATA_CMD_DOWNLOAD_MICRO, ATA_CMD_DOWNLOAD_MICRO_DMA,
+    ATA_CMD_DSM, ATA_CMD_FPDMA_SEND, ATA_CMD_FPDMA_WRITE, ATA_CMD_PIO_WRITE, ATA_CMD_PIO_WRITE_EXT,
+    ATA_CMD_SANITIZE_DEVICE, ATA_CMD_SEC_DISABLE_PASS, ATA_CMD_SEC_ERASE_PREP,
+    ATA_CMD_SEC_ERASE_UNIT, ATA_CMD_SEC_FREEZE_LOCK, ATA_CMD_SEC_SET_PASS, ATA_CMD_SEC_UNLOCK,
+    ATA_CMD_SET_MAX, ATA_CMD_SET_MAX_EXT, ATA_CMD_TRUSTED_NONDATA, ATA_CMD_TRUSTED_SND,
+    ATA_CMD_TRUSTED_SND_DMA, ATA_CMD_WRITE, ATA_CMD_WRITE_EXT, ATA_CMD_WRITE_FUA_EXT,
+    ATA_CMD_WRITE_LOG_DMA_EXT, ATA_CMD_WRITE_LOG_EXT, ATA_CMD_WRITE_MULTI, ATA_CMD_WRITE_MULTI_EXT,
+    ATA_CMD_WRITE_MULTI_FUA_EXT, ATA_CMD_WRITE_QUEUED, ATA_CMD_WRITE_QUEUED_FUA_EXT,
+    ATA_CMD_WRITE_STREAM_DMA_EXT, ATA_CMD_WRITE_STREAM_EXT, ATA_CMD_WRITE_UNCORR_EXT,
+    ATA_CMD_ZAC_MGMT_OUT,
+};
+
+/// Commands that modify or destroy user data, change the drive's capacity,
+/// firmware or security state.
+const DESTRUCTIVE: &[u8] = &[
+    ATA_CMD_WRITE,
+    ATA_CMD_WRITE_EXT,
+    ATA_CMD_WRITE_QUEUED,
+    ATA_CMD_WRITE_STREAM_EXT,
+    ATA_CMD_WRITE_STREAM_DMA_EXT,
+    ATA_CMD_WRITE_FUA_EXT,
+    ATA_CMD_WRITE_QUEUED_FUA_EXT,
+    ATA_CMD_FPDMA_WRITE,
+    ATA_CMD_FPDMA_SEND,
+    ATA_CMD_PIO_WRITE,
+    ATA_CMD_PIO_WRITE_EXT,
+    ATA_CMD_WRITE_MULTI,
+    ATA_CMD_WRITE_MULTI_EXT,
+    ATA_CMD_WRITE_MULTI_FUA_EXT,
+    ATA_CMD_WRITE_UNCORR_EXT,
+    ATA_CMD_WRITE_LOG_EXT,
+    ATA_CMD_WRITE_LOG_DMA_EXT,
+    ATA_CMD_DSM,
+    ATA_CMD_SET_MAX,
+    ATA_CMD_SET_MAX_EXT,
+    ATA_CMD_AMAC,
+    ATA_CMD_CONF_OVERLAY,
+    ATA_CMD_DOWNLOAD_MICRO,
+    ATA_CMD_DOWNLOAD_MICRO_DMA,
+    ATA_CMD_SEC_SET_PASS,
+    ATA_CMD_SEC_UNLOCK,
+    ATA_CMD_SEC_ERASE_PREP,
+    ATA_CMD_SEC_ERASE_UNIT,
+    ATA_CMD_SEC_FREEZE_LOCK,
+    ATA_CMD_SEC_DISABLE_PASS,
+    ATA_CMD_SANITIZE_DEVICE,
+    ATA_CMD_TRUSTED_NONDATA,
+    ATA_CMD_TRUSTED_SND,
+    ATA_CMD_TRUSTED_SND_DMA,
+    ATA_CMD_CFA_ERASE,
+    ATA_CMD_CFA_WRITE_NE,
+    ATA_CMD_CFA_WRITE_MULT_NE,
+    ATA_CMD_CFA_TRANS_SECT,
+    ATA_CMD_ZAC_MGMT_OUT,
+];
+
+/// The set of ATA opcodes that may be issued with
+/// [`AhciDriver::submit_ata_command`](crate::AhciDriver::submit_ata_command).
+///
+/// Commands issued by the driver itself are not filtered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandFilter {
+    allowed: [u64; 4],
+}
+
+impl CommandFilter {
+    /// Allow every command.
+    pub const fn allow_all() -> Self {
+        Self {
+            allowed: [u64::MAX; 4],
+        }
+    }
+
+    /// Deny every command; use [`allow`](Self::allow) to build an allow-list.
+    pub const fn deny_all() -> Self {
+        Self { allowed: [0; 4] }
+    }
+
+    /// Allow every command except those that write or erase user data, or
+    /// change the drive's capacity, firmware or security state, such as
+    /// SECURITY ERASE UNIT, SANITIZE and DOWNLOAD MICROCODE.
+    pub fn non_destructive() -> Self {
+        DESTRUCTIVE
+            .iter()
+            .fold(Self::allow_all(), |filter, &opcode| filter.deny(opcode))
+    }
+
+    /// Allow `opcode`.
+    pub const fn allow(mut self, opcode: u8) -> Self {
+        self.allowed[opcode as usize / 64] |= 1 << (opcode % 64);
+        self
+    }
+
+    /// Deny `opcode`.
+    pub const fn deny(mut self, opcode: u8) -> Self {
+        self.allowed[opcode as usize / 64] &= !(1 << (opcode % 64));
+        self
+    }
+
+    /// Whether `opcode` may be issued.
+    pub const fn is_allowed(&self, opcode: u8) -> bool {
+        self.allowed[opcode as usize / 64] & (1 << (opcode % 64)) != 0
+    }
+}
+
+impl Default for CommandFilter {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
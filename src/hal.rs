@@ -1,29 +1,321 @@
+use alloc::{
+    alloc::{alloc_zeroed, dealloc},
+    vec,
+    vec::Vec,
+};
+use core::{alloc::Layout, marker::PhantomData};
+
+/// An address as seen by the HBA.
+pub type DmaAddr = usize;
+
+/// Direction of a DMA transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The HBA reads from memory.
+    ToDevice,
+    /// The HBA writes to memory.
+    FromDevice,
+    /// The HBA both reads from and writes to memory.
+    Bidirectional,
+}
+
+/// Which memory accessed by the HBA is coherent with the CPU caches, see
+/// [`Hal::DMA_COHERENCE`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCoherence {
+    /// None of it. Every structure and data buffer is synced around a
+    /// command.
+    None,
+    /// Memory from [`dma_alloc`](Hal::dma_alloc), because it is mapped
+    /// uncached or is otherwise coherent with the HBA.
+    ///
+    /// The command list, command tables and received FIS area then need no
+    /// cache maintenance, and only data buffers are synced around a
+    /// command. This saves a Dcache flush per command on platforms where
+    /// [`dma_sync_for_device`](Hal::dma_sync_for_device) is expensive.
+    Allocated,
+    /// All of it (e.g. x86), in which case the driver performs no cache
+    /// maintenance at all.
+    All,
+}
+
 pub trait Hal {
+    /// Which DMA is cache coherent on this platform.
+    const DMA_COHERENCE: DmaCoherence = DmaCoherence::None;
+
+    /// Granularity of the mapping from virtual to DMA addresses. Data
+    /// buffers are mapped one page at a time, so they need not be contiguous
+    /// for the HBA.
+    const PAGE_SIZE: usize = 4096;
+
     /// Convert a virtual address to a physical address.
     fn virt_to_phys(va: usize) -> usize;
 
+    /// Make `len` bytes at `va` accessible to the HBA and return the address
+    /// the HBA must use for them. The range never crosses a
+    /// [`PAGE_SIZE`](Self::PAGE_SIZE) boundary.
+    ///
+    /// Platforms with an IOMMU between the HBA and memory must override this.
+    /// The default implementation returns the physical address.
+    fn dma_map(va: usize, len: usize, dir: DmaDirection) -> DmaAddr {
+        let _ = (len, dir);
+        Self::virt_to_phys(va)
+    }
+
+    /// Release a mapping created by [`dma_map`](Self::dma_map).
+    fn dma_unmap(va: usize, addr: DmaAddr, len: usize, dir: DmaDirection) {
+        let _ = (va, addr, len, dir);
+    }
+
+    /// Allocate zeroed memory for `layout` that the HBA can access as one
+    /// physically contiguous region, below 4 GiB if `dma32` is set, and
+    /// return its virtual address and the address the HBA must use.
+    ///
+    /// The default implementation uses the global allocator, which is only
+    /// correct if it hands out physically contiguous memory, as allocators
+    /// over a linear mapping do. It fails if the memory is not below 4 GiB
+    /// when required.
+    fn dma_alloc(layout: Layout, dma32: bool) -> Option<(usize, DmaAddr)> {
+        // SAFETY: The driver never requests zero-sized allocations.
+        let va = unsafe { alloc_zeroed(layout) } as usize;
+        if va == 0 {
+            return None;
+        }
+        let addr = Self::virt_to_phys(va);
+        if dma32 && (addr + layout.size()) as u64 > 1 << 32 {
+            // SAFETY: Allocated above with the same layout.
+            unsafe { dealloc(va as *mut u8, layout) };
+            return None;
+        }
+        Some((va, addr))
+    }
+
+    /// Free memory allocated with [`dma_alloc`](Self::dma_alloc).
+    fn dma_dealloc(va: usize, addr: DmaAddr, layout: Layout) {
+        let _ = addr;
+        // SAFETY: Allocated by the default `dma_alloc` with the same layout.
+        unsafe { dealloc(va as *mut u8, layout) };
+    }
+
+    /// Run `f` so that it can't race with the interrupt handler of the HBA,
+    /// either with interrupts disabled on the current CPU or under a lock
+    /// shared with the handler.
+    ///
+    /// The driver wraps every access to state shared with the interrupt
+    /// handler in this: slot bookkeeping, PxCI, and the write-1-to-clear
+    /// cycles on PxIS and IS. The default implementation just calls `f`,
+    /// which is only sound if the driver is polled and its interrupt is not
+    /// handled.
+    fn with_irqs_disabled<R>(f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    /// Identifier of the CPU the caller is running on, if the platform wants
+    /// completions steered back to the submitting CPU.
+    ///
+    /// The value is recorded with each command and reported with its
+    /// completion. The default implementation returns `None`.
+    fn current_cpu() -> Option<usize> {
+        None
+    }
+
+    /// Called with `true` when no port of the HBA has outstanding commands
+    /// anymore, and with `false` when the first command is issued again.
+    ///
+    /// Lets the platform coordinate PCIe ASPM and CLKREQ# with the SATA link
+    /// power states. The default implementation does nothing.
+    fn link_idle_hint(idle: bool) {
+        let _ = idle;
+    }
+
+    /// Called with `true` when port `port` starts processing commands and
+    /// with `false` when it has none outstanding anymore, e.g. to drive a
+    /// disk activity LED from a GPIO.
+    ///
+    /// This mirrors the activity pin HBAs with CAP.SAL drive on their own,
+    /// and is called whether or not the HBA has one. The default
+    /// implementation does nothing.
+    fn activity_led(port: u8, active: bool) {
+        let _ = (port, active);
+    }
+
+    /// Block until [`irq_notify`](Self::irq_notify) is called for port
+    /// `port` or `timeout_ms` milliseconds pass. Must return at once if it
+    /// was called since this last returned, or a completion can be missed.
+    ///
+    /// Only called while waiting for a command in interrupt mode, see
+    /// [`AhciDriver::set_irq_mode`](crate::AhciDriver::set_irq_mode). The
+    /// default implementation returns immediately, so waiting keeps polling.
+    fn irq_wait(port: u8, timeout_ms: u64) {
+        let _ = (port, timeout_ms);
+    }
+
+    /// Called by [`IrqHandler`](crate::IrqHandler) for each port that had an
+    /// interrupt pending, e.g. to wake the thread blocked in
+    /// [`irq_wait`](Self::irq_wait). The default implementation does
+    /// nothing.
+    fn irq_notify(port: u8) {
+        let _ = port;
+    }
+
     /// Current time in milliseconds
     fn current_ms() -> u64;
 
     /// Flush the Dcache.
     fn flush_dcache();
+
+    /// Make the CPU's writes to `len` bytes at `va` visible to the HBA before
+    /// it accesses them in direction `dir`.
+    ///
+    /// The default implementation flushes the whole Dcache.
+    fn dma_sync_for_device(va: usize, len: usize, dir: DmaDirection) {
+        let _ = (va, len, dir);
+        Self::flush_dcache();
+    }
+
+    /// Make the HBA's writes to `len` bytes at `va` visible to the CPU after
+    /// a transfer in direction `dir`.
+    ///
+    /// The default implementation flushes the whole Dcache.
+    fn dma_sync_for_cpu(va: usize, len: usize, dir: DmaDirection) {
+        let _ = (va, len, dir);
+        Self::flush_dcache();
+    }
 }
 
-pub(crate) fn wait_until(cond: impl Fn() -> bool) {
-    while !cond() {
-        core::hint::spin_loop();
+/// A monotonic time source in milliseconds.
+///
+/// The timeout and rate limiting code only needs time, so it takes a `Clock`
+/// rather than a whole [`Hal`], which lets it be driven by a virtual clock.
+/// The driver reads the time of its [`Hal`] through [`Hal::current_ms`].
+pub trait Clock {
+    /// Current time in milliseconds.
+    fn now_ms() -> u64;
+}
+
+/// The [`Clock`] of a [`Hal`], read with [`Hal::current_ms`].
+pub(crate) struct HalClock<H>(PhantomData<H>);
+
+impl<H: Hal> Clock for HalClock<H> {
+    fn now_ms() -> u64 {
+        H::current_ms()
     }
 }
 
-pub(crate) fn wait_until_timeout<H: Hal>(cond: impl Fn() -> bool, timeout: u64) -> bool {
-    let start = H::current_ms();
+pub(crate) fn wait_until_timeout<C: Clock>(cond: impl Fn() -> bool, timeout: u64) -> bool {
+    wait_until_idle::<C>(cond, timeout, |_| core::hint::spin_loop())
+}
+
+/// Like [`wait_until_timeout`], but calls `idle` with the remaining time
+/// between polls.
+pub(crate) fn wait_until_idle<C: Clock>(
+    cond: impl Fn() -> bool,
+    timeout: u64,
+    idle: impl Fn(u64),
+) -> bool {
+    let start = C::now_ms();
     loop {
         if cond() {
             return true;
         }
-        if H::current_ms() - start > timeout {
+        let elapsed = C::now_ms() - start;
+        if elapsed > timeout {
             return false;
         }
+        idle(timeout - elapsed);
+    }
+}
+
+/// Poll `cond` for each of `items` until it holds for all of them or `timeout`
+/// expires, and return whether it held for each item.
+pub(crate) fn wait_all_timeout<C: Clock, T>(
+    items: &[T],
+    cond: impl Fn(&T) -> bool,
+    timeout: u64,
+) -> Vec<bool> {
+    let mut done = vec![false; items.len()];
+    let start = C::now_ms();
+    loop {
+        for (done, item) in done.iter_mut().zip(items) {
+            if !*done {
+                *done = cond(item);
+            }
+        }
+        if done.iter().all(|&done| done) || C::now_ms() - start > timeout {
+            return done;
+        }
         core::hint::spin_loop();
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+
+    use super::*;
+
+    std::thread_local! {
+        static NOW: Cell<u64> = const { Cell::new(0) };
+    }
+
+    /// A virtual clock that advances by a millisecond every time it is read.
+    pub(crate) struct FakeClock;
+
+    impl FakeClock {
+        pub(crate) fn now() -> u64 {
+            NOW.get()
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now_ms() -> u64 {
+            NOW.replace(NOW.get() + 1)
+        }
+    }
+
+    #[test]
+    fn wait_times_out() {
+        let start = FakeClock::now();
+        assert!(!wait_until_timeout::<FakeClock>(|| false, 100));
+        let elapsed = FakeClock::now() - start;
+        assert!((101..=103).contains(&elapsed), "{elapsed}");
+    }
+
+    #[test]
+    fn wait_ends_when_condition_holds() {
+        let deadline = FakeClock::now() + 40;
+        assert!(wait_until_timeout::<FakeClock>(
+            || FakeClock::now() >= deadline,
+            100
+        ));
+        assert!(FakeClock::now() - deadline <= 1);
+    }
+
+    #[test]
+    fn idle_gets_remaining_time() {
+        let remaining = Cell::new(Vec::new());
+        wait_until_idle::<FakeClock>(
+            || false,
+            5,
+            |ms| {
+                let mut seen = remaining.take();
+                seen.push(ms);
+                remaining.set(seen);
+            },
+        );
+        assert_eq!(remaining.take(), [4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn wait_all_reports_each_item() {
+        let start = FakeClock::now();
+        let done = wait_all_timeout::<FakeClock, _>(
+            &[10, 1000, 20],
+            |&ms| FakeClock::now() - start >= ms,
+            100,
+        );
+        assert_eq!(done, [true, false, true]);
+    }
+}
@@ -0,0 +1,285 @@
+//! Multiple handles to the same disk with separate accounting.
+
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    AhciDriver, AhciError, Hal,
+    ata::{ATA_DRDY, ATA_ERR, ATA_UNC},
+    hal::{HalClock, wait_until_timeout},
+    throttle::{RateLimit, Throttle},
+};
+
+/// Failure a [`DiskHandle`] simulates instead of performing its requests,
+/// see [`DiskHandle::set_fault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail with [`AhciError::TaskFile`] as the drive does on an
+    /// unrecoverable read error.
+    MediaError,
+    /// Fail with [`AhciError::Timeout`] after waiting as long as the
+    /// command timeout of the disk.
+    Timeout,
+    /// Fail with [`AhciError::DeviceGone`] as if the disk was unplugged.
+    Gone,
+}
+
+/// I/O performed through a [`DiskHandle`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoStats {
+    /// Successful read requests.
+    pub reads: u64,
+    /// Successful write requests.
+    pub writes: u64,
+    /// Bytes read by successful requests.
+    pub bytes_read: u64,
+    /// Bytes written by successful requests.
+    pub bytes_written: u64,
+    /// Failed requests.
+    pub errors: u64,
+}
+
+/// A disk shared by several handles, serialized with a spin lock.
+struct SharedDisk<H> {
+    locked: AtomicBool,
+    disk: UnsafeCell<AhciDriver<H>>,
+}
+
+/// Safety: Access to the disk is serialized by `locked`.
+unsafe impl<H: Hal> Send for SharedDisk<H> {}
+unsafe impl<H: Hal> Sync for SharedDisk<H> {}
+
+impl<H> SharedDisk<H> {
+    fn lock(&self) -> DiskGuard<'_, H> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        DiskGuard { shared: self }
+    }
+}
+
+struct DiskGuard<'a, H> {
+    shared: &'a SharedDisk<H>,
+}
+
+impl<H> Deref for DiskGuard<'_, H> {
+    type Target = AhciDriver<H>;
+
+    fn deref(&self) -> &AhciDriver<H> {
+        // SAFETY: The lock is held.
+        unsafe { &*self.shared.disk.get() }
+    }
+}
+
+impl<H> DerefMut for DiskGuard<'_, H> {
+    fn deref_mut(&mut self) -> &mut AhciDriver<H> {
+        // SAFETY: The lock is held.
+        unsafe { &mut *self.shared.disk.get() }
+    }
+}
+
+impl<H> Drop for DiskGuard<'_, H> {
+    fn drop(&mut self) {
+        self.shared.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A handle to a disk that may be shared with other handles, each keeping
+/// its own [`IoStats`], e.g. one each for swap, the file system and logging.
+///
+/// Requests from different handles are serialized by spinning, so a handle
+/// must not be used from an interrupt handler that may preempt another user
+/// of the same disk on the same CPU.
+///
+/// The disks behind a port multiplier share one driver; a handle created
+/// with [`pm_handle`](Self::pm_handle) addresses one of them.
+pub struct DiskHandle<H> {
+    shared: Arc<SharedDisk<H>>,
+    stats: IoStats,
+    throttle: Option<Throttle>,
+    /// Port multiplier port of the disk, if the handle addresses one.
+    pm_port: Option<u8>,
+    /// Failure simulated for reads and writes, and whether it applies to
+    /// writes only.
+    fault: Option<(Fault, bool)>,
+}
+
+impl<H: Hal> DiskHandle<H> {
+    /// Share `disk`, returning the first handle to it.
+    pub fn new(disk: AhciDriver<H>) -> Self {
+        Self {
+            shared: Arc::new(SharedDisk {
+                locked: AtomicBool::new(false),
+                disk: UnsafeCell::new(disk),
+            }),
+            stats: IoStats::default(),
+            throttle: None,
+            pm_port: None,
+            fault: None,
+        }
+    }
+
+    /// Create another handle to the same disk, with its own statistics and
+    /// no rate limit.
+    pub fn handle(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            stats: IoStats::default(),
+            throttle: None,
+            pm_port: self.pm_port,
+            fault: None,
+        }
+    }
+
+    /// Create a handle to the disk at port multiplier port `pmp`, with its
+    /// own statistics and no rate limit.
+    ///
+    /// Fails with [`AhciError::InvalidArgument`] unless the disk is attached
+    /// through a port multiplier with a disk at `pmp`, see
+    /// [`AhciDriver::port_multiplier`].
+    pub fn pm_handle(&self, pmp: u8) -> Result<Self, AhciError> {
+        let found = self
+            .shared
+            .lock()
+            .port_multiplier()
+            .is_some_and(|pm| pm.disks.contains(&pmp));
+        if !found {
+            return Err(AhciError::InvalidArgument);
+        }
+        Ok(Self {
+            pm_port: Some(pmp),
+            ..self.handle()
+        })
+    }
+
+    /// Lock the disk, addressing the one of this handle.
+    fn lock(&self) -> DiskGuard<'_, H> {
+        let mut disk = self.shared.lock();
+        if let Some(pmp) = self.pm_port {
+            // Checked when the handle was created.
+            let _ = disk.select_pm_port(pmp);
+        }
+        disk
+    }
+
+    /// Limit the rate of requests submitted through this handle, e.g. for a
+    /// background scrub that must not starve other users of the disk.
+    ///
+    /// Requests over the limit wait before submission. `None` removes the
+    /// limit.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.throttle = limit.map(Throttle::new::<HalClock<H>>);
+    }
+
+    fn throttle(&mut self, bytes: usize) {
+        if let Some(throttle) = &mut self.throttle {
+            throttle.acquire::<HalClock<H>>(bytes as u64);
+        }
+    }
+
+    /// Make reads and writes through this handle fail with `fault` without
+    /// touching the disk, or only writes if `writes_only` is set, so the
+    /// layers above can exercise their error handling on a real system.
+    /// `None` turns this off.
+    ///
+    /// Other handles to the same disk are not affected.
+    pub fn set_fault(&mut self, fault: Option<Fault>, writes_only: bool) {
+        self.fault = fault.map(|fault| (fault, writes_only));
+    }
+
+    /// Failure simulated by this handle.
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault.map(|(fault, _)| fault)
+    }
+
+    /// Error of the simulated failure for a read or write, if any.
+    fn simulate(&self, is_write: bool) -> Option<AhciError> {
+        let (fault, writes_only) = self.fault?;
+        if writes_only && !is_write {
+            return None;
+        }
+        let command = self.lock().io_command(is_write);
+        Some(match fault {
+            Fault::MediaError => AhciError::TaskFile {
+                command,
+                status: ATA_DRDY | ATA_ERR,
+                error: ATA_UNC,
+            },
+            Fault::Timeout => {
+                let timeout = self.lock().timeouts().command_ms;
+                wait_until_timeout::<HalClock<H>>(|| false, timeout);
+                AhciError::Timeout { command }
+            }
+            Fault::Gone => AhciError::DeviceGone,
+        })
+    }
+
+    /// I/O performed through this handle.
+    pub fn stats(&self) -> IoStats {
+        self.stats
+    }
+
+    /// Reset the statistics of this handle.
+    pub fn reset_stats(&mut self) {
+        self.stats = IoStats::default();
+    }
+
+    /// Run `f` with exclusive access to the disk, for operations not
+    /// available on the handle. I/O done this way is not accounted.
+    pub fn with_disk<R>(&self, f: impl FnOnce(&mut AhciDriver<H>) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// See [`AhciDriver::block_size`].
+    pub fn block_size(&self) -> usize {
+        self.lock().block_size()
+    }
+
+    /// See [`AhciDriver::capacity`].
+    pub fn capacity(&self) -> u64 {
+        self.lock().capacity()
+    }
+
+    /// See [`AhciDriver::read`].
+    pub fn read(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        if let Some(err) = self.simulate(false) {
+            self.stats.errors += 1;
+            return Err(err);
+        }
+        self.throttle(buf.len());
+        let result = self.lock().read(block_id, buf);
+        if result.is_ok() {
+            self.stats.reads += 1;
+            self.stats.bytes_read += buf.len() as u64;
+        } else {
+            self.stats.errors += 1;
+        }
+        result
+    }
+
+    /// See [`AhciDriver::write`].
+    pub fn write(&mut self, block_id: u64, buf: &[u8]) -> Result<(), AhciError> {
+        if let Some(err) = self.simulate(true) {
+            self.stats.errors += 1;
+            return Err(err);
+        }
+        self.throttle(buf.len());
+        let result = self.lock().write(block_id, buf);
+        if result.is_ok() {
+            self.stats.writes += 1;
+            self.stats.bytes_written += buf.len() as u64;
+        } else {
+            self.stats.errors += 1;
+        }
+        result
+    }
+}
@@ -0,0 +1,30 @@
+//! Detection of devices attached to or removed from the HBA at runtime.
+
+/// A device attached to or removed from a port, see
+/// [`AhciController::poll_hotplug`](crate::AhciController::poll_hotplug).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HotplugEvent {
+    /// A device was attached and identified. Its disk was added to
+    /// [`AhciController::disks`](crate::AhciController::disks).
+    DeviceAttached {
+        /// Port the device is attached to.
+        port: u8,
+    },
+    /// The device of a disk went away. The disk was removed from
+    /// [`AhciController::disks`](crate::AhciController::disks) and its
+    /// outstanding commands were aborted.
+    DeviceRemoved {
+        /// Port the device was attached to.
+        port: u8,
+    },
+}
+
+/// Receives the hot-plug events of an HBA, see
+/// [`AhciController::set_hotplug_sink`](crate::AhciController::set_hotplug_sink).
+///
+/// Called from [`AhciController::poll_hotplug`](crate::AhciController::poll_hotplug)
+/// with the controller borrowed; it must not block.
+pub trait HotplugSink: Send {
+    fn notify(&mut self, event: HotplugEvent);
+}
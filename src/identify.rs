@@ -0,0 +1,189 @@
+//! Parsed IDENTIFY DEVICE data.
+
+use alloc::string::String;
+
+use crate::{
+    IdentifyData,
+    ata::{
+        ATA_ID_FW_REV, ATA_ID_FW_REV_LEN, ATA_ID_PROD, ATA_ID_PROD_LEN, ATA_ID_ROT_SPEED,
+        ATA_ID_SERNO, ATA_ID_SERNO_LEN, ata_id_alignment_offset, ata_id_has_aam, ata_id_has_apm,
+        ata_id_has_fua, ata_id_has_gpl, ata_id_has_lba48, ata_id_has_ncq,
+        ata_id_has_ncq_queue_mgmt, ata_id_has_read_lookahead, ata_id_has_sct_write_same,
+        ata_id_has_trim, ata_id_has_wcache, ata_id_has_wwn, ata_id_has_zero_after_trim,
+        ata_id_logical_sector_size, ata_id_major_version, ata_id_n_sectors,
+        ata_id_physical_sector_size, ata_id_queue_depth, ata_id_read_lookahead_enabled,
+        ata_id_smart_enabled, ata_id_to_string, ata_id_wcache_enabled, ata_id_wwn,
+    },
+};
+
+/// Nominal media rotation rate (IDENTIFY word 217).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// The drive does not report its rotation rate.
+    Unknown,
+    /// A solid state drive.
+    NonRotating,
+    /// A rotating drive, spinning at the given revolutions per minute.
+    Rpm(u16),
+}
+
+impl Rotation {
+    fn from_word(word: u16) -> Self {
+        match word {
+            0x0001 => Self::NonRotating,
+            0x0401..=0xfffe => Self::Rpm(word),
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Feature sets and commands the drive supports, and whether the optional
+/// ones are enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct IdentifyFeatures {
+    /// Major version of the ATA standard, e.g. 8 for ATA8-ACS.
+    pub major_version: u32,
+    /// 48-bit addressing.
+    pub lba48: bool,
+    /// Native Command Queuing, and the queue depth of the drive.
+    pub ncq: Option<u8>,
+    /// NCQ NON-DATA with the ABORT NCQ QUEUE subcommand.
+    pub ncq_queue_management: bool,
+    /// DATA SET MANAGEMENT TRIM.
+    pub trim: bool,
+    /// Trimmed sectors read back as zeroes.
+    pub zero_after_trim: bool,
+    /// WRITE DMA FUA EXT.
+    pub fua: bool,
+    /// Volatile write cache, and whether it is enabled.
+    pub write_cache: Option<bool>,
+    /// Read look-ahead, and whether it is enabled.
+    pub read_lookahead: Option<bool>,
+    /// SMART is enabled.
+    pub smart: bool,
+    /// General Purpose Logging, i.e. READ LOG EXT.
+    pub gpl: bool,
+    /// Advanced Power Management.
+    pub apm: bool,
+    /// Automatic Acoustic Management.
+    pub aam: bool,
+    /// SCT Write Same.
+    pub sct_write_same: bool,
+}
+
+/// The IDENTIFY DEVICE data of a disk, see
+/// [`AhciDriver::identify`](crate::AhciDriver::identify).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Identify {
+    /// Model number, without padding.
+    pub model: String,
+    /// Serial number, without padding.
+    pub serial: String,
+    /// Firmware revision, without padding.
+    pub firmware: String,
+    /// World Wide Name, if the drive reports one.
+    pub wwn: Option<u64>,
+    pub rotation: Rotation,
+    /// Number of user addressable logical sectors.
+    pub sectors: u64,
+    /// Size of a logical sector in bytes.
+    pub logical_sector_size: usize,
+    /// Size of a physical sector in bytes, larger than the logical sector
+    /// size on drives emulating 512-byte sectors.
+    pub physical_sector_size: usize,
+    /// Offset of logical sector 0 within the first physical sector, in
+    /// logical sectors.
+    pub alignment_offset: u16,
+    pub features: IdentifyFeatures,
+    /// The raw data the fields above were parsed from.
+    pub raw: IdentifyData,
+}
+
+impl Identify {
+    /// Parse raw IDENTIFY DEVICE data, as returned by
+    /// [`AhciDriver::identify_data`](crate::AhciDriver::identify_data).
+    pub fn from_raw(id: &IdentifyData) -> Self {
+        let string = |off, len| ata_id_to_string(id, off, len).trim().into();
+        Self {
+            model: string(ATA_ID_PROD, ATA_ID_PROD_LEN),
+            serial: string(ATA_ID_SERNO, ATA_ID_SERNO_LEN),
+            firmware: string(ATA_ID_FW_REV, ATA_ID_FW_REV_LEN),
+            wwn: Some(ata_id_wwn(id)).filter(|&wwn| ata_id_has_wwn(id) && wwn != 0),
+            rotation: Rotation::from_word(id[ATA_ID_ROT_SPEED]),
+            sectors: ata_id_n_sectors(id),
+            logical_sector_size: ata_id_logical_sector_size(id),
+            physical_sector_size: ata_id_physical_sector_size(id),
+            alignment_offset: ata_id_alignment_offset(id),
+            features: IdentifyFeatures {
+                major_version: ata_id_major_version(id),
+                lba48: ata_id_has_lba48(id),
+                ncq: ata_id_has_ncq(id).then(|| ata_id_queue_depth(id)),
+                ncq_queue_management: ata_id_has_ncq_queue_mgmt(id),
+                trim: ata_id_has_trim(id),
+                zero_after_trim: ata_id_has_zero_after_trim(id),
+                fua: ata_id_has_fua(id),
+                write_cache: ata_id_has_wcache(id).then(|| ata_id_wcache_enabled(id)),
+                read_lookahead: ata_id_has_read_lookahead(id)
+                    .then(|| ata_id_read_lookahead_enabled(id)),
+                smart: ata_id_smart_enabled(id),
+                gpl: ata_id_has_gpl(id),
+                apm: ata_id_has_apm(id),
+                aam: ata_id_has_aam(id),
+                sct_write_same: ata_id_has_sct_write_same(id),
+            },
+            raw: *id,
+        }
+    }
+
+    /// Whether the drive is a solid state drive.
+    pub fn is_ssd(&self) -> bool {
+        self.rotation == Rotation::NonRotating
+    }
+
+    /// What changed from `self` to `new`.
+    pub fn diff(&self, new: &Self) -> IdentityChange {
+        IdentityChange {
+            replaced: self.model != new.model || self.serial != new.serial || self.wwn != new.wwn,
+            capacity: changed(self.sectors, new.sectors),
+            logical_sector_size: changed(self.logical_sector_size, new.logical_sector_size),
+            features: changed(self.features, new.features),
+            firmware: changed(&self.firmware, &new.firmware).map(|(o, n)| (o.clone(), n.clone())),
+        }
+    }
+}
+
+fn changed<T: PartialEq>(old: T, new: T) -> Option<(T, T)> {
+    (old != new).then_some((old, new))
+}
+
+/// Differences between two reads of the IDENTIFY DEVICE data of a disk, as
+/// `(old, new)` pairs, see
+/// [`AhciDriver::refresh_identity`](crate::AhciDriver::refresh_identity).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct IdentityChange {
+    /// The model, serial number or WWN changed, i.e. another drive was
+    /// attached to the port.
+    pub replaced: bool,
+    /// Number of user addressable sectors, e.g. after removing a Host
+    /// Protected Area.
+    pub capacity: Option<(u64, u64)>,
+    pub logical_sector_size: Option<(usize, usize)>,
+    /// Supported or enabled features, e.g. after SET FEATURES.
+    pub features: Option<(IdentifyFeatures, IdentifyFeatures)>,
+    /// Firmware revision, e.g. after DOWNLOAD MICROCODE.
+    pub firmware: Option<(String, String)>,
+}
+
+impl IdentityChange {
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        !self.replaced
+            && self.capacity.is_none()
+            && self.logical_sector_size.is_none()
+            && self.features.is_none()
+            && self.firmware.is_none()
+    }
+}
@@ -0,0 +1,145 @@
+//! Interrupt-driven command completion.
+
+use alloc::sync::Arc;
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use volatile::VolatilePtr;
+
+use crate::{
+    Hal,
+    ahci::port_regs,
+    mmio::{
+        AhciMmio, AhciMmioVolatileFieldAccess, GenericHostControlVolatileFieldAccess,
+        PortRegistersVolatileFieldAccess,
+    },
+};
+
+/// Port interrupt status acknowledged by an [`IrqHandler`], kept until the
+/// disk owning the port consumes it.
+pub(crate) struct IrqStatus {
+    ports: [AtomicU32; 32],
+}
+
+impl IrqStatus {
+    pub(crate) fn new() -> Self {
+        Self {
+            ports: [const { AtomicU32::new(0) }; 32],
+        }
+    }
+
+    fn record(&self, port: u8, is: u32) {
+        self.ports[port as usize].fetch_or(is, Ordering::AcqRel);
+    }
+
+    /// Return the recorded status of `port`, clearing the bits in `mask`.
+    pub(crate) fn take(&self, port: u8, mask: u32) -> u32 {
+        self.ports[port as usize].fetch_and(!mask, Ordering::AcqRel)
+    }
+}
+
+/// Completion of large transfers by polling instead of by interrupt, see
+/// [`AhciDriver::set_irq_coalescing`](crate::AhciDriver::set_irq_coalescing).
+///
+/// While the driver waits for a command transferring at least
+/// `poll_threshold` bytes, the completion interrupts of the port are masked
+/// and the command is polled every `poll_interval_ms` milliseconds, so bulk
+/// copies don't interrupt the CPU for every command. Error interrupts stay
+/// enabled and end the wait early.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqCoalescing {
+    /// Size in bytes from which commands are polled.
+    pub poll_threshold: usize,
+    /// Interval between polls, in milliseconds.
+    pub poll_interval_ms: u64,
+}
+
+/// Services the interrupt of an HBA on behalf of all of its disks.
+///
+/// The handler only acknowledges the interrupt and records the port status
+/// for the disks to pick up, so it can run while a disk is waiting for a
+/// command in interrupt mode, see
+/// [`AhciDriver::set_irq_mode`](crate::AhciDriver::set_irq_mode). It is
+/// obtained from
+/// [`AhciController::irq_handler`](crate::AhciController::irq_handler) or
+/// [`AhciDriver::irq_handler`](crate::AhciDriver::irq_handler).
+pub struct IrqHandler<H> {
+    mmio: VolatilePtr<'static, AhciMmio>,
+    status: Arc<IrqStatus>,
+    _h: PhantomData<H>,
+}
+
+/// Safety: The handler only performs write-1-to-clear cycles on the
+/// interrupt status registers, serialized with the disks by
+/// [`Hal::with_irqs_disabled`].
+unsafe impl<H: Hal> Send for IrqHandler<H> {}
+unsafe impl<H: Hal> Sync for IrqHandler<H> {}
+
+impl<H> Clone for IrqHandler<H> {
+    fn clone(&self) -> Self {
+        Self {
+            mmio: self.mmio,
+            status: self.status.clone(),
+            _h: PhantomData,
+        }
+    }
+}
+
+impl<H: Hal> IrqHandler<H> {
+    pub(crate) fn new(mmio: VolatilePtr<'static, AhciMmio>, status: Arc<IrqStatus>) -> Self {
+        Self {
+            mmio,
+            status,
+            _h: PhantomData,
+        }
+    }
+
+    /// Acknowledge the pending interrupts of port `port` and record them.
+    fn ack_port(&self, port: u8) -> bool {
+        let regs = port_regs(&self.mmio, port);
+        let is = regs.IS().read();
+        regs.IS().write(is);
+        self.status.record(port, is.into_bits());
+        is.into_bits() != 0
+    }
+
+    /// Service an interrupt of the HBA, signalled on its only or shared
+    /// vector.
+    ///
+    /// Reads and acknowledges the global IS register and the IS register of
+    /// each port set in it, then calls [`Hal::irq_notify`] for those ports.
+    /// Returns the bitmap of ports that had an interrupt pending, which is 0
+    /// if the interrupt was not raised by this HBA.
+    pub fn handle_irq(&self) -> u32 {
+        let host = self.mmio.host();
+        let pending = H::with_irqs_disabled(|| {
+            let pending = host.is().read();
+            for port in (0..32).filter(|i| pending & (1 << i) != 0) {
+                self.ack_port(port);
+            }
+            // Port status must be cleared first, or IS is set again.
+            host.is().write(pending);
+            pending
+        });
+        for port in (0..32).filter(|i| pending & (1 << i) != 0) {
+            H::irq_notify(port);
+        }
+        pending
+    }
+
+    /// Service an interrupt of port `port`, signalled on an MSI vector of its
+    /// own, see [`AhciController::msi_vector`](crate::AhciController::msi_vector).
+    ///
+    /// Like [`AhciDriver::handle_port_irq`](crate::AhciDriver::handle_port_irq),
+    /// the global IS register is not accessed. Returns whether the port had
+    /// an interrupt pending.
+    pub fn handle_port_irq(&self, port: u8) -> bool {
+        let pending = H::with_irqs_disabled(|| self.ack_port(port));
+        if pending {
+            H::irq_notify(port);
+        }
+        pending
+    }
+}
@@ -0,0 +1,131 @@
+//! Sector and LBA arithmetic, and the transfer limits of the driver.
+
+use core::ops::Range;
+
+use crate::types::{AHCI_MAX_BYTES_PER_CMD, AHCI_MAX_BYTES_PER_SG, AHCI_MAX_SG};
+
+/// Number of PRDT entries per command, see the `prdt-*` features.
+pub const MAX_PRD_ENTRIES: usize = AHCI_MAX_SG;
+/// Number of bytes a single PRDT entry describes.
+pub const MAX_BYTES_PER_PRD: usize = AHCI_MAX_BYTES_PER_SG;
+/// Number of bytes a single command can transfer.
+pub const MAX_BYTES_PER_COMMAND: usize = AHCI_MAX_BYTES_PER_CMD;
+/// Number of sectors a 28-bit command can transfer.
+pub const MAX_SECTORS_LBA28: usize = 256;
+/// Number of sectors a 48-bit command can transfer.
+pub const MAX_SECTORS_LBA48: usize = 65536;
+/// Number of sectors addressable with 28-bit commands.
+pub const MAX_LBA28: u64 = 1 << 28;
+/// Number of sectors addressable with 48-bit commands.
+pub const MAX_LBA48: u64 = 1 << 48;
+
+/// Number of sectors of `sector_size` bytes needed to hold `bytes` bytes.
+pub const fn sectors_for_bytes(bytes: usize, sector_size: usize) -> usize {
+    bytes.div_ceil(sector_size)
+}
+
+/// Whether `offset` is at a sector boundary.
+pub const fn is_sector_aligned(offset: u64, sector_size: usize) -> bool {
+    offset.is_multiple_of(sector_size as u64)
+}
+
+/// The sectors of `sector_size` bytes that the byte range `range` touches,
+/// including partially covered ones at either end.
+pub fn byte_range_to_lba_range(range: Range<u64>, sector_size: usize) -> Range<u64> {
+    let sector_size = sector_size as u64;
+    let start = range.start / sector_size;
+    start..range.end.max(range.start).div_ceil(sector_size)
+}
+
+/// Encode a transfer of `count` sectors for the sector count field of a
+/// 48-bit command, or of a 28-bit one unless `lba48` is set.
+///
+/// The field is 16 bits wide for 48-bit commands and 8 bits for 28-bit
+/// ones, and 0 stands for the maximum: [`MAX_SECTORS_LBA48`] or
+/// [`MAX_SECTORS_LBA28`] sectors. Returns `None` for 0 or more sectors than
+/// a command can transfer.
+pub const fn sector_count_field(count: usize, lba48: bool) -> Option<u16> {
+    let max = if lba48 {
+        MAX_SECTORS_LBA48
+    } else {
+        MAX_SECTORS_LBA28
+    };
+    match count {
+        0 => None,
+        _ if count == max => Some(0),
+        _ if count < max => Some(count as u16),
+        _ => None,
+    }
+}
+
+const _: () = assert!(matches!(sector_count_field(1, false), Some(1)));
+const _: () = assert!(matches!(sector_count_field(255, false), Some(255)));
+const _: () = assert!(matches!(sector_count_field(256, false), Some(0)));
+const _: () = assert!(sector_count_field(257, false).is_none());
+const _: () = assert!(matches!(sector_count_field(256, true), Some(256)));
+const _: () = assert!(matches!(sector_count_field(65535, true), Some(65535)));
+const _: () = assert!(matches!(sector_count_field(65536, true), Some(0)));
+const _: () = assert!(sector_count_field(65537, true).is_none());
+const _: () = assert!(sector_count_field(0, true).is_none());
+
+/// Number of sectors of `sector_size` bytes a single READ/WRITE DMA (EXT)
+/// command can transfer: [`MAX_SECTORS_LBA28`] or [`MAX_SECTORS_LBA48`], but
+/// no more than the PRDT can describe.
+pub const fn max_sectors_per_command(sector_size: usize, lba48: bool) -> usize {
+    let max = if lba48 {
+        MAX_SECTORS_LBA48
+    } else {
+        MAX_SECTORS_LBA28
+    };
+    let prdt = MAX_BYTES_PER_COMMAND / sector_size;
+    if prdt < max { prdt } else { max }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maximal_counts_encode_as_zero() {
+        assert_eq!(sector_count_field(MAX_SECTORS_LBA28, false), Some(0));
+        assert_eq!(sector_count_field(MAX_SECTORS_LBA48, true), Some(0));
+        assert_eq!(sector_count_field(255, false), Some(255));
+        assert_eq!(sector_count_field(256, true), Some(256));
+        assert_eq!(sector_count_field(65535, true), Some(65535));
+    }
+
+    #[test]
+    fn counts_beyond_the_field_are_rejected() {
+        assert_eq!(sector_count_field(257, false), None);
+        assert_eq!(sector_count_field(65537, true), None);
+        assert_eq!(sector_count_field(0, false), None);
+        assert_eq!(sector_count_field(0, true), None);
+    }
+
+    #[test]
+    fn max_sectors_by_addressing_mode() {
+        assert_eq!(max_sectors_per_command(512, false), MAX_SECTORS_LBA28);
+        assert_eq!(max_sectors_per_command(4096, false), MAX_SECTORS_LBA28);
+        assert_eq!(
+            max_sectors_per_command(512, true),
+            MAX_SECTORS_LBA48.min(MAX_BYTES_PER_COMMAND / 512)
+        );
+    }
+
+    #[test]
+    fn max_sectors_capped_by_prdt() {
+        // 65536 sectors of 4 KiB take 256 MiB, more than a full PRDT.
+        let max = max_sectors_per_command(4096, true);
+        assert_eq!(max, MAX_BYTES_PER_COMMAND / 4096);
+        assert!(max < MAX_SECTORS_LBA48);
+        assert!(max * 4096 <= MAX_PRD_ENTRIES * MAX_BYTES_PER_PRD);
+    }
+
+    #[test]
+    fn odd_lengths_round_up() {
+        assert_eq!(sectors_for_bytes(0, 512), 0);
+        assert_eq!(sectors_for_bytes(513, 512), 2);
+        assert_eq!(sectors_for_bytes(4095, 4096), 1);
+        assert_eq!(sectors_for_bytes(4097, 4096), 2);
+    }
+}
@@ -4,9 +4,92 @@ extern crate alloc;
 
 mod ahci;
 mod ata;
+mod builder;
+mod config;
+mod controller;
+#[cfg(feature = "xts")]
+mod crypt;
+mod diag;
+mod disk_id;
+mod dma;
+mod early;
+mod erase;
+mod error;
+mod event;
+mod filter;
 mod hal;
+mod handle;
+mod hotplug;
+mod identify;
+mod irq;
+mod lba;
+mod maintenance;
 mod mmio;
+#[cfg(test)]
+mod mock;
+#[cfg(feature = "ncq")]
+mod ncq;
+mod negotiate;
+mod passive;
+mod pmp;
+mod policy;
+mod port;
+mod probe;
+mod sink;
+#[cfg(feature = "smart")]
+mod smart;
+mod stats;
+mod stream;
+mod taskfile;
+mod throttle;
+mod timeouts;
 mod types;
+mod vendor;
+mod verbosity;
+mod wear;
 
-pub use ahci::AhciDriver;
-pub use hal::Hal;
+pub use ahci::{AhciDriver, FlushTicket, IdentifyData, IoTicket};
+pub use builder::AhciDriverBuilder;
+pub use config::DriverConfig;
+pub use controller::AhciController;
+#[cfg(feature = "xts")]
+pub use crypt::{BlockCipher, SectorCipher, Xts};
+pub use diag::{EngineState, PortDiagnostics, RegisterMismatch, ShadowedRegister};
+pub use disk_id::DiskId;
+pub use dma::DmaBuffer;
+pub use early::early_read;
+pub use erase::EraseReport;
+pub use error::AhciError;
+pub use event::{AhciEvent, HungCommandCheck, LinkPowerState};
+pub use filter::CommandFilter;
+pub use hal::{Clock, DmaAddr, DmaCoherence, DmaDirection, Hal};
+pub use handle::{DiskHandle, Fault, IoStats};
+pub use hotplug::{HotplugEvent, HotplugSink};
+pub use identify::{Identify, IdentifyFeatures, IdentityChange, Rotation};
+pub use irq::{IrqCoalescing, IrqHandler};
+pub use lba::{
+    MAX_BYTES_PER_COMMAND, MAX_BYTES_PER_PRD, MAX_LBA28, MAX_LBA48, MAX_PRD_ENTRIES,
+    MAX_SECTORS_LBA28, MAX_SECTORS_LBA48, byte_range_to_lba_range, is_sector_aligned,
+    max_sectors_per_command, sector_count_field, sectors_for_bytes,
+};
+pub use maintenance::Maintenance;
+pub use mmio::ISS as LinkSpeed;
+#[cfg(feature = "ncq")]
+pub use ncq::{NcqErrorLog, NcqQueueManagement, NcqStats};
+pub use negotiate::Negotiated;
+pub use passive::ReadOnlyDisk;
+pub use pmp::PortMultiplier;
+pub use policy::{DrivePolicy, FeatureLevel, Quirks, ZeroWriteOffload};
+pub use port::{Idle, Port, Running, Uninit};
+pub use probe::{DeviceType, ProbeMode, ProbeOutcome, ProbeReport, ProbeTimeouts};
+pub use sink::{ErrorRecord, ErrorSink, Recovery};
+#[cfg(feature = "smart")]
+pub use smart::{SmartAttribute, SmartData, SmartThreshold};
+pub use stats::AhciStats;
+pub use stream::StreamWriter;
+pub use taskfile::{DataDirection, TaskFile, TaskFileResult, VendorCommand};
+pub use throttle::RateLimit;
+pub use timeouts::{RetryPolicy, Timeouts};
+pub use vendor::VendorRegisters;
+pub use verbosity::{log_verbosity, set_log_verbosity};
+pub use wear::SsdWear;
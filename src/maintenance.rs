@@ -0,0 +1,77 @@
+//! Deferred work run while a disk is idle.
+
+/// Work done by [`AhciDriver::tick`](crate::AhciDriver::tick) once no
+/// foreground I/O has run for a while, see
+/// [`AhciDriver::set_maintenance`](crate::AhciDriver::set_maintenance).
+///
+/// At most one step runs per tick, in the order of the fields below, so a
+/// tick never blocks for longer than a single command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Maintenance {
+    /// Time without foreground commands before maintenance runs, in
+    /// milliseconds.
+    pub idle_ms: u64,
+    /// Flush the volatile write cache once after writes, if it is enabled.
+    pub flush_write_cache: bool,
+    /// Interval between SMART health checks, in milliseconds. A failing
+    /// check is reported with
+    /// [`AhciEvent::SmartThresholdExceeded`](crate::AhciEvent::SmartThresholdExceeded).
+    /// Ignored without the `smart` feature.
+    pub smart_interval_ms: Option<u64>,
+    /// Sectors verified with READ VERIFY SECTORS per step of a background
+    /// scrub. Unreadable ranges are reported with
+    /// [`AhciEvent::ScrubError`](crate::AhciEvent::ScrubError).
+    pub scrub_sectors: Option<u16>,
+    /// Time between the end of a scrub pass and the start of the next one,
+    /// in milliseconds.
+    pub scrub_interval_ms: u64,
+    /// Put the link in the Slumber state once nothing else is due, if the
+    /// HBA supports it (CAP.SSC). The HBA wakes it for the next command.
+    pub slumber: bool,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            idle_ms: 5_000,
+            flush_write_cache: true,
+            smart_interval_ms: None,
+            scrub_sectors: None,
+            scrub_interval_ms: 24 * 60 * 60 * 1000,
+            slumber: false,
+        }
+    }
+}
+
+/// Progress of the maintenance of a disk.
+#[derive(Debug, Default)]
+pub(crate) struct MaintenanceState {
+    pub(crate) config: Maintenance,
+    /// Commands and writes issued on the port, as last seen.
+    pub(crate) issued: u64,
+    pub(crate) writes: u64,
+    /// When the last foreground command was seen.
+    pub(crate) idle_since: u64,
+    /// Whether data was written since the last flush.
+    pub(crate) unflushed: bool,
+    /// When the last SMART check ran.
+    #[cfg(feature = "smart")]
+    pub(crate) last_smart: u64,
+    /// Next sector to scrub, and when the current pass may start.
+    pub(crate) scrub_lba: u64,
+    pub(crate) scrub_after: u64,
+    /// Whether the link was put in Slumber since the last command.
+    pub(crate) slumbering: bool,
+}
+
+impl MaintenanceState {
+    pub(crate) fn new(config: Maintenance, now: u64) -> Self {
+        Self {
+            config,
+            idle_since: now,
+            #[cfg(feature = "smart")]
+            last_smart: now,
+            ..Default::default()
+        }
+    }
+}
@@ -9,10 +9,13 @@ use volatile::{VolatileFieldAccess, access::ReadOnly};
 #[repr(C)]
 pub struct AhciMmio {
     pub host: GenericHostControl,
-    _res: [u8; 0xd8],
+    _res: [u8; 0x74],
+    /// Vendor Specific registers.
+    pub vendor: [u32; 24],
     pub ports: [PortRegisters; 32],
 }
 
+const _: () = assert!(core::mem::offset_of!(AhciMmio, vendor) == 0xa0);
 const _: () = assert!(core::mem::offset_of!(AhciMmio, ports) == 0x100);
 
 #[derive(VolatileFieldAccess)]
@@ -99,6 +102,11 @@ pub struct GenericHostControl {
     /// This register indicates capabilities of the HBA to driver software.
     #[access(ReadOnly)]
     pub cap2: CAP2,
+
+    /// BOHC – BIOS/OS Handoff Control and Status
+    ///
+    /// Only implemented if CAP2.BOH is set.
+    pub bohc: BOHC,
 }
 
 /// CAP – HBA Capabilities
@@ -292,7 +300,8 @@ pub struct CAP {
     pub NP: u8,
 }
 
-/// The maximum speed the HBA can support on its ports.
+/// A SATA link speed generation, e.g. the maximum speed the HBA can support
+/// on its ports (CAP.ISS) or the negotiated speed (PxSSTS.SPD).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum ISS {
@@ -443,6 +452,44 @@ pub struct GHC {
     pub HR: bool,
 }
 
+/// BOHC – BIOS/OS Handoff Control and Status
+///
+/// Used to transfer ownership of the HBA from the BIOS to the OS, see
+/// section 10.6 of the AHCI specification.
+#[bitfield(u32, order = Msb)]
+pub struct BOHC {
+    #[bits(27)]
+    __: u32,
+
+    /// BIOS Busy (BB):
+    ///
+    /// Set by the BIOS when it is busy cleaning up for the ownership change,
+    /// in which case the OS must wait at least two seconds for it.
+    pub BB: bool,
+
+    /// OS Ownership Change (OOC):
+    ///
+    /// Set by the HBA when OOS transitions from '0' to '1'. Cleared by
+    /// writing '1'.
+    pub OOC: bool,
+
+    /// SMI on OS Ownership Change Enable (SOOE):
+    ///
+    /// When set, an SMI is generated when OOC is set.
+    pub SOOE: bool,
+
+    /// OS Owned Semaphore (OOS):
+    ///
+    /// Set by the OS to request ownership of the HBA.
+    pub OOS: bool,
+
+    /// BIOS Owned Semaphore (BOS):
+    ///
+    /// Set by the BIOS while it owns the HBA, and cleared once it released
+    /// it.
+    pub BOS: bool,
+}
+
 /// VS – AHCI Version
 ///
 /// This register indicates the major and minor version of the AHCI
@@ -557,7 +604,7 @@ pub struct PortRegisters {
     /// Serial ATA Status (SCR0: SStatus).
     pub SSTS: PxSSTS,
     /// Serial ATA Control (SCR2: SControl).
-    pub SCTL: u32,
+    pub SCTL: PxSCTL,
     /// Serial ATA Error (SCR1: SError).
     pub SERR: PxSERR,
     /// Serial ATA Active. (SCR3: SActive).
@@ -572,7 +619,7 @@ pub struct PortRegisters {
     pub DEVSLP: u8,
     _reserved1: [u8; 0x28],
     /// Vendor Specific.
-    pub vs: u128,
+    pub vs: [u32; 4],
 }
 
 const _: () = assert!(size_of::<PortRegisters>() == 0x80);
@@ -721,6 +768,30 @@ pub struct PxSSTS {
     pub DET: u8,
 }
 
+#[bitfield(u32, order = Msb)]
+pub struct PxSCTL {
+    #[bits(12)]
+    __: u16,
+    /// Port Multiplier Port: not used by AHCI.
+    #[bits(4)]
+    pub PMP: u8,
+    /// Select Power Management: not used by AHCI.
+    #[bits(4)]
+    pub SPM: u8,
+    /// Interface Power Management Transitions Allowed: bit 0 disables
+    /// Partial, bit 1 Slumber and bit 2 DevSleep.
+    #[bits(4)]
+    pub IPM: u8,
+    /// Speed Allowed: the highest speed the link may negotiate, as an
+    /// [`ISS`] value, or 0 for no restriction.
+    #[bits(4)]
+    pub SPD: u8,
+    /// Device Detection Initialization: 1 performs a COMRESET for as long
+    /// as it is set, 4 takes the Phy offline.
+    #[bits(4)]
+    pub DET: u8,
+}
+
 #[bitfield(u32, order = Msb)]
 pub struct PxSERR {
     #[bits(5)]
@@ -0,0 +1,1008 @@
+//! An in-memory HBA with a single disk, for running the driver on the host.
+//!
+//! The register file is plain memory, so the HBA can't react to accesses as
+//! they happen. Instead it is stepped whenever [`MockHal`] is called: the
+//! clock advances by a millisecond on every read, and the end of every
+//! critical section lets the HBA see what was written in it. Each step
+//! picks up newly issued commands, completes the ones whose latency has
+//! passed and writes back the registers it owns.
+//!
+//! Write-1-to-clear registers can't be emulated in plain memory. PxIS only
+//! holds TFE until the command engine is stopped, and the completion bits
+//! while an [`IrqHandler`] is installed, up to the point it has run.
+
+extern crate std;
+
+use alloc::{vec, vec::Vec};
+use core::{cell::Cell, cell::RefCell, ops::Range, ptr};
+use std::{boxed::Box, thread_local};
+
+use crate::{
+    AhciController, AhciDriver, AhciDriverBuilder, DmaCoherence, Hal, IrqHandler,
+    ata::*,
+    mmio::{AhciMmio, CAP, GHC, ISS, PxCMD, PxI, PxSCTL, PxSSTS},
+    types::{ahci_cmd_hdr, ahci_sg, sata_fis_h2d},
+};
+
+const HOST_CAP: usize = 0x00;
+const HOST_GHC: usize = 0x04;
+const HOST_IS: usize = 0x08;
+const HOST_PI: usize = 0x0c;
+const HOST_VS: usize = 0x10;
+
+/// Registers of port 0.
+const PX_CLB: usize = 0x100;
+const PX_CLBU: usize = 0x104;
+const PX_FB: usize = 0x108;
+const PX_FBU: usize = 0x10c;
+const PX_IS: usize = 0x110;
+const PX_IE: usize = 0x114;
+const PX_CMD: usize = 0x118;
+const PX_TFD: usize = 0x120;
+const PX_SIG: usize = 0x124;
+const PX_SSTS: usize = 0x128;
+const PX_SCTL: usize = 0x12c;
+const PX_SACT: usize = 0x134;
+const PX_CI: usize = 0x138;
+
+/// Offset of the D2H Register FIS in the received FIS area.
+const RX_FIS_D2H_REG: usize = 0x40;
+/// Offset of the Set Device Bits FIS in the received FIS area.
+const RX_FIS_SDB: usize = 0x58;
+
+/// DRDY and DSC, the status of an idle device.
+const STATUS_READY: u8 = 0x50;
+/// Error, in the status register.
+const STATUS_ERR: u8 = 0x01;
+/// Aborted command, in the error register.
+const ERROR_ABRT: u8 = 0x04;
+/// ID not found, in the error register.
+const ERROR_IDNF: u8 = 0x10;
+/// Uncorrectable data error, in the error register.
+const ERROR_UNC: u8 = 0x40;
+
+/// The disk attached to the mock HBA.
+#[derive(Debug, Clone)]
+pub(crate) struct MockDisk {
+    pub sectors: u64,
+    pub sector_size: usize,
+    pub lba48: bool,
+    /// Queue depth reported in IDENTIFY, 1 for no NCQ support.
+    pub ncq_depth: u8,
+    pub fua: bool,
+    pub write_cache: bool,
+}
+
+impl Default for MockDisk {
+    fn default() -> Self {
+        Self {
+            sectors: 8192,
+            sector_size: 512,
+            lba48: true,
+            ncq_depth: 32,
+            fua: true,
+            write_cache: true,
+        }
+    }
+}
+
+/// Time the disk takes for each command, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Latency {
+    Fixed(u64),
+    /// Uniformly distributed in `min..=max`, from a seeded generator so
+    /// that runs are reproducible.
+    Uniform {
+        min: u64,
+        max: u64,
+    },
+    /// The disk never completes a command.
+    Hang,
+}
+
+/// A command the HBA fetched.
+struct Pending {
+    slot: usize,
+    queued: bool,
+    due: u64,
+}
+
+struct Hba {
+    base: usize,
+    disk: MockDisk,
+    data: Vec<u8>,
+    now: u64,
+    latency: Latency,
+    reorder: bool,
+    rng: u64,
+    fail_lba: Option<u64>,
+    link: bool,
+    /// COMRESETs after which the link still has no Phy communication.
+    stuck_comresets: usize,
+    /// Set while PxSCTL.DET is 1.
+    in_comreset: bool,
+    /// HBA resets and COMRESETs so far.
+    resets: usize,
+
+    /// Commands issued and not completed, as PxCI and PxSACT should read.
+    ci: u32,
+    sact: u32,
+    /// PxIS bits raised by the HBA.
+    is: u32,
+    tfd: u32,
+    running: bool,
+    /// Set by an error until the command engine is stopped.
+    halted: bool,
+    pending: Vec<Pending>,
+
+    handler: Option<IrqHandler<MockHal>>,
+    irq_pending: bool,
+    spurious_irqs: bool,
+    interrupts: usize,
+    /// Opcodes of the fetched commands.
+    commands: Vec<u8>,
+    /// Slots in the order their commands completed.
+    completions: Vec<usize>,
+}
+
+thread_local! {
+    static HBA: RefCell<Option<Hba>> = const { RefCell::new(None) };
+    static IRQS_DISABLED: Cell<usize> = const { Cell::new(0) };
+    static DELIVERING: Cell<bool> = const { Cell::new(false) };
+    static NOTIFIED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn with<R>(f: impl FnOnce(&mut Hba) -> R) -> R {
+    HBA.with(|hba| f(hba.borrow_mut().as_mut().expect("mock HBA not installed")))
+}
+
+/// Advance the clock by `ms`, step the HBA and deliver its interrupt if
+/// interrupts are enabled.
+fn run(ms: u64) {
+    if DELIVERING.get() {
+        return;
+    }
+    with(|hba| {
+        hba.now += ms;
+        hba.step();
+    });
+    if IRQS_DISABLED.get() > 0 {
+        return;
+    }
+    let Some(handler) = with(|hba| {
+        let handler = hba
+            .handler
+            .clone()
+            .filter(|_| hba.irq_pending || hba.spurious_irqs);
+        hba.irq_pending = false;
+        handler
+    }) else {
+        return;
+    };
+    DELIVERING.set(true);
+    handler.handle_irq();
+    DELIVERING.set(false);
+    with(|hba| {
+        hba.interrupts += 1;
+        // The handler acknowledged everything it saw.
+        hba.is = 0;
+        hba.write_back();
+    });
+}
+
+/// The [`Hal`] of the mock HBA. Memory is identity mapped and coherent.
+pub(crate) struct MockHal;
+
+impl Hal for MockHal {
+    const DMA_COHERENCE: DmaCoherence = DmaCoherence::All;
+
+    fn virt_to_phys(va: usize) -> usize {
+        va
+    }
+
+    fn with_irqs_disabled<R>(f: impl FnOnce() -> R) -> R {
+        IRQS_DISABLED.set(IRQS_DISABLED.get() + 1);
+        let result = f();
+        IRQS_DISABLED.set(IRQS_DISABLED.get() - 1);
+        if IRQS_DISABLED.get() == 0 {
+            run(0);
+        }
+        result
+    }
+
+    fn irq_wait(_port: u8, _timeout_ms: u64) {
+        if !NOTIFIED.take() {
+            run(1);
+        }
+    }
+
+    fn irq_notify(_port: u8) {
+        NOTIFIED.set(true);
+    }
+
+    fn current_ms() -> u64 {
+        run(1);
+        with(|hba| hba.now)
+    }
+
+    fn flush_dcache() {}
+}
+
+/// Install a mock HBA with `disk` attached to port 0 for the current thread,
+/// replacing any previous one, and return its MMIO base address.
+///
+/// The disk starts filled with a pattern that differs for every sector.
+pub(crate) fn install(disk: MockDisk) -> usize {
+    // SAFETY: All registers are plain integers, for which zero is valid.
+    let regs: &mut AhciMmio = Box::leak(unsafe { Box::new_zeroed().assume_init() });
+    let base = ptr::from_mut(regs).addr();
+    let mut data = vec![0; disk.sectors as usize * disk.sector_size];
+    for (lba, sector) in data.chunks_mut(disk.sector_size).enumerate() {
+        sector.fill(lba as u8 ^ (lba >> 8) as u8 ^ 0x5a);
+    }
+    let hba = Hba {
+        base,
+        disk,
+        data,
+        now: 0,
+        latency: Latency::Fixed(0),
+        reorder: false,
+        rng: 0x2545_f491_4f6c_dd1d,
+        fail_lba: None,
+        link: true,
+        stuck_comresets: 0,
+        in_comreset: false,
+        resets: 0,
+        ci: 0,
+        sact: 0,
+        is: 0,
+        tfd: STATUS_READY as u32,
+        running: false,
+        halted: false,
+        pending: Vec::new(),
+        handler: None,
+        irq_pending: false,
+        spurious_irqs: false,
+        interrupts: 0,
+        commands: Vec::new(),
+        completions: Vec::new(),
+    };
+    let cap = CAP::new()
+        .with_NCS(31)
+        .with_SNCQ(true)
+        .with_S64A(true)
+        .with_SCLO(true)
+        .with_ISS(ISS::Gen3);
+    hba.write(HOST_CAP, cap.into_bits());
+    hba.write(HOST_PI, 1);
+    hba.write(HOST_VS, 0x0001_0301);
+    hba.write(PX_SIG, 0x0000_0101);
+    hba.write_back();
+    HBA.with(|slot| *slot.borrow_mut() = Some(hba));
+    IRQS_DISABLED.set(0);
+    base
+}
+
+/// Install a mock HBA with `disk` and bring up the driver for it.
+pub(crate) fn driver(disk: MockDisk) -> AhciDriver<MockHal> {
+    controller(disk).into_disks().pop().unwrap()
+}
+
+/// Install a mock HBA with `disk` and bring up a controller for it.
+pub(crate) fn controller(disk: MockDisk) -> AhciController<MockHal> {
+    let base = install(disk);
+    // SAFETY: `base` is the register file of the mock HBA, which is leaked.
+    unsafe { AhciController::try_new(base) }.unwrap()
+}
+
+pub(crate) fn set_latency(latency: Latency) {
+    with(|hba| hba.latency = latency);
+}
+
+/// Complete the commands that are due in a random order rather than in the
+/// order they were issued.
+#[cfg(feature = "ncq")]
+pub(crate) fn set_reorder(reorder: bool) {
+    with(|hba| hba.reorder = reorder);
+}
+
+/// Fail reads and writes covering `lba` with an uncorrectable error.
+pub(crate) fn set_fail_lba(lba: Option<u64>) {
+    with(|hba| hba.fail_lba = lba);
+}
+
+/// Attach or remove the disk.
+pub(crate) fn set_link(up: bool) {
+    with(|hba| hba.link = up);
+}
+
+/// Keep the link at DET=1, with the device present but no Phy
+/// communication, until `comresets` more COMRESETs were issued.
+pub(crate) fn set_stuck_link(comresets: usize) {
+    with(|hba| hba.stuck_comresets = comresets);
+}
+
+/// Number of HBA resets and COMRESETs so far.
+pub(crate) fn resets() -> usize {
+    with(|hba| hba.resets)
+}
+
+/// Deliver the HBA's interrupt to `handler`, whenever interrupts are
+/// enabled and the HBA raised one.
+pub(crate) fn set_irq_handler(handler: Option<IrqHandler<MockHal>>) {
+    with(|hba| hba.handler = handler);
+}
+
+/// Also deliver the interrupt whenever it is enabled but was not raised, so
+/// that the handler runs between as many steps of the driver as possible.
+pub(crate) fn set_spurious_irqs(spurious: bool) {
+    with(|hba| hba.spurious_irqs = spurious);
+}
+
+/// Number of interrupts delivered so far.
+pub(crate) fn interrupts() -> usize {
+    with(|hba| hba.interrupts)
+}
+
+/// Take the opcodes of the commands fetched so far.
+pub(crate) fn take_commands() -> Vec<u8> {
+    with(|hba| core::mem::take(&mut hba.commands))
+}
+
+/// Take the slots of the commands completed so far, in completion order.
+#[cfg(feature = "ncq")]
+pub(crate) fn take_completions() -> Vec<usize> {
+    with(|hba| core::mem::take(&mut hba.completions))
+}
+
+/// Contents of sectors `lbas` of the disk.
+pub(crate) fn sectors(lbas: Range<u64>) -> Vec<u8> {
+    with(|hba| {
+        let size = hba.disk.sector_size;
+        hba.data[lbas.start as usize * size..lbas.end as usize * size].to_vec()
+    })
+}
+
+impl Hba {
+    fn read(&self, offset: usize) -> u32 {
+        // SAFETY: All offsets are within the leaked register file.
+        unsafe { ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        // SAFETY: As above.
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    fn fis_base(&self) -> usize {
+        (self.read(PX_FB) as u64 | (self.read(PX_FBU) as u64) << 32) as usize
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn due(&mut self) -> u64 {
+        match self.latency {
+            Latency::Fixed(ms) => self.now + ms,
+            Latency::Uniform { min, max } => self.now + min + self.next_random() % (max - min + 1),
+            Latency::Hang => u64::MAX,
+        }
+    }
+
+    /// Write back the registers the HBA owns.
+    fn write_back(&self) {
+        self.write(PX_CI, self.ci);
+        self.write(PX_SACT, self.sact);
+        self.write(PX_IS, self.is);
+        self.write(PX_TFD, self.tfd);
+        let enabled = self.is & self.read(PX_IE) != 0;
+        self.write(HOST_IS, enabled as u32);
+    }
+
+    fn step(&mut self) {
+        let ghc = GHC::from_bits(self.read(HOST_GHC));
+        if ghc.HR() {
+            self.resets += 1;
+            self.write(HOST_GHC, 0);
+            self.write(PX_CMD, 0);
+            self.stop();
+        }
+
+        let mut cmd = PxCMD::from_bits(self.read(PX_CMD));
+        if self.running && !cmd.ST() {
+            self.stop();
+        }
+        self.running = cmd.ST();
+        cmd = cmd
+            .with_CR(cmd.ST())
+            .with_FR(cmd.FRE())
+            .with_CLO(false)
+            .with_ICC(Default::default());
+        self.write(PX_CMD, cmd.into_bits());
+
+        let sctl = PxSCTL::from_bits(self.read(PX_SCTL));
+        if sctl.DET() == 1 {
+            self.resets += !self.in_comreset as usize;
+            self.in_comreset = true;
+        } else if self.in_comreset {
+            self.in_comreset = false;
+            self.stuck_comresets = self.stuck_comresets.saturating_sub(1);
+        }
+        let det = match sctl.DET() {
+            1 => 0,
+            4 => 4,
+            _ if self.link && self.stuck_comresets > 0 => 1,
+            _ if self.link => 3,
+            _ => 0,
+        };
+        let spd = match sctl.SPD() {
+            0 => 3,
+            spd => spd.min(3),
+        };
+        let ssts = PxSSTS::new()
+            .with_DET(det)
+            .with_SPD(if det == 3 { spd } else { 0 })
+            .with_IPM((det == 3) as u8);
+        self.write(PX_SSTS, ssts.into_bits());
+
+        if self.running && !self.halted && self.link {
+            self.fetch();
+            self.complete_due();
+        }
+        let completion = PxI::new().with_DHR(true).with_SDB(true).into_bits();
+        if self.handler.is_none() {
+            self.is &= !completion;
+        }
+        let ie = self.read(PX_IE);
+        self.irq_pending |= self.handler.is_some() && ghc.IE() && self.is & ie != 0;
+        self.write_back();
+    }
+
+    /// Stop the command engine, which clears PxCI and PxSACT. The device
+    /// answers the restart with a clean status.
+    fn stop(&mut self) {
+        self.ci = 0;
+        self.sact = 0;
+        self.is &= !PxI::new().with_TFE(true).into_bits();
+        self.tfd = STATUS_READY as u32;
+        self.running = false;
+        self.halted = false;
+        self.pending.clear();
+    }
+
+    /// Fetch the commands issued since the last step. Queued commands are
+    /// accepted by the device right away, clearing their PxCI bit.
+    fn fetch(&mut self) {
+        self.sact |= self.read(PX_SACT);
+        let issued = self.read(PX_CI) & !self.ci;
+        for slot in (0..32).filter(|slot| issued & (1 << slot) != 0) {
+            let (_, fis, _) = self.command(slot);
+            let queued = matches!(
+                fis.command,
+                ATA_CMD_FPDMA_READ | ATA_CMD_FPDMA_WRITE | ATA_CMD_NCQ_NON_DATA
+            );
+            self.commands.push(fis.command);
+            if !queued {
+                self.ci |= 1 << slot;
+            }
+            let due = self.due();
+            self.pending.push(Pending { slot, queued, due });
+        }
+    }
+
+    fn complete_due(&mut self) {
+        let mut due: Vec<_> = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.due <= self.now)
+            .map(|(n, _)| n)
+            .collect();
+        if self.reorder {
+            for n in (1..due.len()).rev() {
+                let m = self.next_random() as usize % (n + 1);
+                due.swap(n, m);
+            }
+        } else {
+            due.sort_by_key(|&n| self.pending[n].due);
+        }
+        let slots: Vec<_> = due.iter().map(|&n| self.pending[n].slot).collect();
+        for slot in slots {
+            let n = self.pending.iter().position(|p| p.slot == slot).unwrap();
+            let Pending { queued, .. } = self.pending.remove(n);
+            let result = self.execute(slot);
+            self.completions.push(slot);
+            if let Err(error) = result {
+                self.tfd = (error as u32) << 8 | (STATUS_READY | STATUS_ERR) as u32;
+                self.is |= PxI::new().with_TFE(true).into_bits();
+                self.halted = true;
+                self.d2h_fis(STATUS_READY | STATUS_ERR, error);
+                return;
+            }
+            if queued {
+                self.sact &= !(1 << slot);
+                let sactive = (self.fis_base() + RX_FIS_SDB + 4) as *mut u32;
+                // SAFETY: The received FIS area the driver programmed.
+                unsafe { sactive.write_volatile(sactive.read_volatile() | 1 << slot) };
+                self.is |= PxI::new().with_SDB(true).into_bits();
+            } else {
+                self.ci &= !(1 << slot);
+                self.tfd = STATUS_READY as u32;
+                self.d2h_fis(STATUS_READY, 0);
+                self.is |= PxI::new().with_DHR(true).into_bits();
+            }
+        }
+    }
+
+    fn d2h_fis(&self, status: u8, error: u8) {
+        let fis = (self.fis_base() + RX_FIS_D2H_REG) as *mut u8;
+        // SAFETY: The received FIS area the driver programmed.
+        unsafe {
+            fis.write_volatile(SATA_FIS_TYPE_REGISTER_D2H);
+            fis.add(2).write_volatile(status);
+            fis.add(3).write_volatile(error);
+        }
+    }
+
+    /// The command header, command FIS and PRDT of `slot`.
+    fn command(&self, slot: usize) -> (ahci_cmd_hdr, sata_fis_h2d, Vec<(usize, usize)>) {
+        let clb = (self.read(PX_CLB) as u64 | (self.read(PX_CLBU) as u64) << 32) as usize;
+        // SAFETY: The command list, command table and PRDT the driver
+        // programmed for an issued slot.
+        unsafe {
+            let hdr = ptr::read_volatile((clb + slot * 32) as *const ahci_cmd_hdr);
+            let tbl = (hdr.tbl_addr_lo as u64 | (hdr.tbl_addr_hi as u64) << 32) as usize;
+            let fis = ptr::read_volatile(tbl as *const sata_fis_h2d);
+            let prds = (0..(hdr.opts >> 16) as usize)
+                .map(|i| {
+                    let sg = ptr::read_volatile((tbl + 0x80 + i * 16) as *const ahci_sg);
+                    let addr = sg.addr_lo as u64 | (sg.addr_hi as u64) << 32;
+                    (addr as usize, (sg.flags_size & 0x3f_ffff) as usize + 1)
+                })
+                .collect();
+            (hdr, fis, prds)
+        }
+    }
+
+    /// Run the command in `slot`, failing with the error register.
+    fn execute(&mut self, slot: usize) -> Result<(), u8> {
+        let (_, fis, prds) = self.command(slot);
+        // Data base addresses and byte counts must be even.
+        assert!(
+            prds.iter()
+                .all(|&(addr, len)| addr % 2 == 0 && len % 2 == 0),
+            "invalid PRD entry in {prds:x?}"
+        );
+        let ext_count = |low: u8, high: u8| match (high as usize) << 8 | low as usize {
+            0 => 65536,
+            count => count,
+        };
+        let (write, count) = match fis.command {
+            ATA_CMD_ID_ATA => {
+                let id = self.identify();
+                let bytes: Vec<u8> = id.iter().flat_map(|w| w.to_le_bytes()).collect();
+                copy_to_prds(&prds, &bytes);
+                return Ok(());
+            }
+            ATA_CMD_READ_LOG_EXT => {
+                copy_to_prds(&prds, &[0; 512]);
+                return Ok(());
+            }
+            ATA_CMD_FLUSH | ATA_CMD_FLUSH_EXT | ATA_CMD_SET_FEATURES | ATA_CMD_VERIFY
+            | ATA_CMD_VERIFY_EXT => return Ok(()),
+            ATA_CMD_READ => (false, ext_count(fis.sector_count, 0).min(256)),
+            ATA_CMD_WRITE => (true, ext_count(fis.sector_count, 0).min(256)),
+            ATA_CMD_READ_EXT => (false, ext_count(fis.sector_count, fis.sector_count_exp)),
+            ATA_CMD_WRITE_EXT | ATA_CMD_WRITE_FUA_EXT => {
+                (true, ext_count(fis.sector_count, fis.sector_count_exp))
+            }
+            ATA_CMD_FPDMA_READ => (false, ext_count(fis.features, fis.features_exp)),
+            ATA_CMD_FPDMA_WRITE => (true, ext_count(fis.features, fis.features_exp)),
+            _ => return Err(ERROR_ABRT),
+        };
+        let lba = lba(&fis);
+        if lba + count as u64 > self.disk.sectors {
+            return Err(ERROR_IDNF);
+        }
+        if self
+            .fail_lba
+            .is_some_and(|fail| (lba..lba + count as u64).contains(&fail))
+        {
+            return Err(ERROR_UNC);
+        }
+        let size = self.disk.sector_size;
+        let range = lba as usize * size..(lba as usize + count) * size;
+        if write {
+            copy_from_prds(&prds, &mut self.data[range]);
+        } else {
+            copy_to_prds(&prds, &self.data[range]);
+        }
+        Ok(())
+    }
+
+    fn identify(&self) -> [u16; ATA_ID_WORDS] {
+        let disk = &self.disk;
+        let mut id = [0u16; ATA_ID_WORDS];
+        for (n, pair) in b"MOCK0001            ".chunks(2).enumerate() {
+            id[ATA_ID_SERNO + n] = u16::from_be_bytes([pair[0], pair[1]]);
+        }
+        id[ATA_ID_CAPABILITY] = 1 << 9 | 1 << 8;
+        let lba28 = disk.sectors.min(0x0fff_ffff) as u32;
+        id[ATA_ID_LBA_CAPACITY] = lba28 as u16;
+        id[ATA_ID_LBA_CAPACITY + 1] = (lba28 >> 16) as u16;
+        id[ATA_ID_QUEUE_DEPTH] = (disk.ncq_depth.max(1) - 1) as u16;
+        id[ATA_ID_SATA_CAPABILITY] = ((disk.ncq_depth > 1) as u16) << 8 | 1 << 2;
+        id[ATA_ID_MAJOR_VER] = 1 << 8;
+        id[ATA_ID_COMMAND_SET_1] = (disk.write_cache as u16) << 5;
+        id[ATA_ID_COMMAND_SET_2] = 0x4000 | (disk.lba48 as u16) << 10;
+        id[ATA_ID_CFSSE] = 0x4000 | (disk.fua as u16) << 6;
+        id[ATA_ID_CFS_ENABLE_1] = (disk.write_cache as u16) << 5;
+        id[ATA_ID_CFS_ENABLE_2] = (disk.lba48 as u16) << 10;
+        id[ATA_ID_CSF_DEFAULT] = 0x4000;
+        if disk.lba48 {
+            for n in 0..4 {
+                id[ATA_ID_LBA_CAPACITY_2 + n] = (disk.sectors >> (16 * n)) as u16;
+            }
+        }
+        if disk.sector_size != 512 {
+            let words = (disk.sector_size / 2) as u32;
+            id[ATA_ID_SECTOR_SIZE] = 0x5000;
+            id[ATA_ID_LOGICAL_SECTOR_SIZE] = words as u16;
+            id[ATA_ID_LOGICAL_SECTOR_SIZE + 1] = (words >> 16) as u16;
+        }
+        id
+    }
+}
+
+/// The LBA of a command, either 48-bit or 28-bit with the upper bits in
+/// `device`.
+fn lba(fis: &sata_fis_h2d) -> u64 {
+    let lba = u64::from_le_bytes([
+        fis.lba_low,
+        fis.lba_mid,
+        fis.lba_high,
+        fis.lba_low_exp,
+        fis.lba_mid_exp,
+        fis.lba_high_exp,
+        0,
+        0,
+    ]);
+    lba | ((fis.device & 0x0f) as u64) << 24
+}
+
+fn copy_to_prds(prds: &[(usize, usize)], mut data: &[u8]) {
+    for &(addr, len) in prds {
+        let len = len.min(data.len());
+        // SAFETY: The PRDT describes memory the driver mapped for the HBA.
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), addr as *mut u8, len) };
+        data = &data[len..];
+    }
+}
+
+fn copy_from_prds(prds: &[(usize, usize)], mut data: &mut [u8]) {
+    for &(addr, len) in prds {
+        let len = len.min(data.len());
+        // SAFETY: As above.
+        unsafe { ptr::copy_nonoverlapping(addr as *const u8, data.as_mut_ptr(), len) };
+        data = &mut data[len..];
+    }
+}
+
+mod tests {
+    use super::*;
+    use crate::{
+        AhciError, AhciEvent, DmaBuffer, DrivePolicy, RetryPolicy, Timeouts, VendorCommand,
+        ahci::COMRESET_RETRIES, probe::ProbeOutcome,
+    };
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|n| (n as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_with_latency() {
+        let mut disk = driver(MockDisk::default());
+        set_latency(Latency::Uniform { min: 1, max: 20 });
+        let data = pattern(64 * 1024, 7);
+        disk.write(100, &data).unwrap();
+        assert_eq!(sectors(100..228), data);
+        let mut buf = vec![0; data.len()];
+        disk.read(100, &mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn dma_buffers_transfer_directly() {
+        let mut disk = driver(MockDisk::default());
+        let mut buf = disk.alloc_dma_buffer(8 * 512).unwrap();
+        buf.copy_from_slice(&pattern(8 * 512, 7));
+        disk.write_dma(300, &buf).unwrap();
+        assert_eq!(sectors(300..308), *buf);
+        buf.fill(0);
+        disk.read_dma(300, &mut buf).unwrap();
+        assert_eq!(sectors(300..308), *buf);
+        assert!(!disk.policy().disable_bounce_buffers);
+
+        let mut odd = disk.alloc_dma_buffer(100).unwrap();
+        assert_eq!(disk.read_dma(0, &mut odd), Err(AhciError::InvalidArgument));
+    }
+
+    #[test]
+    fn large_transfer_is_split() {
+        let mut disk = driver(MockDisk {
+            lba48: false,
+            ncq_depth: 1,
+            ..Default::default()
+        });
+        set_latency(Latency::Fixed(3));
+        // 256 sectors is the limit of a 28-bit command.
+        let mut buf = vec![0; 600 * 512];
+        take_commands();
+        disk.read(1000, &mut buf).unwrap();
+        assert_eq!(buf, sectors(1000..1600));
+        assert_eq!(take_commands(), [ATA_CMD_READ; 3]);
+    }
+
+    #[cfg(feature = "ncq")]
+    #[test]
+    fn queued_completions_out_of_order() {
+        let mut disk = driver(MockDisk::default());
+        assert_eq!(disk.negotiated().ncq_depth, Some(32));
+        set_latency(Latency::Uniform { min: 1, max: 50 });
+        set_reorder(true);
+        take_completions();
+        let tickets: Vec<_> = (0..16u64)
+            .map(|n| {
+                let buf = DmaBuffer::new(4096, false).unwrap();
+                (n, disk.try_submit(n * 8, buf, false).ok().unwrap())
+            })
+            .collect();
+        let mut left = tickets;
+        while !left.is_empty() {
+            left.retain(|&(n, ticket)| match disk.poll_io(ticket) {
+                Some((result, buf)) => {
+                    result.unwrap();
+                    assert_eq!(&buf[..], sectors(n * 8..n * 8 + 8));
+                    false
+                }
+                None => true,
+            });
+            MockHal::current_ms();
+        }
+        let completions = take_completions();
+        assert_eq!(completions.len(), 16);
+        assert!(!completions.is_sorted());
+    }
+
+    #[test]
+    fn timeout_recovers_port() {
+        let mut disk = driver(MockDisk::default());
+        disk.set_timeouts(Timeouts {
+            command_ms: 50,
+            ..Default::default()
+        });
+        set_latency(Latency::Hang);
+        let mut buf = vec![0; 512];
+        assert!(matches!(
+            disk.read(10, &mut buf),
+            Err(AhciError::Timeout { .. })
+        ));
+        set_latency(Latency::Fixed(2));
+        disk.read(10, &mut buf).unwrap();
+        assert_eq!(buf, sectors(10..11));
+    }
+
+    #[test]
+    fn policy_applied_again_after_reset() {
+        let mut disk = driver(MockDisk {
+            ncq_depth: 1,
+            ..Default::default()
+        });
+        disk.set_policy(DrivePolicy {
+            write_cache: Some(false),
+            ..Default::default()
+        })
+        .unwrap();
+        disk.set_timeouts(Timeouts {
+            command_ms: 50,
+            ..Default::default()
+        });
+        set_latency(Latency::Hang);
+        let mut buf = vec![0; 512];
+        assert!(disk.read(10, &mut buf).is_err());
+        set_latency(Latency::Fixed(2));
+        take_commands();
+        disk.read(10, &mut buf).unwrap();
+        assert_eq!(take_commands(), [ATA_CMD_SET_FEATURES, ATA_CMD_READ_EXT]);
+        // Only once.
+        disk.read(10, &mut buf).unwrap();
+        assert_eq!(take_commands(), [ATA_CMD_READ_EXT]);
+        disk.resume().unwrap();
+        assert_eq!(take_commands(), [ATA_CMD_SET_FEATURES]);
+    }
+
+    #[test]
+    fn device_error_recovers_port() {
+        let mut disk = driver(MockDisk::default());
+        set_fail_lba(Some(20));
+        let mut buf = vec![0; 4 * 512];
+        assert!(matches!(
+            disk.read(18, &mut buf),
+            Err(AhciError::TaskFile { .. })
+        ));
+        set_fail_lba(None);
+        disk.read(18, &mut buf).unwrap();
+        assert_eq!(buf, sectors(18..22));
+    }
+
+    #[test]
+    fn aborted_and_passed_through_commands_are_not_retried() {
+        let mut disk = driver(MockDisk::default());
+        disk.set_retry_policy(RetryPolicy {
+            command_retries: 2,
+            ..Default::default()
+        });
+        take_commands();
+        // Unknown to the mock, so the device aborts it.
+        let cmd = VendorCommand::new(0xf8).retries(2);
+        assert!(matches!(
+            disk.exec_vendor_command(&cmd, &mut []),
+            Err(AhciError::TaskFile { .. })
+        ));
+        assert_eq!(take_commands(), [0xf8]);
+        set_fail_lba(Some(20));
+        let mut buf = vec![0; 512];
+        assert!(matches!(
+            disk.exec_ata(ATA_CMD_READ_EXT, 0, 20, 1, &mut buf, false),
+            Err(AhciError::TaskFile { .. })
+        ));
+        assert_eq!(take_commands(), [ATA_CMD_READ_EXT]);
+    }
+
+    #[test]
+    fn completes_in_irq_mode() {
+        let controller = controller(MockDisk::default());
+        set_irq_handler(Some(controller.irq_handler()));
+        let mut disk = controller.into_disks().pop().unwrap();
+        disk.set_irq_mode(true);
+        set_latency(Latency::Fixed(5));
+        let data = pattern(8192, 3);
+        disk.write(40, &data).unwrap();
+        let mut buf = vec![0; data.len()];
+        disk.read(40, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert!(interrupts() >= 2);
+    }
+
+    #[cfg(feature = "ncq")]
+    #[test]
+    fn irq_between_submissions_loses_no_completion() {
+        let controller = controller(MockDisk::default());
+        set_irq_handler(Some(controller.irq_handler()));
+        let mut disk = controller.into_disks().pop().unwrap();
+        disk.set_irq_mode(true);
+        set_spurious_irqs(true);
+        set_latency(Latency::Uniform { min: 0, max: 3 });
+        set_reorder(true);
+        take_completions();
+        let (mut next, mut left) = (0u64, Vec::new());
+        while next < 128 || !left.is_empty() {
+            while next < 128 && disk.available_slots() > 0 {
+                let buf = DmaBuffer::new(4096, false).unwrap();
+                left.push((next, disk.try_submit(next * 8, buf, false).ok().unwrap()));
+                next += 1;
+            }
+            left.retain(|&(n, ticket)| match disk.poll_io(ticket) {
+                Some((result, buf)) => {
+                    result.unwrap();
+                    assert_eq!(&buf[..], sectors(n * 8..n * 8 + 8));
+                    false
+                }
+                None => true,
+            });
+            MockHal::current_ms();
+        }
+        assert_eq!(take_completions().len(), 128);
+        assert!(interrupts() > 128);
+    }
+
+    #[test]
+    fn error_acknowledged_by_irq_is_reported() {
+        let controller = controller(MockDisk::default());
+        set_irq_handler(Some(controller.irq_handler()));
+        let mut disk = controller.into_disks().pop().unwrap();
+        disk.set_irq_mode(true);
+        set_spurious_irqs(true);
+        set_latency(Latency::Fixed(2));
+        set_fail_lba(Some(20));
+        let mut buf = vec![0; 4 * 512];
+        // The handler acknowledges PxIS.TFE before the waiter looks at it.
+        assert!(matches!(
+            disk.read(18, &mut buf),
+            Err(AhciError::TaskFile { .. })
+        ));
+        set_fail_lba(None);
+        disk.read(18, &mut buf).unwrap();
+        assert_eq!(buf, sectors(18..22));
+    }
+
+    #[test]
+    fn passive_mode_never_resets() {
+        let base = install(MockDisk::default());
+        // SAFETY: `base` is the register file of the mock HBA, which is leaked.
+        let mut disks = unsafe { AhciController::<MockHal>::try_new_passive(base) }.unwrap();
+        let mut disk = disks.pop().unwrap();
+        set_latency(Latency::Hang);
+        let mut buf = vec![0; 512];
+        assert!(matches!(
+            disk.read(10, &mut buf),
+            Err(AhciError::Timeout { .. })
+        ));
+        set_latency(Latency::Fixed(2));
+        disk.read(10, &mut buf).unwrap();
+        assert_eq!(buf, sectors(10..11));
+        assert_eq!(resets(), 0);
+    }
+
+    /// Bring up a controller for a disk whose link only comes up after
+    /// `comresets` COMRESETs.
+    fn stuck_controller(comresets: usize) -> Result<AhciController<MockHal>, AhciError> {
+        let base = install(MockDisk::default());
+        set_stuck_link(comresets);
+        // SAFETY: `base` is the register file of the mock HBA, which is leaked.
+        unsafe { AhciDriverBuilder::new().build(base) }
+    }
+
+    #[test]
+    fn stuck_link_recovered_by_comreset() {
+        let controller = stuck_controller(COMRESET_RETRIES - 1).unwrap();
+        assert_eq!(controller.probe_reports()[0].outcome, ProbeOutcome::Started);
+        let disk = controller.into_disks().pop().unwrap();
+        assert_eq!(disk.link_speed(), ISS::Gen3);
+    }
+
+    #[test]
+    fn stuck_link_falls_back_to_lower_speed() {
+        let controller = stuck_controller(COMRESET_RETRIES + 1).unwrap();
+        let disk = controller.into_disks().pop().unwrap();
+        assert_eq!(disk.link_speed(), ISS::Gen2);
+    }
+
+    #[test]
+    fn stuck_link_gives_up() {
+        // One more than the retries at full speed, at Gen 2 and at Gen 1.
+        assert!(matches!(
+            stuck_controller(COMRESET_RETRIES + 3),
+            Err(AhciError::NoDisks)
+        ));
+        assert_eq!(PxSSTS::from_bits(with(|hba| hba.read(PX_SSTS))).DET(), 1);
+    }
+
+    #[test]
+    fn removal_aborts_outstanding_io() {
+        let mut disk = driver(MockDisk::default());
+        set_latency(Latency::Hang);
+        let buf = DmaBuffer::new(512, false).unwrap();
+        let ticket = disk.try_submit(0, buf, false).ok().unwrap();
+        set_link(false);
+        let (result, _) = loop {
+            if let Some(io) = disk.poll_io(ticket) {
+                break io;
+            }
+            disk.tick();
+        };
+        assert_eq!(result, Err(AhciError::Aborted));
+        assert!(!disk.is_present());
+        assert!(matches!(
+            disk.pop_event(),
+            Some(AhciEvent::DeviceGone { aborted: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+//! Native Command Queuing statistics and error reporting.
+
+/// Byte of the NCQ Queue Management log listing the supported ABORT NCQ QUEUE
+/// subcommands.
+const NCQ_QM_ABORT: usize = 0;
+/// Byte of the NCQ Queue Management log listing the supported DEADLINE
+/// HANDLING subcommands.
+const NCQ_QM_DEADLINE: usize = 4;
+
+/// Byte of the Hybrid Information log holding the maximum hybrid priority
+/// level.
+pub(crate) const HYBRID_INFO_MAX_PRIORITY: usize = 6;
+
+/// The failed command and its registers, as reported in the NCQ Command
+/// Error log, see
+/// [`AhciDriver::read_ncq_error_log`](crate::AhciDriver::read_ncq_error_log).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NcqErrorLog {
+    /// Tag of the failed command, `None` if it was not a queued command
+    /// (NQ).
+    pub tag: Option<u8>,
+    /// Status register.
+    pub status: u8,
+    /// Error register.
+    pub error: u8,
+    /// LBA register: the first sector in error for a media error.
+    pub lba: u64,
+    /// Count register.
+    pub count: u16,
+    /// Device register.
+    pub device: u8,
+    /// Sense key, additional sense code and qualifier, all 0 if the drive
+    /// does not report sense data.
+    pub sense_key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl NcqErrorLog {
+    /// Parse page 0 of the NCQ Command Error log.
+    pub(crate) fn from_log(page: &[u8]) -> Self {
+        let lba = [page[4], page[5], page[6], page[8], page[9], page[10], 0, 0];
+        Self {
+            tag: (page[0] & 0x80 == 0).then_some(page[0] & 0x1f),
+            status: page[2],
+            error: page[3],
+            lba: u64::from_le_bytes(lba),
+            count: u16::from_le_bytes([page[12], page[13]]),
+            device: page[7],
+            sense_key: page[14],
+            asc: page[15],
+            ascq: page[16],
+        }
+    }
+}
+
+/// Queue management commands supported by the drive, from the NCQ Queue
+/// Management log.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NcqQueueManagement {
+    /// ABORT NCQ QUEUE, aborting a single tag.
+    pub abort_ncq: bool,
+    /// ABORT NCQ QUEUE, aborting all outstanding commands.
+    pub abort_all: bool,
+    /// ABORT NCQ QUEUE, aborting streaming commands only.
+    pub abort_streaming: bool,
+    /// ABORT NCQ QUEUE, aborting non-streaming commands only.
+    pub abort_non_streaming: bool,
+    /// ABORT NCQ QUEUE, aborting the commands selected by priority.
+    pub abort_selected: bool,
+    /// DEADLINE HANDLING, to make the drive honor completion deadlines.
+    pub deadline_handling: bool,
+}
+
+impl NcqQueueManagement {
+    /// Parse page 0 of the NCQ Queue Management log.
+    pub(crate) fn from_log(page: &[u8]) -> Self {
+        let abort = page[NCQ_QM_ABORT];
+        Self {
+            abort_ncq: abort & (1 << 0) != 0,
+            abort_all: abort & (1 << 1) != 0,
+            abort_streaming: abort & (1 << 2) != 0,
+            abort_non_streaming: abort & (1 << 3) != 0,
+            abort_selected: abort & (1 << 4) != 0,
+            deadline_handling: page[NCQ_QM_DEADLINE] & 0x3 != 0,
+        }
+    }
+}
+
+/// Queueing statistics of a drive, see
+/// [`AhciDriver::ncq_stats`](crate::AhciDriver::ncq_stats).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NcqStats {
+    /// Queue depth reported by the drive.
+    pub max_queue_depth: u8,
+    /// Queue depth currently in use.
+    pub queue_depth: u8,
+    /// Commands completed by the drive since it was attached.
+    pub completed: u64,
+    /// Commands aborted by the driver since it was attached, e.g. during
+    /// error recovery.
+    pub aborted: u64,
+    /// Queue management commands supported, if the drive implements the NCQ
+    /// Queue Management log.
+    pub queue_management: Option<NcqQueueManagement>,
+}
@@ -0,0 +1,128 @@
+//! The transfer mode a disk is operated in, decided once it is identified.
+
+use core::fmt;
+
+use crate::{
+    DrivePolicy, ZeroWriteOffload,
+    ata::{
+        ata_id_has_fua, ata_id_has_lba48, ata_id_has_ncq, ata_id_has_read_lookahead,
+        ata_id_has_sct_write_same, ata_id_has_trim, ata_id_has_wcache, ata_id_has_zero_after_trim,
+        ata_id_queue_depth, ata_id_read_lookahead_enabled, ata_id_wcache_enabled,
+    },
+    lba::max_sectors_per_command,
+    mmio::CAP,
+};
+
+/// Features used for a disk, from what the HBA, the drive and the
+/// [`DrivePolicy`] all allow, see
+/// [`AhciDriver::negotiated`](crate::AhciDriver::negotiated).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Negotiated {
+    /// Whether 48-bit commands are used.
+    pub lba48: bool,
+    /// Number of queued commands supported by both the HBA and the drive.
+    pub max_queue_depth: u8,
+    /// Queue depth used for reads and writes, `None` if they are not issued
+    /// as native queued commands, always without the `ncq` feature.
+    pub ncq_depth: Option<u8>,
+    /// Sectors per data block when transferring with READ/WRITE MULTIPLE,
+    /// `None` when transferring with DMA.
+    pub multiple: Option<u8>,
+    /// Whether writes can force unit access with WRITE DMA FUA EXT.
+    pub fua: bool,
+    /// Whether sectors can be discarded with DATA SET MANAGEMENT TRIM.
+    pub trim: bool,
+    /// How writes of all-zero sectors are offloaded, `Disabled` if the
+    /// drive can't honor the policy.
+    pub zero_write_offload: ZeroWriteOffload,
+    /// Maximum number of sectors transferred by a single command.
+    pub max_sectors: usize,
+    /// Whether the volatile write cache is enabled, `None` if the drive has
+    /// none.
+    pub write_cache: Option<bool>,
+    /// Whether read look-ahead is enabled, `None` if not supported.
+    pub read_lookahead: Option<bool>,
+    /// Whether data buffers may be above 4 GiB (CAP.S64A).
+    pub dma64: bool,
+}
+
+impl Negotiated {
+    /// Intersect the HBA capabilities, the IDENTIFY data of the drive and
+    /// `policy`.
+    ///
+    /// `multiple` is the data block size set with SET MULTIPLE MODE, if
+    /// that succeeded.
+    pub(crate) fn new(
+        cap: CAP,
+        id: &[u16],
+        policy: &DrivePolicy,
+        multiple: Option<u8>,
+        block_size: usize,
+    ) -> Self {
+        let lba48 = ata_id_has_lba48(id);
+        let max_queue_depth = if cap.SNCQ() && ata_id_has_ncq(id) {
+            ata_id_queue_depth(id).min(cap.NCS() + 1)
+        } else {
+            1
+        };
+        let depth = policy
+            .ncq_depth
+            .map_or(max_queue_depth, |depth| depth.min(max_queue_depth));
+        let zero_write_offload = match policy.zero_write_offload {
+            ZeroWriteOffload::Trim if ata_id_has_zero_after_trim(id) => ZeroWriteOffload::Trim,
+            ZeroWriteOffload::WriteSame if ata_id_has_sct_write_same(id) => {
+                ZeroWriteOffload::WriteSame
+            }
+            _ => ZeroWriteOffload::Disabled,
+        };
+        Self {
+            lba48,
+            max_queue_depth,
+            // Queued commands are 48-bit DMA commands.
+            ncq_depth: (cfg!(feature = "ncq") && lba48 && depth > 1 && multiple.is_none())
+                .then_some(depth),
+            multiple,
+            fua: lba48 && ata_id_has_fua(id),
+            trim: ata_id_has_trim(id),
+            zero_write_offload,
+            max_sectors: max_sectors_per_command(block_size, lba48),
+            write_cache: ata_id_has_wcache(id)
+                .then(|| policy.write_cache.unwrap_or(ata_id_wcache_enabled(id))),
+            read_lookahead: ata_id_has_read_lookahead(id).then(|| {
+                policy
+                    .read_lookahead
+                    .unwrap_or(ata_id_read_lookahead_enabled(id))
+            }),
+            dma64: cap.S64A(),
+        }
+    }
+
+    /// Queue depth the driver may use, 1 if commands are not queued.
+    pub fn queue_depth(&self) -> u8 {
+        self.ncq_depth.unwrap_or(1)
+    }
+}
+
+impl fmt::Display for Negotiated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LBA{}", if self.lba48 { 48 } else { 28 })?;
+        match (self.ncq_depth, self.multiple) {
+            (Some(depth), _) => write!(f, ", NCQ depth {depth}")?,
+            (None, Some(count)) => write!(f, ", PIO multiple {count}")?,
+            (None, None) => write!(f, ", DMA")?,
+        }
+        write!(f, ", {} sectors/cmd", self.max_sectors)?;
+        if self.fua {
+            write!(f, ", FUA")?;
+        }
+        if self.trim {
+            write!(f, ", TRIM")?;
+        }
+        match self.write_cache {
+            Some(true) => write!(f, ", write cache on"),
+            Some(false) => write!(f, ", write cache off"),
+            None => write!(f, ", no write cache"),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Read-only access to disks, for inspecting them without risk.
+
+use core::ops::{Deref, Range};
+
+#[cfg(feature = "ncq")]
+use crate::NcqStats;
+use crate::{AhciDriver, AhciError, AhciEvent, DmaBuffer, EraseReport, Hal, SsdWear};
+
+/// A disk probed with [`ProbeMode::Passive`](crate::ProbeMode::Passive).
+///
+/// Only operations that neither write to the disk nor change its settings
+/// are available: everything [`AhciDriver`] offers through a shared
+/// reference, plus reads. The drive policy was not applied when the disk
+/// was attached.
+pub struct ReadOnlyDisk<H>(AhciDriver<H>);
+
+impl<H: Hal> ReadOnlyDisk<H> {
+    pub(crate) fn new(disk: AhciDriver<H>) -> Self {
+        Self(disk)
+    }
+
+    /// See [`AhciDriver::read`].
+    pub fn read(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        self.0.read(block_id, buf)
+    }
+
+    /// See [`AhciDriver::read_at`].
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        self.0.read_at(offset, buf)
+    }
+
+    /// See [`AhciDriver::read_dma`].
+    pub fn read_dma(&mut self, block_id: u64, buf: &mut DmaBuffer<H>) -> Result<(), AhciError> {
+        self.0.read_dma(block_id, buf)
+    }
+
+    /// See [`AhciDriver::ssd_wear`].
+    pub fn ssd_wear(&mut self) -> Option<SsdWear> {
+        self.0.ssd_wear()
+    }
+
+    /// See [`AhciDriver::ncq_stats`].
+    #[cfg(feature = "ncq")]
+    pub fn ncq_stats(&mut self) -> Option<NcqStats> {
+        self.0.ncq_stats()
+    }
+
+    /// See [`AhciDriver::verify_erased`].
+    pub fn verify_erased(&mut self, range: Range<u64>, sample_stride: u64) -> Option<EraseReport> {
+        self.0.verify_erased(range, sample_stride)
+    }
+
+    /// See [`AhciDriver::dirty_flag`]. The marker sector must have been set
+    /// with [`set_dirty_marker`](Self::set_dirty_marker).
+    pub fn dirty_flag(&mut self) -> Option<bool> {
+        self.0.dirty_flag()
+    }
+
+    /// See [`AhciDriver::set_dirty_marker`].
+    pub fn set_dirty_marker(&mut self, lba: Option<u64>) -> Result<(), AhciError> {
+        self.0.set_dirty_marker(lba)
+    }
+
+    /// See [`AhciDriver::tick`].
+    pub fn tick(&mut self) {
+        self.0.tick();
+    }
+
+    /// See [`AhciDriver::pop_event`].
+    pub fn pop_event(&mut self) -> Option<AhciEvent> {
+        self.0.pop_event()
+    }
+}
+
+impl<H> Deref for ReadOnlyDisk<H> {
+    type Target = AhciDriver<H>;
+
+    fn deref(&self) -> &AhciDriver<H> {
+        &self.0
+    }
+}
@@ -0,0 +1,49 @@
+//! Port multiplier registers.
+
+use alloc::vec::Vec;
+
+/// Port number addressing the port multiplier itself.
+pub(crate) const PMP_CONTROL: u8 = 15;
+
+/// General Status and Control Registers, read and written through the
+/// control port.
+pub(crate) const PMP_GSCR_PROD_ID: u16 = 0;
+pub(crate) const PMP_GSCR_REV: u16 = 1;
+pub(crate) const PMP_GSCR_PORT_INFO: u16 = 2;
+
+/// Port Status and Control Registers of every device port, mirroring
+/// PxSSTS, PxSERR and PxSCTL.
+pub(crate) const PMP_PSCR_SSTATUS: u16 = 0;
+pub(crate) const PMP_PSCR_SERROR: u16 = 1;
+pub(crate) const PMP_PSCR_SCONTROL: u16 = 2;
+
+/// A port multiplier attached to an HBA port, see
+/// [`AhciDriver::port_multiplier`](crate::AhciDriver::port_multiplier).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PortMultiplier {
+    /// PCI-style vendor ID.
+    pub vendor_id: u16,
+    /// PCI-style device ID.
+    pub device_id: u16,
+    /// Revision of the Port Multiplier specification implemented, as a
+    /// bitmap of the supported revisions (GSCR[1]).
+    pub revision: u32,
+    /// Number of device ports, not counting the control port.
+    pub fan_out: u8,
+    /// Device ports with an ATA disk attached.
+    pub disks: Vec<u8>,
+}
+
+impl PortMultiplier {
+    /// Decode GSCR[0] to GSCR[2].
+    pub(crate) fn from_gscr(prod_id: u32, revision: u32, port_info: u32) -> Self {
+        Self {
+            vendor_id: prod_id as u16,
+            device_id: (prod_id >> 16) as u16,
+            revision,
+            // The count includes the control port on some devices.
+            fan_out: (port_info & 0xf).min(PMP_CONTROL as u32) as u8,
+            disks: Vec::new(),
+        }
+    }
+}
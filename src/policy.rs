@@ -0,0 +1,105 @@
+/// A power or acoustic management level for features that can either be
+/// disabled or set to a vendor-defined level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureLevel {
+    /// Turn the feature off.
+    Disabled,
+    /// Turn the feature on with the given level.
+    ///
+    /// For APM, `0x01..=0x7f` permit spin-down and `0x80..=0xfe` do not. For
+    /// AAM, `0x80` is the quietest and `0xfe` the fastest setting.
+    Level(u8),
+}
+
+/// How writes of all-zero sectors are handled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroWriteOffload {
+    /// Write zero sectors like any other data.
+    #[default]
+    Disabled,
+    /// Discard runs of zero sectors with TRIM, if the drive guarantees that
+    /// trimmed sectors read back as zeroes.
+    Trim,
+    /// Zero runs of zero sectors with SCT WRITE SAME, if supported.
+    WriteSame,
+}
+
+/// Workarounds for drives, or bridges in front of them, that misbehave with
+/// the commands the driver normally uses.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// Transfer data with PIO READ/WRITE MULTIPLE (EXT) instead of DMA, for
+    /// devices whose DMA reads return bad data.
+    ///
+    /// The number of sectors per data block is set to the maximum from
+    /// IDENTIFY word 47 with SET MULTIPLE MODE. Native command queuing is not
+    /// used. Ignored if the drive does not support the commands.
+    pub pio_multiple: bool,
+}
+
+/// Per-drive settings applied every time a device is attached.
+///
+/// Every field is optional: `None` leaves whatever the drive or the firmware
+/// configured untouched. Settings the drive does not advertise in its
+/// IDENTIFY data are skipped.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DrivePolicy {
+    /// Enable or disable the volatile write cache.
+    pub write_cache: Option<bool>,
+
+    /// Enable or disable read look-ahead.
+    pub read_lookahead: Option<bool>,
+
+    /// Advanced Power Management level.
+    pub apm: Option<FeatureLevel>,
+
+    /// Automatic Acoustic Management level.
+    pub aam: Option<FeatureLevel>,
+
+    /// Enable or disable HBA aggressive link power management (PxCMD.ALPE).
+    ///
+    /// Ignored when the HBA does not set CAP.SALP.
+    pub link_power_management: Option<bool>,
+
+    /// Hard cap on the service time of a command, in milliseconds.
+    ///
+    /// Commands still outstanding after this long time out and the port is
+    /// reset, even if the [`Timeouts`](crate::Timeouts) of the driver allow
+    /// more.
+    pub max_latency_ms: Option<u64>,
+
+    /// Limit on the time the drive spends on error recovery for a read or
+    /// write, in units of 100 ms, set with SCT Error Recovery Control.
+    ///
+    /// Without a limit a drive may retry a bad sector for tens of seconds.
+    /// 0 removes the limit.
+    pub error_recovery_limit: Option<u16>,
+
+    /// Upper bound on the number of queued commands used for the drive, see
+    /// [`AhciDriver::set_queue_depth`](crate::AhciDriver::set_queue_depth).
+    ///
+    /// A depth of 1 disables native command queuing.
+    pub ncq_depth: Option<u8>,
+
+    /// Replace writes of all-zero sectors with TRIM or WRITE SAME.
+    ///
+    /// Falls back to a regular write when the drive can't guarantee zeroes.
+    pub zero_write_offload: ZeroWriteOffload,
+
+    /// Present drives with larger logical sectors (4Kn) as having 512-byte
+    /// sectors, emulating partial sector writes with read-modify-write.
+    pub emulate_512: bool,
+
+    /// Wipe bounce buffers and other scratch memory used for data transfers
+    /// as soon as the command completes, so that sensitive data such as key
+    /// material does not linger in memory that was handed to the device.
+    pub zeroize_buffers: bool,
+
+    /// Fail transfers whose buffer the HBA can't use directly, because it
+    /// is not 4-byte aligned or, without CAP.S64A, not below 4 GiB, instead
+    /// of staging them in a bounce buffer.
+    pub disable_bounce_buffers: bool,
+
+    /// Workarounds for misbehaving devices.
+    pub quirks: Quirks,
+}
@@ -0,0 +1,212 @@
+//! Low-level access to a single port, with its lifecycle tracked in the type.
+//!
+//! The AHCI specification only allows some registers to be reprogrammed while
+//! the command engine and FIS receive are stopped. [`Port`] encodes the
+//! engine state in its type parameter so that such operations are only
+//! available in states where they are allowed:
+//!
+//! - [`Uninit`]: state unknown, e.g. as left behind by firmware.
+//! - [`Idle`]: PxCMD.ST, CR, FRE and FR are all clear. The command list and
+//!   FIS base addresses and FIS-based switching may be changed.
+//! - [`Running`]: FIS receive and the command engine are running. Commands
+//!   may be issued.
+
+use core::{marker::PhantomData, ptr::NonNull};
+
+use volatile::VolatilePtr;
+
+use crate::{
+    Hal,
+    ahci::port_regs,
+    hal::{DmaAddr, HalClock, wait_until_timeout},
+    mmio::{AhciMmio, PortRegisters, PortRegistersVolatileFieldAccess},
+};
+
+/// Port state: unknown.
+#[derive(Debug)]
+pub struct Uninit;
+
+/// Port state: command engine and FIS receive stopped.
+#[derive(Debug)]
+pub struct Idle;
+
+/// Port state: command engine and FIS receive running.
+#[derive(Debug)]
+pub struct Running;
+
+/// A port of an AHCI HBA in state `S`.
+pub struct Port<S> {
+    index: u8,
+    regs: VolatilePtr<'static, PortRegisters>,
+    _s: PhantomData<S>,
+}
+
+impl<S> Port<S> {
+    fn into_state<T>(self) -> Port<T> {
+        Port {
+            index: self.index,
+            regs: self.regs,
+            _s: PhantomData,
+        }
+    }
+
+    /// Port index.
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Interrupt Status (PxIS).
+    pub fn interrupt_status(&self) -> u32 {
+        self.regs.IS().read().into_bits()
+    }
+
+    /// Clear the bits set in `mask` in PxIS.
+    pub fn clear_interrupt_status(&mut self, mask: u32) {
+        self.regs.IS().write(mask.into());
+    }
+
+    /// Task File Data (PxTFD).
+    pub fn task_file(&self) -> u32 {
+        self.regs.TFD().read().into_bits()
+    }
+
+    /// Port Multiplier ports that sent a notification (PxSNTF.PMN), if the
+    /// HBA implements PxSNTF.
+    pub fn notifications(&self) -> u16 {
+        self.regs.SNTF().read() as u16
+    }
+
+    /// Clear the notifications set in `mask` in PxSNTF.
+    pub fn clear_notifications(&mut self, mask: u16) {
+        self.regs.SNTF().write(mask as u32);
+    }
+
+    /// Serial ATA Status (PxSSTS).
+    pub fn sata_status(&self) -> u32 {
+        self.regs.SSTS().read().into_bits()
+    }
+
+    /// Stop the command engine and FIS receive.
+    ///
+    /// Returns the port in an unknown state if they don't stop within the
+    /// 500 ms allowed by the specification.
+    pub fn stop<H: Hal>(self) -> Result<Port<Idle>, Port<Uninit>> {
+        let regs = self.regs;
+        regs.CMD().update(|cmd| cmd.with_ST(false));
+        if !wait_until_timeout::<HalClock<H>>(|| !regs.CMD().read().CR(), 500) {
+            return Err(self.into_state());
+        }
+        regs.CMD().update(|cmd| cmd.with_FRE(false));
+        if !wait_until_timeout::<HalClock<H>>(|| !regs.CMD().read().FR(), 500) {
+            return Err(self.into_state());
+        }
+        Ok(self.into_state())
+    }
+}
+
+impl Port<Uninit> {
+    /// Take over port `index` of the HBA whose registers are at `base`.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the virtual address of the HBA's MMIO register block,
+    /// `index` must be an implemented port, and nothing else, including an
+    /// [`AhciDriver`](crate::AhciDriver), may access that port.
+    pub unsafe fn new(base: usize, index: u8) -> Self {
+        assert!(index < 32);
+        // SAFETY: The caller guarantees `base` is a valid AHCI MMIO base address.
+        let mmio: VolatilePtr<'static, AhciMmio> =
+            unsafe { VolatilePtr::new(NonNull::new(base as *mut _).unwrap()) };
+        Self::from_regs(index, port_regs(&mmio, index))
+    }
+
+    /// Take over port `index` whose registers are `regs`, for the driver's
+    /// own bring-up of the port.
+    pub(crate) fn from_regs(index: u8, regs: VolatilePtr<'static, PortRegisters>) -> Self {
+        Self {
+            index,
+            regs,
+            _s: PhantomData,
+        }
+    }
+}
+
+impl Port<Idle> {
+    /// Set the Command List Base Address (PxCLB/PxCLBU), which must be 1 KiB
+    /// aligned.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be the address, as seen by the HBA, of a zeroed command
+    /// list of 32 command headers that stays valid and is not otherwise
+    /// used until the command engine is stopped again.
+    pub unsafe fn set_command_list_base(&mut self, addr: DmaAddr) {
+        assert!(addr.is_multiple_of(1024));
+        self.regs.CLB().write(addr as u32);
+        self.regs.CLBU().write((addr as u64 >> 32) as u32);
+    }
+
+    /// Set the FIS Base Address (PxFB/PxFBU), which must be 256 byte aligned,
+    /// or 4 KiB aligned when FIS-based switching is enabled.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be the address, as seen by the HBA, of a received FIS
+    /// area of 256 bytes, or 4 KiB with FIS-based switching, that stays
+    /// valid and is not otherwise used until FIS receive is stopped again.
+    pub unsafe fn set_fis_base(&mut self, addr: DmaAddr) {
+        assert!(addr.is_multiple_of(256));
+        self.regs.FB().write(addr as u32);
+        self.regs.FBU().write((addr as u64 >> 32) as u32);
+    }
+
+    /// Write FIS-based Switching Control (PxFBS).
+    pub fn set_fis_based_switching(&mut self, fbs: u32) {
+        self.regs.FBS().write(fbs);
+    }
+
+    /// Enable FIS receive, then start the command engine.
+    ///
+    /// The device should not be busy (PxTFD.STS.BSY and DRQ clear). Returns
+    /// the port in an unknown state if FIS receive does not start.
+    ///
+    /// # Safety
+    ///
+    /// The command list and FIS base addresses must satisfy the requirements
+    /// of [`set_command_list_base`](Self::set_command_list_base) and
+    /// [`set_fis_base`](Self::set_fis_base), and every command header in the
+    /// command list must point to a valid command table, as the HBA starts
+    /// accessing them through DMA.
+    pub unsafe fn start<H: Hal>(self) -> Result<Port<Running>, Port<Uninit>> {
+        let regs = self.regs;
+        regs.CMD().update(|cmd| cmd.with_FRE(true));
+        if !wait_until_timeout::<HalClock<H>>(|| regs.CMD().read().FR(), 500) {
+            return Err(self.into_state());
+        }
+        regs.CMD().update(|cmd| cmd.with_ST(true));
+        Ok(self.into_state())
+    }
+}
+
+impl Port<Running> {
+    /// Issue the commands in the slots set in `slots` (PxCI).
+    pub fn issue(&mut self, slots: u32) {
+        self.regs.CI().write(slots);
+    }
+
+    /// Commands issued and not completed yet (PxCI).
+    pub fn issued(&self) -> u32 {
+        self.regs.CI().read()
+    }
+
+    /// Mark the queued commands in `slots` as outstanding (PxSACT), before
+    /// issuing them.
+    pub fn set_active(&mut self, slots: u32) {
+        self.regs.SACT().write(slots);
+    }
+
+    /// Queued commands outstanding (PxSACT).
+    pub fn active(&self) -> u32 {
+        self.regs.SACT().read()
+    }
+}
@@ -0,0 +1,139 @@
+use core::fmt;
+
+/// How far the bring-up of a port got.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    /// The port was not probed.
+    #[default]
+    Skipped,
+    /// The port was initialized and its command engine started.
+    Started,
+    /// PxCMD.SUD could not be set.
+    SpinUpTimeout,
+    /// No device was detected on the port (PxSSTS.DET never left 0).
+    NoDevice,
+    /// A device is present but Phy communication was not established
+    /// (PxSSTS.DET stuck at 1).
+    NoCommunication,
+    /// The device did not clear BSY, DRQ and ERR after the engine was
+    /// started.
+    StartTimeout,
+    /// The command list, received FIS area or command tables could not be
+    /// allocated with [`Hal::dma_alloc`](crate::Hal::dma_alloc).
+    DmaAllocFailed,
+}
+
+/// Kind of device attached to a port, from its signature (PxSIG).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    /// ATA disk.
+    Ata,
+    /// ATAPI device, e.g. an optical drive.
+    Atapi,
+    /// Enclosure management bridge.
+    Semb,
+    /// Port multiplier.
+    PortMultiplier,
+    /// Unrecognized signature.
+    Unknown(u32),
+}
+
+impl DeviceType {
+    /// Decode a PxSIG value.
+    pub fn from_signature(sig: u32) -> Self {
+        match sig {
+            0x0000_0101 => Self::Ata,
+            0xeb14_0101 => Self::Atapi,
+            0xc33c_0101 => Self::Semb,
+            0x9669_0101 => Self::PortMultiplier,
+            sig => Self::Unknown(sig),
+        }
+    }
+}
+
+/// Raw register snapshots taken while a port was brought up.
+///
+/// The values are kept verbatim so that they can be included in bug reports
+/// about undetected disks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeReport {
+    /// Port index.
+    pub port: u8,
+    /// Result of the bring-up.
+    pub outcome: ProbeOutcome,
+    /// PxSIG at the end of the bring-up.
+    pub sig: u32,
+    /// PxTFD before the driver touched the port.
+    pub initial_tfd: u32,
+    /// PxSERR before it was cleared.
+    pub serr: u32,
+    /// PxSSTS at the end of the bring-up.
+    pub ssts: u32,
+}
+
+impl ProbeReport {
+    /// Kind of device found on the port.
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from_signature(self.sig)
+    }
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "port {}: {:?} (SIG={:#010x} TFD={:#x} SERR={:#x} SSTS={:#x})",
+            self.port, self.outcome, self.sig, self.initial_tfd, self.serr, self.ssts
+        )
+    }
+}
+
+/// How disks are brought up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMode {
+    /// Apply the drive policy and allow all commands.
+    #[default]
+    Active,
+    /// Only identify the disks, without changing their settings, and hand
+    /// them out as [`ReadOnlyDisk`](crate::ReadOnlyDisk)s, which can't issue
+    /// any command that writes to the disk.
+    ///
+    /// Neither the HBA nor the disks are ever reset: error recovery only
+    /// restarts the command engine of the port.
+    ///
+    /// Meant for rescue environments and for inspecting unknown disks.
+    Passive,
+}
+
+/// Timeouts of the phases of bring-up, in milliseconds, see
+/// [`AhciDriverBuilder::timeouts`](crate::AhciDriverBuilder::timeouts).
+///
+/// The phases of all ports probed together run in parallel, so each
+/// timeout bounds the time spent waiting for the slowest port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeTimeouts {
+    /// Resetting the HBA, until GHC.HR clears.
+    pub hba_reset_ms: u64,
+    /// Spinning up the device, until PxCMD.SUD reads back as set.
+    pub spin_up_ms: u64,
+    /// Detecting a device (PxSSTS.DET 1 or 3).
+    pub link_detect_ms: u64,
+    /// Establishing Phy communication (PxSSTS.DET 3), before falling back
+    /// to COMRESET and lower link speeds.
+    pub link_establish_ms: u64,
+    /// Waiting for the device to clear BSY, DRQ and ERR once the command
+    /// engine is started.
+    pub device_ready_ms: u64,
+}
+
+impl Default for ProbeTimeouts {
+    fn default() -> Self {
+        Self {
+            hba_reset_ms: 1000,
+            spin_up_ms: 1000,
+            link_detect_ms: 1000,
+            link_establish_ms: 1000,
+            device_ready_ms: 1000,
+        }
+    }
+}
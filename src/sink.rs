@@ -0,0 +1,54 @@
+//! Reporting of command errors to external consumers.
+
+use crate::AhciError;
+
+/// What the driver did to get the port working again after an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// The port was left as is, e.g. because the device reported the error
+    /// and is ready for the next command.
+    NotAttempted,
+    /// The command engine was restarted, aborting outstanding commands.
+    Recovered,
+    /// The port could not be recovered.
+    Failed,
+}
+
+impl From<bool> for Recovery {
+    fn from(recovered: bool) -> Self {
+        if recovered {
+            Self::Recovered
+        } else {
+            Self::Failed
+        }
+    }
+}
+
+/// A failed command, as passed to an [`ErrorSink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorRecord {
+    /// Port the command was issued on.
+    pub port: u8,
+    /// ATA command opcode.
+    pub command: u8,
+    /// Starting LBA, `None` for commands without one or if the failed
+    /// command is not known. For native queued commands this is the LBA
+    /// from the NCQ Command Error log, if it could be read.
+    pub lba: Option<u64>,
+    /// The error returned for the command.
+    pub error: AhciError,
+    /// PxTFD when the error was detected.
+    pub tfd: u32,
+    /// PxSERR when the error was detected.
+    pub serr: u32,
+    pub recovery: Recovery,
+}
+
+/// Receives the errors of a disk, for health monitoring or telemetry, see
+/// [`AhciDriver::set_error_sink`](crate::AhciDriver::set_error_sink).
+///
+/// Called from the context that detected the error, which is the one
+/// issuing or polling commands, with the driver borrowed; it must not block.
+pub trait ErrorSink: Send {
+    fn report(&mut self, record: &ErrorRecord);
+}
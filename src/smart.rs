@@ -0,0 +1,126 @@
+//! SMART attributes and health status.
+
+use alloc::vec::Vec;
+
+/// Number of attribute entries in the SMART data and thresholds sectors.
+const SMART_ATTR_ENTRIES: usize = 30;
+/// Size of an attribute or threshold entry.
+const SMART_ATTR_LEN: usize = 12;
+/// Byte offset of the first entry, after the revision number.
+const SMART_ATTR_OFFSET: usize = 2;
+
+/// Byte offset of the off-line data collection status in SMART READ DATA.
+const SMART_OFFLINE_STATUS: usize = 362;
+/// Byte offset of the self-test execution status in SMART READ DATA.
+const SMART_SELF_TEST_STATUS: usize = 363;
+
+/// An attribute reported by SMART READ DATA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmartAttribute {
+    /// Attribute ID, with vendor-specific meaning.
+    pub id: u8,
+    /// Status flags; bit 0 marks a pre-failure attribute.
+    pub flags: u16,
+    /// Normalized current value, usually 1 to 253 with higher being better.
+    pub value: u8,
+    /// Worst normalized value seen.
+    pub worst: u8,
+    /// Raw value, 48 bits with vendor-specific encoding.
+    pub raw: u64,
+}
+
+impl SmartAttribute {
+    /// Whether failure of this attribute predicts failure of the drive, as
+    /// opposed to reflecting its age.
+    pub fn is_prefailure(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    /// Whether the value is at or below `threshold`. A threshold of 0 is
+    /// never crossed.
+    pub fn crossed(&self, threshold: &SmartThreshold) -> bool {
+        threshold.id == self.id && threshold.threshold != 0 && self.value <= threshold.threshold
+    }
+}
+
+/// The threshold of an attribute, from SMART READ THRESHOLDS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmartThreshold {
+    /// Attribute ID.
+    pub id: u8,
+    /// Value at or below which the attribute is considered failing.
+    pub threshold: u8,
+}
+
+/// The SMART data of a drive, see
+/// [`AhciDriver::read_smart_data`](crate::AhciDriver::read_smart_data).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmartData {
+    /// Revision of the data structure.
+    pub revision: u16,
+    /// Attributes in use, in the order the drive reports them.
+    pub attributes: Vec<SmartAttribute>,
+    /// Off-line data collection status byte.
+    pub offline_status: u8,
+    /// Self-test execution status byte; the high nibble is the result of the
+    /// last self-test, 0 meaning it passed.
+    pub self_test_status: u8,
+}
+
+impl SmartData {
+    /// Parse the sector returned by SMART READ DATA.
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        let attributes = entries(data)
+            .map(|attr| {
+                let mut raw = [0u8; 8];
+                raw[..6].copy_from_slice(&attr[5..11]);
+                SmartAttribute {
+                    id: attr[0],
+                    flags: u16::from_le_bytes([attr[1], attr[2]]),
+                    value: attr[3],
+                    worst: attr[4],
+                    raw: u64::from_le_bytes(raw),
+                }
+            })
+            .collect();
+        Self {
+            revision: u16::from_le_bytes([data[0], data[1]]),
+            attributes,
+            offline_status: data[SMART_OFFLINE_STATUS],
+            self_test_status: data[SMART_SELF_TEST_STATUS],
+        }
+    }
+
+    /// The attribute with ID `id`, if the drive reports it.
+    pub fn attribute(&self, id: u8) -> Option<&SmartAttribute> {
+        self.attributes.iter().find(|attr| attr.id == id)
+    }
+
+    /// The attributes at or below their threshold in `thresholds`.
+    pub fn failing<'a>(
+        &'a self,
+        thresholds: &'a [SmartThreshold],
+    ) -> impl Iterator<Item = &'a SmartAttribute> {
+        self.attributes
+            .iter()
+            .filter(|attr| thresholds.iter().any(|t| attr.crossed(t)))
+    }
+}
+
+/// Parse the sector returned by SMART READ THRESHOLDS.
+pub(crate) fn parse_thresholds(data: &[u8]) -> Vec<SmartThreshold> {
+    entries(data)
+        .map(|entry| SmartThreshold {
+            id: entry[0],
+            threshold: entry[1],
+        })
+        .collect()
+}
+
+/// The entries of a SMART data or thresholds sector, skipping unused ones
+/// (ID 0).
+fn entries(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data[SMART_ATTR_OFFSET..SMART_ATTR_OFFSET + SMART_ATTR_ENTRIES * SMART_ATTR_LEN]
+        .chunks_exact(SMART_ATTR_LEN)
+        .filter(|entry| entry[0] != 0)
+}
@@ -0,0 +1,50 @@
+//! Per-port command statistics.
+
+/// Commands processed on a port since it was attached or the statistics
+/// were last reset, see [`AhciDriver::stats`](crate::AhciDriver::stats).
+///
+/// Unlike the [`IoStats`](crate::IoStats) of a handle, this counts every
+/// command the driver issues, including those issued internally.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AhciStats {
+    /// Commands issued to the HBA.
+    pub commands: u64,
+    /// Commands the HBA completed, successfully or not.
+    pub completed: u64,
+    /// Bytes transferred from the device by successful commands.
+    pub bytes_read: u64,
+    /// Bytes transferred to the device by successful commands.
+    pub bytes_written: u64,
+    /// Commands that timed out.
+    pub timeouts: u64,
+    /// Commands the device completed with an error.
+    pub task_file_errors: u64,
+    /// Commands issued again after failing.
+    pub retries: u64,
+    /// Shortest completion latency in milliseconds, `None` before the
+    /// first completion.
+    pub min_latency_ms: Option<u64>,
+    /// Longest completion latency in milliseconds.
+    pub max_latency_ms: u64,
+    /// Sum of the completion latencies in milliseconds.
+    pub total_latency_ms: u64,
+}
+
+impl AhciStats {
+    /// Mean completion latency in milliseconds, `None` before the first
+    /// completion.
+    pub fn average_latency_ms(&self) -> Option<u64> {
+        self.total_latency_ms.checked_div(self.completed)
+    }
+
+    /// Account a command that completed after `latency_ms` milliseconds.
+    pub(crate) fn complete(&mut self, latency_ms: u64) {
+        self.completed += 1;
+        self.total_latency_ms += latency_ms;
+        self.max_latency_ms = self.max_latency_ms.max(latency_ms);
+        self.min_latency_ms = Some(
+            self.min_latency_ms
+                .map_or(latency_ms, |min| min.min(latency_ms)),
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Double-buffered sequential writes.
+
+use crate::{AhciDriver, AhciError, DmaBuffer, Hal, IoTicket};
+
+/// Writes a stream of data to consecutive blocks, keeping one command in
+/// flight while the next buffer is filled.
+///
+/// This hides most of the device latency of large sequential writes
+/// without native command queuing. The data is written in chunks of a
+/// fixed size; call [`finish`](Self::finish) to write the remainder and
+/// wait for everything to reach the disk.
+pub struct StreamWriter<'a, H: Hal> {
+    disk: &'a mut AhciDriver<H>,
+    /// Block the next chunk is written to.
+    next_block: u64,
+    /// Buffer being filled and the number of bytes in it.
+    fill: Option<DmaBuffer<H>>,
+    filled: usize,
+    /// Request writing the previous chunk.
+    inflight: Option<IoTicket>,
+    /// Buffer ready to be filled once the current one is submitted.
+    spare: Option<DmaBuffer<H>>,
+    /// Blocks submitted so far.
+    written: u64,
+}
+
+impl<'a, H: Hal> StreamWriter<'a, H> {
+    /// Start writing at `block_id` in chunks of `chunk_size` bytes.
+    ///
+    /// Fails with [`AhciError::InvalidArgument`] unless `chunk_size` is a
+    /// non-zero multiple of the native sector size that fits in a single command.
+    pub fn new(
+        disk: &'a mut AhciDriver<H>,
+        block_id: u64,
+        chunk_size: usize,
+    ) -> Result<Self, AhciError> {
+        let sector = disk.native_block_size();
+        let max = disk.negotiated().max_sectors * sector;
+        if chunk_size == 0 || !chunk_size.is_multiple_of(sector) || chunk_size > max {
+            return Err(AhciError::InvalidArgument);
+        }
+        let fill = disk.alloc_dma_buffer(chunk_size)?;
+        let spare = disk.alloc_dma_buffer(chunk_size)?;
+        Ok(Self {
+            disk,
+            next_block: block_id,
+            fill: Some(fill),
+            filled: 0,
+            inflight: None,
+            spare: Some(spare),
+            written: 0,
+        })
+    }
+
+    /// Block the next chunk is written to.
+    pub fn position(&self) -> u64 {
+        self.next_block
+    }
+
+    /// Append `data` to the stream, submitting every chunk that fills up.
+    ///
+    /// On error the stream is left at the failed chunk and should be
+    /// abandoned.
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), AhciError> {
+        while !data.is_empty() {
+            let buf = self.fill.as_mut().ok_or(AhciError::InvalidArgument)?;
+            let len = data.len().min(buf.len() - self.filled);
+            buf[self.filled..self.filled + len].copy_from_slice(&data[..len]);
+            self.filled += len;
+            data = &data[len..];
+            if self.filled == buf.len() {
+                self.submit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any buffered data and wait for all of it to complete.
+    ///
+    /// Returns the number of blocks written. Fails with
+    /// [`AhciError::PartialBlock`] if the data written does not end on a
+    /// native sector boundary.
+    pub fn finish(mut self) -> Result<u64, AhciError> {
+        if self.filled > 0 {
+            let block_size = self.disk.native_block_size();
+            if !self.filled.is_multiple_of(block_size) {
+                return Err(AhciError::PartialBlock {
+                    len: self.filled,
+                    block_size,
+                });
+            }
+            // Shrink the last chunk to the data in it.
+            let mut last = self.disk.alloc_dma_buffer(self.filled)?;
+            let buf = self.fill.as_ref().ok_or(AhciError::InvalidArgument)?;
+            last.copy_from_slice(&buf[..self.filled]);
+            self.fill = Some(last);
+            self.submit()?;
+        }
+        self.wait()?;
+        Ok(self.written)
+    }
+
+    /// Submit the buffer being filled, after the previous chunk completed.
+    fn submit(&mut self) -> Result<(), AhciError> {
+        self.wait()?;
+        let buf = self.fill.take().ok_or(AhciError::InvalidArgument)?;
+        let blocks = (buf.len() / self.disk.block_size()) as u64;
+        let ticket = self
+            .disk
+            .try_submit(self.next_block, buf, true)
+            .map_err(|(err, _)| err)?;
+        self.inflight = Some(ticket);
+        self.next_block += blocks;
+        self.written += blocks;
+        self.filled = 0;
+        self.fill = self.spare.take();
+        Ok(())
+    }
+
+    /// Wait for the chunk in flight and keep its buffer for reuse.
+    fn wait(&mut self) -> Result<(), AhciError> {
+        let Some(ticket) = self.inflight.take() else {
+            return Ok(());
+        };
+        let command = self.disk.io_command(true);
+        let (result, buf) = self
+            .disk
+            .wait_io(ticket)
+            .ok_or(AhciError::Timeout { command })?;
+        self.spare = Some(buf);
+        result
+    }
+}
+
+impl<H: Hal> Drop for StreamWriter<'_, H> {
+    fn drop(&mut self) {
+        // Collect the last chunk so its buffer is not left with the driver.
+        let _ = self.wait();
+    }
+}
@@ -0,0 +1,222 @@
+//! ATA pass-through commands.
+
+use crate::{
+    AhciError,
+    lba::{MAX_BYTES_PER_COMMAND, MAX_LBA48},
+    types::sata_fis_h2d,
+};
+
+/// Size of the sectors [`VendorCommand`] payloads are counted in.
+const SECTOR_SIZE: usize = 512;
+
+/// Direction of the data phase of a pass-through command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataDirection {
+    /// The command transfers no data; the buffer must be empty.
+    None,
+    /// The buffer is filled with data read from the device.
+    FromDevice,
+    /// The buffer is written to the device.
+    ToDevice,
+}
+
+/// The registers of an ATA command, see
+/// [`AhciDriver::submit_ata_command`](crate::AhciDriver::submit_ata_command).
+///
+/// `lba` and `count` are placed in the 48-bit LBA and sector count fields;
+/// 28-bit commands ignore their upper bytes and take LBA bits 27:24 from the
+/// low nibble of `device`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TaskFile {
+    pub command: u8,
+    pub features: u16,
+    pub lba: u64,
+    pub count: u16,
+    /// Device register, 0x40 (LBA mode) by default.
+    pub device: u8,
+}
+
+impl TaskFile {
+    /// A task file for `command` with all other registers cleared.
+    pub fn new(command: u8) -> Self {
+        Self {
+            command,
+            device: 0x40,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn to_fis(self) -> sata_fis_h2d {
+        let mut fis = sata_fis_h2d::command(self.command);
+        fis.set_lba48(self.lba, self.count);
+        fis.features = self.features as u8;
+        fis.features_exp = (self.features >> 8) as u8;
+        fis.device = self.device;
+        fis
+    }
+}
+
+/// A vendor specific ATA command and the shape of its payload, see
+/// [`AhciDriver::exec_vendor_command`](crate::AhciDriver::exec_vendor_command).
+///
+/// Built from the opcode, e.g.
+/// `VendorCommand::new(0xF8).features(1).read_sectors(8)` for a command
+/// reading 8 sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VendorCommand {
+    tf: TaskFile,
+    dir: DataDirection,
+    len: usize,
+    timeout_ms: Option<u64>,
+    retries: Option<u8>,
+}
+
+impl VendorCommand {
+    /// A command with opcode `command`, no payload and all other registers
+    /// cleared.
+    pub fn new(command: u8) -> Self {
+        Self {
+            tf: TaskFile::new(command),
+            dir: DataDirection::None,
+            len: 0,
+            timeout_ms: None,
+            retries: None,
+        }
+    }
+
+    /// Set the features register.
+    pub fn features(mut self, features: u16) -> Self {
+        self.tf.features = features;
+        self
+    }
+
+    /// Set the 48-bit LBA registers.
+    pub fn lba(mut self, lba: u64) -> Self {
+        self.tf.lba = lba;
+        self
+    }
+
+    /// Set the sector count register, without changing the payload.
+    pub fn count(mut self, count: u16) -> Self {
+        self.tf.count = count;
+        self
+    }
+
+    /// Set the device register.
+    pub fn device(mut self, device: u8) -> Self {
+        self.tf.device = device;
+        self
+    }
+
+    /// Read a payload of `len` bytes from the device.
+    pub fn data_in(mut self, len: usize) -> Self {
+        self.dir = DataDirection::FromDevice;
+        self.len = len;
+        self
+    }
+
+    /// Write a payload of `len` bytes to the device.
+    pub fn data_out(mut self, len: usize) -> Self {
+        self.dir = DataDirection::ToDevice;
+        self.len = len;
+        self
+    }
+
+    /// Read `count` 512-byte sectors, with the count in the sector count
+    /// register as most vendor commands expect.
+    pub fn read_sectors(self, count: u16) -> Self {
+        self.count(count).data_in(count as usize * SECTOR_SIZE)
+    }
+
+    /// Write `count` 512-byte sectors, with the count in the sector count
+    /// register.
+    pub fn write_sectors(self, count: u16) -> Self {
+        self.count(count).data_out(count as usize * SECTOR_SIZE)
+    }
+
+    /// Wait up to `timeout_ms` milliseconds for the command instead of the
+    /// driver's [`Timeouts`](crate::Timeouts), e.g. for a slow vendor
+    /// diagnostic.
+    pub fn timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.timeout_ms = Some(timeout_ms);
+        self
+    }
+
+    /// Issue the command again up to `retries` times if it fails.
+    ///
+    /// Vendor commands are not retried otherwise, whatever the driver's
+    /// [`RetryPolicy`](crate::RetryPolicy), since they may not be safe to
+    /// issue twice.
+    pub fn retries(mut self, retries: u8) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Time limit set with [`timeout_ms`](Self::timeout_ms).
+    pub fn timeout(&self) -> Option<u64> {
+        self.timeout_ms
+    }
+
+    /// Retry count set with [`retries`](Self::retries).
+    pub fn retry_count(&self) -> Option<u8> {
+        self.retries
+    }
+
+    /// The registers the command is issued with.
+    pub fn task_file(&self) -> TaskFile {
+        self.tf
+    }
+
+    /// Direction of the payload.
+    pub fn direction(&self) -> DataDirection {
+        self.dir
+    }
+
+    /// Size of the payload in bytes.
+    pub fn payload_len(&self) -> usize {
+        self.len
+    }
+
+    /// Check that a single command can carry the payload and that the LBA
+    /// fits in 48 bits.
+    ///
+    /// Payloads must be non-empty unless the direction is
+    /// [`DataDirection::None`], of an even number of bytes as required by
+    /// the PRDT, and at most
+    /// [`MAX_BYTES_PER_COMMAND`](crate::MAX_BYTES_PER_COMMAND) bytes.
+    pub fn validate(&self) -> Result<(), AhciError> {
+        let payload_ok = match self.dir {
+            DataDirection::None => self.len == 0,
+            _ => self.len != 0 && self.len.is_multiple_of(2) && self.len <= MAX_BYTES_PER_COMMAND,
+        };
+        if !payload_ok || self.tf.lba >= MAX_LBA48 {
+            return Err(AhciError::InvalidArgument);
+        }
+        Ok(())
+    }
+}
+
+/// The registers returned by the device on completion of a pass-through
+/// command.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TaskFileResult {
+    pub status: u8,
+    pub error: u8,
+    pub lba: u64,
+    pub count: u16,
+    pub device: u8,
+}
+
+impl TaskFileResult {
+    /// Parse the register fields of a D2H Register or PIO Setup FIS, which
+    /// share their layout, with `status` and `error` taken from PxTFD.
+    pub(crate) fn from_fis(fis: &[u8], status: u8, error: u8) -> Self {
+        Self {
+            status,
+            error,
+            lba: u64::from_le_bytes([fis[4], fis[5], fis[6], fis[8], fis[9], fis[10], 0, 0]),
+            count: u16::from_le_bytes([fis[12], fis[13]]),
+            device: fis[7],
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Token bucket rate limiting of disk handles.
+
+use core::hint::spin_loop;
+
+use crate::hal::Clock;
+
+/// Bandwidth and request rate caps for a
+/// [`DiskHandle`](crate::DiskHandle).
+///
+/// Up to one second worth of unused budget accumulates, allowing short
+/// bursts above the rate. A rate of 0 means no limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Maximum bytes transferred per second.
+    pub bytes_per_sec: Option<u64>,
+    /// Maximum requests per second.
+    pub iops: Option<u64>,
+}
+
+/// A token bucket refilled at `rate` tokens per second, holding at most one
+/// second worth of tokens.
+///
+/// Tokens are counted in thousandths so that refilling by the millisecond
+/// is exact.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    /// May go negative when a request costs more than a full bucket.
+    milli_tokens: i64,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let mut bucket = Self {
+            rate,
+            milli_tokens: 0,
+        };
+        bucket.milli_tokens = bucket.capacity();
+        bucket
+    }
+
+    fn capacity(&self) -> i64 {
+        scaled(self.rate, 1000)
+    }
+
+    fn refill(&mut self, elapsed_ms: u64) {
+        let added = scaled(self.rate, elapsed_ms);
+        self.milli_tokens = self.milli_tokens.saturating_add(added).min(self.capacity());
+    }
+
+    /// Whether a request costing `cost` tokens may proceed. Requests larger
+    /// than the bucket wait for a full bucket.
+    fn ready(&self, cost: u64) -> bool {
+        self.milli_tokens >= scaled(cost, 1000).min(self.capacity())
+    }
+
+    fn take(&mut self, cost: u64) {
+        self.milli_tokens = self.milli_tokens.saturating_sub(scaled(cost, 1000));
+    }
+}
+
+/// `a * b`, saturating at `i64::MAX`.
+fn scaled(a: u64, b: u64) -> i64 {
+    a.saturating_mul(b).min(i64::MAX as u64) as i64
+}
+
+/// The state of a [`RateLimit`].
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    bytes: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    last_ms: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new<C: Clock>(limit: RateLimit) -> Self {
+        Self {
+            bytes: limit.bytes_per_sec.filter(|&r| r > 0).map(TokenBucket::new),
+            ops: limit.iops.filter(|&r| r > 0).map(TokenBucket::new),
+            last_ms: C::now_ms(),
+        }
+    }
+
+    fn refill<C: Clock>(&mut self) {
+        let now = C::now_ms();
+        let elapsed = now.saturating_sub(self.last_ms);
+        self.last_ms = now;
+        for bucket in [&mut self.bytes, &mut self.ops].into_iter().flatten() {
+            bucket.refill(elapsed);
+        }
+    }
+
+    fn ready(&self, bytes: u64) -> bool {
+        self.bytes.as_ref().is_none_or(|b| b.ready(bytes))
+            && self.ops.as_ref().is_none_or(|b| b.ready(1))
+    }
+
+    /// Wait until a request transferring `bytes` is within the limit, then
+    /// charge it.
+    pub(crate) fn acquire<C: Clock>(&mut self, bytes: u64) {
+        self.refill::<C>();
+        while !self.ready(bytes) {
+            spin_loop();
+            self.refill::<C>();
+        }
+        if let Some(bucket) = &mut self.bytes {
+            bucket.take(bytes);
+        }
+        if let Some(bucket) = &mut self.ops {
+            bucket.take(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::tests::FakeClock;
+
+    #[test]
+    fn burst_then_rate() {
+        let mut throttle = Throttle::new::<FakeClock>(RateLimit {
+            bytes_per_sec: None,
+            iops: Some(100),
+        });
+        // A full bucket lets a second worth of requests through without
+        // waiting, reading the clock once each.
+        let start = FakeClock::now();
+        for _ in 0..100 {
+            throttle.acquire::<FakeClock>(0);
+        }
+        assert_eq!(FakeClock::now() - start, 100);
+        // Beyond that requests go at the rate.
+        for _ in 0..100 {
+            throttle.acquire::<FakeClock>(0);
+        }
+        let elapsed = FakeClock::now() - start;
+        assert!((1000..=1002).contains(&elapsed), "{elapsed}");
+    }
+
+    #[test]
+    fn large_request_waits_for_full_bucket() {
+        let mut throttle = Throttle::new::<FakeClock>(RateLimit {
+            bytes_per_sec: Some(1000),
+            iops: None,
+        });
+        throttle.acquire::<FakeClock>(1000);
+        let start = FakeClock::now();
+        // Larger than the bucket: waits until it is full again, then goes
+        // into debt.
+        throttle.acquire::<FakeClock>(5000);
+        let elapsed = FakeClock::now() - start;
+        assert!((999..=1001).contains(&elapsed), "{elapsed}");
+        let start = FakeClock::now();
+        throttle.acquire::<FakeClock>(1);
+        let elapsed = FakeClock::now() - start;
+        assert!((4000..=4002).contains(&elapsed), "{elapsed}");
+    }
+}
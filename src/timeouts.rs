@@ -0,0 +1,52 @@
+//! Time limits and retries of commands and port recovery.
+
+/// How long the driver waits for an attached disk, in milliseconds, see
+/// [`AhciDriver::set_timeouts`](crate::AhciDriver::set_timeouts).
+///
+/// Bringing up the ports is configured separately with
+/// [`ProbeTimeouts`](crate::ProbeTimeouts).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Completion of a command.
+    pub command_ms: u64,
+    /// Completion of the first command after the link left a low power
+    /// state, when the drive may have to spin up as well.
+    pub spin_up_ms: u64,
+    /// Command engine to stop (PxCMD.CR).
+    pub engine_stop_ms: u64,
+    /// Link to come back up after a COMRESET.
+    pub link_up_ms: u64,
+    /// Device to clear BSY and DRQ once the port was recovered.
+    pub device_ready_ms: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            command_ms: 1000,
+            spin_up_ms: 10_000,
+            engine_stop_ms: 500,
+            link_up_ms: 1000,
+            device_ready_ms: 1000,
+        }
+    }
+}
+
+/// How failed commands are retried, see
+/// [`AhciDriver::set_retry_policy`](crate::AhciDriver::set_retry_policy).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a command that failed with a device error or timed
+    /// out is issued again after the port was recovered, before the error
+    /// is returned.
+    ///
+    /// Only commands waited for one at a time are retried, not native
+    /// queued commands. Commands the device aborted and commands passed
+    /// through with [`submit_ata_command`](crate::AhciDriver::submit_ata_command)
+    /// are not retried either, see
+    /// [`VendorCommand::retries`](crate::VendorCommand::retries).
+    pub command_retries: u8,
+    /// How many more COMRESETs recovery tries if the link does not come
+    /// back up after the first.
+    pub comreset_retries: u8,
+}
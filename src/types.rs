@@ -2,6 +2,8 @@
 
 use volatile::VolatileFieldAccess;
 
+use crate::ata::SATA_FIS_TYPE_REGISTER_H2D;
+
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct ahci_cmd_hdr {
@@ -12,7 +14,7 @@ pub struct ahci_cmd_hdr {
     pub reserved: [u32; 4],
 }
 
-const AHCI_MAX_CMDS: usize = 32;
+pub const AHCI_MAX_CMDS: usize = 32;
 
 pub type ahci_cmd_list = [ahci_cmd_hdr; AHCI_MAX_CMDS];
 
@@ -27,10 +29,24 @@ pub struct ahci_sg {
     pub flags_size: u32,
 }
 
-pub const AHCI_MAX_SG: usize = 56;
+/// Number of PRDT entries per command table, see the `prdt-*` features.
+pub const AHCI_MAX_SG: usize = if cfg!(feature = "prdt-8") {
+    8
+} else if cfg!(feature = "prdt-16") {
+    16
+} else if cfg!(feature = "prdt-32") {
+    32
+} else {
+    56
+};
 pub const AHCI_MAX_BYTES_PER_SG: usize = 4 * 1024 * 1024; // 4 MiB
 pub const AHCI_MAX_BYTES_PER_CMD: usize = AHCI_MAX_SG * AHCI_MAX_BYTES_PER_SG;
 
+// PRDTL is 16 bits wide.
+const _: () = assert!(AHCI_MAX_SG >= 1 && AHCI_MAX_SG <= 0xffff);
+// DBC is 22 bits wide, 0-based, and must describe an even byte count.
+const _: () = assert!(AHCI_MAX_BYTES_PER_SG <= 1 << 22 && AHCI_MAX_BYTES_PER_SG.is_multiple_of(2));
+
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct sata_fis_h2d {
@@ -50,7 +66,49 @@ pub struct sata_fis_h2d {
     pub sector_count_exp: u8,
     pub res1: u8,
     pub control: u8,
-    pub res2: [u8; 4],
+    pub auxiliary: [u8; 4],
+}
+
+impl sata_fis_h2d {
+    /// A register H2D FIS carrying `command`.
+    pub fn command(command: u8) -> Self {
+        Self {
+            fis_type: SATA_FIS_TYPE_REGISTER_H2D,
+            pm_port_c: 0x80,
+            command,
+            ..Default::default()
+        }
+    }
+
+    /// The LBA of the command, either 48-bit or 28-bit with the upper bits in
+    /// `device`.
+    pub fn lba(&self) -> u64 {
+        let lba = u64::from_le_bytes([
+            self.lba_low,
+            self.lba_mid,
+            self.lba_high,
+            self.lba_low_exp,
+            self.lba_mid_exp,
+            self.lba_high_exp,
+            0,
+            0,
+        ]);
+        // Bits 0-3 of `device` are reserved by 48-bit commands.
+        lba | ((self.device & 0x0f) as u64) << 24
+    }
+
+    /// Fill in a 48-bit LBA and a 16-bit sector count.
+    pub fn set_lba48(&mut self, lba: u64, count: u16) {
+        self.lba_low = lba as u8;
+        self.lba_mid = (lba >> 8) as u8;
+        self.lba_high = (lba >> 16) as u8;
+        self.lba_low_exp = (lba >> 24) as u8;
+        self.lba_mid_exp = (lba >> 32) as u8;
+        self.lba_high_exp = (lba >> 40) as u8;
+        self.device = 0x40; // LBA mode
+        self.sector_count = count as u8;
+        self.sector_count_exp = (count >> 8) as u8;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -61,3 +119,7 @@ pub struct ahci_cmd_tbl {
     res: [u8; 0x6c],
     pub sgs: [ahci_sg; AHCI_MAX_SG],
 }
+
+// The PRDT starts at offset 0x80 of the command table.
+const _: () = assert!(core::mem::offset_of!(ahci_cmd_tbl, sgs) == 0x80);
+const _: () = assert!(size_of::<ahci_cmd_tbl>() == 0x80 + AHCI_MAX_SG * size_of::<ahci_sg>());
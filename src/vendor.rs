@@ -0,0 +1,60 @@
+//! Access to the vendor specific register areas, for applying errata.
+
+use volatile::VolatilePtr;
+
+/// A vendor specific register area, as an array of 32-bit registers.
+///
+/// The meaning of the registers depends on the HBA; the driver never touches
+/// them. Obtained from
+/// [`AhciController::vendor_registers`](crate::AhciController::vendor_registers)
+/// (offsets 0xA0–0xFF of the HBA) or
+/// [`AhciDriver::port_vendor_registers`](crate::AhciDriver::port_vendor_registers)
+/// (offsets 0x70–0x7F of the port).
+pub struct VendorRegisters<'a> {
+    base: VolatilePtr<'a, u32>,
+    len: usize,
+}
+
+impl<'a> VendorRegisters<'a> {
+    /// # Safety
+    ///
+    /// `base` must point to `N` registers.
+    pub(crate) unsafe fn new<const N: usize>(base: VolatilePtr<'a, [u32; N]>) -> Self {
+        Self {
+            // SAFETY: An array starts with its first element.
+            base: unsafe { base.map(|regs| regs.cast()) },
+            len: N,
+        }
+    }
+
+    fn reg(&self, index: usize) -> VolatilePtr<'a, u32> {
+        assert!(index < self.len, "vendor register {index} out of range");
+        // SAFETY: `index` is within the area.
+        unsafe { self.base.map(|base| base.add(index)) }
+    }
+
+    /// Number of registers in the area.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the area has no registers.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Read the register at byte offset `index * 4` in the area.
+    pub fn read(&self, index: usize) -> u32 {
+        self.reg(index).read()
+    }
+
+    /// Write the register at byte offset `index * 4` in the area.
+    pub fn write(&mut self, index: usize, value: u32) {
+        self.reg(index).write(value);
+    }
+
+    /// Read, modify and write back the register at byte offset `index * 4`.
+    pub fn update(&mut self, index: usize, f: impl FnOnce(u32) -> u32) {
+        self.reg(index).update(f);
+    }
+}
@@ -0,0 +1,82 @@
+//! Per-port log verbosity, independent of the global filter of `log`.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use log::{Level, LevelFilter};
+
+/// Number of ports an HBA can have.
+const MAX_PORTS: usize = 32;
+/// Stored for ports that follow the global filter.
+const GLOBAL: u8 = u8::MAX;
+
+static LEVELS: [AtomicU8; MAX_PORTS] = [const { AtomicU8::new(GLOBAL) }; MAX_PORTS];
+
+/// Log the messages about port `port` up to `level`, e.g. to silence a
+/// flapping port or to debug one disk among many, or go back to the global
+/// filter of `log` with `None`.
+///
+/// Applies to port `port` of every HBA. The messages still pass through the
+/// filter of the installed logger, if it has one.
+pub fn set_log_verbosity(port: u8, level: Option<LevelFilter>) {
+    if let Some(slot) = LEVELS.get(port as usize) {
+        slot.store(level.map_or(GLOBAL, |level| level as u8), Ordering::Relaxed);
+    }
+}
+
+/// Verbosity set for port `port` with [`set_log_verbosity`], `None` if it
+/// follows the global filter.
+pub fn log_verbosity(port: u8) -> Option<LevelFilter> {
+    let level = LEVELS.get(port as usize)?.load(Ordering::Relaxed);
+    LevelFilter::iter().find(|filter| *filter as u8 == level)
+}
+
+/// Whether a message about port `port` at `level` is logged.
+pub(crate) fn port_enabled(port: u8, level: Level) -> bool {
+    level <= log_verbosity(port).unwrap_or_else(log::max_level)
+}
+
+/// Log a message about a port, subject to its verbosity rather than the
+/// global filter.
+macro_rules! port_log {
+    ($level:expr, $port:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::verbosity::port_enabled($port, level) {
+            log::logger().log(
+                &log::Record::builder()
+                    .args(format_args!($($arg)+))
+                    .level(level)
+                    .target(module_path!())
+                    .module_path_static(Some(module_path!()))
+                    .file_static(Some(file!()))
+                    .line(Some(line!()))
+                    .build(),
+            );
+        }
+    }};
+}
+
+macro_rules! port_error {
+    ($port:expr, $($arg:tt)+) => {
+        $crate::verbosity::port_log!(log::Level::Error, $port, $($arg)+)
+    };
+}
+
+macro_rules! port_warn {
+    ($port:expr, $($arg:tt)+) => {
+        $crate::verbosity::port_log!(log::Level::Warn, $port, $($arg)+)
+    };
+}
+
+macro_rules! port_info {
+    ($port:expr, $($arg:tt)+) => {
+        $crate::verbosity::port_log!(log::Level::Info, $port, $($arg)+)
+    };
+}
+
+macro_rules! port_debug {
+    ($port:expr, $($arg:tt)+) => {
+        $crate::verbosity::port_log!(log::Level::Debug, $port, $($arg)+)
+    };
+}
+
+pub(crate) use {port_debug, port_error, port_info, port_log, port_warn};
@@ -0,0 +1,85 @@
+//! Endurance estimation for solid state drives.
+
+#[cfg(feature = "smart")]
+use crate::SmartData;
+
+/// SMART attribute: SSD Life Left, normalized value is the remaining life in
+/// percent.
+#[cfg(feature = "smart")]
+const SMART_ATTR_LIFE_LEFT: u8 = 231;
+/// SMART attribute: Media Wearout Indicator, counts down from 100.
+#[cfg(feature = "smart")]
+const SMART_ATTR_MEDIA_WEAROUT: u8 = 233;
+/// SMART attribute: Total LBAs Written, raw value in 512-byte units.
+#[cfg(feature = "smart")]
+const SMART_ATTR_LBAS_WRITTEN: u8 = 241;
+
+/// Byte offset of "Logical Sectors Written" in the General Statistics page.
+const DEVSTAT_SECTORS_WRITTEN: usize = 0x18;
+/// Byte offset of "Percentage Used Endurance Indicator" in the Solid State
+/// Device Statistics page.
+const DEVSTAT_PERCENTAGE_USED: usize = 0x08;
+
+/// Estimated wear of a solid state drive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SsdWear {
+    /// Estimated percentage of the rated endurance that has been used.
+    ///
+    /// May exceed 100 once the drive is past its rated endurance.
+    pub percentage_used: Option<u8>,
+    /// Total bytes written by the host over the drive's lifetime.
+    pub bytes_written: Option<u64>,
+}
+
+impl SsdWear {
+    /// Whether no estimate is available.
+    pub fn is_empty(&self) -> bool {
+        self.percentage_used.is_none() && self.bytes_written.is_none()
+    }
+
+    /// Fill in values missing from `self` from `other`.
+    #[cfg(feature = "smart")]
+    pub(crate) fn or(self, other: Self) -> Self {
+        Self {
+            percentage_used: self.percentage_used.or(other.percentage_used),
+            bytes_written: self.bytes_written.or(other.bytes_written),
+        }
+    }
+
+    /// Parse the General Statistics and Solid State Device Statistics pages
+    /// of the Device Statistics log.
+    pub(crate) fn from_device_stats(general: &[u8], ssd: &[u8], sector_size: usize) -> Self {
+        Self {
+            percentage_used: devstat_value(ssd, DEVSTAT_PERCENTAGE_USED).map(|v| v as u8),
+            bytes_written: devstat_value(general, DEVSTAT_SECTORS_WRITTEN)
+                .map(|sectors| sectors.saturating_mul(sector_size as u64)),
+        }
+    }
+
+    /// Parse the vendor specific SMART attributes.
+    #[cfg(feature = "smart")]
+    pub(crate) fn from_smart(data: &SmartData) -> Self {
+        let mut wear = Self::default();
+        for attr in &data.attributes {
+            match attr.id {
+                SMART_ATTR_LIFE_LEFT | SMART_ATTR_MEDIA_WEAROUT if attr.value <= 100 => {
+                    wear.percentage_used = wear.percentage_used.or(Some(100 - attr.value));
+                }
+                SMART_ATTR_LBAS_WRITTEN => {
+                    wear.bytes_written = Some(attr.raw.saturating_mul(512));
+                }
+                _ => {}
+            }
+        }
+        wear
+    }
+}
+
+/// Read a statistic from a Device Statistics log page, if it is supported and
+/// valid.
+fn devstat_value(page: &[u8], offset: usize) -> Option<u64> {
+    let qword = u64::from_le_bytes(page[offset..offset + 8].try_into().unwrap());
+    let supported = qword & (1 << 63) != 0;
+    let valid = qword & (1 << 62) != 0;
+    (supported && valid).then_some(qword & 0xffff_ffff_ffff)
+}